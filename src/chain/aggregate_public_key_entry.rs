@@ -0,0 +1,94 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+use crate::chain::BlockIndex;
+use tapyrus::consensus::encode;
+use tapyrus::consensus::{Decodable, Encodable};
+use tapyrus::PublicKey;
+
+/// This struct is an entry of aggregate public key history. The federation can replace the
+/// aggregate public key by putting new one in xfield of block header. The new key comes into
+/// force from the next block, so the blocks from 'activated_height' must be signed by this key.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AggregatePublicKeyEntry {
+    /// The height of the first block which must be signed by the key.
+    pub activated_height: i32,
+    /// The aggregate public key.
+    pub public_key: PublicKey,
+}
+
+impl AggregatePublicKeyEntry {
+    /// Return the entry of the key in xfield of the block, or None if the block doesn't have it.
+    /// The key in genesis block is in force from genesis block itself.
+    pub fn from_block_index(index: &BlockIndex) -> Option<AggregatePublicKeyEntry> {
+        let public_key = index.header.aggregated_public_key()?;
+        let activated_height = if index.height == 0 {
+            0
+        } else {
+            index.height + 1
+        };
+        Some(AggregatePublicKeyEntry {
+            activated_height,
+            public_key,
+        })
+    }
+}
+
+impl Encodable for AggregatePublicKeyEntry {
+    #[inline]
+    fn consensus_encode<S: std::io::Write>(&self, mut s: S) -> Result<usize, encode::Error> {
+        let mut len = 0;
+        len += self.activated_height.consensus_encode(&mut s)?;
+        len += self.public_key.to_bytes().consensus_encode(&mut s)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for AggregatePublicKeyEntry {
+    #[inline]
+    fn consensus_decode<D: std::io::Read>(mut d: D) -> Result<Self, encode::Error> {
+        let activated_height = Decodable::consensus_decode(&mut d)?;
+        let bytes: Vec<u8> = Decodable::consensus_decode(&mut d)?;
+        let public_key = PublicKey::from_slice(&bytes)
+            .map_err(|_| encode::Error::ParseFailed("aggregate public key"))?;
+
+        Ok(AggregatePublicKeyEntry {
+            activated_height,
+            public_key,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helper::get_test_genesis_block;
+    use tapyrus::consensus::{deserialize, serialize};
+
+    const SERIALIZED_GENESIS_KEY_ENTRY: &str =
+        "000000002102260b9be70a87125fd0e2da368db857a2d8ee1cb85a3c8b81490f4f35f99b212a";
+
+    fn genesis_key_entry() -> AggregatePublicKeyEntry {
+        AggregatePublicKeyEntry {
+            activated_height: 0,
+            public_key: get_test_genesis_block()
+                .header
+                .aggregated_public_key()
+                .unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_encode() {
+        let expected = hex::decode(SERIALIZED_GENESIS_KEY_ENTRY).unwrap();
+        assert_eq!(serialize(&genesis_key_entry()), expected);
+    }
+
+    #[test]
+    fn test_decode() {
+        let entry: AggregatePublicKeyEntry =
+            deserialize(&hex::decode(SERIALIZED_GENESIS_KEY_ENTRY).unwrap()).unwrap();
+        assert_eq!(entry, genesis_key_entry());
+    }
+}
@@ -9,8 +9,11 @@ use tapyrus::{BlockHash, BlockHeader};
 /// 'height', 'next_blockhash' for that.
 #[derive(Debug, Clone, PartialEq)]
 pub struct BlockIndex {
+    /// The block header.
     pub header: BlockHeader,
+    /// The height of the block. The height of genesis block is 0.
     pub height: i32,
+    /// The hash of the next block in the active chain. It is all zero if the block is the tip.
     pub next_blockhash: BlockHash,
 }
 
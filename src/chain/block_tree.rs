@@ -0,0 +1,89 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+use crate::chain::BlockIndex;
+use std::collections::HashMap;
+use tapyrus::BlockHash;
+
+/// This struct holds block indexes which are not in the active chain, that is, blocks on the
+/// competing branches. Each branch is rooted at a block in the active chain and can be followed
+/// with 'prev_blockhash' of the headers.
+#[derive(Debug, Default)]
+pub struct BlockTree {
+    indexes: HashMap<BlockHash, BlockIndex>,
+}
+
+impl BlockTree {
+    pub fn new() -> BlockTree {
+        BlockTree {
+            indexes: HashMap::new(),
+        }
+    }
+
+    /// Return block index which is indicated by hash.
+    pub fn get(&self, hash: &BlockHash) -> Option<&BlockIndex> {
+        self.indexes.get(hash)
+    }
+
+    /// Return whether the tree has the block.
+    pub fn contains(&self, hash: &BlockHash) -> bool {
+        self.indexes.contains_key(hash)
+    }
+
+    /// Add block index to the tree. 'next_blockhash' is cleared because it is meaningful only in
+    /// the active chain.
+    pub fn insert(&mut self, mut index: BlockIndex) {
+        index.next_blockhash = BlockHash::default();
+        self.indexes.insert(index.header.block_hash(), index);
+    }
+
+    /// Remove block index from the tree.
+    pub fn remove(&mut self, hash: &BlockHash) -> Option<BlockIndex> {
+        self.indexes.remove(hash)
+    }
+
+    /// Return the branch which ends with the block indicated by `hash`. The blocks are ordered
+    /// from the tip of the branch to the fork point. The fork point block is not included.
+    pub fn branch(&self, hash: &BlockHash) -> Vec<BlockIndex> {
+        let mut branch = Vec::new();
+        let mut hash = *hash;
+
+        while let Some(index) = self.indexes.get(&hash) {
+            hash = index.header.prev_blockhash;
+            branch.push(index.clone());
+        }
+
+        branch
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helper::get_test_block_index;
+
+    #[test]
+    fn test_branch() {
+        let mut tree = BlockTree::new();
+        for i in 3..6 {
+            let mut index = get_test_block_index(i);
+            index.next_blockhash = get_test_block_index(i + 1).header.block_hash();
+            tree.insert(index);
+        }
+
+        assert!(tree.contains(&get_test_block_index(4).header.block_hash()));
+        assert_eq!(
+            tree.get(&get_test_block_index(4).header.block_hash()),
+            Some(&get_test_block_index(4))
+        );
+
+        let branch = tree.branch(&get_test_block_index(5).header.block_hash());
+        let heights: Vec<i32> = branch.iter().map(|index| index.height).collect();
+        assert_eq!(heights, vec![5, 4, 3]);
+
+        tree.remove(&get_test_block_index(4).header.block_hash());
+        let branch = tree.branch(&get_test_block_index(5).header.block_hash());
+        assert_eq!(branch, vec![get_test_block_index(5)]);
+    }
+}
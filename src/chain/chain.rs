@@ -2,36 +2,195 @@
 // Distributed under the MIT software license, see the accompanying
 // file COPYING or http://www.opensource.org/licenses/mit-license.php.
 
-use crate::chain::{BlockIndex, Error};
+use crate::chain::{AggregatePublicKeyEntry, BlockIndex, BlockTree, Checkpoints, Error};
 use core::cmp;
-use hex;
-use tapyrus::{Block, BlockHash, BlockHeader};
+use tapyrus::hashes::Hash;
+use tapyrus::{Block, BlockHash, BlockHeader, PublicKey};
 
 /// This struct presents the way to use single chain.
+///
+/// The chain consists of the active chain which is stored in `store` and the competing branches
+/// in `block_tree`. The longest valid signed chain is always selected as the active chain.
 #[derive(Debug)]
 pub struct Chain<T>
 where
     T: ChainStore,
 {
     store: T,
+    block_tree: BlockTree,
+    checkpoints: Checkpoints,
+}
+
+/// Changes of the active chain caused by connecting block header.
+///
+/// When reorganization occurs, `disconnected` has the blocks removed from the active chain which
+/// are ordered from the old tip, and `connected` has the blocks added to the active chain which
+/// are ordered from the fork point.
+#[derive(Debug, Default, PartialEq)]
+pub struct ChainChange {
+    /// The blocks removed from the active chain.
+    pub disconnected: Vec<BlockIndex>,
+    /// The blocks added to the active chain.
+    pub connected: Vec<BlockIndex>,
+}
+
+impl ChainChange {
+    /// Return whether the active chain is reorganized.
+    pub fn is_reorg(&self) -> bool {
+        !self.disconnected.is_empty()
+    }
+
+    /// Merge the change which occurred after this change. The block which was connected by this
+    /// change and disconnected by `other` is not reported in either list.
+    pub fn append(&mut self, other: ChainChange) {
+        for index in other.disconnected {
+            let hash = index.header.block_hash();
+            match self
+                .connected
+                .iter()
+                .position(|connected| connected.header.block_hash() == hash)
+            {
+                Some(i) => {
+                    self.connected.remove(i);
+                }
+                None => self.disconnected.push(index),
+            }
+        }
+        self.connected.extend(other.connected);
+    }
 }
 
 impl<T: ChainStore> Chain<T> {
+    /// Return chain which uses `store` as the active chain. The store should be initialized.
     pub fn new(store: T) -> Chain<T> {
-        Chain { store }
+        Chain::with_checkpoints(store, Checkpoints::default())
+    }
+
+    /// Return chain which accepts only the headers which are consistent with `checkpoints`.
+    pub fn with_checkpoints(store: T, checkpoints: Checkpoints) -> Chain<T> {
+        Chain {
+            store,
+            block_tree: BlockTree::new(),
+            checkpoints,
+        }
     }
 }
 
 impl<T: ChainStore> Chain<T> {
-    // validate block header and connect to chain tip.
-    // TODO: implement validation
-    pub fn connect_block_header(&mut self, header: BlockHeader) -> Result<(), Error> {
+    /// Validate block header and connect to the block tree. If the header makes the longer chain
+    /// than the active chain, the active chain is reorganized.
+    pub fn connect_block_header(&mut self, header: BlockHeader) -> Result<ChainChange, Error> {
+        let hash = header.block_hash();
+
+        if self.block_tree.contains(&hash) {
+            return Ok(ChainChange::default());
+        }
+
+        let prev = match self.get_block_index(&header.prev_blockhash)? {
+            Some(prev) => prev,
+            None => return Err(Error::DisconnectedHeader(hash)),
+        };
+
+        // The block is already in the active chain.
+        if prev.next_blockhash == hash {
+            return Ok(ChainChange::default());
+        }
+
+        let height = prev.height + 1;
+        let extends_tip = prev.header.block_hash() == self.tip()?.header.block_hash();
+
+        // The proofs below the last checkpoint are not verified, so only the headers extending
+        // the active chain are accepted there. Otherwise unsigned branches could grow the block
+        // tree without limit.
+        if !extends_tip && !self.checkpoints.should_verify_proof(height) {
+            return Err(Error::ForkBelowCheckpoint(hash));
+        }
+
+        match self.checkpoints.get(height) {
+            Some(checkpoint) if *checkpoint != hash => return Err(Error::CheckpointMismatch(hash)),
+            _ => {}
+        }
+
+        // The active chain must not be reorganized below the checkpoint which it already passed.
+        if !extends_tip {
+            if let Some(checkpoint_height) = self.checkpoints.last_height_at_or_below(self.height())
+            {
+                if self.fork_height(&prev)? < checkpoint_height {
+                    return Err(Error::ForkBelowCheckpoint(hash));
+                }
+            }
+        }
+
+        if self.checkpoints.should_verify_proof(height) {
+            let aggregate_public_key = self.aggregate_pubkey_for_next(&prev)?;
+            verify_block_proof(&header, &aggregate_public_key)?;
+        }
+
         let block_index = BlockIndex {
             header,
-            height: self.height() + 1,
+            height,
             next_blockhash: BlockHash::default(),
         };
 
+        if extends_tip {
+            self.connect_tip(&block_index)?;
+            return Ok(ChainChange {
+                disconnected: vec![],
+                connected: vec![block_index],
+            });
+        }
+
+        trace!(
+            "Block is added to competing branch. height: {}, hash: {}",
+            block_index.height,
+            hash
+        );
+        self.block_tree.insert(block_index);
+
+        if height > self.height() {
+            self.reorganize(&hash)
+        } else {
+            Ok(ChainChange::default())
+        }
+    }
+
+    /// Remove the blocks above the last checkpoint which the active chain passed if their proofs
+    /// were not verified. It should be called when a header extending the active chain conflicts
+    /// with the checkpoint, because the active chain may be a forged branch which can't be
+    /// reorganized otherwise.
+    pub fn rewind_unverified(&mut self) -> Result<ChainChange, Error> {
+        let height = self.height();
+        if self.checkpoints.should_verify_proof(height) {
+            return Ok(ChainChange::default());
+        }
+
+        let checkpoint_height = self
+            .checkpoints
+            .last_height_at_or_below(height)
+            .unwrap_or(0);
+        let disconnected = ((checkpoint_height + 1)..=height)
+            .rev()
+            .map(|height| self.get_active(height))
+            .collect::<Result<Vec<_>, _>>()?;
+        if disconnected.is_empty() {
+            return Ok(ChainChange::default());
+        }
+
+        warn!(
+            "Rewind unverified blocks to the checkpoint at height {}. disconnected: {}",
+            checkpoint_height,
+            disconnected.len()
+        );
+        self.store.rewind(checkpoint_height)?;
+
+        Ok(ChainChange {
+            disconnected,
+            connected: vec![],
+        })
+    }
+
+    /// Connect block to the tip of the active chain.
+    fn connect_tip(&mut self, block_index: &BlockIndex) -> Result<(), Error> {
         if log_enabled!(log::Level::Trace) {
             let hash = hex::encode(block_index.header.block_hash());
             trace!(
@@ -41,33 +200,213 @@ impl<T: ChainStore> Chain<T> {
             );
         }
 
-        self.store.update_tip(&block_index);
+        // The federation can replace the aggregate public key by putting new one in xfield. The
+        // store records the new key with the block, and it is used to verify the blocks after
+        // this block.
+        if block_index.header.aggregated_public_key().is_some() {
+            info!(
+                "Aggregate public key is updated at height {}.",
+                block_index.height
+            );
+        }
+        self.store.update_tip(block_index)
+    }
+
+    /// Switch the active chain to the branch which ends with the block indicated by `hash`.
+    fn reorganize(&mut self, hash: &BlockHash) -> Result<ChainChange, Error> {
+        let mut connected = self.block_tree.branch(hash);
+        connected.reverse();
 
+        // The branch is rooted at the active chain, so the fork point exists.
+        let fork_height = connected.first().unwrap().height - 1;
+
+        let disconnected = ((fork_height + 1)..=self.height())
+            .rev()
+            .map(|height| self.get_active(height))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        info!(
+            "Reorganize chain. fork point height: {}, disconnected: {}, connected: {}",
+            fork_height,
+            disconnected.len(),
+            connected.len()
+        );
+
+        self.store.rewind(fork_height)?;
+        for index in &disconnected {
+            self.block_tree.insert(index.clone());
+        }
+        // The branch is removed from the block tree only after all of it is connected, so that
+        // it is not lost if the store fails in the middle.
+        for index in &connected {
+            if let Err(e) = self.connect_tip(index) {
+                if let Err(restore_error) = self.restore_active_chain(fork_height, &disconnected) {
+                    error!(
+                        "Can not restore the active chain after reorganization failed: {}",
+                        restore_error
+                    );
+                }
+                return Err(e);
+            }
+        }
+        for index in &connected {
+            self.block_tree.remove(&index.header.block_hash());
+        }
+
+        Ok(ChainChange {
+            disconnected,
+            connected,
+        })
+    }
+
+    /// Reconnect the blocks which were disconnected by the failed reorganization. `disconnected`
+    /// is ordered from the old tip. The blocks which can't be reconnected are left in the block
+    /// tree.
+    fn restore_active_chain(
+        &mut self,
+        fork_height: i32,
+        disconnected: &[BlockIndex],
+    ) -> Result<(), Error> {
+        self.store.rewind(fork_height)?;
+        for index in disconnected.iter().rev() {
+            let mut index = index.clone();
+            index.next_blockhash = BlockHash::default();
+            self.store.update_tip(&index)?;
+            self.block_tree.remove(&index.header.block_hash());
+        }
         Ok(())
     }
 
+    /// Return block index which is indicated by hash from the active chain or the block tree.
+    fn get_block_index(&self, hash: &BlockHash) -> Result<Option<BlockIndex>, Error> {
+        match self.block_tree.get(hash) {
+            Some(index) => Ok(Some(index.clone())),
+            None => self.store.get_by_hash(hash),
+        }
+    }
+
+    /// Return block in the active chain at the height which is not above the tip.
+    fn get_active(&self, height: i32) -> Result<BlockIndex, Error> {
+        // Blocks at or below the tip always exist, so we can call unwrap()
+        Ok(self.get(height)?.unwrap())
+    }
+
+    /// Return height of the block in the active chain where the branch including the block
+    /// diverges.
+    fn fork_height(&self, index: &BlockIndex) -> Result<i32, Error> {
+        let mut index = index.clone();
+
+        while !self.store.contains(&index.header.block_hash())? {
+            // Branches are always rooted at the active chain, so we can call unwrap()
+            index = self.get_block_index(&index.header.prev_blockhash)?.unwrap();
+        }
+
+        Ok(index.height)
+    }
+
+    /// Return the ancestor of the block at specific height. The ancestor is in the same branch
+    /// with the block.
+    fn get_ancestor(&self, index: &BlockIndex, height: i32) -> Result<BlockIndex, Error> {
+        let mut index = index.clone();
+
+        // Follow the branch until reaching the active chain or the height.
+        while index.height > height && !self.store.contains(&index.header.block_hash())? {
+            // Branches are always rooted at the active chain, so we can call unwrap()
+            index = self.get_block_index(&index.header.prev_blockhash)?.unwrap();
+        }
+
+        if index.height == height {
+            Ok(index)
+        } else {
+            self.get_active(height)
+        }
+    }
+
+    /// Return aggregate public key which the next block of `prev` should be signed by.
+    fn aggregate_pubkey_for_next(&self, prev: &BlockIndex) -> Result<PublicKey, Error> {
+        let mut index = prev.clone();
+
+        // Follow the branch until reaching the active chain. The latest key in the branch is in
+        // force if the federation replaced the key in the branch.
+        while let Some(branch_index) = self.block_tree.get(&index.header.block_hash()) {
+            if let Some(public_key) = branch_index.header.aggregated_public_key() {
+                return Ok(public_key);
+            }
+            // Branches are always rooted at the active chain, so we can call unwrap()
+            index = self
+                .get_block_index(&branch_index.header.prev_blockhash)?
+                .unwrap();
+        }
+
+        // Genesis block always has aggregate public key, so we can call unwrap()
+        Ok(self.aggregate_pubkey_at(index.height + 1).unwrap())
+    }
+
+    /// Return aggregate public key which is in force at specific height. The block at the height
+    /// should be signed by this key.
+    pub fn aggregate_pubkey_at(&self, height: i32) -> Option<PublicKey> {
+        self.store
+            .aggregate_public_keys()
+            .iter()
+            .rev()
+            .find(|entry| entry.activated_height <= height)
+            .map(|entry| entry.public_key)
+    }
+
+    /// Make the blocks connected to the active chain durable. The store may buffer the writes
+    /// until this is called, so call it after connecting a batch of block headers.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.store.flush()
+    }
+
     /// Return height of tip.
     pub fn height(&self) -> i32 {
         self.store.height()
     }
 
     /// Return specific block which is indicated by height.
-    pub fn get(&self, height: i32) -> Option<BlockIndex> {
+    pub fn get(&self, height: i32) -> Result<Option<BlockIndex>, Error> {
         self.store.get(height)
     }
 
+    /// Return specific block which is indicated by hash from the active chain.
+    pub fn get_by_hash(&self, hash: &BlockHash) -> Result<Option<BlockIndex>, Error> {
+        self.store.get_by_hash(hash)
+    }
+
+    /// Return whether the active chain has the block.
+    pub fn contains(&self, hash: &BlockHash) -> Result<bool, Error> {
+        self.store.contains(hash)
+    }
+
+    /// Return height of the block in the active chain.
+    pub fn height_of(&self, hash: &BlockHash) -> Result<Option<i32>, Error> {
+        self.store.height_of(hash)
+    }
+
     /// Return latest block in this chain.
-    pub fn tip(&self) -> BlockIndex {
-        // Genesis block always exist, so we can call unwrap()
-        self.get(self.height()).unwrap()
+    pub fn tip(&self) -> Result<BlockIndex, Error> {
+        self.store.tip()
     }
 
     /// Return block hash list for indicate which blocks are include in block.
-    pub fn get_locator(&self) -> Vec<BlockHash> {
+    pub fn get_locator(&self) -> Result<Vec<BlockHash>, Error> {
+        // Tip always exists, so we can call unwrap()
+        Ok(self
+            .get_locator_from(&self.tip()?.header.block_hash())?
+            .unwrap())
+    }
+
+    /// Return block hash list for indicate which blocks are include in the branch which ends with
+    /// the block indicated by `hash`. Return None if the block is unknown.
+    pub fn get_locator_from(&self, hash: &BlockHash) -> Result<Option<Vec<BlockHash>>, Error> {
         let mut step: i32 = 1;
         let mut have = Vec::<BlockHash>::with_capacity(32);
 
-        let mut index = self.tip();
+        let mut index = match self.get_block_index(hash)? {
+            Some(index) => index,
+            None => return Ok(None),
+        };
 
         loop {
             have.push(index.header.block_hash());
@@ -78,63 +417,124 @@ impl<T: ChainStore> Chain<T> {
             }
 
             let height = cmp::max(index.height - step, 0);
-
-            // TODO: Add implementation for forked chain.
-            // Check this chain contains `index`. If it is true, update `index` like below. If it is
-            // not true, we should explore ancestor block and set to 'index'.
-            index = self.get(height).unwrap();
+            index = self.get_ancestor(&index, height)?;
 
             if have.len() > 10 {
                 step *= 2;
             }
         }
 
-        have
+        Ok(Some(have))
+    }
+}
+
+/// Verify that the proof of the header is the signature by the aggregate public key.
+fn verify_block_proof(header: &BlockHeader, aggregate_public_key: &PublicKey) -> Result<(), Error> {
+    let sighash = header.signature_hash().into_inner();
+
+    match header.proof {
+        Some(ref proof) if proof.verify(&sighash, aggregate_public_key).is_ok() => Ok(()),
+        _ => Err(Error::InvalidBlockProof(header.block_hash())),
     }
 }
 
 /// This is a trait which presents interfaces to access block headers storing anywhere (e.g. on
 /// memory, flash).
+///
+/// The implementations can be validated with the test suite in `store::conformance` module.
 pub trait ChainStore {
     /// Initialize chain store.
-    /// This method should be called before start to use store.
+    /// This method should be called before start to use store. Return error if the genesis block
+    /// doesn't have aggregate public key in xfield, because the following blocks can't be
+    /// verified without it.
     ///
     /// ## implemnt
     /// You should implement process which should be done before use store such as setting genesis
     /// block.
-    fn initialize(&mut self, genesis: Block) {
-        if let None = self.get(0) {
+    fn initialize(&mut self, genesis: Block) -> Result<(), Error> {
+        if self.get(0)?.is_none() {
+            if genesis.header.aggregated_public_key().is_none() {
+                return Err(Error::MissingAggregatePublicKey(
+                    genesis.header.block_hash(),
+                ));
+            }
             let genesis = BlockIndex {
                 header: genesis.header,
                 height: 0,
                 next_blockhash: BlockHash::default(),
             };
 
-            self.update_tip(&genesis);
+            self.update_tip(&genesis)?;
+            self.flush()?;
         }
+        Ok(())
     }
 
     /// Return height of tip.
     fn height(&self) -> i32;
 
-    /// Return specific block which is indicated by height.
-    fn get(&self, height: i32) -> Option<BlockIndex>;
+    /// Return specific block which is indicated by height. Return error if the store can not be
+    /// read.
+    fn get(&self, height: i32) -> Result<Option<BlockIndex>, Error>;
+
+    /// Return height of the block which is indicated by hash. Return None if this chain doesn't
+    /// have the block.
+    fn height_of(&self, hash: &BlockHash) -> Result<Option<i32>, Error>;
 
-    /// Update chain tip to passed BlockIndex.
-    fn update_tip(&mut self, index: &BlockIndex);
+    /// Return specific block which is indicated by hash.
+    fn get_by_hash(&self, hash: &BlockHash) -> Result<Option<BlockIndex>, Error> {
+        match self.height_of(hash)? {
+            Some(height) => self.get(height),
+            None => Ok(None),
+        }
+    }
+
+    /// Return whether this chain has the block which is indicated by hash.
+    fn contains(&self, hash: &BlockHash) -> Result<bool, Error> {
+        Ok(self.height_of(hash)?.is_some())
+    }
+
+    /// Update chain tip to passed BlockIndex. If the header has aggregate public key in xfield,
+    /// it is appended to the history in the same write. The write may be buffered until `flush`
+    /// is called. Return error if the store can not be written, and the chain should not be used
+    /// after that.
+    fn update_tip(&mut self, index: &BlockIndex) -> Result<(), Error>;
+
+    /// Remove blocks above the height from the chain, so the block at the height becomes the tip.
+    /// Aggregate public keys which were activated by removed blocks are also removed. Return
+    /// error if the height is above the tip.
+    fn rewind(&mut self, height: i32) -> Result<(), Error>;
+
+    /// Make the blocks written by `update_tip` durable. The stores which write each block
+    /// immediately don't need to implement this.
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
 
     /// Return latest block in this chain.
-    fn tip(&self) -> BlockIndex {
+    fn tip(&self) -> Result<BlockIndex, Error> {
         // Genesis block always exist, so we can call unwrap()
-        self.get(self.height()).unwrap()
+        Ok(self.get(self.height())?.unwrap())
     }
+
+    /// Return history of aggregate public keys ordered by activated height. It is called for
+    /// every block header, so the store should hold the history on memory.
+    ///
+    /// The history is determined by xfield of the stored headers, so a store may rebuild it from
+    /// the headers when it is opened instead of persisting it separately. It must be the same
+    /// after reopening in either way.
+    fn aggregate_public_keys(&self) -> &[AggregatePublicKeyEntry];
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::chain::store::OnMemoryChainStore;
-    use crate::test_helper::{get_chain, get_test_block_hash, get_test_headers};
+    use crate::test_helper::{
+        build_branch, build_signed_header, get_chain, get_test_block_hash, get_test_genesis_block,
+        get_test_headers, get_test_private_key, private_key_from_seed,
+    };
+    use tapyrus::blockdata::block::XField;
     use tapyrus::consensus::serialize;
 
     fn build_chain(height: usize) -> Chain<OnMemoryChainStore> {
@@ -154,12 +554,27 @@ mod tests {
     #[test]
     fn test_block_index_serialize() {
         let chain = build_chain(0);
-        let index = chain.get(0).unwrap();
+        let index = chain.get(0).unwrap().unwrap();
         let bytes = serialize(&index);
 
         println!("{:?}", bytes);
     }
 
+    #[test]
+    fn test_initialize_fails_without_aggregate_public_key() {
+        let mut genesis = get_test_genesis_block();
+        genesis.header.xfield = XField::None;
+
+        let mut store = OnMemoryChainStore::new();
+        match store.initialize(genesis.clone()) {
+            Err(Error::MissingAggregatePublicKey(hash)) => {
+                assert_eq!(hash, genesis.header.block_hash())
+            }
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        assert_eq!(store.height(), -1);
+    }
+
     #[test]
     fn test_connect_block_header_set_next_blockhash() {
         let mut chain = build_chain(0);
@@ -167,14 +582,434 @@ mod tests {
         let hash = header.block_hash();
 
         let _ = chain.connect_block_header(header);
-        assert_eq!(chain.get(0).unwrap().next_blockhash, hash);
+        assert_eq!(chain.get(0).unwrap().unwrap().next_blockhash, hash);
+    }
+
+    #[test]
+    fn test_connect_block_header_fails_when_proof_is_invalid() {
+        let mut chain = build_chain(0);
+
+        // tampered header
+        let mut header = get_test_headers(1, 1).pop().unwrap();
+        header.time += 1;
+        match chain.connect_block_header(header) {
+            Err(Error::InvalidBlockProof(_)) => {}
+            _ => panic!("should fail with InvalidBlockProof"),
+        }
+
+        // header which has no proof
+        let mut header = get_test_headers(1, 1).pop().unwrap();
+        header.proof = None;
+        match chain.connect_block_header(header) {
+            Err(Error::InvalidBlockProof(_)) => {}
+            _ => panic!("should fail with InvalidBlockProof"),
+        }
+
+        assert_eq!(chain.height(), 0);
+
+        // valid header
+        let header = get_test_headers(1, 1).pop().unwrap();
+        assert!(chain.connect_block_header(header).is_ok());
+        assert_eq!(chain.height(), 1);
+    }
+
+    #[test]
+    fn test_connect_block_header_fails_when_prev_block_is_unknown() {
+        let mut chain = build_chain(0);
+        let header = get_test_headers(2, 1).pop().unwrap();
+
+        match chain.connect_block_header(header) {
+            Err(Error::DisconnectedHeader(_)) => {}
+            _ => panic!("should fail with DisconnectedHeader"),
+        }
+        assert_eq!(chain.height(), 0);
+    }
+
+    #[test]
+    fn test_connect_block_header_ignores_known_header() {
+        let mut chain = build_chain(9);
+
+        let header = get_test_headers(5, 1).pop().unwrap();
+        assert_eq!(
+            chain.connect_block_header(header).unwrap(),
+            ChainChange::default()
+        );
+        assert_eq!(chain.height(), 9);
+    }
+
+    #[test]
+    fn test_reorganize() {
+        let mut chain = build_chain(9);
+        let branch = build_branch(&chain.get(7).unwrap().unwrap().header, 3);
+
+        // The branch which is not longer than the active chain doesn't change the active chain.
+        for header in &branch[..2] {
+            let change = chain.connect_block_header(header.clone()).unwrap();
+            assert_eq!(change, ChainChange::default());
+        }
+        assert_eq!(
+            chain.tip().unwrap().header,
+            get_test_headers(9, 1).pop().unwrap()
+        );
+
+        // The branch which becomes longer than the active chain is activated.
+        let change = chain.connect_block_header(branch[2].clone()).unwrap();
+        assert!(change.is_reorg());
+
+        let disconnected: Vec<BlockHash> = change
+            .disconnected
+            .iter()
+            .map(|index| index.header.block_hash())
+            .collect();
+        assert_eq!(
+            disconnected,
+            vec![get_test_block_hash(9), get_test_block_hash(8)]
+        );
+
+        let connected: Vec<BlockHeader> = change
+            .connected
+            .iter()
+            .map(|index| index.header.clone())
+            .collect();
+        assert_eq!(connected, branch);
+
+        assert_eq!(chain.height(), 10);
+        assert_eq!(chain.tip().unwrap().header, branch[2]);
+        assert_eq!(chain.get(8).unwrap().unwrap().header, branch[0]);
+        assert_eq!(
+            chain.get(7).unwrap().unwrap().next_blockhash,
+            branch[0].block_hash()
+        );
+
+        // The old branch can be activated again when it becomes longer.
+        let mut old_branch = get_test_headers(8, 2);
+        old_branch.extend(build_branch(&old_branch[1], 2));
+        let mut change = ChainChange::default();
+        for header in old_branch {
+            change = chain.connect_block_header(header).unwrap();
+        }
+        assert!(change.is_reorg());
+        assert_eq!(change.disconnected.len(), 3);
+        assert_eq!(change.connected.len(), 4);
+        assert_eq!(chain.height(), 11);
+        assert_eq!(
+            chain.get(9).unwrap().unwrap().header.block_hash(),
+            get_test_block_hash(9)
+        );
+    }
+
+    /// ChainStore which fails to store the block indicated by `fail_hash`.
+    struct FailingChainStore {
+        inner: OnMemoryChainStore,
+        fail_hash: Option<BlockHash>,
+    }
+
+    impl ChainStore for FailingChainStore {
+        fn height(&self) -> i32 {
+            self.inner.height()
+        }
+
+        fn get(&self, height: i32) -> Result<Option<BlockIndex>, Error> {
+            self.inner.get(height)
+        }
+
+        fn height_of(&self, hash: &BlockHash) -> Result<Option<i32>, Error> {
+            self.inner.height_of(hash)
+        }
+
+        fn update_tip(&mut self, index: &BlockIndex) -> Result<(), Error> {
+            if self.fail_hash == Some(index.header.block_hash()) {
+                return Err(std::io::Error::other("fail").into());
+            }
+            self.inner.update_tip(index)
+        }
+
+        fn rewind(&mut self, height: i32) -> Result<(), Error> {
+            self.inner.rewind(height)
+        }
+
+        fn aggregate_public_keys(&self) -> &[AggregatePublicKeyEntry] {
+            self.inner.aggregate_public_keys()
+        }
+    }
+
+    #[test]
+    fn test_restore_active_chain_when_reorganization_fails() {
+        let mut inner = OnMemoryChainStore::new();
+        inner.initialize(get_test_genesis_block()).unwrap();
+        let store = FailingChainStore {
+            inner,
+            fail_hash: None,
+        };
+        let mut chain = Chain::new(store);
+        for header in get_test_headers(1, 9) {
+            chain.connect_block_header(header).unwrap();
+        }
+        let old_tip = chain.tip().unwrap();
+
+        // The store fails in the middle of connecting the branch.
+        let branch = build_branch(&chain.get(7).unwrap().unwrap().header, 3);
+        chain.store.fail_hash = Some(branch[1].block_hash());
+        for header in &branch[..2] {
+            chain.connect_block_header(header.clone()).unwrap();
+        }
+        match chain.connect_block_header(branch[2].clone()) {
+            Err(Error::IoError(_)) => {}
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        assert_eq!(chain.tip().unwrap(), old_tip);
+        assert_eq!(
+            chain.get(8).unwrap().unwrap().header.block_hash(),
+            get_test_block_hash(8)
+        );
+
+        // The branch is kept, so it is activated when the store recovers.
+        chain.store.fail_hash = None;
+        let header = build_branch(&branch[2], 1).pop().unwrap();
+        let change = chain.connect_block_header(header.clone()).unwrap();
+        assert_eq!(change.disconnected.len(), 2);
+        assert_eq!(change.connected.len(), 4);
+        assert_eq!(chain.tip().unwrap().header, header);
+    }
+
+    #[test]
+    fn test_append_chain_change() {
+        let mut chain = build_chain(9);
+        let branch = build_branch(&chain.get(7).unwrap().unwrap().header, 3);
+        let mut old_branch = get_test_headers(8, 2);
+        old_branch.extend(build_branch(&old_branch[1], 2));
+
+        // The branch is activated and deactivated again within the merged change.
+        let mut change = ChainChange::default();
+        for header in branch.iter().chain(old_branch.iter()) {
+            change.append(chain.connect_block_header(header.clone()).unwrap());
+        }
+
+        let disconnected: Vec<BlockHash> = change
+            .disconnected
+            .iter()
+            .map(|index| index.header.block_hash())
+            .collect();
+        assert_eq!(
+            disconnected,
+            vec![get_test_block_hash(9), get_test_block_hash(8)]
+        );
+        let connected: Vec<BlockHeader> = change
+            .connected
+            .iter()
+            .map(|index| index.header.clone())
+            .collect();
+        assert_eq!(connected, old_branch);
+    }
+
+    #[test]
+    fn test_get_locator_from_branch() {
+        let mut chain = build_chain(20);
+        let branch = build_branch(&chain.get(15).unwrap().unwrap().header, 3);
+        for header in &branch {
+            let _ = chain.connect_block_header(header.clone());
+        }
+        assert_eq!(chain.height(), 20);
+
+        let mut expected: Vec<BlockHash> = branch.iter().rev().map(|h| h.block_hash()).collect();
+        for i in &[15, 14, 13, 12, 11, 10, 9, 8, 7, 5, 1, 0] {
+            expected.push(get_test_block_hash(*i as usize));
+        }
+        assert_eq!(
+            chain.get_locator_from(&branch[2].block_hash()).unwrap(),
+            Some(expected)
+        );
+
+        assert_eq!(chain.get_locator_from(&BlockHash::default()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_aggregate_public_key_rotation() {
+        let mut chain = build_chain(9);
+        let genesis_key = get_test_private_key();
+        let new_key = private_key_from_seed(1);
+        let secp = tapyrus::secp256k1::Secp256k1::signing_only();
+        let genesis_public_key = genesis_key.public_key(&secp);
+        let new_public_key = new_key.public_key(&secp);
+
+        // Block at height 10 is signed by current key and announces new key.
+        let header = build_signed_header(
+            &chain.tip().unwrap().header,
+            XField::AggregatePublicKey(new_public_key),
+            &genesis_key,
+        );
+        assert!(chain.connect_block_header(header).is_ok());
+
+        assert_eq!(chain.aggregate_pubkey_at(0), Some(genesis_public_key));
+        assert_eq!(chain.aggregate_pubkey_at(10), Some(genesis_public_key));
+        assert_eq!(chain.aggregate_pubkey_at(11), Some(new_public_key));
+
+        // Block at height 11 signed by old key is rejected.
+        let header = build_signed_header(&chain.tip().unwrap().header, XField::None, &genesis_key);
+        match chain.connect_block_header(header) {
+            Err(Error::InvalidBlockProof(_)) => {}
+            _ => panic!("should fail with InvalidBlockProof"),
+        }
+
+        // Block at height 11 signed by new key is accepted.
+        let header = build_signed_header(&chain.tip().unwrap().header, XField::None, &new_key);
+        assert!(chain.connect_block_header(header).is_ok());
+        assert_eq!(chain.height(), 11);
+    }
+
+    fn build_chain_with_checkpoints(checkpoints: Checkpoints) -> Chain<OnMemoryChainStore> {
+        let mut store = OnMemoryChainStore::new();
+        store.initialize(get_test_genesis_block()).unwrap();
+        Chain::with_checkpoints(store, checkpoints)
+    }
+
+    #[test]
+    fn test_connect_block_header_fails_when_conflicts_with_checkpoint() {
+        let checkpoints = Checkpoints::new(vec![(3, get_test_block_hash(3))], false);
+        let mut chain = build_chain_with_checkpoints(checkpoints);
+        for header in get_test_headers(1, 2) {
+            assert!(chain.connect_block_header(header).is_ok());
+        }
+
+        // validly signed header which is not the checkpoint
+        let header = build_signed_header(
+            &chain.tip().unwrap().header,
+            XField::None,
+            &get_test_private_key(),
+        );
+        match chain.connect_block_header(header) {
+            Err(Error::CheckpointMismatch(_)) => {}
+            _ => panic!("should fail with CheckpointMismatch"),
+        }
+        assert_eq!(chain.height(), 2);
+
+        let header = get_test_headers(3, 1).pop().unwrap();
+        assert!(chain.connect_block_header(header).is_ok());
+        assert_eq!(chain.height(), 3);
+    }
+
+    #[test]
+    fn test_connect_block_header_fails_when_fork_below_checkpoint() {
+        let checkpoints = Checkpoints::new(vec![(5, get_test_block_hash(5))], false);
+        let mut chain = build_chain_with_checkpoints(checkpoints);
+        for header in get_test_headers(1, 7) {
+            assert!(chain.connect_block_header(header).is_ok());
+        }
+
+        // branch from height 3 is refused even if it is longer.
+        let branch = build_branch(&chain.get(3).unwrap().unwrap().header, 6);
+        match chain.connect_block_header(branch[0].clone()) {
+            Err(Error::ForkBelowCheckpoint(_)) => {}
+            _ => panic!("should fail with ForkBelowCheckpoint"),
+        }
+        assert_eq!(chain.height(), 7);
+
+        // branch from the checkpoint is accepted.
+        for header in build_branch(&chain.get(5).unwrap().unwrap().header, 3) {
+            assert!(chain.connect_block_header(header).is_ok());
+        }
+        assert_eq!(chain.height(), 8);
+        assert_eq!(
+            chain.get(5).unwrap().unwrap().header.block_hash(),
+            get_test_block_hash(5)
+        );
+    }
+
+    #[test]
+    fn test_skip_proof_verification_below_checkpoint() {
+        // headers signed by the key which is not the aggregate public key.
+        let wrong_key = private_key_from_seed(1);
+        let mut headers = vec![get_test_genesis_block().header];
+        for _ in 0..4 {
+            let header = build_signed_header(headers.last().unwrap(), XField::None, &wrong_key);
+            headers.push(header);
+        }
+
+        let checkpoints = Checkpoints::new(vec![(3, headers[3].block_hash())], true);
+        let mut chain = build_chain_with_checkpoints(checkpoints);
+        for header in &headers[1..4] {
+            assert!(chain.connect_block_header(header.clone()).is_ok());
+        }
+        assert_eq!(chain.height(), 3);
+
+        // the proof above the last checkpoint is verified.
+        match chain.connect_block_header(headers[4].clone()) {
+            Err(Error::InvalidBlockProof(_)) => {}
+            _ => panic!("should fail with InvalidBlockProof"),
+        }
+        assert_eq!(chain.height(), 3);
+    }
+
+    #[test]
+    fn test_refuse_unsigned_branch_below_checkpoint() {
+        let checkpoints = Checkpoints::new(vec![(20, get_test_block_hash(20))], true);
+        let mut chain = build_chain_with_checkpoints(checkpoints);
+        for header in get_test_headers(1, 10) {
+            assert!(chain.connect_block_header(header).is_ok());
+        }
+
+        // long branch from height 3 which is signed by the wrong key.
+        let wrong_key = private_key_from_seed(1);
+        let mut prev = chain.get(3).unwrap().unwrap().header;
+        for _ in 0..100 {
+            let header = build_signed_header(&prev, XField::None, &wrong_key);
+            match chain.connect_block_header(header.clone()) {
+                Err(Error::ForkBelowCheckpoint(_)) | Err(Error::DisconnectedHeader(_)) => {}
+                _ => panic!("should refuse the branch"),
+            }
+            assert!(!chain.block_tree.contains(&header.block_hash()));
+            prev = header;
+        }
+        assert_eq!(chain.height(), 10);
+        assert_eq!(chain.tip().unwrap().header, get_test_headers(10, 1)[0]);
+    }
+
+    #[test]
+    fn test_rewind_unverified() {
+        let checkpoints = Checkpoints::new(
+            vec![(5, get_test_block_hash(5)), (20, get_test_block_hash(20))],
+            true,
+        );
+        let mut chain = build_chain_with_checkpoints(checkpoints);
+        for header in get_test_headers(1, 6) {
+            assert!(chain.connect_block_header(header).is_ok());
+        }
+
+        // forged branch which doesn't lead to the checkpoint at height 20.
+        let wrong_key = private_key_from_seed(1);
+        for _ in 0..13 {
+            let header =
+                build_signed_header(&chain.tip().unwrap().header, XField::None, &wrong_key);
+            assert!(chain.connect_block_header(header).is_ok());
+        }
+        assert_eq!(chain.height(), 19);
+        let header = build_signed_header(&chain.tip().unwrap().header, XField::None, &wrong_key);
+        match chain.connect_block_header(header) {
+            Err(Error::CheckpointMismatch(_)) => {}
+            _ => panic!("should fail with CheckpointMismatch"),
+        }
+
+        let change = chain.rewind_unverified().unwrap();
+        let disconnected: Vec<i32> = change.disconnected.iter().map(|i| i.height).collect();
+        assert_eq!(disconnected, (6..20).rev().collect::<Vec<i32>>());
+        assert_eq!(chain.height(), 5);
+
+        // the valid chain can be connected again.
+        for header in get_test_headers(6, 15) {
+            assert!(chain.connect_block_header(header).is_ok());
+        }
+        assert_eq!(chain.height(), 20);
+
+        // the blocks above the last checkpoint are verified, so they are never rewound.
+        assert_eq!(chain.rewind_unverified().unwrap(), ChainChange::default());
+        assert_eq!(chain.height(), 20);
     }
 
     #[test]
     fn test_get_locator() {
         // when chain size is 1
         let chain = build_chain(0);
-        assert_eq!(chain.get_locator(), vec![get_test_block_hash(0)]);
+        assert_eq!(chain.get_locator().unwrap(), vec![get_test_block_hash(0)]);
 
         // when chain size is 10
         let chain = build_chain(9);
@@ -183,7 +1018,7 @@ mod tests {
             .rev()
             .map(|v| v.block_hash())
             .collect();
-        assert_eq!(chain.get_locator(), expected);
+        assert_eq!(chain.get_locator().unwrap(), expected);
 
         // when chain size is 100
         let chain = build_chain(99);
@@ -194,6 +1029,6 @@ mod tests {
         ] {
             expected.push(get_test_block_hash(*i as usize));
         }
-        assert_eq!(chain.get_locator(), expected);
+        assert_eq!(chain.get_locator().unwrap(), expected);
     }
 }
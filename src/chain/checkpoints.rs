@@ -0,0 +1,96 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+use tapyrus::BlockHash;
+
+/// This struct holds the blocks which are trusted to be in the chain. The chain can not have
+/// another block at the height of a checkpoint and can not be reorganized below the checkpoint
+/// which the chain already passed.
+///
+/// If 'skip_proof_verification' is set, the proofs of the blocks up to the last checkpoint are
+/// not verified. It is safe because the headers which don't lead to the last checkpoint can not
+/// be longer than the chain which passes it. The competing branches are not accepted there, and
+/// the unverified blocks are rewound when the active chain conflicts with the checkpoint.
+#[derive(Debug, Clone, Default)]
+pub struct Checkpoints {
+    /// Pairs of height and block hash ordered by height.
+    entries: Vec<(i32, BlockHash)>,
+    skip_proof_verification: bool,
+}
+
+impl Checkpoints {
+    /// Return checkpoints which consist of pairs of height and block hash.
+    pub fn new(mut entries: Vec<(i32, BlockHash)>, skip_proof_verification: bool) -> Checkpoints {
+        entries.sort_by_key(|(height, _)| *height);
+        Checkpoints {
+            entries,
+            skip_proof_verification,
+        }
+    }
+
+    /// Return hash of the checkpoint at the height.
+    pub fn get(&self, height: i32) -> Option<&BlockHash> {
+        self.entries
+            .binary_search_by_key(&height, |(h, _)| *h)
+            .ok()
+            .map(|i| &self.entries[i].1)
+    }
+
+    /// Return height of the last checkpoint.
+    pub fn last_height(&self) -> Option<i32> {
+        self.entries.last().map(|(height, _)| *height)
+    }
+
+    /// Return height of the last checkpoint which is not above the height.
+    pub fn last_height_at_or_below(&self, height: i32) -> Option<i32> {
+        self.entries
+            .iter()
+            .rev()
+            .map(|(h, _)| *h)
+            .find(|h| *h <= height)
+    }
+
+    /// Return whether the proof of the block at the height should be verified.
+    pub fn should_verify_proof(&self, height: i32) -> bool {
+        match self.last_height() {
+            Some(last_height) if self.skip_proof_verification => height > last_height,
+            _ => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helper::get_test_block_hash;
+
+    #[test]
+    fn test_checkpoints() {
+        let checkpoints = Checkpoints::new(
+            vec![(10, get_test_block_hash(10)), (5, get_test_block_hash(5))],
+            false,
+        );
+
+        assert_eq!(checkpoints.get(5), Some(&get_test_block_hash(5)));
+        assert_eq!(checkpoints.get(10), Some(&get_test_block_hash(10)));
+        assert_eq!(checkpoints.get(7), None);
+        assert_eq!(checkpoints.last_height(), Some(10));
+        assert_eq!(checkpoints.last_height_at_or_below(4), None);
+        assert_eq!(checkpoints.last_height_at_or_below(5), Some(5));
+        assert_eq!(checkpoints.last_height_at_or_below(9), Some(5));
+        assert_eq!(checkpoints.last_height_at_or_below(20), Some(10));
+        assert!(checkpoints.should_verify_proof(1));
+    }
+
+    #[test]
+    fn test_should_verify_proof() {
+        let checkpoints = Checkpoints::new(vec![(10, get_test_block_hash(10))], true);
+        assert!(!checkpoints.should_verify_proof(1));
+        assert!(!checkpoints.should_verify_proof(10));
+        assert!(checkpoints.should_verify_proof(11));
+
+        let checkpoints = Checkpoints::new(vec![], true);
+        assert!(checkpoints.should_verify_proof(1));
+    }
+}
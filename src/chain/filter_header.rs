@@ -0,0 +1,68 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+use crate::chain::Error;
+use tapyrus::consensus::{Decodable, Encodable};
+use tapyrus::hash_types::FilterHash;
+use tapyrus::BlockHash;
+
+/// This struct is an entry of the filter header chain defined in BIP157. It has the hash of the
+/// block which the filter is for, so that the entries of disconnected blocks can be detected.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FilterHeaderEntry {
+    /// The hash of the block.
+    pub block_hash: BlockHash,
+    /// The filter header which commits to the basic filter of the block and the previous filter
+    /// header.
+    pub filter_header: FilterHash,
+}
+
+impl Encodable for FilterHeaderEntry {
+    #[inline]
+    fn consensus_encode<S: std::io::Write>(
+        &self,
+        mut s: S,
+    ) -> Result<usize, tapyrus::consensus::encode::Error> {
+        let mut len = 0;
+        len += self.block_hash.consensus_encode(&mut s)?;
+        len += self.filter_header.consensus_encode(&mut s)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for FilterHeaderEntry {
+    #[inline]
+    fn consensus_decode<D: std::io::Read>(
+        mut d: D,
+    ) -> Result<Self, tapyrus::consensus::encode::Error> {
+        Ok(FilterHeaderEntry {
+            block_hash: Decodable::consensus_decode(&mut d)?,
+            filter_header: Decodable::consensus_decode(&mut d)?,
+        })
+    }
+}
+
+/// This trait presents the storage of the filter header chain. The entries are stored from the
+/// genesis block in order of height, and follow the active chain of block headers. The store also
+/// keeps the cursor of the filter scan, so that the scan resumes after restart.
+pub trait FilterHeaderStore {
+    /// Return the height of the last entry. It is -1 if the store is empty.
+    fn height(&self) -> i32;
+
+    /// Return the entry at the height.
+    fn get(&self, height: i32) -> Option<FilterHeaderEntry>;
+
+    /// Append the entry at the next height of the last entry.
+    fn push(&mut self, entry: FilterHeaderEntry) -> Result<(), Error>;
+
+    /// Remove the entries above the height.
+    fn rewind(&mut self, height: i32) -> Result<(), Error>;
+
+    /// Return the height and hash of the last block whose filter was scanned.
+    fn scanned(&self) -> Option<(i32, BlockHash)>;
+
+    /// Store the height and hash of the last block whose filter was scanned. None means that no
+    /// block was scanned.
+    fn set_scanned(&mut self, scanned: Option<(i32, BlockHash)>) -> Result<(), Error>;
+}
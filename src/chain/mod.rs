@@ -7,18 +7,74 @@
 //! This is a module for storing chains which is consisted of block headers and provide useful API
 //! to access block headers in the chain.
 
+mod aggregate_public_key_entry;
 mod block_index;
+mod block_tree;
+#[allow(clippy::module_inception)]
 mod chain;
+mod checkpoints;
+mod filter_header;
 pub mod store;
 
+pub use aggregate_public_key_entry::AggregatePublicKeyEntry;
 pub use block_index::BlockIndex;
+pub(crate) use block_tree::BlockTree;
 pub use chain::Chain;
+pub use chain::ChainChange;
 pub use chain::ChainStore;
+pub use checkpoints::Checkpoints;
+pub use filter_header::{FilterHeaderEntry, FilterHeaderStore};
 
+use std::fmt;
+use tapyrus::BlockHash;
+
+/// Error which occurs in chain module.
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug)]
 pub enum Error {
+    /// IO error in the chain store.
+    IoError(std::io::Error),
+    /// Encode error of the data in the chain store.
     EncodeError(tapyrus::consensus::encode::Error),
+    /// Hashes error of the data in the chain store.
     BitcoinHashesError(bitcoin_hashes::Error),
+    /// The block header doesn't have valid proof signed by the aggregate public key.
+    InvalidBlockProof(BlockHash),
+    /// The previous block of the block header is unknown.
+    DisconnectedHeader(BlockHash),
+    /// The block header conflicts with the checkpoint at the same height.
+    CheckpointMismatch(BlockHash),
+    /// The block header is in the branch which diverges below the checkpoint, or it doesn't
+    /// extend the active chain below the last checkpoint where the proofs are not verified.
+    ForkBelowCheckpoint(BlockHash),
+    /// The height to rewind the chain to is above the tip.
+    RewindAboveTip(i32),
+    /// The genesis block doesn't have aggregate public key in xfield.
+    MissingAggregatePublicKey(BlockHash),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::IoError(e) => write!(f, "IO error: {}", e),
+            Error::EncodeError(e) => write!(f, "Encode error: {}", e),
+            Error::BitcoinHashesError(e) => write!(f, "Hashes error: {}", e),
+            Error::InvalidBlockProof(hash) => write!(f, "Invalid block proof: {}", hash),
+            Error::DisconnectedHeader(hash) => write!(f, "Disconnected header: {}", hash),
+            Error::CheckpointMismatch(hash) => write!(f, "Checkpoint mismatch: {}", hash),
+            Error::ForkBelowCheckpoint(hash) => write!(f, "Fork below checkpoint: {}", hash),
+            Error::RewindAboveTip(height) => write!(f, "Rewind above tip: {}", height),
+            Error::MissingAggregatePublicKey(hash) => {
+                write!(f, "Missing aggregate public key: {}", hash)
+            }
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Error {
+        Error::IoError(e)
+    }
 }
 
 impl From<tapyrus::consensus::encode::Error> for Error {
@@ -0,0 +1,302 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+//! # Conformance test suite for ChainStore
+//!
+//! The functions in this module check that a ChainStore implementation behaves as `Chain`
+//! expects. They panic if the store doesn't conform, so they can be called from unit tests of
+//! the store directly. This module is exported as `tapyrus_spv::conformance` with `conformance`
+//! feature.
+//!
+//! ```ignore
+//! #[test]
+//! fn test_conformance() {
+//!     conformance::test_chain_store(|| MyChainStore::new());
+//!     conformance::test_reload(|| MyChainStore::open(&path));
+//! }
+//! ```
+
+use crate::chain::{AggregatePublicKeyEntry, BlockIndex, ChainStore, Error};
+use crate::chain_params::ChainParams;
+use tapyrus::blockdata::block::XField;
+use tapyrus::secp256k1::{Secp256k1, SecretKey};
+use tapyrus::{Block, BlockHash, BlockHeader, Network, PrivateKey, PublicKey};
+
+/// Run all test cases which don't need persistence. `new_store` should return an empty store
+/// every time it is called.
+pub fn test_chain_store<S, F>(mut new_store: F)
+where
+    S: ChainStore,
+    F: FnMut() -> S,
+{
+    test_initialize_is_idempotent(new_store());
+    test_update_tip_sets_next_blockhash(new_store());
+    test_get_beyond_tip(new_store());
+    test_rewind_on_reorg(new_store());
+    test_rewind_above_tip(new_store());
+}
+
+/// Check that initializing the store twice doesn't change the store.
+pub fn test_initialize_is_idempotent<S: ChainStore>(mut store: S) {
+    let genesis = genesis_block();
+
+    store.initialize(genesis.clone()).unwrap();
+    store.initialize(genesis.clone()).unwrap();
+
+    assert_eq!(store.height(), 0, "Store should have only genesis block.");
+    assert_eq!(store.tip().unwrap().header, genesis.header);
+    assert_eq!(store.tip().unwrap().next_blockhash, BlockHash::default());
+    assert_eq!(
+        store.height_of(&genesis.header.block_hash()).unwrap(),
+        Some(0)
+    );
+    assert_eq!(
+        store.aggregate_public_keys(),
+        vec![AggregatePublicKeyEntry {
+            activated_height: 0,
+            public_key: genesis.header.aggregated_public_key().unwrap(),
+        }],
+        "Store should have the aggregate public key of genesis block."
+    );
+
+    // The tip must be kept if the store is initialized after extending the chain.
+    extend(&mut store, 3, 0, None);
+    store.initialize(genesis).unwrap();
+    assert_eq!(store.height(), 3, "Initialize should not reset the chain.");
+    assert_eq!(store.aggregate_public_keys().len(), 1);
+}
+
+/// Check that `update_tip` links the previous tip to the new tip.
+pub fn test_update_tip_sets_next_blockhash<S: ChainStore>(mut store: S) {
+    store.initialize(genesis_block()).unwrap();
+    let indexes = extend(&mut store, 5, 0, None);
+
+    assert_eq!(store.height(), 5);
+    assert_eq!(store.tip().unwrap(), *indexes.last().unwrap());
+
+    for index in &indexes {
+        let hash = index.header.block_hash();
+        let prev = store.get(index.height - 1).unwrap().unwrap();
+        assert_eq!(
+            prev.next_blockhash, hash,
+            "next_blockhash of block {} should be set.",
+            prev.height
+        );
+        assert_eq!(store.height_of(&hash).unwrap(), Some(index.height));
+        assert!(store.contains(&hash).unwrap());
+        assert_eq!(
+            store.get_by_hash(&hash).unwrap().map(|i| i.header),
+            Some(index.header.clone())
+        );
+    }
+    assert_eq!(store.tip().unwrap().next_blockhash, BlockHash::default());
+}
+
+/// Check that the blocks above the tip can not be got.
+pub fn test_get_beyond_tip<S: ChainStore>(mut store: S) {
+    store.initialize(genesis_block()).unwrap();
+    extend(&mut store, 5, 0, None);
+
+    assert_eq!(
+        store.get(6).unwrap(),
+        None,
+        "Block above the tip should not exist."
+    );
+    assert_eq!(store.get(100).unwrap(), None);
+    assert_eq!(
+        store.get(-1).unwrap(),
+        None,
+        "Block at negative height should not exist."
+    );
+    assert_eq!(store.height_of(&BlockHash::default()).unwrap(), None);
+    assert!(!store.contains(&BlockHash::default()).unwrap());
+
+    store.rewind(3).unwrap();
+    assert_eq!(
+        store.get(4).unwrap(),
+        None,
+        "Block above the tip should not exist after rewind."
+    );
+    assert_eq!(store.get(5).unwrap(), None);
+}
+
+/// Check that rewinding and extending the store switch the chain to another branch as `Chain`
+/// does in reorganization.
+pub fn test_rewind_on_reorg<S: ChainStore>(mut store: S) {
+    store.initialize(genesis_block()).unwrap();
+    let old_branch = extend(&mut store, 10, 0, Some(7));
+    assert_eq!(store.aggregate_public_keys().len(), 2);
+
+    store.rewind(5).unwrap();
+    assert_eq!(store.height(), 5);
+    assert_eq!(
+        store.tip().unwrap(),
+        old_branch[4],
+        "next_blockhash of the tip should be cleared."
+    );
+    for index in &old_branch[5..] {
+        let hash = index.header.block_hash();
+        assert!(
+            !store.contains(&hash).unwrap(),
+            "Disconnected block should not exist."
+        );
+        assert_eq!(store.get_by_hash(&hash).unwrap(), None);
+        assert_eq!(store.height_of(&hash).unwrap(), None);
+    }
+    assert_eq!(
+        store.aggregate_public_keys().len(),
+        1,
+        "The key activated above the tip should be removed."
+    );
+
+    let new_branch = extend(&mut store, 3, 1, None);
+    assert_eq!(store.height(), 8);
+    assert_eq!(
+        store.get(5).unwrap().unwrap().next_blockhash,
+        new_branch[0].header.block_hash()
+    );
+    for index in &new_branch {
+        let hash = index.header.block_hash();
+        assert_eq!(store.height_of(&hash).unwrap(), Some(index.height));
+        assert_eq!(
+            store.get(index.height).unwrap().map(|i| i.header),
+            Some(index.header.clone())
+        );
+    }
+}
+
+/// Check that rewinding above the tip fails without changing the store.
+pub fn test_rewind_above_tip<S: ChainStore>(mut store: S) {
+    store.initialize(genesis_block()).unwrap();
+    let indexes = extend(&mut store, 5, 0, None);
+
+    match store.rewind(6) {
+        Err(Error::RewindAboveTip(6)) => {}
+        r => panic!("Rewind above the tip should fail. result: {:?}", r),
+    }
+    assert_eq!(store.height(), 5);
+    assert_eq!(store.tip().unwrap(), *indexes.last().unwrap());
+}
+
+/// Check that the store keeps its contents after reopening. `open` should return the store which
+/// has the data written by the store returned previously. The store returned by the first call
+/// should be empty.
+pub fn test_reload<S, F>(mut open: F)
+where
+    S: ChainStore,
+    F: FnMut() -> S,
+{
+    let genesis = genesis_block();
+
+    let mut store = open();
+    store.initialize(genesis.clone()).unwrap();
+    let indexes = extend(&mut store, 10, 0, Some(7));
+    let keys = store.aggregate_public_keys().to_vec();
+    assert_eq!(
+        keys.len(),
+        2,
+        "update_tip should store the aggregate public key in xfield."
+    );
+    drop(store);
+
+    let mut store = open();
+    store.initialize(genesis.clone()).unwrap();
+    assert_eq!(
+        store.height(),
+        10,
+        "Store should keep the tip after reopen."
+    );
+    assert_eq!(store.tip().unwrap(), *indexes.last().unwrap());
+    assert_eq!(
+        store.get(3).unwrap().unwrap().next_blockhash,
+        indexes[3].header.block_hash()
+    );
+    assert_eq!(
+        store.height_of(&indexes[6].header.block_hash()).unwrap(),
+        Some(7)
+    );
+    assert_eq!(
+        store.aggregate_public_keys(),
+        keys,
+        "Aggregate public keys should survive reopen."
+    );
+
+    // Switch to another branch and reopen.
+    store.rewind(5).unwrap();
+    let new_branch = extend(&mut store, 3, 1, Some(6));
+    let keys = store.aggregate_public_keys().to_vec();
+    drop(store);
+
+    let mut store = open();
+    store.initialize(genesis).unwrap();
+    assert_eq!(store.height(), 8, "Store should keep the tip after rewind.");
+    assert_eq!(store.tip().unwrap(), *new_branch.last().unwrap());
+    assert!(!store.contains(&indexes[9].header.block_hash()).unwrap());
+    assert_eq!(store.get(9).unwrap(), None);
+    assert_eq!(store.aggregate_public_keys(), keys);
+}
+
+/// Extend the chain in the store with `count` blocks in the same way as `Chain`. `branch` makes
+/// different blocks from the other branches. If `key_height` is given, the block at the height
+/// has new aggregate public key in xfield. Return the added blocks.
+fn extend<S: ChainStore>(
+    store: &mut S,
+    count: i32,
+    branch: u32,
+    key_height: Option<i32>,
+) -> Vec<BlockIndex> {
+    let mut result = vec![];
+
+    for _ in 0..count {
+        let tip = store.tip().unwrap();
+        let height = tip.height + 1;
+        let xfield = if key_height == Some(height) {
+            XField::AggregatePublicKey(public_key_from_seed(height as u8))
+        } else {
+            XField::None
+        };
+
+        let index = BlockIndex {
+            header: build_header(&tip.header, xfield, branch),
+            height,
+            next_blockhash: BlockHash::default(),
+        };
+        store.update_tip(&index).unwrap();
+        result.push(index);
+    }
+
+    result
+}
+
+/// Build block header which is connected to `prev`. The proof is not set because ChainStore
+/// doesn't verify it.
+fn build_header(prev: &BlockHeader, xfield: XField, branch: u32) -> BlockHeader {
+    BlockHeader {
+        version: prev.version,
+        prev_blockhash: prev.block_hash(),
+        merkle_root: prev.merkle_root,
+        im_merkle_root: prev.im_merkle_root,
+        time: prev.time + 1 + branch * 1000,
+        xfield,
+        proof: None,
+    }
+}
+
+/// Return the genesis block of regtest which the test cases use.
+fn genesis_block() -> Block {
+    ChainParams::regtest().genesis
+}
+
+/// Return private key which is derived from seed. It is useful for making another federation.
+fn private_key_from_seed(seed: u8) -> PrivateKey {
+    PrivateKey {
+        compressed: true,
+        network: Network::Dev,
+        key: SecretKey::from_slice(&[seed; 32]).unwrap(),
+    }
+}
+
+fn public_key_from_seed(seed: u8) -> PublicKey {
+    private_key_from_seed(seed).public_key(&Secp256k1::signing_only())
+}
@@ -0,0 +1,490 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+use crate::chain::{AggregatePublicKeyEntry, BlockIndex, ChainStore, Error};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use tapyrus::consensus::{deserialize, serialize};
+use tapyrus::hashes::{sha256d, Hash};
+use tapyrus::BlockHash;
+
+/// File name for storing block indexes.
+pub const HEADERS_FILE_NAME: &str = "headers.dat";
+
+/// File name for storing the tip of the chain.
+pub const TIP_FILE_NAME: &str = "tip.dat";
+
+/// Size of length prefix and checksum suffix of each record in headers file.
+const RECORD_OVERHEAD: usize = 4 + 4;
+
+/// ChainStore which stores block indexes into files under the data directory.
+///
+/// ## File format
+/// * `headers.dat` is an append only file. Each record is consisted of the length of the payload
+///   (4 bytes little endian), the payload which is the consensus encoding of BlockIndex, and the
+///   checksum which is the first 4 bytes of double SHA256 of the payload.
+/// * `tip.dat` has the height and the hash of the tip. This file is replaced atomically by
+///   renaming temporary file, so it is never broken by crash.
+///
+/// Records are appended by `update_tip`, and they are synced and the tip is updated once by
+/// `flush`, so a batch of block headers costs a few fsyncs. When the store is opened, the records
+/// are scanned and broken records or records above the tip are discarded. The history of aggregate
+/// public keys is also rebuilt from xfield of the headers in this scan.
+pub struct FileChainStore {
+    headers_file: File,
+    tip_path: PathBuf,
+    headers: Vec<BlockIndex>,
+    heights: HashMap<BlockHash, i32>,
+    /// Start position of each record in headers file.
+    offsets: Vec<u64>,
+    /// Length of valid records in headers file.
+    file_len: u64,
+    aggregate_public_keys: Vec<AggregatePublicKeyEntry>,
+    /// Whether records are appended after the tip file was written.
+    dirty: bool,
+}
+
+impl FileChainStore {
+    /// Open the store in the data directory. The directory is created if it doesn't exist.
+    pub fn open(datadir: &Path) -> Result<FileChainStore, Error> {
+        fs::create_dir_all(datadir)?;
+
+        let headers_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(datadir.join(HEADERS_FILE_NAME))?;
+
+        let mut store = FileChainStore {
+            headers_file,
+            tip_path: datadir.join(TIP_FILE_NAME),
+            headers: vec![],
+            heights: HashMap::new(),
+            offsets: vec![],
+            file_len: 0,
+            aggregate_public_keys: vec![],
+            dirty: false,
+        };
+        store.load()?;
+
+        Ok(store)
+    }
+
+    /// Load block indexes from files and recover from the broken state.
+    fn load(&mut self) -> Result<(), Error> {
+        let tip = read_tip(&self.tip_path)?;
+
+        let mut buf = Vec::new();
+        self.headers_file.seek(SeekFrom::Start(0))?;
+        self.headers_file.read_to_end(&mut buf)?;
+
+        let mut pos = 0;
+        while let Some((index, len)) = read_record(&buf[pos..]) {
+            let connected = match self.headers.last() {
+                Some(prev) => {
+                    index.height == prev.height + 1
+                        && index.header.prev_blockhash == prev.header.block_hash()
+                }
+                None => index.height == 0,
+            };
+            if !connected {
+                break;
+            }
+
+            self.push_index(index, pos as u64);
+            pos += len;
+            self.file_len = pos as u64;
+        }
+
+        // Records above the tip are written before the tip was updated, or removed by rewind.
+        let tip_height = match tip {
+            Some((height, hash))
+                if self.get_index(height).map(|i| i.header.block_hash()) == Some(hash) =>
+            {
+                height
+            }
+            Some((height, _)) => {
+                warn!(
+                    "Tip at height {} is not found in {}. Recover with {} valid records.",
+                    height,
+                    HEADERS_FILE_NAME,
+                    self.headers.len()
+                );
+                self.height()
+            }
+            None => -1,
+        };
+        if tip_height < self.height() {
+            info!("Discard block indexes above height {}.", tip_height);
+            self.truncate(tip_height);
+        }
+
+        self.headers_file.set_len(self.file_len)?;
+        self.headers_file.sync_all()?;
+
+        if tip.map(|(height, _)| height) != Some(tip_height) {
+            if let Some(tip) = self.headers.last() {
+                write_tip(&self.tip_path, tip)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Return block index in memory at the height.
+    fn get_index(&self, height: i32) -> Option<&BlockIndex> {
+        self.headers.get(height as usize)
+    }
+
+    /// Add block index which is written in headers file to memory.
+    fn push_index(&mut self, index: BlockIndex, offset: u64) {
+        if let Some(prev) = self.headers.last_mut() {
+            prev.next_blockhash = index.header.block_hash();
+        }
+
+        // The history of aggregate public keys is rebuilt from xfield.
+        if let Some(entry) = AggregatePublicKeyEntry::from_block_index(&index) {
+            self.aggregate_public_keys.push(entry);
+        }
+
+        self.heights.insert(index.header.block_hash(), index.height);
+        self.offsets.push(offset);
+        self.headers.push(index);
+    }
+
+    /// Remove block indexes above the height from memory.
+    fn truncate(&mut self, height: i32) {
+        let len = (height + 1) as usize;
+
+        for index in self.headers.drain(len..) {
+            self.heights.remove(&index.header.block_hash());
+        }
+        if let Some(tip) = self.headers.last_mut() {
+            tip.next_blockhash = BlockHash::default();
+        }
+        if let Some(offset) = self.offsets.get(len) {
+            self.file_len = *offset;
+        }
+        self.offsets.truncate(len);
+        self.aggregate_public_keys
+            .retain(|entry| entry.activated_height <= height + 1);
+    }
+}
+
+impl ChainStore for FileChainStore {
+    fn height(&self) -> i32 {
+        self.headers.len() as i32 - 1
+    }
+
+    fn get(&self, height: i32) -> Result<Option<BlockIndex>, Error> {
+        Ok(self.get_index(height).cloned())
+    }
+
+    fn height_of(&self, hash: &BlockHash) -> Result<Option<i32>, Error> {
+        Ok(self.heights.get(hash).cloned())
+    }
+
+    fn update_tip(&mut self, index: &BlockIndex) -> Result<(), Error> {
+        let mut record_index = index.clone();
+        record_index.next_blockhash = BlockHash::default();
+        let record = encode_record(&record_index);
+
+        // A broken record left by failed write is overwritten by the next record, so the memory
+        // is updated only after the write succeeds.
+        let offset = self.file_len;
+        self.headers_file.seek(SeekFrom::Start(offset))?;
+        self.headers_file.write_all(&record)?;
+
+        self.file_len += record.len() as u64;
+        self.push_index(record_index, offset);
+        self.dirty = true;
+        Ok(())
+    }
+
+    fn rewind(&mut self, height: i32) -> Result<(), Error> {
+        if height > self.height() {
+            return Err(Error::RewindAboveTip(height));
+        }
+
+        // Update tip at first. The records above the tip are discarded when the store is opened
+        // even if the process is crashed before truncating the file. The records below the new
+        // tip are synced before the tip file refers to them.
+        let tip = self
+            .get_index(height)
+            .ok_or(Error::RewindAboveTip(height))?;
+        if self.dirty {
+            self.headers_file.sync_data()?;
+        }
+        write_tip(&self.tip_path, tip)?;
+        self.dirty = false;
+
+        let len = self
+            .offsets
+            .get((height + 1) as usize)
+            .cloned()
+            .unwrap_or(self.file_len);
+        self.headers_file.set_len(len)?;
+        self.headers_file.sync_data()?;
+
+        self.truncate(height);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        if !self.dirty {
+            return Ok(());
+        }
+
+        self.headers_file.sync_data()?;
+        // Genesis block always exist after a record is appended.
+        write_tip(&self.tip_path, self.headers.last().unwrap())?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    fn aggregate_public_keys(&self) -> &[AggregatePublicKeyEntry] {
+        &self.aggregate_public_keys
+    }
+}
+
+impl Drop for FileChainStore {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            warn!("Failed to flush {}: {}", HEADERS_FILE_NAME, e);
+        }
+    }
+}
+
+/// Encode block index as a record of headers file.
+fn encode_record(index: &BlockIndex) -> Vec<u8> {
+    let payload = serialize(index);
+    let checksum = sha256d::Hash::hash(&payload);
+
+    let mut record = Vec::with_capacity(payload.len() + RECORD_OVERHEAD);
+    record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    record.extend_from_slice(&payload);
+    record.extend_from_slice(&checksum[..4]);
+    record
+}
+
+/// Read a record from the head of the buffer. Return the block index and the length of the
+/// record, or None if the record is incomplete or broken.
+fn read_record(buf: &[u8]) -> Option<(BlockIndex, usize)> {
+    if buf.len() < RECORD_OVERHEAD {
+        return None;
+    }
+
+    let mut len_bytes = [0u8; 4];
+    len_bytes.copy_from_slice(&buf[..4]);
+    let payload_len = u32::from_le_bytes(len_bytes) as usize;
+    let record_len = payload_len.checked_add(RECORD_OVERHEAD)?;
+    if buf.len() < record_len {
+        return None;
+    }
+
+    let payload = &buf[4..4 + payload_len];
+    let checksum = sha256d::Hash::hash(payload);
+    if checksum[..4] != buf[4 + payload_len..record_len] {
+        return None;
+    }
+
+    deserialize(payload).ok().map(|index| (index, record_len))
+}
+
+/// Read the height and the hash of the tip from tip file.
+fn read_tip(path: &Path) -> Result<Option<(i32, BlockHash)>, Error> {
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let bytes = fs::read(path)?;
+    Ok(Some(deserialize(&bytes)?))
+}
+
+/// Replace tip file atomically.
+fn write_tip(path: &Path, tip: &BlockIndex) -> Result<(), Error> {
+    let tmp_path = path.with_extension("tmp");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(&serialize(&(tip.height, tip.header.block_hash())))?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chain::store::conformance;
+    use crate::test_helper::{get_test_block_index, get_test_genesis_block, temp_dir};
+
+    fn open_store(datadir: &Path) -> FileChainStore {
+        let mut store = FileChainStore::open(datadir).unwrap();
+        store.initialize(get_test_genesis_block()).unwrap();
+        store
+    }
+
+    #[test]
+    fn test_conformance() {
+        let datadir = temp_dir("file_chain_store");
+        let mut datadirs = vec![];
+        conformance::test_chain_store(|| {
+            let datadir = temp_dir("file_chain_store");
+            let store = FileChainStore::open(&datadir).unwrap();
+            datadirs.push(datadir);
+            store
+        });
+        conformance::test_reload(|| FileChainStore::open(&datadir).unwrap());
+
+        fs::remove_dir_all(&datadir).unwrap();
+        for datadir in datadirs {
+            fs::remove_dir_all(&datadir).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_store_and_reload() {
+        let datadir = temp_dir("test_store_and_reload");
+
+        let mut store = open_store(&datadir);
+        assert_eq!(store.height(), 0);
+        assert_eq!(store.aggregate_public_keys().len(), 1);
+        for i in 1..11 {
+            store.update_tip(&get_test_block_index(i)).unwrap();
+        }
+        drop(store);
+
+        let store = open_store(&datadir);
+        assert_eq!(store.height(), 10);
+        assert_eq!(store.tip().unwrap(), get_test_block_index(10));
+        assert_eq!(store.aggregate_public_keys().len(), 1);
+
+        let mut expected = get_test_block_index(3);
+        expected.next_blockhash = get_test_block_index(4).header.block_hash();
+        assert_eq!(store.get(3).unwrap(), Some(expected));
+        assert_eq!(
+            store
+                .height_of(&get_test_block_index(7).header.block_hash())
+                .unwrap(),
+            Some(7)
+        );
+
+        fs::remove_dir_all(&datadir).unwrap();
+    }
+
+    #[test]
+    fn test_rewind_and_reload() {
+        let datadir = temp_dir("test_rewind_and_reload");
+
+        let mut store = open_store(&datadir);
+        for i in 1..11 {
+            store.update_tip(&get_test_block_index(i)).unwrap();
+        }
+        store.rewind(5).unwrap();
+        store.update_tip(&get_test_block_index(6)).unwrap();
+        drop(store);
+
+        let store = open_store(&datadir);
+        assert_eq!(store.height(), 6);
+        assert_eq!(store.tip().unwrap(), get_test_block_index(6));
+
+        fs::remove_dir_all(&datadir).unwrap();
+    }
+
+    #[test]
+    fn test_recover_from_broken_record() {
+        let datadir = temp_dir("test_recover_from_broken_record");
+
+        let mut store = open_store(&datadir);
+        for i in 1..6 {
+            store.update_tip(&get_test_block_index(i)).unwrap();
+        }
+        drop(store);
+
+        // Crash while writing next record.
+        let record = encode_record(&get_test_block_index(6));
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(datadir.join(HEADERS_FILE_NAME))
+            .unwrap();
+        file.write_all(&record[..record.len() / 2]).unwrap();
+        drop(file);
+
+        let mut store = open_store(&datadir);
+        assert_eq!(store.height(), 5);
+        store.update_tip(&get_test_block_index(6)).unwrap();
+        drop(store);
+
+        let store = open_store(&datadir);
+        assert_eq!(store.height(), 6);
+        assert_eq!(store.tip().unwrap(), get_test_block_index(6));
+
+        fs::remove_dir_all(&datadir).unwrap();
+    }
+
+    #[test]
+    fn test_discard_records_above_tip() {
+        let datadir = temp_dir("test_discard_records_above_tip");
+
+        let mut store = open_store(&datadir);
+        for i in 1..6 {
+            store.update_tip(&get_test_block_index(i)).unwrap();
+        }
+        drop(store);
+
+        // Crash after writing the record but before updating tip.
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(datadir.join(HEADERS_FILE_NAME))
+            .unwrap();
+        file.write_all(&encode_record(&get_test_block_index(6)))
+            .unwrap();
+        drop(file);
+
+        let store = open_store(&datadir);
+        assert_eq!(store.height(), 5);
+        assert_eq!(store.tip().unwrap(), get_test_block_index(5));
+
+        fs::remove_dir_all(&datadir).unwrap();
+    }
+
+    #[test]
+    fn test_write_tip_on_flush() {
+        let datadir = temp_dir("test_write_tip_on_flush");
+        let tip_path = datadir.join(TIP_FILE_NAME);
+
+        let mut store = open_store(&datadir);
+        for i in 1..6 {
+            store.update_tip(&get_test_block_index(i)).unwrap();
+        }
+        assert_eq!(read_tip(&tip_path).unwrap().unwrap().0, 0);
+
+        store.flush().unwrap();
+        let hash = get_test_block_index(5).header.block_hash();
+        assert_eq!(read_tip(&tip_path).unwrap(), Some((5, hash)));
+
+        drop(store);
+        fs::remove_dir_all(&datadir).unwrap();
+    }
+
+    #[test]
+    fn test_rewind_above_tip() {
+        let datadir = temp_dir("test_rewind_above_tip");
+
+        let mut store = open_store(&datadir);
+        for i in 1..6 {
+            store.update_tip(&get_test_block_index(i)).unwrap();
+        }
+        match store.rewind(6) {
+            Err(Error::RewindAboveTip(6)) => {}
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        assert_eq!(store.height(), 5);
+
+        drop(store);
+        fs::remove_dir_all(&datadir).unwrap();
+    }
+}
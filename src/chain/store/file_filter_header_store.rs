@@ -0,0 +1,275 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+use crate::chain::{Error, FilterHeaderEntry, FilterHeaderStore};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use tapyrus::consensus::{deserialize, serialize};
+use tapyrus::hashes::{sha256d, Hash};
+use tapyrus::BlockHash;
+
+/// File name for storing filter headers.
+pub const FILTER_HEADERS_FILE_NAME: &str = "filter_headers.dat";
+
+/// File name for storing the cursor of the filter scan.
+pub const FILTER_SCAN_FILE_NAME: &str = "filter_scan.dat";
+
+/// Size of each record in filter headers file. The payload is the block hash and the filter
+/// header, and the checksum follows it.
+const RECORD_LEN: usize = 32 + 32 + 4;
+
+/// FilterHeaderStore which stores the filter headers into a file under the data directory, next
+/// to the files of FileChainStore.
+///
+/// ## File format
+/// * `filter_headers.dat` is an append only file of fixed length records. Each record is
+///   consisted of the consensus encoding of FilterHeaderEntry and the checksum which is the first
+///   4 bytes of double SHA256 of it. The record at offset `height * 68` is for the height.
+/// * `filter_scan.dat` has the height and the hash of the last scanned block, followed by the
+///   checksum in the same way. It is empty if no block was scanned. It is replaced by renaming
+///   the new file, so that it is not broken by crash.
+///
+/// The entries are loaded on memory when the store is opened, and the broken record at the end
+/// which is written partially by crash is discarded.
+pub struct FileFilterHeaderStore {
+    file: File,
+    entries: Vec<FilterHeaderEntry>,
+    datadir: PathBuf,
+    scanned: Option<(i32, BlockHash)>,
+}
+
+impl FileFilterHeaderStore {
+    /// Open the store in the data directory. The directory is created if it doesn't exist.
+    pub fn open(datadir: &Path) -> Result<FileFilterHeaderStore, Error> {
+        fs::create_dir_all(datadir)?;
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(datadir.join(FILTER_HEADERS_FILE_NAME))?;
+
+        let mut buf = Vec::new();
+        file.seek(SeekFrom::Start(0))?;
+        file.read_to_end(&mut buf)?;
+
+        let entries: Vec<FilterHeaderEntry> = buf
+            .chunks_exact(RECORD_LEN)
+            .map_while(read_record)
+            .collect();
+        if entries.len() * RECORD_LEN < buf.len() {
+            warn!(
+                "Discard broken records in {} above height {}.",
+                FILTER_HEADERS_FILE_NAME,
+                entries.len() as i32 - 1
+            );
+            file.set_len((entries.len() * RECORD_LEN) as u64)?;
+            file.sync_all()?;
+        }
+
+        let scanned = read_scanned(&datadir.join(FILTER_SCAN_FILE_NAME))?;
+
+        Ok(FileFilterHeaderStore {
+            file,
+            entries,
+            datadir: datadir.to_path_buf(),
+            scanned,
+        })
+    }
+}
+
+impl FilterHeaderStore for FileFilterHeaderStore {
+    fn height(&self) -> i32 {
+        self.entries.len() as i32 - 1
+    }
+
+    fn get(&self, height: i32) -> Option<FilterHeaderEntry> {
+        if height < 0 {
+            return None;
+        }
+        self.entries.get(height as usize).cloned()
+    }
+
+    fn push(&mut self, entry: FilterHeaderEntry) -> Result<(), Error> {
+        let offset = (self.entries.len() * RECORD_LEN) as u64;
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(&encode_record(&entry))?;
+        self.file.sync_data()?;
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    fn rewind(&mut self, height: i32) -> Result<(), Error> {
+        let len = std::cmp::max(height + 1, 0) as usize;
+        self.entries.truncate(len);
+        self.file.set_len((len * RECORD_LEN) as u64)?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    fn scanned(&self) -> Option<(i32, BlockHash)> {
+        self.scanned
+    }
+
+    fn set_scanned(&mut self, scanned: Option<(i32, BlockHash)>) -> Result<(), Error> {
+        let mut buf = Vec::new();
+        if let Some(scanned) = scanned {
+            buf = serialize(&scanned);
+            let checksum = sha256d::Hash::hash(&buf);
+            buf.extend_from_slice(&checksum[..4]);
+        }
+
+        let path = self.datadir.join(FILTER_SCAN_FILE_NAME);
+        let tmp_path = path.with_extension("tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&buf)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &path)?;
+        self.scanned = scanned;
+        Ok(())
+    }
+}
+
+/// Read the cursor of the filter scan. The broken file is regarded as no block was scanned, and
+/// the filters are scanned again.
+fn read_scanned(path: &Path) -> Result<Option<(i32, BlockHash)>, Error> {
+    let buf = match fs::read(path) {
+        Ok(buf) => buf,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    if buf.is_empty() {
+        return Ok(None);
+    }
+
+    let scanned = if buf.len() > 4 {
+        let (payload, checksum) = buf.split_at(buf.len() - 4);
+        if sha256d::Hash::hash(payload)[..4] == *checksum {
+            deserialize(payload).ok()
+        } else {
+            None
+        }
+    } else {
+        None
+    };
+    if scanned.is_none() {
+        warn!("Discard broken {}.", FILTER_SCAN_FILE_NAME);
+    }
+    Ok(scanned)
+}
+
+/// Encode the entry as a record of filter headers file.
+fn encode_record(entry: &FilterHeaderEntry) -> Vec<u8> {
+    let mut record = serialize(entry);
+    let checksum = sha256d::Hash::hash(&record);
+    record.extend_from_slice(&checksum[..4]);
+    record
+}
+
+/// Decode the record, or return None if it is broken.
+fn read_record(record: &[u8]) -> Option<FilterHeaderEntry> {
+    let (payload, checksum) = record.split_at(RECORD_LEN - 4);
+    if sha256d::Hash::hash(payload)[..4] != *checksum {
+        return None;
+    }
+    deserialize(payload).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helper::{get_test_block_hash, temp_dir};
+    use tapyrus::hash_types::FilterHash;
+
+    fn entry(height: usize) -> FilterHeaderEntry {
+        FilterHeaderEntry {
+            block_hash: get_test_block_hash(height),
+            filter_header: FilterHash::hash(&[height as u8]),
+        }
+    }
+
+    #[test]
+    fn test_store_and_reload() {
+        let datadir = temp_dir("test_filter_header_store_and_reload");
+
+        let mut store = FileFilterHeaderStore::open(&datadir).unwrap();
+        assert_eq!(store.height(), -1);
+        for i in 0..10 {
+            store.push(entry(i)).unwrap();
+        }
+        store.rewind(5).unwrap();
+        store.push(entry(6)).unwrap();
+        drop(store);
+
+        let store = FileFilterHeaderStore::open(&datadir).unwrap();
+        assert_eq!(store.height(), 6);
+        assert_eq!(store.get(3), Some(entry(3)));
+        assert_eq!(store.get(6), Some(entry(6)));
+        assert_eq!(store.get(7), None);
+        assert_eq!(store.get(-1), None);
+
+        fs::remove_dir_all(&datadir).unwrap();
+    }
+
+    #[test]
+    fn test_store_scanned() {
+        let datadir = temp_dir("test_filter_header_store_scanned");
+
+        let mut store = FileFilterHeaderStore::open(&datadir).unwrap();
+        assert_eq!(store.scanned(), None);
+        store
+            .set_scanned(Some((3, get_test_block_hash(3))))
+            .unwrap();
+        drop(store);
+
+        let mut store = FileFilterHeaderStore::open(&datadir).unwrap();
+        assert_eq!(store.scanned(), Some((3, get_test_block_hash(3))));
+        store.set_scanned(None).unwrap();
+        drop(store);
+
+        let store = FileFilterHeaderStore::open(&datadir).unwrap();
+        assert_eq!(store.scanned(), None);
+        drop(store);
+
+        // The broken cursor is discarded.
+        fs::write(datadir.join(FILTER_SCAN_FILE_NAME), [1, 2, 3, 4, 5]).unwrap();
+        let store = FileFilterHeaderStore::open(&datadir).unwrap();
+        assert_eq!(store.scanned(), None);
+
+        fs::remove_dir_all(&datadir).unwrap();
+    }
+
+    #[test]
+    fn test_recover_from_broken_record() {
+        let datadir = temp_dir("test_filter_header_recover_from_broken_record");
+
+        let mut store = FileFilterHeaderStore::open(&datadir).unwrap();
+        for i in 0..5 {
+            store.push(entry(i)).unwrap();
+        }
+        drop(store);
+
+        // Crash while writing next record.
+        let record = encode_record(&entry(5));
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(datadir.join(FILTER_HEADERS_FILE_NAME))
+            .unwrap();
+        file.write_all(&record[..record.len() / 2]).unwrap();
+        drop(file);
+
+        let mut store = FileFilterHeaderStore::open(&datadir).unwrap();
+        assert_eq!(store.height(), 4);
+        store.push(entry(5)).unwrap();
+        drop(store);
+
+        let store = FileFilterHeaderStore::open(&datadir).unwrap();
+        assert_eq!(store.height(), 5);
+        assert_eq!(store.get(5), Some(entry(5)));
+
+        fs::remove_dir_all(&datadir).unwrap();
+    }
+}
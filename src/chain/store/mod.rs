@@ -2,6 +2,33 @@
 // Distributed under the MIT software license, see the accompanying
 // file COPYING or http://www.opensource.org/licenses/mit-license.php.
 
+//! # Store module
+//!
+//! This module has the implementations of ChainStore and the test suite to validate them, and
+//! the implementations of FilterHeaderStore.
+
+#[cfg(any(test, feature = "conformance"))]
+pub mod conformance;
+mod file_chain_store;
+mod file_filter_header_store;
 mod on_memory_chain_store;
+#[cfg(test)]
+mod on_memory_filter_header_store;
+#[cfg(feature = "sled")]
+mod sled_chain_store;
 
+pub use file_chain_store::FileChainStore;
+pub use file_filter_header_store::FileFilterHeaderStore;
 pub use on_memory_chain_store::OnMemoryChainStore;
+#[cfg(test)]
+pub use on_memory_filter_header_store::OnMemoryFilterHeaderStore;
+#[cfg(feature = "sled")]
+pub use sled_chain_store::SledChainStore;
+
+/// ChainStore which SPV node uses. It is selected by `sled` feature at build time.
+#[cfg(feature = "sled")]
+pub type DefaultChainStore = SledChainStore;
+
+/// ChainStore which SPV node uses. It is selected by `sled` feature at build time.
+#[cfg(not(feature = "sled"))]
+pub type DefaultChainStore = FileChainStore;
@@ -0,0 +1,52 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+use crate::chain::{Error, FilterHeaderEntry, FilterHeaderStore};
+use tapyrus::BlockHash;
+
+/// FilterHeaderStore which holds filter headers only on memory. It is useful for testing.
+#[derive(Default)]
+pub struct OnMemoryFilterHeaderStore {
+    entries: Vec<FilterHeaderEntry>,
+    scanned: Option<(i32, BlockHash)>,
+}
+
+impl OnMemoryFilterHeaderStore {
+    /// Return empty store.
+    pub fn new() -> OnMemoryFilterHeaderStore {
+        OnMemoryFilterHeaderStore::default()
+    }
+}
+
+impl FilterHeaderStore for OnMemoryFilterHeaderStore {
+    fn height(&self) -> i32 {
+        self.entries.len() as i32 - 1
+    }
+
+    fn get(&self, height: i32) -> Option<FilterHeaderEntry> {
+        if height < 0 {
+            return None;
+        }
+        self.entries.get(height as usize).cloned()
+    }
+
+    fn push(&mut self, entry: FilterHeaderEntry) -> Result<(), Error> {
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    fn rewind(&mut self, height: i32) -> Result<(), Error> {
+        self.entries.truncate(std::cmp::max(height + 1, 0) as usize);
+        Ok(())
+    }
+
+    fn scanned(&self) -> Option<(i32, BlockHash)> {
+        self.scanned
+    }
+
+    fn set_scanned(&mut self, scanned: Option<(i32, BlockHash)>) -> Result<(), Error> {
+        self.scanned = scanned;
+        Ok(())
+    }
+}
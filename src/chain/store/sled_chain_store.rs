@@ -0,0 +1,206 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+use crate::chain::{AggregatePublicKeyEntry, BlockIndex, ChainStore, Error};
+use std::path::Path;
+use tapyrus::consensus::{deserialize, serialize};
+use tapyrus::BlockHash;
+
+/// Key prefix for block index. The key is followed by big endian height.
+const PREFIX_BLOCK_INDEX: u8 = b'h';
+/// Key prefix for height of block. The key is followed by block hash.
+const PREFIX_HEIGHT: u8 = b'b';
+/// Key prefix for aggregate public key. The key is followed by big endian activated height.
+const PREFIX_AGGREGATE_PUBLIC_KEY: u8 = b'k';
+/// Key for the height and the hash of the tip.
+const KEY_TIP: &[u8] = b"tip";
+
+/// ChainStore which stores block indexes into sled embedded database.
+///
+/// Every update of the chain is applied with a single batch, so the database is always
+/// consistent even if the process is crashed while updating. The history of aggregate public keys
+/// is loaded on memory when the database is opened, and updated with the database.
+pub struct SledChainStore {
+    db: sled::Db,
+    height: i32,
+    aggregate_public_keys: Vec<AggregatePublicKeyEntry>,
+}
+
+impl SledChainStore {
+    /// Open the database in the directory. The directory is created if it doesn't exist.
+    pub fn open(path: &Path) -> Result<SledChainStore, Error> {
+        let db = sled::open(path).map_err(std::io::Error::from)?;
+
+        let height = match db.get(KEY_TIP).map_err(std::io::Error::from)? {
+            Some(value) => {
+                let (height, _): (i32, BlockHash) = deserialize(&value)?;
+                height
+            }
+            None => -1,
+        };
+
+        // Keys are ordered by big endian activated height.
+        let mut aggregate_public_keys = vec![];
+        for value in db.scan_prefix([PREFIX_AGGREGATE_PUBLIC_KEY]).values() {
+            let value = value.map_err(std::io::Error::from)?;
+            aggregate_public_keys.push(deserialize(&value)?);
+        }
+
+        Ok(SledChainStore {
+            db,
+            height,
+            aggregate_public_keys,
+        })
+    }
+
+    fn get_value(&self, key: &[u8]) -> Result<Option<sled::IVec>, Error> {
+        Ok(self.db.get(key).map_err(std::io::Error::from)?)
+    }
+
+    fn apply(&self, batch: sled::Batch) -> Result<(), Error> {
+        self.db.apply_batch(batch).map_err(std::io::Error::from)?;
+        Ok(())
+    }
+}
+
+impl ChainStore for SledChainStore {
+    fn height(&self) -> i32 {
+        self.height
+    }
+
+    fn get(&self, height: i32) -> Result<Option<BlockIndex>, Error> {
+        if height < 0 || height > self.height {
+            return Ok(None);
+        }
+
+        match self.get_value(&block_index_key(height))? {
+            Some(value) => Ok(Some(deserialize(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn height_of(&self, hash: &BlockHash) -> Result<Option<i32>, Error> {
+        match self.get_value(&height_key(hash))? {
+            Some(value) => Ok(Some(deserialize(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn update_tip(&mut self, index: &BlockIndex) -> Result<(), Error> {
+        let mut batch = sled::Batch::default();
+
+        if let Some(mut tip) = self.get(self.height)? {
+            tip.next_blockhash = index.header.block_hash();
+            batch.insert(block_index_key(tip.height), serialize(&tip));
+        }
+
+        let hash = index.header.block_hash();
+        batch.insert(block_index_key(index.height), serialize(index));
+        batch.insert(height_key(&hash), serialize(&index.height));
+        batch.insert(KEY_TIP, serialize(&(index.height, hash)));
+        let entry = AggregatePublicKeyEntry::from_block_index(index);
+        if let Some(ref entry) = entry {
+            batch.insert(
+                aggregate_public_key_key(entry.activated_height),
+                serialize(entry),
+            );
+        }
+        self.apply(batch)?;
+
+        self.height = index.height;
+        self.aggregate_public_keys.extend(entry);
+        Ok(())
+    }
+
+    fn rewind(&mut self, height: i32) -> Result<(), Error> {
+        let mut tip = match self.get(height)? {
+            Some(tip) => tip,
+            None => return Err(Error::RewindAboveTip(height)),
+        };
+
+        let mut batch = sled::Batch::default();
+
+        for h in (height + 1)..=self.height {
+            batch.remove(block_index_key(h));
+            if let Some(index) = self.get(h)? {
+                batch.remove(height_key(&index.header.block_hash()));
+            }
+        }
+
+        tip.next_blockhash = BlockHash::default();
+        batch.insert(block_index_key(height), serialize(&tip));
+        batch.insert(KEY_TIP, serialize(&(height, tip.header.block_hash())));
+
+        for entry in &self.aggregate_public_keys {
+            if entry.activated_height > height + 1 {
+                batch.remove(aggregate_public_key_key(entry.activated_height));
+            }
+        }
+
+        self.apply(batch)?;
+        self.height = height;
+        self.aggregate_public_keys
+            .retain(|entry| entry.activated_height <= height + 1);
+        Ok(())
+    }
+
+    fn aggregate_public_keys(&self) -> &[AggregatePublicKeyEntry] {
+        &self.aggregate_public_keys
+    }
+}
+
+fn block_index_key(height: i32) -> Vec<u8> {
+    let mut key = vec![PREFIX_BLOCK_INDEX];
+    key.extend_from_slice(&(height as u32).to_be_bytes());
+    key
+}
+
+fn height_key(hash: &BlockHash) -> Vec<u8> {
+    let mut key = vec![PREFIX_HEIGHT];
+    key.extend_from_slice(&hash[..]);
+    key
+}
+
+fn aggregate_public_key_key(activated_height: i32) -> Vec<u8> {
+    let mut key = vec![PREFIX_AGGREGATE_PUBLIC_KEY];
+    key.extend_from_slice(&(activated_height as u32).to_be_bytes());
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chain::store::conformance;
+    use crate::test_helper::temp_dir;
+
+    /// Open the database which may have been closed just before. Sled releases the lock of the
+    /// database in the background thread, so opening it is retried for a while.
+    fn reopen(path: &Path) -> SledChainStore {
+        for _ in 0..20 {
+            if let Ok(store) = SledChainStore::open(path) {
+                return store;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+        SledChainStore::open(path).unwrap()
+    }
+
+    #[test]
+    fn test_conformance() {
+        let datadir = temp_dir("sled_chain_store");
+        let mut datadirs = vec![];
+        conformance::test_chain_store(|| {
+            let datadir = temp_dir("sled_chain_store");
+            let store = SledChainStore::open(&datadir).unwrap();
+            datadirs.push(datadir);
+            store
+        });
+        conformance::test_reload(|| reopen(&datadir));
+
+        std::fs::remove_dir_all(&datadir).unwrap();
+        for datadir in datadirs {
+            std::fs::remove_dir_all(&datadir).unwrap();
+        }
+    }
+}
@@ -0,0 +1,125 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+use crate::chain::Checkpoints;
+use tapyrus::consensus::deserialize;
+use tapyrus::hash_types::FilterHash;
+use tapyrus::network::constants::{Network, NetworkId};
+use tapyrus::Block;
+
+/// Genesis block of Tapyrus public testnet.
+///
+/// The aggregate public key is 0366262690cbdf648132ce0c088962c6361112582364ede120f3780ab73438fc4b.
+const TESTNET_GENESIS: &str = "01000000000000000000000000000000000000000000000000000000000000000000000044cc181bd0e95c5b999a13d1fc0d193fa8223af97511ad2098217555a841b3518f18ec2536f0bb9d6d4834fcc712e9563840fe9f089db9e8fe890bffb82165849f52ba5e01210366262690cbdf648132ce0c088962c6361112582364ede120f3780ab73438fc4b402b1ed9996920f57a425f6f9797557c0e73d0c9fbafdebcaa796b136e0946ffa98d928f8130b6a572f83da39530b13784eeb7007465b673aa95091619e7ee208501010000000100000000000000000000000000000000000000000000000000000000000000000000000000ffffffff0100f2052a010000002776a92231415132437447336a686f37385372457a4b6533766636647863456b4a74356e7a4188ac00000000";
+
+/// Genesis block for regtest.
+///
+/// The aggregated keys for the chain based on this genesis block is here.
+/// private key: 9b90c1704259341b5d08a585abe3544f8b4a10dfdc97b402d274220c06da28a2
+/// public key: 02260b9be70a87125fd0e2da368db857a2d8ee1cb85a3c8b81490f4f35f99b212a
+const REGTEST_GENESIS: &str = "010000000000000000000000000000000000000000000000000000000000000000000000623fd6e71aaec98e129d8b447ba7c6fe88cd27346cc556353d2d8232a2829f0a49b4a19f4dc3f0526dca905dcaff6a8e34537d04b450e0ac5568ce89a9373e301665c860012102260b9be70a87125fd0e2da368db857a2d8ee1cb85a3c8b81490f4f35f99b212a40f457d5dd7caae6bf89a50efd13cf4a9e3857760f747e107d1184263e1212ef98cda52410bd822e92c44b4a22a2f6116a0df2b130afe315af6a7289567b32c1ca01010000000100000000000000000000000000000000000000000000000000000000000000000000000000ffffffff0100f2052a010000002776a9226d6b597162714c54584e52344568747853376e37734e5357385646546f314e55336e88ac00000000";
+
+/// Parameters for Blockchain network
+#[derive(Debug, Clone)]
+pub struct ChainParams {
+    /// Network Type
+    pub network: Network,
+    /// Genesis block for network to be connected
+    pub genesis: Block,
+    /// Network ID
+    pub network_id: NetworkId,
+    /// Default port which the peers listen on
+    pub port: u16,
+    /// Blocks which are trusted to be in the chain
+    pub checkpoints: Checkpoints,
+    /// Pairs of height and BIP157 filter header which are trusted to be in the filter header
+    /// chain. When none is configured, the filter headers are verified against the filter
+    /// checkpoints which the majority of the peers agree.
+    pub filter_checkpoints: Vec<(i32, FilterHash)>,
+    /// DNS names which are resolved to the addresses of the peers
+    pub seeds: Vec<String>,
+}
+
+impl ChainParams {
+    /// Return parameters for Tapyrus public testnet.
+    ///
+    /// Testnet has no checkpoints yet. The genesis block is trusted anyway, and the hashes of the
+    /// later blocks should be taken from a fully validating node before they are hard-coded here.
+    /// The embedder can set `checkpoints` with the blocks which it trusts until then.
+    pub fn testnet() -> ChainParams {
+        ChainParams {
+            network: Network::Prod,
+            genesis: decode_genesis(TESTNET_GENESIS),
+            network_id: NetworkId::TESTNET,
+            port: 2377,
+            checkpoints: Checkpoints::default(),
+            filter_checkpoints: vec![],
+            seeds: vec!["static-seed.tapyrus.dev.chaintope.com".to_string()],
+        }
+    }
+
+    /// Return parameters for regtest. The peer is expected to be run on local machine.
+    ///
+    /// Regtest has no checkpoints because the chain is generated locally and can be thrown away
+    /// at any time, so no block can be trusted in advance.
+    pub fn regtest() -> ChainParams {
+        ChainParams {
+            network: Network::Dev,
+            genesis: decode_genesis(REGTEST_GENESIS),
+            network_id: NetworkId::REGTEST,
+            port: 12383,
+            checkpoints: Checkpoints::default(),
+            filter_checkpoints: vec![],
+            seeds: vec![],
+        }
+    }
+
+    /// Return parameters for the network which is indicated by name. The name should be
+    /// "testnet" or "regtest".
+    pub fn from_name(name: &str) -> Option<ChainParams> {
+        match name {
+            "testnet" => Some(ChainParams::testnet()),
+            "regtest" => Some(ChainParams::regtest()),
+            _ => None,
+        }
+    }
+}
+
+fn decode_genesis(hex: &str) -> Block {
+    // The genesis blocks are hard-coded, so we can call unwrap()
+    deserialize(&hex::decode(hex).unwrap()).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tapyrus::hashes::Hash;
+
+    #[test]
+    fn test_presets() {
+        for name in &["testnet", "regtest"] {
+            let params = ChainParams::from_name(name).unwrap();
+            let header = &params.genesis.header;
+            let public_key = header.aggregated_public_key().unwrap();
+            let sighash = header.signature_hash().into_inner();
+            assert!(header.proof.unwrap().verify(&sighash, &public_key).is_ok());
+        }
+
+        assert_eq!(
+            ChainParams::testnet()
+                .genesis
+                .header
+                .block_hash()
+                .to_string(),
+            "038b114875c2f78f5a2fd7d8549a905f38ea5faee6e29a3d79e547151d6bdd8a"
+        );
+        assert!(ChainParams::from_name("unknown").is_none());
+    }
+
+    #[test]
+    fn test_checkpoints() {
+        assert!(ChainParams::testnet().checkpoints.last_height().is_none());
+        assert!(ChainParams::regtest().checkpoints.last_height().is_none());
+    }
+}
@@ -5,11 +5,58 @@
 extern crate android_logger;
 extern crate jni;
 
-use self::jni::objects::{JClass, JString};
-use self::jni::JNIEnv;
-use crate::tapyrus_spv_run;
+use self::jni::objects::{GlobalRef, JClass, JObject, JString, JValue};
+use self::jni::sys::jint;
+use self::jni::{JNIEnv, JavaVM};
+use crate::ffi::c::{broadcast_result_code, broadcast_transaction, run_with_network};
+use crate::{tapyrus_spv_run, tapyrus_spv_run_with_network, Resolver};
 use android_logger::{Config, FilterBuilder};
 use log::Level;
+use std::io;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+/// Resolver which calls `String[] resolve(String host)` method of the java object.
+struct JniResolver {
+    vm: JavaVM,
+    resolver: GlobalRef,
+}
+
+impl Resolver for JniResolver {
+    fn resolve(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        let to_io_error = |e: jni::errors::Error| io::Error::other(e.to_string());
+
+        let env = self.vm.attach_current_thread().map_err(to_io_error)?;
+        let host = env.new_string(host).map_err(to_io_error)?;
+        let addresses = env
+            .call_method(
+                self.resolver.as_obj(),
+                "resolve",
+                "(Ljava/lang/String;)[Ljava/lang/String;",
+                &[JValue::Object(host.into())],
+            )
+            .and_then(|value| value.l())
+            .map_err(to_io_error)?;
+        if addresses.is_null() {
+            return Err(io::Error::other("resolver returned null"));
+        }
+
+        let addresses = addresses.into_inner();
+        let len = env.get_array_length(addresses).map_err(to_io_error)?;
+        (0..len)
+            .map(|i| {
+                let address: JString = env
+                    .get_object_array_element(addresses, i)
+                    .map_err(to_io_error)?
+                    .into();
+                let address: String = env.get_string(address).map_err(to_io_error)?.into();
+                address
+                    .parse()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            })
+            .collect()
+    }
+}
 
 /// Make it possible to show logs on android
 #[no_mangle]
@@ -27,14 +74,16 @@ pub extern "system" fn Java_com_chaintope_tapyrus_spv_FFI_enableLog(_env: JNIEnv
     );
 }
 
-/// Run spv node
+/// Run spv node. The chain and the peers are stored in `datadir`.
 #[no_mangle]
 pub unsafe extern "C" fn Java_com_chaintope_tapyrus_spv_FFI_spvRun(
     env: JNIEnv,
     _: JClass,
     remote: JString,
     network: JString,
+    networkId: JString,
     genesisHex: JString,
+    datadir: JString,
 ) {
     tapyrus_spv_run(
         env.get_string(remote)
@@ -43,8 +92,143 @@ pub unsafe extern "C" fn Java_com_chaintope_tapyrus_spv_FFI_spvRun(
         env.get_string(network)
             .expect("invalid pattern string")
             .as_ptr(),
+        env.get_string(networkId)
+            .expect("invalid pattern string")
+            .as_ptr(),
         env.get_string(genesisHex)
             .expect("invalid pattern string")
             .as_ptr(),
+        env.get_string(datadir)
+            .expect("invalid pattern string")
+            .as_ptr(),
+    )
+}
+
+/// Run spv node on the network which is indicated by name such as "testnet" and "regtest".
+/// If `remote` is null, the peers are looked up from the DNS seeds of the network. The chain and
+/// the peers are stored in `datadir`.
+#[no_mangle]
+pub unsafe extern "C" fn Java_com_chaintope_tapyrus_spv_FFI_spvRunWithNetwork(
+    env: JNIEnv,
+    _: JClass,
+    remote: JString,
+    networkName: JString,
+    datadir: JString,
+) {
+    if remote.is_null() {
+        return tapyrus_spv_run_with_network(
+            std::ptr::null(),
+            env.get_string(networkName)
+                .expect("invalid pattern string")
+                .as_ptr(),
+            env.get_string(datadir)
+                .expect("invalid pattern string")
+                .as_ptr(),
+        );
+    }
+
+    tapyrus_spv_run_with_network(
+        env.get_string(remote)
+            .expect("invalid pattern string")
+            .as_ptr(),
+        env.get_string(networkName)
+            .expect("invalid pattern string")
+            .as_ptr(),
+        env.get_string(datadir)
+            .expect("invalid pattern string")
+            .as_ptr(),
     )
 }
+
+/// Run spv node on the network which is indicated by name such as "testnet" and "regtest", and
+/// resolve the DNS seeds with `resolver` object which has `String[] resolve(String host)` method.
+/// If `remote` is null, the peers are looked up from the DNS seeds of the network. The chain and
+/// the peers are stored in `datadir`.
+#[no_mangle]
+pub unsafe extern "C" fn Java_com_chaintope_tapyrus_spv_FFI_spvRunWithResolver(
+    env: JNIEnv,
+    _: JClass,
+    remote: JString,
+    networkName: JString,
+    datadir: JString,
+    resolver: JObject,
+) {
+    let remote: Option<String> = if remote.is_null() {
+        None
+    } else {
+        Some(
+            env.get_string(remote)
+                .expect("invalid pattern string")
+                .into(),
+        )
+    };
+    let network_name: String = env
+        .get_string(networkName)
+        .expect("invalid pattern string")
+        .into();
+    let datadir: String = env
+        .get_string(datadir)
+        .expect("invalid pattern string")
+        .into();
+
+    let resolver = JniResolver {
+        vm: env.get_java_vm().expect("can not get java vm"),
+        resolver: env
+            .new_global_ref(resolver)
+            .expect("can not create global reference of resolver"),
+    };
+
+    run_with_network(remote, &network_name, datadir, Arc::new(resolver))
+}
+
+/// Broadcast the serialized transaction in hex through the running spv node. `listener` object
+/// should have `void onBroadcast(String txid, int result, String reason)` method, which is called
+/// once with the outcome. `result` is 0 for accepted, 1 for rejected, 2 for sent without being
+/// seen from other peers and 3 for timed out. Return 0 if the broadcast is started, 1 if `txHex`
+/// is not a valid transaction or 2 if spv node is not running.
+#[no_mangle]
+pub unsafe extern "C" fn Java_com_chaintope_tapyrus_spv_FFI_broadcastTransaction(
+    env: JNIEnv,
+    _: JClass,
+    txHex: JString,
+    listener: JObject,
+) -> jint {
+    let tx_hex: String = env
+        .get_string(txHex)
+        .expect("invalid pattern string")
+        .into();
+
+    let vm = env.get_java_vm().expect("can not get java vm");
+    let listener = env
+        .new_global_ref(listener)
+        .expect("can not create global reference of listener");
+
+    broadcast_transaction(&tx_hex, move |txid, result| {
+        let (code, reason) = broadcast_result_code(&result);
+        let env = match vm.attach_current_thread() {
+            Ok(env) => env,
+            Err(e) => {
+                error!("Can not attach thread to java vm: {}", e);
+                return;
+            }
+        };
+        let called = env
+            .new_string(txid.to_string())
+            .and_then(|txid| env.new_string(reason).map(|reason| (txid, reason)))
+            .and_then(|(txid, reason)| {
+                env.call_method(
+                    listener.as_obj(),
+                    "onBroadcast",
+                    "(Ljava/lang/String;ILjava/lang/String;)V",
+                    &[
+                        JValue::Object(txid.into()),
+                        JValue::Int(code),
+                        JValue::Object(reason.into()),
+                    ],
+                )
+            });
+        if let Err(e) = called {
+            error!("Can not call broadcast listener: {}", e);
+        }
+    })
+}
@@ -2,13 +2,159 @@
 // Distributed under the MIT software license, see the accompanying
 // file COPYING or http://www.opensource.org/licenses/mit-license.php.
 
-use crate::{ChainParams, Options, SPV};
+use crate::chain::Checkpoints;
+use crate::{
+    BroadcastResult, ChainParams, ConnectionState, Options, Resolver, SystemResolver,
+    DEFAULT_GETHEADERS_TIMEOUT, DEFAULT_HANDSHAKE_TIMEOUT, DEFAULT_OUTBOUND_CONNECTIONS, SPV,
+};
 use env_logger::Env;
-use std::ffi::CStr;
+use std::ffi::{CStr, CString};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
 use std::os::raw::c_char;
+use std::sync::{Arc, Mutex};
 use tapyrus::consensus::deserialize;
 use tapyrus::network::constants::NetworkId;
-use tapyrus::Network;
+use tapyrus::{Network, Transaction, Txid};
+
+/// The size of the buffer which is passed to `ResolveCallback`.
+const RESOLVE_BUFFER_SIZE: usize = 4096;
+
+/// Callback which resolves DNS seed `host`. It writes the IP addresses separated by newline into
+/// `buf` as nul-terminated string, and returns 0 on success or other value on failure.
+pub type ResolveCallback =
+    extern "C" fn(host: *const c_char, buf: *mut c_char, buf_len: usize) -> i32;
+
+/// Resolver which calls back the host application.
+struct CallbackResolver {
+    callback: ResolveCallback,
+}
+
+impl Resolver for CallbackResolver {
+    fn resolve(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        let host =
+            CString::new(host).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let mut buf = vec![0u8; RESOLVE_BUFFER_SIZE];
+        let result = (self.callback)(host.as_ptr(), buf.as_mut_ptr() as *mut c_char, buf.len());
+        if result != 0 {
+            return Err(io::Error::other(format!(
+                "resolve callback failed with {}",
+                result
+            )));
+        }
+
+        // Don't read over the buffer even if the callback doesn't terminate the string.
+        let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+        let addresses = std::str::from_utf8(&buf[..len])
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        addresses
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(|line| {
+                line.parse()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            })
+            .collect()
+    }
+}
+
+/// Callback which is called when the state of the connections changes. `state` is 0 for
+/// disconnected, 1 for connecting and 2 for connected, and `peers` is the number of the connected
+/// peers.
+pub type ConnectionCallback = extern "C" fn(state: i32, peers: u32);
+
+static CONNECTION_CALLBACK: Mutex<Option<ConnectionCallback>> = Mutex::new(None);
+
+/// set callback which is called when the state of the connections changes. It should be called
+/// before running spv.
+#[no_mangle]
+pub extern "C" fn tapyrus_set_connection_callback(callback: ConnectionCallback) {
+    *CONNECTION_CALLBACK.lock().unwrap() = Some(callback);
+}
+
+/// Callback which is called when the progress of the block header download changes. `height` is
+/// the height of our chain, and `best_known_height` is the highest height which the peers
+/// advertised.
+pub type SyncProgressCallback = extern "C" fn(height: i32, best_known_height: i32);
+
+static SYNC_PROGRESS_CALLBACK: Mutex<Option<SyncProgressCallback>> = Mutex::new(None);
+
+/// set callback which is called when the progress of the block header download changes. It
+/// should be called before running spv.
+#[no_mangle]
+pub extern "C" fn tapyrus_set_sync_progress_callback(callback: SyncProgressCallback) {
+    *SYNC_PROGRESS_CALLBACK.lock().unwrap() = Some(callback);
+}
+
+/// Callback which is called once with the outcome of the transaction broadcast. `txid` is the
+/// transaction id in hex, and `result` is 0 for accepted, 1 for rejected, 2 for sent without
+/// being seen from other peers and 3 for timed out. `reason` is the reason of the rejection, or
+/// empty string for other results.
+pub type BroadcastCallback = extern "C" fn(txid: *const c_char, result: i32, reason: *const c_char);
+
+/// The spv which is running. Transactions are broadcast through it.
+static RUNNING_SPV: Mutex<Option<SPV>> = Mutex::new(None);
+
+/// Return the code of the broadcast result which is passed to the host application, with the
+/// reason of the rejection.
+pub(crate) fn broadcast_result_code(result: &BroadcastResult) -> (i32, &str) {
+    match result {
+        BroadcastResult::Accepted => (0, ""),
+        BroadcastResult::Rejected(reason) => (1, reason),
+        BroadcastResult::Sent => (2, ""),
+        BroadcastResult::TimedOut => (3, ""),
+    }
+}
+
+/// Broadcast the transaction in hex through the running spv, and call `listener` with the
+/// transaction id and the outcome. Return 0 on success, 1 if `tx_hex` is not a valid
+/// transaction or 2 if spv is not running.
+pub(crate) fn broadcast_transaction<F>(tx_hex: &str, listener: F) -> i32
+where
+    F: FnOnce(Txid, BroadcastResult) + Send + 'static,
+{
+    let tx: Transaction = match hex::decode(tx_hex)
+        .ok()
+        .and_then(|bytes| deserialize(&bytes).ok())
+    {
+        Some(tx) => tx,
+        None => return 1,
+    };
+
+    match RUNNING_SPV.lock().unwrap().as_ref() {
+        Some(spv) => {
+            let txid = tx.txid();
+            spv.broadcast_transaction(tx, move |result| listener(txid, result));
+            0
+        }
+        None => 2,
+    }
+}
+
+/// broadcast the transaction through the running spv. `tx_hex` is the serialized transaction in
+/// hex, and `callback` is called once with the outcome. Return 0 if the broadcast is started, 1
+/// if `tx_hex` is not a valid transaction or 2 if spv is not running.
+///
+/// # Safety
+///
+/// `tx_hex` must be valid pointer to nul-terminated C string.
+#[no_mangle]
+pub unsafe extern "C" fn tapyrus_spv_broadcast_transaction(
+    tx_hex: *const c_char,
+    callback: BroadcastCallback,
+) -> i32 {
+    let tx_hex = match CStr::from_ptr(tx_hex).to_str() {
+        Ok(tx_hex) => tx_hex,
+        Err(_) => return 1,
+    };
+    broadcast_transaction(tx_hex, move |txid, result| {
+        let txid = CString::new(txid.to_string()).unwrap();
+        let (code, reason) = broadcast_result_code(&result);
+        let reason = CString::new(reason).unwrap_or_default();
+        callback(txid.as_ptr(), code, reason.as_ptr());
+    })
+}
 
 /// initialize logger
 #[no_mangle]
@@ -20,20 +166,25 @@ pub extern "C" fn tapyrus_enable_log() {
     env_logger::try_init_from_env(env).unwrap();
 }
 
-/// run spv
+/// run spv. The chain and the peers are stored in `datadir`.
+///
+/// # Safety
+///
+/// All arguments must be valid pointers to nul-terminated C strings.
 #[no_mangle]
-pub extern "C" fn tapyrus_spv_run(
+pub unsafe extern "C" fn tapyrus_spv_run(
     remote: *const c_char,
     network: *const c_char,
     network_id: *const c_char,
     genesis_hex: *const c_char,
+    datadir: *const c_char,
 ) {
-    let remote = unsafe { CStr::from_ptr(remote) }
+    let remote = CStr::from_ptr(remote)
         .to_str()
         .expect("wrong string passed as remote address.")
         .to_string();
 
-    let network = unsafe { CStr::from_ptr(network) }
+    let network = CStr::from_ptr(network)
         .to_str()
         .expect("wrong string passed as network.");
 
@@ -43,30 +194,157 @@ pub extern "C" fn tapyrus_spv_run(
         _ => panic!("network should be \"prod\" or \"dev\""),
     };
 
-    let genesis_hex = unsafe { CStr::from_ptr(genesis_hex) }
+    let genesis_hex = CStr::from_ptr(genesis_hex)
         .to_str()
         .expect("wrong string passed as genesis_hex.");
 
     let genesis = deserialize(&hex::decode(genesis_hex).expect("genesis_hex is invalid hex."))
         .expect("genesis_hex is invalid block data");
 
-    let id = unsafe { CStr::from_ptr(network_id) }
+    let id = CStr::from_ptr(network_id)
         .to_str()
         .expect("wrong string passed as network_id.")
         .parse::<u32>()
         .expect("network_id must be integer.");
     let network_id = NetworkId::from(id);
 
+    let port = remote
+        .parse::<SocketAddr>()
+        .expect("remote should be socket address.")
+        .port();
+
+    let chain_params = ChainParams {
+        network,
+        genesis,
+        network_id,
+        port,
+        checkpoints: Checkpoints::default(),
+        filter_checkpoints: vec![],
+        seeds: vec![],
+    };
+
+    run(
+        Some(remote),
+        to_datadir(datadir),
+        chain_params,
+        Arc::new(SystemResolver),
+    );
+}
+
+/// run spv on the network which is indicated by name such as "testnet" and "regtest".
+/// If `remote` is NULL, the peers are looked up from the DNS seeds of the network. The chain and
+/// the peers are stored in `datadir`, so each network should have its own directory.
+///
+/// # Safety
+///
+/// `network_name` and `datadir` must be valid pointers to nul-terminated C strings. `remote` must
+/// be NULL or valid pointer to nul-terminated C string.
+#[no_mangle]
+pub unsafe extern "C" fn tapyrus_spv_run_with_network(
+    remote: *const c_char,
+    network_name: *const c_char,
+    datadir: *const c_char,
+) {
+    let network_name = CStr::from_ptr(network_name)
+        .to_str()
+        .expect("wrong string passed as network_name.");
+
+    run_with_network(
+        optional_remote(remote),
+        network_name,
+        to_datadir(datadir),
+        Arc::new(SystemResolver),
+    );
+}
+
+/// run spv on the network which is indicated by name such as "testnet" and "regtest", and
+/// resolve the DNS seeds with `resolve` callback. If `remote` is NULL, the peers are looked up
+/// from the DNS seeds of the network. The chain and the peers are stored in `datadir`, so each
+/// network should have its own directory.
+///
+/// # Safety
+///
+/// `network_name` and `datadir` must be valid pointers to nul-terminated C strings. `remote` must
+/// be NULL or valid pointer to nul-terminated C string.
+#[no_mangle]
+pub unsafe extern "C" fn tapyrus_spv_run_with_resolver(
+    remote: *const c_char,
+    network_name: *const c_char,
+    datadir: *const c_char,
+    resolve: ResolveCallback,
+) {
+    let network_name = CStr::from_ptr(network_name)
+        .to_str()
+        .expect("wrong string passed as network_name.");
+
+    run_with_network(
+        optional_remote(remote),
+        network_name,
+        to_datadir(datadir),
+        Arc::new(CallbackResolver { callback: resolve }),
+    );
+}
+
+unsafe fn to_datadir(datadir: *const c_char) -> String {
+    CStr::from_ptr(datadir)
+        .to_str()
+        .expect("wrong string passed as datadir.")
+        .to_string()
+}
+
+unsafe fn optional_remote(remote: *const c_char) -> Option<String> {
+    if remote.is_null() {
+        return None;
+    }
+
+    let remote = CStr::from_ptr(remote)
+        .to_str()
+        .expect("wrong string passed as remote address.");
+    Some(remote.to_string())
+}
+
+pub(crate) fn run_with_network(
+    remote: Option<String>,
+    network_name: &str,
+    datadir: String,
+    resolver: Arc<dyn Resolver>,
+) {
+    let chain_params = ChainParams::from_name(network_name)
+        .unwrap_or_else(|| panic!("unknown network name: \"{}\"", network_name));
+
+    run(remote, datadir, chain_params, resolver);
+}
+
+fn run(
+    remote: Option<String>,
+    datadir: String,
+    chain_params: ChainParams,
+    resolver: Arc<dyn Resolver>,
+) {
     let params = Options {
         remote,
-        datadir: "/tmp/tapyrus-spv".to_string(),
-        chain_params: ChainParams {
-            network,
-            genesis,
-            network_id,
-        },
+        datadir,
+        chain_params,
+        outbound_connections: DEFAULT_OUTBOUND_CONNECTIONS,
+        handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+        getheaders_timeout: DEFAULT_GETHEADERS_TIMEOUT,
+        compact_filters: false,
     };
 
-    let spv = SPV::new(params);
+    let mut spv = SPV::with_resolver(params, resolver);
+    if let Some(callback) = *CONNECTION_CALLBACK.lock().unwrap() {
+        spv.set_connection_listener(move |state| match state {
+            ConnectionState::Disconnected => callback(0, 0),
+            ConnectionState::Connecting => callback(1, 0),
+            ConnectionState::Connected { peers } => callback(2, peers as u32),
+        });
+    }
+    if let Some(callback) = *SYNC_PROGRESS_CALLBACK.lock().unwrap() {
+        spv.set_sync_progress_listener(move |progress| {
+            callback(progress.height, progress.best_known_height)
+        });
+    }
+    *RUNNING_SPV.lock().unwrap() = Some(spv.clone());
     spv.run();
+    *RUNNING_SPV.lock().unwrap() = None;
 }
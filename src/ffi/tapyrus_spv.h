@@ -2,7 +2,35 @@
 // Distributed under the MIT software license, see the accompanying
 // file COPYING or http://www.opensource.org/licenses/mit-license.php.
 
+#include <stddef.h>
 #include <stdint.h>
 
+// Resolve DNS seed `host`, and write the IP addresses separated by newline into `buf` as
+// nul-terminated string. Return 0 on success, or other value on failure.
+typedef int32_t (*tapyrus_resolve_callback)(const char* host, char* buf, size_t buf_len);
+
+// Called when the state of the connections changes. `state` is 0 for disconnected, 1 for
+// connecting and 2 for connected, and `peers` is the number of the connected peers.
+typedef void (*tapyrus_connection_callback)(int32_t state, uint32_t peers);
+
+// Called when the progress of the block header download changes. `height` is the height of our
+// chain, and `best_known_height` is the highest height which the peers advertised.
+typedef void (*tapyrus_sync_progress_callback)(int32_t height, int32_t best_known_height);
+
+// Called once with the outcome of the transaction broadcast. `txid` is the transaction id in hex,
+// and `result` is 0 for accepted, 1 for rejected, 2 for sent without being seen from other peers
+// and 3 for timed out. `reason` is the reason of the rejection, or empty string for other results.
+typedef void (*tapyrus_broadcast_callback)(const char* txid, int32_t result, const char* reason);
+
 void tapyrus_enable_log(void);
-void tapyrus_spv_run(const char* remote, const char* network, const char* genesis_hex);
+void tapyrus_set_connection_callback(tapyrus_connection_callback callback);
+void tapyrus_set_sync_progress_callback(tapyrus_sync_progress_callback callback);
+// Run spv. The chain and the peers are stored in `datadir`, so each network should have its own
+// directory.
+void tapyrus_spv_run(const char* remote, const char* network, const char* network_id, const char* genesis_hex, const char* datadir);
+void tapyrus_spv_run_with_network(const char* remote, const char* network_name, const char* datadir);
+void tapyrus_spv_run_with_resolver(const char* remote, const char* network_name, const char* datadir, tapyrus_resolve_callback resolve);
+
+// Broadcast the serialized transaction in hex through the running spv. Return 0 if the broadcast
+// is started, 1 if `tx_hex` is not a valid transaction or 2 if spv is not running.
+int32_t tapyrus_spv_broadcast_transaction(const char* tx_hex, tapyrus_broadcast_callback callback);
@@ -21,22 +21,43 @@ extern crate log;
 extern crate byteorder;
 extern crate bytes;
 
-use crate::chain::store::OnMemoryChainStore;
-use crate::chain::{Chain, ChainStore};
-use crate::network::{connect, BlockHeaderDownload, Handshake};
+use crate::chain::store::{FileFilterHeaderStore, OnMemoryChainStore};
+use crate::chain::Chain;
+use crate::network::{
+    query_seeds, AddressBook, BanList, BroadcastQueue, ConnectionListener, Mempool, PeerManager,
+    SyncProgressListener, TcpConnector, TransactionListener, WatchList, REQUIRED_SERVICES,
+};
+use std::net::SocketAddr;
 use std::path::Path;
 use std::sync::{Arc, Mutex};
-use tapyrus::network::constants::{Network, NetworkId};
-use tapyrus::Block;
+use std::time::Duration;
+use tapyrus::network::address::Address;
+use tapyrus::network::constants::ServiceFlags;
+use tapyrus::{OutPoint, Script, Transaction};
 use tokio::prelude::Future;
 
 mod chain;
+mod chain_params;
 mod ffi;
 mod network;
 
+#[cfg(feature = "conformance")]
+pub use crate::chain::store::conformance;
+#[cfg(feature = "sled")]
+pub use crate::chain::store::SledChainStore;
+pub use crate::chain::store::{DefaultChainStore, FileChainStore};
+#[cfg(not(feature = "conformance"))]
+use crate::chain::ChainStore;
+#[cfg(feature = "conformance")]
+pub use crate::chain::{AggregatePublicKeyEntry, BlockIndex, ChainStore, Error as ChainError};
+pub use crate::chain_params::ChainParams;
 #[cfg(target_os = "android")]
 pub use crate::ffi::android::*;
 pub use crate::ffi::c::*;
+pub use crate::network::{
+    BlockConsumer, BroadcastResult, ConnectionState, MatchedTransaction, PeerStats, Resolver,
+    SyncProgress, SystemResolver, DEFAULT_GETHEADERS_TIMEOUT, DEFAULT_HANDSHAKE_TIMEOUT,
+};
 
 #[cfg(test)]
 mod test_helper;
@@ -45,12 +66,131 @@ mod test_helper;
 #[derive(Clone)]
 pub struct SPV {
     options: Options,
+    resolver: Arc<dyn Resolver>,
+    peer_stats: Arc<Mutex<Vec<PeerStats>>>,
+    connection_listener: Option<ConnectionListener>,
+    sync_progress_listener: Option<SyncProgressListener>,
+    watch_list: Arc<Mutex<WatchList>>,
+    transaction_listener: Option<TransactionListener>,
+    disconnected_transaction_listener: Option<TransactionListener>,
+    block_consumer: Option<Arc<dyn BlockConsumer + Send + Sync>>,
+    broadcasts: Arc<Mutex<BroadcastQueue>>,
+    mempool: Arc<Mutex<Mempool>>,
 }
 
 impl SPV {
     /// returns SPV instance.
     pub fn new(params: Options) -> SPV {
-        SPV { options: params }
+        SPV::with_resolver(params, Arc::new(SystemResolver))
+    }
+
+    /// returns SPV instance which resolves DNS seeds with `resolver`.
+    pub fn with_resolver(params: Options, resolver: Arc<dyn Resolver>) -> SPV {
+        SPV {
+            options: params,
+            resolver,
+            peer_stats: Arc::new(Mutex::new(vec![])),
+            connection_listener: None,
+            sync_progress_listener: None,
+            watch_list: Arc::new(Mutex::new(WatchList::new())),
+            transaction_listener: None,
+            disconnected_transaction_listener: None,
+            block_consumer: None,
+            broadcasts: Arc::new(Mutex::new(BroadcastQueue::new())),
+            mempool: Arc::new(Mutex::new(Mempool::new())),
+        }
+    }
+
+    /// set callback which is called when the state of the connections to the network changes.
+    /// The disconnected peers are reconnected automatically with backoff while the node runs.
+    pub fn set_connection_listener<F>(&mut self, listener: F)
+    where
+        F: Fn(ConnectionState) + Send + Sync + 'static,
+    {
+        self.connection_listener = Some(Arc::new(listener));
+    }
+
+    /// set callback which is called when the progress of the block header download changes.
+    /// The progress is reported as our height and the highest height which the peers advertised.
+    pub fn set_sync_progress_listener<F>(&mut self, listener: F)
+    where
+        F: Fn(SyncProgress) + Send + Sync + 'static,
+    {
+        self.sync_progress_listener = Some(Arc::new(listener));
+    }
+
+    /// set callback which is called when the transaction which pays to the watched scripts or
+    /// spends the watched outpoints is found in the blocks.
+    pub fn set_transaction_listener<F>(&mut self, listener: F)
+    where
+        F: Fn(MatchedTransaction) + Send + Sync + 'static,
+    {
+        self.transaction_listener = Some(Arc::new(listener));
+    }
+
+    /// set callback which is called when the block including the transaction which was reported
+    /// to the transaction listener is disconnected by reorganization. The transaction is reported
+    /// with the height of the disconnected block, and returns to the unconfirmed transactions.
+    pub fn set_disconnected_transaction_listener<F>(&mut self, listener: F)
+    where
+        F: Fn(MatchedTransaction) + Send + Sync + 'static,
+    {
+        self.disconnected_transaction_listener = Some(Arc::new(listener));
+    }
+
+    /// set consumer which receives the full blocks downloaded after their compact block filters
+    /// matched the watched scripts. The blocks are verified against the block headers.
+    pub fn set_block_consumer<C>(&mut self, consumer: C)
+    where
+        C: BlockConsumer + Send + Sync + 'static,
+    {
+        self.block_consumer = Some(Arc::new(consumer));
+    }
+
+    /// watch the transactions which pay to `script`. The clone of the SPV instance which is
+    /// running can be used to add it, and the peers are told it immediately.
+    pub fn watch_script(&self, script: Script) {
+        self.watch_list.lock().unwrap().add_script(script);
+    }
+
+    /// watch the transactions which pay to `script` in the blocks from `birth_height`. The
+    /// compact block filters of the blocks are scanned again if they were scanned already.
+    pub fn watch_script_with_birth_height(&self, script: Script, birth_height: i32) {
+        self.watch_list
+            .lock()
+            .unwrap()
+            .add_script_with_birth_height(script, Some(birth_height));
+    }
+
+    /// watch the transactions which spend `outpoint`. The clone of the SPV instance which is
+    /// running can be used to add it, and the peers are told it immediately.
+    pub fn watch_outpoint(&self, outpoint: OutPoint) {
+        self.watch_list.lock().unwrap().add_outpoint(outpoint);
+    }
+
+    /// broadcast `tx` to the network. It is announced to the peers, and sent to the peers which
+    /// request it. `listener` is called once with the outcome when a peer rejects it, when other
+    /// peer announces it, or when the time limit expires. The clone of the SPV instance which is
+    /// running can be used to broadcast.
+    pub fn broadcast_transaction<F>(&self, tx: Transaction, listener: F)
+    where
+        F: FnOnce(BroadcastResult) + Send + 'static,
+    {
+        self.broadcasts.lock().unwrap().add(tx, Box::new(listener));
+    }
+
+    /// returns the unconfirmed transactions which pay to the watched scripts or spend the watched
+    /// outpoints, in the order they were found. They are announced by the peers which the bloom
+    /// filters are loaded to, and removed when they or the conflicting transactions are confirmed.
+    /// This is always empty with `Options::compact_filters`. The clone of the SPV instance which is running can be used to get them.
+    pub fn unconfirmed_transactions(&self) -> Vec<Transaction> {
+        self.mempool.lock().unwrap().transactions()
+    }
+
+    /// returns statistics of the connected peers. The clone of the SPV instance which is running
+    /// can be used to get them.
+    pub fn peer_stats(&self) -> Vec<PeerStats> {
+        self.peer_stats.lock().unwrap().clone()
     }
 
     /// run spv node.
@@ -60,35 +200,121 @@ impl SPV {
         // initialize chain_state
         let datadir_path = Path::new(&self.options.datadir);
         info!("datadir is {}", datadir_path.display());
-        let remote_socket_addr = self.options.remote.parse().expect(&format!(
-            "Can not parse remote peer address: \"{}\"",
-            self.options.remote
-        ));
-
-        let mut chain_store = OnMemoryChainStore::new();
-        chain_store.initialize(self.options.chain_params.genesis.clone());
-        let chain_active = Chain::new(chain_store);
+        // The peers are looked up from the seeds and the address book if the remote peer address
+        // is malformed.
+        let remote_socket_addr =
+            self.options
+                .remote
+                .as_ref()
+                .and_then(|remote| match remote.parse::<SocketAddr>() {
+                    Ok(addr) => Some(addr),
+                    Err(e) => {
+                        error!("Can not parse remote peer address \"{}\": {}", remote, e);
+                        None
+                    }
+                });
+
+        let mut chain_store = match DefaultChainStore::open(datadir_path) {
+            Ok(store) => store,
+            Err(e) => {
+                error!("Can not open chain store: {}", e);
+                return;
+            }
+        };
+        if let Err(e) = chain_store.initialize(self.options.chain_params.genesis.clone()) {
+            error!("Can not initialize chain store: {}", e);
+            return;
+        }
+        let chain_active =
+            Chain::with_checkpoints(chain_store, self.options.chain_params.checkpoints.clone());
         let chain_state = Arc::new(Mutex::new(ChainState::new(chain_active)));
 
-        let chain_state_for_block_header_download = chain_state.clone();
+        // The remote peer is added to the address book which the other peers are learned into.
+        let mut address_book = match AddressBook::open(datadir_path) {
+            Ok(book) => book,
+            Err(e) => {
+                error!("Can not open address book: {}", e);
+                return;
+            }
+        };
+        let chain_params = &self.options.chain_params;
+        if address_book.is_empty() {
+            query_seeds(
+                &*self.resolver,
+                &chain_params.seeds,
+                chain_params.port,
+                &mut address_book,
+            );
+        }
+        if let Some(remote_socket_addr) = remote_socket_addr {
+            address_book.add(
+                &Address::new(&remote_socket_addr, ServiceFlags::NETWORK),
+                network::address_book::now(),
+            );
+        }
+        if address_book.is_empty() {
+            error!("No peer address is known. Specify remote peer or DNS seeds.");
+            return;
+        }
+        info!("{} addresses are known.", address_book.len());
+
+        let ban_list = match BanList::open(datadir_path) {
+            Ok(list) => list,
+            Err(e) => {
+                error!("Can not open ban list: {}", e);
+                return;
+            }
+        };
 
-        info!(
-            "Connect to remote peer {}. Network is {}.",
-            remote_socket_addr, self.options.chain_params.network
+        let filter_header_store = if self.options.compact_filters {
+            match FileFilterHeaderStore::open(datadir_path) {
+                Ok(store) => Some(store),
+                Err(e) => {
+                    error!("Can not open filter header store: {}", e);
+                    return;
+                }
+            }
+        } else {
+            None
+        };
+
+        info!("Connect to peers. Network is {}.", chain_params.network);
+        let magic = chain_params.network_id.clone().magic();
+        let mut peer_manager = PeerManager::new(
+            TcpConnector::new(magic, self.options.handshake_timeout)
+                .require_services(self.options.required_services()),
+            chain_state,
+            address_book,
+            ban_list,
+            self.options.outbound_connections,
         );
-        let magic = self.options.chain_params.network_id.clone().magic();
-        let connection = connect(&remote_socket_addr, magic)
-            .and_then(|peer| Handshake::new(peer))
-            .and_then(move |peer| {
-                BlockHeaderDownload::new(peer, chain_state_for_block_header_download)
-            })
-            .map(move |_peer| {
-                let chain_state = chain_state.lock().unwrap();
-                let chain_active = chain_state.borrow_chain_active();
-                info!("current block height: {}", chain_active.height());
-            })
-            .map_err(|e| error!("Error: {:?}", e));
-        tokio::run(connection);
+        peer_manager.share_stats(self.peer_stats.clone());
+        peer_manager.set_getheaders_timeout(self.options.getheaders_timeout);
+        peer_manager.share_watch_list(self.watch_list.clone());
+        peer_manager.share_broadcasts(self.broadcasts.clone());
+        peer_manager.share_mempool(self.mempool.clone());
+        if let Some(store) = filter_header_store {
+            peer_manager
+                .enable_compact_filters(Box::new(store), chain_params.filter_checkpoints.clone());
+        }
+        if let Some(listener) = self.transaction_listener.clone() {
+            peer_manager.set_transaction_listener(listener);
+        }
+        if let Some(listener) = self.disconnected_transaction_listener.clone() {
+            peer_manager.set_disconnected_transaction_listener(listener);
+        }
+        if let Some(consumer) = self.block_consumer.clone() {
+            peer_manager.set_block_consumer(consumer);
+        }
+        if let Some(listener) = self.connection_listener.clone() {
+            peer_manager.set_connection_listener(listener);
+        }
+        if let Some(listener) = self.sync_progress_listener.clone() {
+            peer_manager.set_sync_progress_listener(listener);
+        }
+
+        // The manager keeps following new blocks, so this doesn't return until the process exits.
+        tokio::run(peer_manager.map_err(|e| error!("Error: {:?}", e)));
     }
 }
 
@@ -119,21 +345,38 @@ impl<T: ChainStore> ChainState<T> {
 /// Parameters for SPV node
 #[derive(Debug, Clone)]
 pub struct Options {
-    /// Remote peer address to connect.
-    pub remote: String,
+    /// Remote peer address to connect. If it is None or malformed, the peers are looked up from
+    /// DNS seeds.
+    pub remote: Option<String>,
     /// Data directory for putting database files.
     pub datadir: String,
     /// Chain parameter for network type which the SPV node work on.
     pub chain_params: ChainParams,
+    /// Number of outbound connections which SPV node keeps.
+    pub outbound_connections: usize,
+    /// Time limit to complete the handshake with the peer.
+    pub handshake_timeout: Duration,
+    /// Time limit for the peer to answer getheaders message. The peer which doesn't answer in
+    /// time is disconnected, and the block headers are requested to another peer.
+    pub getheaders_timeout: Duration,
+    /// Whether to look up the transactions with the compact block filters of BIP157 instead of
+    /// the bloom filters of BIP37. The filter headers are stored in the data directory. The
+    /// unconfirmed transactions are not collected then, because they are announced only to the
+    /// peers which load the bloom filters.
+    pub compact_filters: bool,
 }
 
-/// Parameters for Blockchain network
-#[derive(Debug, Clone)]
-pub struct ChainParams {
-    /// Network Type
-    pub network: Network,
-    /// Genesis block for network to be connected
-    pub genesis: Block,
-    /// Network ID
-    pub network_id: NetworkId,
+impl Options {
+    /// Return the services which the peers must provide. We download the block headers and the
+    /// filters to look up the transactions from them.
+    fn required_services(&self) -> ServiceFlags {
+        if self.compact_filters {
+            REQUIRED_SERVICES | ServiceFlags::COMPACT_FILTERS
+        } else {
+            REQUIRED_SERVICES | ServiceFlags::BLOOM
+        }
+    }
 }
+
+/// Default number of outbound connections.
+pub const DEFAULT_OUTBOUND_CONNECTIONS: usize = 8;
@@ -0,0 +1,421 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+use crate::network::Error;
+use rand::seq::SliceRandom;
+use std::collections::HashMap;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tapyrus::consensus::encode::{self, Decodable, Encodable, VarInt};
+use tapyrus::consensus::serialize;
+use tapyrus::network::address::Address;
+use tapyrus::network::constants::ServiceFlags;
+
+/// The name of the file which the address book is stored in.
+pub const PEERS_FILE_NAME: &str = "peers.dat";
+
+/// The maximum number of addresses in the address book.
+const MAX_ADDRESSES: usize = 10_000;
+
+/// The number of failures in a row after which the address which was never connected is removed.
+const MAX_RETRIES: u32 = 3;
+
+/// The number of failures in a row after which the address is removed.
+const MAX_FAILURES: u32 = 10;
+
+/// This struct is an entry of the address book.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AddressEntry {
+    pub address: Address,
+    /// The last time which the address was announced or connected in unix time.
+    pub last_seen: u32,
+    /// The last time which the connection to the address succeeded in unix time. It is 0 if the
+    /// connection never succeeded.
+    pub last_success: u32,
+    /// The number of connection failures since the last success. It is not stored in the file.
+    pub failures: u32,
+}
+
+impl Encodable for AddressEntry {
+    #[inline]
+    fn consensus_encode<S: std::io::Write>(&self, mut s: S) -> Result<usize, encode::Error> {
+        let mut len = 0;
+        len += self.address.consensus_encode(&mut s)?;
+        len += self.last_seen.consensus_encode(&mut s)?;
+        len += self.last_success.consensus_encode(&mut s)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for AddressEntry {
+    #[inline]
+    fn consensus_decode<D: std::io::Read>(mut d: D) -> Result<Self, encode::Error> {
+        Ok(AddressEntry {
+            address: Decodable::consensus_decode(&mut d)?,
+            last_seen: Decodable::consensus_decode(&mut d)?,
+            last_success: Decodable::consensus_decode(&mut d)?,
+            failures: 0,
+        })
+    }
+}
+
+/// This struct holds the addresses of the peers which are learned from addr messages and the
+/// connections. It is stored in the data directory if it is opened with `open()`.
+#[derive(Debug, Default)]
+pub struct AddressBook {
+    path: Option<PathBuf>,
+    entries: HashMap<SocketAddr, AddressEntry>,
+    dirty: bool,
+}
+
+impl AddressBook {
+    /// Return empty address book which is not stored.
+    #[cfg(test)]
+    pub fn new() -> AddressBook {
+        AddressBook::default()
+    }
+
+    /// Open the address book in the data directory. The directory is created if it doesn't
+    /// exist. If the file is broken, the address book starts with empty.
+    pub fn open(datadir: &Path) -> Result<AddressBook, Error> {
+        fs::create_dir_all(datadir)?;
+        let path = datadir.join(PEERS_FILE_NAME);
+
+        let mut book = AddressBook {
+            path: Some(path.clone()),
+            ..AddressBook::default()
+        };
+
+        if path.exists() {
+            match decode_entries(&fs::read(&path)?) {
+                Ok(entries) => {
+                    for entry in entries {
+                        if let Ok(addr) = entry.address.socket_addr() {
+                            book.entries.insert(addr, entry);
+                        }
+                    }
+                }
+                Err(e) => warn!("Address book is broken, so it is discarded: {}", e),
+            }
+        }
+
+        Ok(book)
+    }
+
+    /// Write the address book into the file if it is changed.
+    pub fn save(&mut self) -> Result<(), Error> {
+        let path = match self.path {
+            Some(ref path) if self.dirty => path,
+            _ => return Ok(()),
+        };
+
+        let mut entries: Vec<&AddressEntry> = self.entries.values().collect();
+        entries.sort_by_key(|entry| std::cmp::Reverse(entry.last_seen));
+
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, encode_entries(&entries))?;
+        fs::rename(&tmp_path, path)?;
+
+        self.dirty = false;
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    #[cfg(test)]
+    pub fn get(&self, addr: &SocketAddr) -> Option<&AddressEntry> {
+        self.entries.get(addr)
+    }
+
+    /// Add the address which is announced by the peer. The peers which don't serve the full
+    /// blocks are ignored.
+    pub fn add(&mut self, address: &Address, last_seen: u32) {
+        if !address.services.has(ServiceFlags::NETWORK) {
+            return;
+        }
+
+        let addr = match address.socket_addr() {
+            Ok(addr) => addr,
+            Err(_) => return,
+        };
+
+        if let Some(entry) = self.entries.get_mut(&addr) {
+            if entry.last_seen < last_seen {
+                entry.last_seen = last_seen;
+                entry.address.services = address.services;
+                self.dirty = true;
+            }
+            return;
+        }
+
+        if self.entries.len() >= MAX_ADDRESSES && !self.evict(last_seen) {
+            return;
+        }
+
+        self.entries.insert(
+            addr,
+            AddressEntry {
+                address: address.clone(),
+                last_seen,
+                last_success: 0,
+                failures: 0,
+            },
+        );
+        self.dirty = true;
+    }
+
+    /// Record that the connection to the address succeeded.
+    pub fn mark_success(&mut self, addr: &SocketAddr) {
+        let now = now();
+        let entry = self.entries.entry(*addr).or_insert_with(|| AddressEntry {
+            address: Address::new(addr, ServiceFlags::NETWORK),
+            last_seen: now,
+            last_success: 0,
+            failures: 0,
+        });
+        entry.last_seen = now;
+        entry.last_success = now;
+        entry.failures = 0;
+        self.dirty = true;
+    }
+
+    /// Record that the connection to the address failed. The address is removed if it fails
+    /// repeatedly.
+    pub fn mark_failure(&mut self, addr: &SocketAddr) {
+        let entry = match self.entries.get_mut(addr) {
+            Some(entry) => entry,
+            None => return,
+        };
+
+        entry.failures += 1;
+        let max_failures = if entry.last_success == 0 {
+            MAX_RETRIES
+        } else {
+            MAX_FAILURES
+        };
+        if entry.failures >= max_failures {
+            info!(
+                "Forget address {} which failed {} times.",
+                addr, entry.failures
+            );
+            self.remove(addr);
+        }
+    }
+
+    /// Remove the address which is the least likely to be connected to make room for the address
+    /// seen at `last_seen`. Only the addresses which were never connected and are older than the
+    /// new one can be removed. Return whether an address is removed.
+    fn evict(&mut self, last_seen: u32) -> bool {
+        let stale = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.last_success == 0 && entry.last_seen < last_seen)
+            .min_by_key(|(_, entry)| entry.last_seen)
+            .map(|(addr, _)| *addr);
+
+        match stale {
+            Some(addr) => {
+                self.remove(&addr);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Forget the address. It is used for the peers which we can never talk to.
+    pub fn remove(&mut self, addr: &SocketAddr) {
+        if self.entries.remove(addr).is_some() {
+            self.dirty = true;
+        }
+    }
+
+    /// Select the address to connect from the addresses which `exclude` returns false. The
+    /// addresses which were connected successfully are preferred.
+    pub fn select<F>(&self, exclude: F) -> Option<SocketAddr>
+    where
+        F: Fn(&SocketAddr) -> bool,
+    {
+        let mut candidates: Vec<(&SocketAddr, &AddressEntry)> = self
+            .entries
+            .iter()
+            .filter(|(addr, _)| !exclude(addr))
+            .collect();
+        candidates.shuffle(&mut rand::thread_rng());
+
+        candidates
+            .iter()
+            .find(|(_, entry)| entry.last_success > 0)
+            .or_else(|| candidates.first())
+            .map(|(addr, _)| **addr)
+    }
+}
+
+/// Return current unix time.
+pub fn now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as u32
+}
+
+fn encode_entries(entries: &[&AddressEntry]) -> Vec<u8> {
+    let mut bytes = serialize(&VarInt(entries.len() as u64));
+    for entry in entries {
+        bytes.extend(serialize(*entry));
+    }
+    bytes
+}
+
+fn decode_entries(bytes: &[u8]) -> Result<Vec<AddressEntry>, encode::Error> {
+    let mut cursor = std::io::Cursor::new(bytes);
+    let VarInt(len) = Decodable::consensus_decode(&mut cursor)?;
+
+    let mut entries = vec![];
+    for _ in 0..len {
+        entries.push(Decodable::consensus_decode(&mut cursor)?);
+    }
+
+    if cursor.position() as usize != bytes.len() {
+        // Trailing bytes mean that the file is broken.
+        return Err(encode::Error::ParseFailed("trailing bytes in address book"));
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helper::temp_dir;
+
+    fn address(s: &str, services: ServiceFlags) -> Address {
+        Address::new(&s.parse().unwrap(), services)
+    }
+
+    #[test]
+    fn test_add() {
+        let mut book = AddressBook::new();
+        book.add(&address("10.0.0.1:2377", ServiceFlags::NETWORK), 100);
+        book.add(&address("10.0.0.2:2377", ServiceFlags::NONE), 100);
+        assert_eq!(book.len(), 1);
+
+        // last_seen is updated only by newer one.
+        let addr = "10.0.0.1:2377".parse().unwrap();
+        book.add(&address("10.0.0.1:2377", ServiceFlags::NETWORK), 200);
+        assert_eq!(book.get(&addr).unwrap().last_seen, 200);
+        book.add(&address("10.0.0.1:2377", ServiceFlags::NETWORK), 150);
+        assert_eq!(book.get(&addr).unwrap().last_seen, 200);
+        assert_eq!(book.get(&addr).unwrap().last_success, 0);
+
+        book.mark_success(&addr);
+        assert!(book.get(&addr).unwrap().last_success > 0);
+
+        book.remove(&addr);
+        assert!(book.is_empty());
+    }
+
+    #[test]
+    fn test_evict_stale_address_when_full() {
+        let mut book = AddressBook::new();
+        for i in 0..MAX_ADDRESSES {
+            let addr = SocketAddr::from(([10, 0, (i / 256) as u8, (i % 256) as u8], 2377));
+            book.add(&Address::new(&addr, ServiceFlags::NETWORK), 100 + i as u32);
+        }
+        let oldest: SocketAddr = "10.0.0.0:2377".parse().unwrap();
+        let connected: SocketAddr = "10.0.0.1:2377".parse().unwrap();
+        book.mark_success(&connected);
+        assert_eq!(book.len(), MAX_ADDRESSES);
+
+        // the address which is older than all others is dropped.
+        book.add(&address("10.1.0.0:2377", ServiceFlags::NETWORK), 50);
+        assert!(book.get(&"10.1.0.0:2377".parse().unwrap()).is_none());
+
+        // new address replaces the oldest one which was never connected.
+        book.add(&address("10.1.0.1:2377", ServiceFlags::NETWORK), 1_000_000);
+        assert_eq!(book.len(), MAX_ADDRESSES);
+        assert!(book.get(&"10.1.0.1:2377".parse().unwrap()).is_some());
+        assert!(book.get(&oldest).is_none());
+        assert!(book.get(&connected).is_some());
+    }
+
+    #[test]
+    fn test_remove_address_which_fails_repeatedly() {
+        let mut book = AddressBook::new();
+        let addr1: SocketAddr = "10.0.0.1:2377".parse().unwrap();
+        let addr2: SocketAddr = "10.0.0.2:2377".parse().unwrap();
+        book.add(&Address::new(&addr1, ServiceFlags::NETWORK), 100);
+        book.add(&Address::new(&addr2, ServiceFlags::NETWORK), 100);
+        book.mark_success(&addr2);
+
+        // the address which was never connected is removed soon.
+        for _ in 0..MAX_RETRIES - 1 {
+            book.mark_failure(&addr1);
+        }
+        assert_eq!(book.get(&addr1).unwrap().failures, MAX_RETRIES - 1);
+        book.mark_failure(&addr1);
+        assert!(book.get(&addr1).is_none());
+
+        // success resets the failures.
+        for _ in 0..MAX_FAILURES - 1 {
+            book.mark_failure(&addr2);
+        }
+        book.mark_success(&addr2);
+        assert_eq!(book.get(&addr2).unwrap().failures, 0);
+        for _ in 0..MAX_FAILURES {
+            book.mark_failure(&addr2);
+        }
+        assert!(book.is_empty());
+    }
+
+    #[test]
+    fn test_select() {
+        let mut book = AddressBook::new();
+        assert_eq!(book.select(|_| false), None);
+
+        let addr1: SocketAddr = "10.0.0.1:2377".parse().unwrap();
+        let addr2: SocketAddr = "10.0.0.2:2377".parse().unwrap();
+        book.add(&Address::new(&addr1, ServiceFlags::NETWORK), 100);
+        book.add(&Address::new(&addr2, ServiceFlags::NETWORK), 100);
+        book.mark_success(&addr2);
+
+        // the address which was connected is preferred.
+        for _ in 0..10 {
+            assert_eq!(book.select(|_| false), Some(addr2));
+        }
+        assert_eq!(book.select(|addr| *addr == addr2), Some(addr1));
+        assert_eq!(book.select(|_| true), None);
+    }
+
+    #[test]
+    fn test_save_and_reload() {
+        let datadir = temp_dir("address_book");
+
+        let mut book = AddressBook::open(&datadir).unwrap();
+        assert!(book.is_empty());
+        book.add(&address("10.0.0.1:2377", ServiceFlags::NETWORK), 100);
+        book.add(&address("[::1]:2377", ServiceFlags::NETWORK), 200);
+        book.mark_success(&"10.0.0.1:2377".parse().unwrap());
+        book.save().unwrap();
+
+        let reloaded = AddressBook::open(&datadir).unwrap();
+        assert_eq!(reloaded.len(), 2);
+        for (addr, entry) in book.entries.iter() {
+            assert_eq!(reloaded.get(addr), Some(entry));
+        }
+
+        // broken file is discarded.
+        fs::write(datadir.join(PEERS_FILE_NAME), [1, 2, 3]).unwrap();
+        assert!(AddressBook::open(&datadir).unwrap().is_empty());
+
+        fs::remove_dir_all(&datadir).unwrap();
+    }
+}
@@ -0,0 +1,119 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+use rand::Rng;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Default delay before reconnecting to the address after the first failure.
+pub const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// The maximum delay before reconnecting to the address.
+pub const MAX_BACKOFF: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug)]
+struct Entry {
+    failures: u32,
+    next_attempt: Instant,
+}
+
+/// This struct decides when the addresses which failed to connect can be tried again.
+///
+/// The delay is doubled on each failure up to the maximum, and randomized between the half and
+/// the whole of it, so that the connections to the same peer are not retried at the same time.
+#[derive(Debug)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    entries: HashMap<SocketAddr, Entry>,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Backoff {
+        Backoff {
+            initial,
+            max,
+            entries: HashMap::new(),
+        }
+    }
+
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    /// Record the failure of the connection to the address. Return the delay until the next
+    /// attempt.
+    pub fn failed(&mut self, addr: &SocketAddr) -> Duration {
+        let now = Instant::now();
+        let entry = self.entries.entry(*addr).or_insert(Entry {
+            failures: 0,
+            next_attempt: now,
+        });
+
+        // 2^16 times of the initial delay is long enough to reach the maximum.
+        let exponent = std::cmp::min(entry.failures, 16);
+        entry.failures += 1;
+
+        let delay = std::cmp::min(self.initial * 2u32.pow(exponent), self.max);
+        let delay = delay.mul_f64(rand::thread_rng().gen_range(0.5, 1.0));
+        entry.next_attempt = now + delay;
+        delay
+    }
+
+    /// Forget the failures of the address.
+    pub fn succeeded(&mut self, addr: &SocketAddr) {
+        self.entries.remove(addr);
+    }
+
+    /// Return true if the address should not be connected yet.
+    pub fn is_waiting(&self, addr: &SocketAddr) -> bool {
+        match self.entries.get(addr) {
+            Some(entry) => entry.next_attempt > Instant::now(),
+            None => false,
+        }
+    }
+
+    /// Return the time until the earliest attempt of the addresses which are waiting.
+    pub fn next_attempt_in(&self) -> Option<Duration> {
+        let now = Instant::now();
+        self.entries
+            .values()
+            .filter(|entry| entry.next_attempt > now)
+            .map(|entry| entry.next_attempt - now)
+            .min()
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Backoff {
+        Backoff::new(INITIAL_BACKOFF, MAX_BACKOFF)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exponential_backoff() {
+        let addr: SocketAddr = "10.0.0.1:2377".parse().unwrap();
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(10));
+        assert!(!backoff.is_waiting(&addr));
+
+        // the delay is randomized between the half and the whole.
+        let expected = [1, 2, 4, 8, 10, 10];
+        for secs in expected.iter() {
+            let max = Duration::from_secs(*secs);
+            let delay = backoff.failed(&addr);
+            assert!(delay >= max / 2 && delay <= max, "delay: {:?}", delay);
+        }
+        assert!(backoff.is_waiting(&addr));
+        assert!(backoff.next_attempt_in().unwrap() <= Duration::from_secs(10));
+
+        backoff.succeeded(&addr);
+        assert!(!backoff.is_waiting(&addr));
+        assert_eq!(backoff.next_attempt_in(), None);
+    }
+}
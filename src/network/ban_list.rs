@@ -0,0 +1,184 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+use crate::network::address_book::now;
+use crate::network::Error;
+use std::collections::HashMap;
+use std::fs;
+use std::net::{IpAddr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use tapyrus::consensus::encode::{self, Decodable, VarInt};
+use tapyrus::consensus::serialize;
+
+/// The name of the file which the ban list is stored in.
+pub const BAN_LIST_FILE_NAME: &str = "banlist.dat";
+
+/// Default duration of the ban in seconds.
+pub const DEFAULT_BAN_DURATION: u32 = 24 * 60 * 60;
+
+/// This struct holds the IP addresses of the peers which are banned, with the time when the ban
+/// expires. It is stored in the data directory if it is opened with `open()`.
+#[derive(Debug, Default)]
+pub struct BanList {
+    path: Option<PathBuf>,
+    /// The expiry of the ban in unix time for each address.
+    entries: HashMap<IpAddr, u32>,
+    dirty: bool,
+}
+
+impl BanList {
+    /// Return empty ban list which is not stored.
+    #[cfg(test)]
+    pub fn new() -> BanList {
+        BanList::default()
+    }
+
+    /// Open the ban list in the data directory. The directory is created if it doesn't exist.
+    /// If the file is broken, the ban list starts with empty.
+    pub fn open(datadir: &Path) -> Result<BanList, Error> {
+        fs::create_dir_all(datadir)?;
+        let path = datadir.join(BAN_LIST_FILE_NAME);
+
+        let mut list = BanList {
+            path: Some(path.clone()),
+            ..BanList::default()
+        };
+
+        if path.exists() {
+            match decode_entries(&fs::read(&path)?) {
+                Ok(entries) => list.entries = entries,
+                Err(e) => warn!("Ban list is broken, so it is discarded: {}", e),
+            }
+        }
+
+        Ok(list)
+    }
+
+    /// Write the ban list into the file if it is changed. The expired bans are removed.
+    pub fn save(&mut self) -> Result<(), Error> {
+        let now = now();
+        let len = self.entries.len();
+        self.entries.retain(|_, until| *until > now);
+        if self.entries.len() != len {
+            self.dirty = true;
+        }
+
+        let path = match self.path {
+            Some(ref path) if self.dirty => path,
+            _ => return Ok(()),
+        };
+
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, encode_entries(&self.entries))?;
+        fs::rename(&tmp_path, path)?;
+
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Ban the address until `until` in unix time. The ban is never shortened.
+    pub fn ban(&mut self, ip: IpAddr, until: u32) {
+        let entry = self.entries.entry(ip).or_insert(0);
+        if *entry < until {
+            *entry = until;
+            self.dirty = true;
+        }
+    }
+
+    /// Return true if the address is banned and the ban is not expired.
+    pub fn is_banned(&self, ip: &IpAddr) -> bool {
+        match self.entries.get(ip) {
+            Some(until) => *until > now(),
+            None => false,
+        }
+    }
+}
+
+fn encode_entries(entries: &HashMap<IpAddr, u32>) -> Vec<u8> {
+    let mut bytes = serialize(&VarInt(entries.len() as u64));
+    for (ip, until) in entries {
+        let octets = match ip {
+            IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+            IpAddr::V6(ip) => ip.octets(),
+        };
+        bytes.extend(serialize(&octets));
+        bytes.extend(serialize(until));
+    }
+    bytes
+}
+
+fn decode_entries(bytes: &[u8]) -> Result<HashMap<IpAddr, u32>, encode::Error> {
+    let mut cursor = std::io::Cursor::new(bytes);
+    let VarInt(len) = Decodable::consensus_decode(&mut cursor)?;
+
+    let mut entries = HashMap::new();
+    for _ in 0..len {
+        let octets: [u8; 16] = Decodable::consensus_decode(&mut cursor)?;
+        let until: u32 = Decodable::consensus_decode(&mut cursor)?;
+
+        let ip = Ipv6Addr::from(octets);
+        let ip = match ip.to_ipv4_mapped() {
+            Some(ip) => IpAddr::V4(ip),
+            None => IpAddr::V6(ip),
+        };
+        entries.insert(ip, until);
+    }
+
+    if cursor.position() as usize != bytes.len() {
+        // Trailing bytes mean that the file is broken.
+        return Err(encode::Error::ParseFailed("trailing bytes in ban list"));
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helper::temp_dir;
+
+    #[test]
+    fn test_ban_expiry() {
+        let ip1: IpAddr = "10.0.0.1".parse().unwrap();
+        let ip2: IpAddr = "10.0.0.2".parse().unwrap();
+
+        let mut list = BanList::new();
+        assert!(!list.is_banned(&ip1));
+
+        list.ban(ip1, now() + 100);
+        list.ban(ip2, now() - 1);
+        assert!(list.is_banned(&ip1));
+        assert!(!list.is_banned(&ip2));
+
+        // the ban is not shortened.
+        list.ban(ip1, now() - 1);
+        assert!(list.is_banned(&ip1));
+    }
+
+    #[test]
+    fn test_save_and_reload() {
+        let datadir = temp_dir("ban_list");
+        let ip1: IpAddr = "10.0.0.1".parse().unwrap();
+        let ip2: IpAddr = "2001:db8::1".parse().unwrap();
+        let ip3: IpAddr = "10.0.0.3".parse().unwrap();
+
+        let mut list = BanList::open(&datadir).unwrap();
+        list.ban(ip1, now() + 100);
+        list.ban(ip2, now() + 100);
+        list.ban(ip3, now() - 1);
+        list.save().unwrap();
+
+        // expired ban is removed.
+        let reloaded = BanList::open(&datadir).unwrap();
+        assert_eq!(reloaded.entries.len(), 2);
+        assert!(reloaded.is_banned(&ip1));
+        assert!(reloaded.is_banned(&ip2));
+
+        // broken file is discarded.
+        fs::write(datadir.join(BAN_LIST_FILE_NAME), [1, 2, 3]).unwrap();
+        assert!(BanList::open(&datadir).unwrap().entries.is_empty());
+
+        fs::remove_dir_all(&datadir).unwrap();
+    }
+}
@@ -0,0 +1,246 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+use crate::chain::{Chain, ChainStore};
+use crate::network::peer::PeerID;
+use crate::network::{Error, MaliciousPeerCause};
+use std::time::{Duration, Instant};
+use tapyrus::{Block, BlockHash};
+
+/// The maximum number of blocks which are requested to single peer at once.
+pub const MAX_BLOCKS_IN_FLIGHT_PER_PEER: usize = 16;
+
+/// This trait presents the receiver of the blocks which are downloaded by BlockFetcher.
+pub trait BlockConsumer {
+    /// Process the block which is verified against the block header at the height in the active
+    /// chain.
+    fn consume(&self, block: &Block, height: i32);
+}
+
+impl<F: Fn(&Block, i32)> BlockConsumer for F {
+    fn consume(&self, block: &Block, height: i32) {
+        self(block, height)
+    }
+}
+
+/// Block which is waited.
+struct PendingBlock {
+    hash: BlockHash,
+    /// The peer which is requested the block now.
+    peer: Option<PeerID>,
+    requested_at: Option<Instant>,
+    /// The peers which were requested the block before.
+    tried: Vec<PeerID>,
+}
+
+/// This struct downloads the full blocks in the active chain.
+///
+/// The blocks are requested by getdata message with MSG_BLOCK inventories, spread over the
+/// peers. The received block is verified against the block header in the active chain, which
+/// commits to the transactions by the merkle root and the immutable merkle root, and handed to
+/// BlockConsumer. The block which the peer doesn't send in time or sends broken is requested to
+/// another peer.
+#[derive(Default)]
+pub struct BlockFetcher {
+    pending: Vec<PendingBlock>,
+}
+
+impl BlockFetcher {
+    pub fn new() -> BlockFetcher {
+        BlockFetcher::default()
+    }
+
+    /// Add the block to download. It is requested by the next call of `requests`.
+    pub fn fetch(&mut self, hash: BlockHash) {
+        if !self.pending.iter().any(|pending| pending.hash == hash) {
+            self.pending.push(PendingBlock {
+                hash,
+                peer: None,
+                requested_at: None,
+                tried: vec![],
+            });
+        }
+    }
+
+    /// Return true if no block is waited.
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Assign the blocks which are not requested to the peers, and return the hashes of the blocks
+    /// to request to each peer. The peers which were tried before are avoided if possible.
+    pub fn requests(&mut self, peers: &[PeerID]) -> Vec<(PeerID, Vec<BlockHash>)> {
+        let mut requests: Vec<(PeerID, Vec<BlockHash>)> = vec![];
+        let mut in_flight: Vec<(PeerID, usize)> = peers
+            .iter()
+            .map(|id| {
+                let count = self
+                    .pending
+                    .iter()
+                    .filter(|pending| pending.peer == Some(*id))
+                    .count();
+                (*id, count)
+            })
+            .collect();
+
+        for pending in self.pending.iter_mut().filter(|p| p.peer.is_none()) {
+            let available = in_flight
+                .iter_mut()
+                .filter(|(_, count)| *count < MAX_BLOCKS_IN_FLIGHT_PER_PEER);
+            let (id, count) =
+                match available.min_by_key(|(id, count)| (pending.tried.contains(id), *count)) {
+                    Some(entry) => entry,
+                    None => break,
+                };
+
+            *count += 1;
+            pending.peer = Some(*id);
+            pending.requested_at = Some(Instant::now());
+            match requests.iter_mut().find(|(peer, _)| peer == id) {
+                Some((_, hashes)) => hashes.push(pending.hash),
+                None => requests.push((*id, vec![pending.hash])),
+            }
+        }
+        requests
+    }
+
+    /// Verify the block from the peer and hand it to the consumer.
+    pub fn on_block<S: ChainStore>(
+        &mut self,
+        id: PeerID,
+        chain_active: &Chain<S>,
+        block: Block,
+        consumer: &dyn BlockConsumer,
+    ) -> Result<(), Error> {
+        // The block which was requested to the peer before is accepted even if it is late.
+        let hash = block.block_hash();
+        let i = self
+            .pending
+            .iter()
+            .position(|p| p.hash == hash && (p.peer == Some(id) || p.tried.contains(&id)))
+            .ok_or(Error::MaliciousPeer(
+                id,
+                MaliciousPeerCause::UnsolicitedData,
+            ))?;
+
+        let index = match chain_active.get_by_hash(&hash)? {
+            Some(index) => index,
+            None => {
+                // The block is disconnected by reorganization after the request.
+                self.pending.swap_remove(i);
+                return Ok(());
+            }
+        };
+
+        // The header is the same as the block index because the hash is the same, so the merkle
+        // roots of the transactions are checked against the header.
+        if block.header != index.header || !block.check_merkle_root() {
+            self.retry(i);
+            return Err(Error::MaliciousPeer(id, MaliciousPeerCause::InvalidBlock));
+        }
+
+        self.pending.swap_remove(i);
+        trace!("Block {} at height {} is downloaded.", hash, index.height);
+        consumer.consume(&block, index.height);
+        Ok(())
+    }
+
+    /// Request the block to another peer.
+    fn retry(&mut self, i: usize) {
+        let pending = &mut self.pending[i];
+        if let Some(peer) = pending.peer.take() {
+            pending.tried.push(peer);
+        }
+        pending.requested_at = None;
+    }
+
+    /// Request the blocks which the peers don't send within `timeout` to another peer.
+    pub fn check_timeouts(&mut self, timeout: Duration) {
+        for i in 0..self.pending.len() {
+            let pending = &self.pending[i];
+            if pending.requested_at.is_some_and(|t| t.elapsed() > timeout) {
+                debug!(
+                    "Peer {:?} did not send block {} in time.",
+                    pending.peer, pending.hash
+                );
+                self.retry(i);
+            }
+        }
+    }
+
+    /// Request the blocks which the peer was requested to another peer.
+    pub fn peer_disconnected(&mut self, id: PeerID) {
+        for i in 0..self.pending.len() {
+            if self.pending[i].peer == Some(id) {
+                self.retry(i);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helper::{get_chain, get_test_genesis_block};
+    use std::cell::RefCell;
+
+    #[test]
+    fn test_fetch_and_consume() {
+        let chain = get_chain();
+        let genesis = get_test_genesis_block();
+        let hash = genesis.block_hash();
+
+        let mut fetcher = BlockFetcher::new();
+        fetcher.fetch(hash);
+        fetcher.fetch(hash);
+        assert_eq!(fetcher.requests(&[1, 2]), vec![(1, vec![hash])]);
+        assert!(fetcher.requests(&[1, 2]).is_empty());
+
+        let consumed = RefCell::new(vec![]);
+        let consumer = |block: &Block, height: i32| {
+            consumed.borrow_mut().push((block.block_hash(), height));
+        };
+
+        // The block is accepted only from the requested peer.
+        assert!(fetcher
+            .on_block(2, &chain, genesis.clone(), &consumer)
+            .is_err());
+        fetcher.on_block(1, &chain, genesis, &consumer).unwrap();
+        assert_eq!(*consumed.borrow(), vec![(hash, 0)]);
+        assert!(fetcher.is_empty());
+    }
+
+    #[test]
+    fn test_retry_with_other_peer() {
+        let chain = get_chain();
+        let genesis = get_test_genesis_block();
+        let hash = genesis.block_hash();
+        let consumer = |_: &Block, _: i32| {};
+
+        let mut fetcher = BlockFetcher::new();
+        fetcher.fetch(hash);
+        assert_eq!(fetcher.requests(&[1, 2]), vec![(1, vec![hash])]);
+
+        // The peer doesn't send the block in time.
+        fetcher.check_timeouts(Duration::from_secs(0));
+        assert_eq!(fetcher.requests(&[1, 2]), vec![(2, vec![hash])]);
+
+        // The peer sends the block whose transactions don't match the header.
+        let mut broken = genesis.clone();
+        broken.txdata[0].lock_time += 1;
+        match fetcher.on_block(2, &chain, broken, &consumer) {
+            Err(Error::MaliciousPeer(_, MaliciousPeerCause::InvalidBlock)) => {}
+            _ => panic!("should fail with InvalidBlock"),
+        }
+
+        // All peers were tried, so the block is requested to them again.
+        assert_eq!(fetcher.requests(&[1, 2]), vec![(1, vec![hash])]);
+        fetcher.peer_disconnected(1);
+        assert_eq!(fetcher.requests(&[2, 3]), vec![(3, vec![hash])]);
+
+        // The late block from the peer which was requested before is accepted.
+        fetcher.on_block(1, &chain, genesis, &consumer).unwrap();
+        assert!(fetcher.is_empty());
+    }
+}
@@ -2,55 +2,27 @@
 // Distributed under the MIT software license, see the accompanying
 // file COPYING or http://www.opensource.org/licenses/mit-license.php.
 
-use crate::chain::{Chain, ChainStore};
-use crate::network::{error::MaliciousPeerCause, Error, Peer};
-use crate::ChainState;
-use std::cell::RefCell;
-use std::sync::{Arc, Mutex};
-use tapyrus::network::message::NetworkMessage;
-use tapyrus::network::message::RawNetworkMessage;
-use tapyrus::BlockHeader;
-use tokio::prelude::{Async, Future, Sink, Stream};
+use crate::chain::{self, Chain, ChainChange, ChainStore};
+use crate::network::message::RawMessage;
+use crate::network::{Error, MaliciousPeerCause, Peer};
+use tapyrus::{BlockHash, BlockHeader};
+use tokio::prelude::{Sink, Stream};
 
 /// The maximum number of block headers that can be in a single headers message.
 pub const MAX_HEADERS_RESULTS: usize = 2_000;
 
-pub struct BlockHeaderDownload<T, S>
-where
-    T: Sink<SinkItem = RawNetworkMessage> + Stream<Item = RawNetworkMessage>,
-    S: ChainStore,
-{
-    peer: Option<RefCell<Peer<T>>>,
-    started: bool,
-    chain_state: Arc<Mutex<ChainState<S>>>,
-    max_headers_results: usize,
-}
-
-impl<T, S> BlockHeaderDownload<T, S>
-where
-    T: Sink<SinkItem = RawNetworkMessage> + Stream<Item = RawNetworkMessage>,
-    S: ChainStore,
-{
-    pub fn new(peer: Peer<T>, chain_state: Arc<Mutex<ChainState<S>>>) -> BlockHeaderDownload<T, S> {
-        BlockHeaderDownload {
-            peer: Some(RefCell::new(peer)),
-            started: false,
-            chain_state,
-            max_headers_results: MAX_HEADERS_RESULTS,
-        }
-    }
-}
-
 /// Process received headers message.
-/// Return flag for whether all block headers received.
-fn process_headers<T, S: ChainStore>(
-    peer: &mut Peer<T>,
+/// Return locator to request the following headers, or None if all block headers received. The
+/// changes of the active chain are appended to `change` even if a later header is invalid.
+pub fn process_headers<T, S: ChainStore>(
+    peer: &Peer<T>,
     chain_active: &mut Chain<S>,
     headers: Vec<BlockHeader>,
     max_headers_results: usize,
-) -> Result<bool, Error>
+    change: &mut ChainChange,
+) -> Result<Option<Vec<BlockHash>>, Error>
 where
-    T: Sink<SinkItem = RawNetworkMessage> + Stream<Item = RawNetworkMessage>,
+    T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
 {
     if headers.len() > max_headers_results {
         return Err(Error::MaliciousPeer(
@@ -59,84 +31,110 @@ where
         ));
     }
 
+    // Each header should be connected to previous one in the message.
+    let continuous = headers
+        .windows(2)
+        .all(|pair| pair[1].prev_blockhash == pair[0].block_hash());
+    if !continuous {
+        return Err(Error::MaliciousPeer(
+            peer.id,
+            MaliciousPeerCause::DisconnectedHeaders,
+        ));
+    }
+
     let all_headers_downloaded = headers.len() < max_headers_results;
+    let last_hash = headers.last().map(|header| header.block_hash());
 
-    for header in headers {
-        let _ = chain_active.connect_block_header(header);
-    }
+    let result = connect_headers(peer, chain_active, headers, change);
+    // The store may buffer the headers, so flush them once per message even if a header is
+    // invalid.
+    chain_active.flush()?;
+    result?;
 
-    if !all_headers_downloaded {
-        peer.send_getheaders(chain_active);
+    if all_headers_downloaded {
+        return Ok(None);
     }
 
-    Ok(all_headers_downloaded)
+    // Request following headers of the last received header. It may be on the competing
+    // branch which isn't active yet.
+    let locators = match last_hash {
+        Some(hash) => chain_active.get_locator_from(&hash)?,
+        None => None,
+    };
+    match locators {
+        Some(locators) => Ok(Some(locators)),
+        None => Ok(Some(chain_active.get_locator()?)),
+    }
 }
 
-impl<T, S> Future for BlockHeaderDownload<T, S>
+/// Connect headers to the chain in order. The changes of the active chain are appended to
+/// `change` until a header is invalid.
+fn connect_headers<T, S: ChainStore>(
+    peer: &Peer<T>,
+    chain_active: &mut Chain<S>,
+    headers: Vec<BlockHeader>,
+    change: &mut ChainChange,
+) -> Result<(), Error>
 where
-    T: Sink<SinkItem = RawNetworkMessage> + Stream<Item = RawNetworkMessage>,
-    S: ChainStore,
-    Error: From<T::Error>,
+    T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
 {
-    type Item = Peer<T>;
-    type Error = Error;
-
-    fn poll(&mut self) -> Result<Async<Self::Item>, Self::Error> {
-        let mut done = false;
-
-        let mut chain_state = self.chain_state.lock().unwrap();
-        let chain_active = chain_state.borrow_mut_chain_active();
-
-        if let Some(ref peer) = self.peer {
-            let mut peer = peer.borrow_mut();
-
-            if !self.started {
-                peer.send_getheaders(chain_active);
-                self.started = true;
+    for header in headers {
+        let extends_tip = header.prev_blockhash == chain_active.tip()?.header.block_hash();
+        let header_change = match chain_active.connect_block_header(header) {
+            Ok(header_change) => header_change,
+            Err(chain::Error::CheckpointMismatch(_)) if extends_tip => {
+                // The active chain which doesn't lead to the checkpoint may be a forged branch.
+                change.append(chain_active.rewind_unverified()?);
+                return Err(Error::MaliciousPeer(
+                    peer.id,
+                    MaliciousPeerCause::CheckpointMismatch,
+                ));
             }
-
-            loop {
-                match peer.poll()? {
-                    Async::Ready(Some(NetworkMessage::Headers(headers))) => {
-                        done = process_headers(
-                            &mut peer,
-                            chain_active,
-                            headers,
-                            self.max_headers_results,
-                        )?;
+            Err(e) => {
+                return Err(match e {
+                    chain::Error::InvalidBlockProof(_) => {
+                        Error::MaliciousPeer(peer.id, MaliciousPeerCause::InvalidBlockProof)
+                    }
+                    chain::Error::DisconnectedHeader(_) => {
+                        Error::MaliciousPeer(peer.id, MaliciousPeerCause::DisconnectedHeaders)
                     }
-                    Async::Ready(None) | Async::NotReady => break,
-                    Async::Ready(_) => {} // ignore other messages.
-                }
+                    chain::Error::CheckpointMismatch(_) | chain::Error::ForkBelowCheckpoint(_) => {
+                        Error::MaliciousPeer(peer.id, MaliciousPeerCause::CheckpointMismatch)
+                    }
+                    e => Error::from(e),
+                })
             }
-            peer.flush();
-        } else {
-            panic!("BlockHeaderDownload should have peer instance when call poll.");
-        }
-
-        if done {
-            let peer = self.peer.take().unwrap();
-            Ok(Async::Ready(peer.into_inner()))
-        } else {
-            Ok(Async::NotReady)
+        };
+
+        if header_change.is_reorg() {
+            info!(
+                "Chain is reorganized by headers from peer {}. new tip height: {}",
+                peer.id,
+                chain_active.height()
+            );
         }
+        change.append(header_change);
     }
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::chain::store::OnMemoryChainStore;
+    use crate::chain::Checkpoints;
     use crate::test_helper::{
-        channel, get_chain, get_test_genesis_block, get_test_headers, TwoWayChannel,
+        build_branch, build_signed_header, channel, get_chain, get_test_block_hash,
+        get_test_genesis_block, get_test_headers, private_key_from_seed,
     };
+    use crate::ChainState;
+    use tapyrus::blockdata::block::XField;
     use tapyrus::network::constants::NetworkId;
-    use tapyrus::network::message_blockdata::GetHeadersMessage;
-    use tapyrus::BlockHash;
 
     #[test]
     fn test_process_headers_fails_when_passed_over_max_headers_results() {
-        let (_here, there) = channel::<RawNetworkMessage>();
-        let mut peer = Peer::new(
+        let (_here, there) = channel::<RawMessage>();
+        let peer = Peer::new(
             0,
             there,
             "0.0.0.0:0".parse().unwrap(),
@@ -144,137 +142,112 @@ mod tests {
         );
 
         let mut chain_state = ChainState::new(get_chain());
-        let mut chain_active = chain_state.borrow_mut_chain_active();
+        let chain_active = chain_state.borrow_mut_chain_active();
         let headers = get_test_headers(1, 11);
-        let result = process_headers(&mut peer, &mut chain_active, headers, 10);
+        let result = process_headers(
+            &peer,
+            chain_active,
+            headers,
+            10,
+            &mut ChainChange::default(),
+        );
 
         assert!(result.is_err());
         match result {
             Err(Error::MaliciousPeer(peer_id, _)) => assert_eq!(peer_id, 0),
-            _ => assert!(false),
+            _ => unreachable!(),
         }
     }
 
-    /// Build remote peer for testing BlockHeaderDownload future.
-    /// Remote peer checks and responds messages from local peer.
-    ///
-    /// ## Situation
-    /// Local peer has only genesis block. Remote peer has 24 blocks includes genesis block.
-    ///
-    /// ## Flow
-    /// 1st message round trip, local peer send getheaders message and get 10 blocks from remote.
-    /// 2nd message round trip, local peer send getheaders message and get 10 blocks from remote.
-    /// 3rd message round trip, local peer send getheaders message and get 3 blocks from remote.
-    /// And finish sending getheaders message.
-    fn remote_peer(stream: TwoWayChannel<RawNetworkMessage>) -> impl Future<Item = (), Error = ()> {
-        stream
-            .into_future()
-            .and_then(|(msg, mut here)| {
-                // 1st message round trip.
-                // local peer request block headers with `getheaders` message. And remote peer sends
-                // MAX_HEADERS_RESULTS headers.
-                if let Some(RawNetworkMessage {
-                    payload: NetworkMessage::GetHeaders(getheaders_msg),
-                    ..
-                }) = msg
-                {
-                    match getheaders_msg {
-                        GetHeadersMessage {
-                            locator_hashes,
-                            stop_hash,
-                            ..
-                        } => {
-                            // test BlockHeaderDownload future send collect message.
-                            assert_eq!(
-                                locator_hashes,
-                                vec![get_test_genesis_block().header.block_hash()]
-                            );
-                            assert_eq!(stop_hash, BlockHash::default());
-                        }
-                    }
-                } else {
-                    assert!(false, "Peer should send 1st getheaders message.");
-                }
-
-                let headers_message = RawNetworkMessage {
-                    magic: NetworkId::REGTEST.magic(),
-                    payload: NetworkMessage::Headers(get_test_headers(1, 10)),
-                };
-
-                let _ = here.start_send(headers_message);
-
-                here.into_future()
-            })
-            .and_then(|(msg, mut here)| {
-                // 2nd message round trip.
-                // Remote peer send next 10 headers.
-                if let Some(RawNetworkMessage {
-                    payload: NetworkMessage::GetHeaders(getheaders_msg),
-                    ..
-                }) = msg
-                {
-                    match getheaders_msg {
-                        GetHeadersMessage {
-                            locator_hashes,
-                            stop_hash,
-                            ..
-                        } => {
-                            // test BlockHeaderDownload future send collect message.
-                            let expected: Vec<BlockHash> = get_test_headers(0, 11)
-                                .into_iter()
-                                .rev()
-                                .map(|v| v.block_hash())
-                                .collect();
-                            assert_eq!(locator_hashes, expected);
-                            assert_eq!(stop_hash, BlockHash::default());
-                        }
-                    }
-                } else {
-                    assert!(false, "Peer should send 2nd getheaders message.");
-                }
-
-                let headers_message = RawNetworkMessage {
-                    magic: NetworkId::REGTEST.magic(),
-                    payload: NetworkMessage::Headers(get_test_headers(10, 10)),
-                };
-
-                let _ = here.start_send(headers_message);
-
-                here.into_future()
-            })
-            .map(|(msg, mut here)| {
-                // 3rd message round trip.
-                // Remote peer send 3 headers as latest headers.
-                if let Some(RawNetworkMessage {
-                    payload: NetworkMessage::GetHeaders(getheaders_msg),
-                    ..
-                }) = msg
-                {
-                    match getheaders_msg {
-                        GetHeadersMessage { stop_hash, .. } => {
-                            // test BlockHeaderDownload future send collect message.
-                            assert_eq!(stop_hash, BlockHash::default());
-                        }
-                    }
-                } else {
-                    assert!(false, "Peer should send 3rd getheaders message.");
-                }
+    #[test]
+    fn test_process_headers_fails_when_passed_invalid_block_proof() {
+        let (_here, there) = channel::<RawMessage>();
+        let peer = Peer::new(
+            0,
+            there,
+            "0.0.0.0:0".parse().unwrap(),
+            NetworkId::REGTEST.magic(),
+        );
+
+        let mut chain_state = ChainState::new(get_chain());
+        let chain_active = chain_state.borrow_mut_chain_active();
+        let mut headers = get_test_headers(1, 5);
+        headers[4].proof = None;
+        let result = process_headers(
+            &peer,
+            chain_active,
+            headers,
+            10,
+            &mut ChainChange::default(),
+        );
+
+        match result {
+            Err(Error::MaliciousPeer(0, MaliciousPeerCause::InvalidBlockProof)) => {}
+            _ => panic!("should fail with InvalidBlockProof"),
+        }
 
-                let headers_message = RawNetworkMessage {
-                    magic: NetworkId::REGTEST.magic(),
-                    payload: NetworkMessage::Headers(get_test_headers(20, 3)),
-                };
+        // headers before invalid one are connected.
+        assert_eq!(chain_active.height(), 4);
+    }
+
+    #[test]
+    fn test_process_headers_fails_when_passed_disconnected_headers() {
+        let (_here, there) = channel::<RawMessage>();
+        let peer = Peer::new(
+            0,
+            there,
+            "0.0.0.0:0".parse().unwrap(),
+            NetworkId::REGTEST.magic(),
+        );
 
-                let _ = here.start_send(headers_message);
+        let mut chain_state = ChainState::new(get_chain());
+        let chain_active = chain_state.borrow_mut_chain_active();
 
-                ()
-            })
-            .map_err(|_| {})
+        // headers which are not connected to the tip.
+        let headers = get_test_headers(2, 5);
+        match process_headers(
+            &peer,
+            chain_active,
+            headers,
+            10,
+            &mut ChainChange::default(),
+        ) {
+            Err(Error::MaliciousPeer(0, MaliciousPeerCause::DisconnectedHeaders)) => {}
+            _ => panic!("should fail with DisconnectedHeaders"),
+        }
+        assert_eq!(chain_active.height(), 0);
+
+        // headers which are not continuous in the message.
+        let mut headers = get_test_headers(1, 3);
+        headers.extend(get_test_headers(5, 2));
+        match process_headers(
+            &peer,
+            chain_active,
+            headers,
+            10,
+            &mut ChainChange::default(),
+        ) {
+            Err(Error::MaliciousPeer(0, MaliciousPeerCause::DisconnectedHeaders)) => {}
+            _ => panic!("should fail with DisconnectedHeaders"),
+        }
+        assert_eq!(chain_active.height(), 0);
+
+        // connected headers
+        let headers = get_test_headers(1, 5);
+        assert!(process_headers(
+            &peer,
+            chain_active,
+            headers,
+            10,
+            &mut ChainChange::default()
+        )
+        .is_ok());
+        assert_eq!(chain_active.height(), 5);
     }
 
     #[test]
-    fn test_block_header_download() {
-        let (here, there) = channel::<RawNetworkMessage>();
+    fn test_process_headers_returns_locator_for_following_headers() {
+        let (_here, there) = channel::<RawMessage>();
         let peer = Peer::new(
             0,
             there,
@@ -282,31 +255,105 @@ mod tests {
             NetworkId::REGTEST.magic(),
         );
 
-        let chain_state = Arc::new(Mutex::new(ChainState::new(get_chain())));
-        let chain_state_for_block_header_download = chain_state.clone();
+        let mut chain_state = ChainState::new(get_chain());
+        let chain_active = chain_state.borrow_mut_chain_active();
 
-        let future = tokio::prelude::future::lazy(move || {
-            tokio::spawn(remote_peer(here));
+        // full headers message means that the peer has more headers.
+        let headers = get_test_headers(1, 10);
+        match process_headers(
+            &peer,
+            chain_active,
+            headers,
+            10,
+            &mut ChainChange::default(),
+        ) {
+            Ok(Some(locators)) => assert_eq!(locators, chain_active.get_locator().unwrap()),
+            _ => panic!("should return locator"),
+        }
 
-            let blockheaderdownload = BlockHeaderDownload {
-                peer: Some(RefCell::new(peer)),
-                started: false,
-                chain_state: chain_state_for_block_header_download,
-                max_headers_results: 10,
-            }
-            .map(move |_| {
-                // test after BlockHeaderDownload future finished
-                let chain_state = chain_state.lock().unwrap();
-                let chain_active = chain_state.borrow_chain_active();
-                assert_eq!(chain_active.height(), 23);
-            })
-            .map_err(|_| {});
+        let headers = get_test_headers(11, 3);
+        match process_headers(
+            &peer,
+            chain_active,
+            headers,
+            10,
+            &mut ChainChange::default(),
+        ) {
+            Ok(None) => {}
+            _ => panic!("should finish downloading"),
+        }
+        assert_eq!(chain_active.height(), 13);
+    }
 
-            tokio::spawn(blockheaderdownload);
+    #[test]
+    fn test_process_headers_reports_reorganization() {
+        let (_here, there) = channel::<RawMessage>();
+        let peer = Peer::new(
+            0,
+            there,
+            "0.0.0.0:0".parse().unwrap(),
+            NetworkId::REGTEST.magic(),
+        );
 
-            Ok(())
-        });
+        let mut chain_state = ChainState::new(get_chain());
+        let chain_active = chain_state.borrow_mut_chain_active();
+        let mut change = ChainChange::default();
+        process_headers(&peer, chain_active, get_test_headers(1, 5), 10, &mut change).unwrap();
+        assert!(!change.is_reorg());
+        assert_eq!(change.connected.len(), 5);
+
+        // The branch from height 3 is longer than the active chain.
+        let branch = build_branch(&get_test_headers(3, 1)[0], 3);
+        let mut change = ChainChange::default();
+        process_headers(&peer, chain_active, branch, 10, &mut change).unwrap();
+        let disconnected: Vec<i32> = change.disconnected.iter().map(|i| i.height).collect();
+        assert_eq!(disconnected, vec![5, 4]);
+        assert_eq!(change.connected.len(), 3);
+        assert_eq!(chain_active.height(), 6);
+    }
 
-        tokio::runtime::current_thread::run(future);
+    #[test]
+    fn test_process_headers_rewinds_forged_chain() {
+        let (_here, there) = channel::<RawMessage>();
+        let peer = Peer::new(
+            0,
+            there,
+            "0.0.0.0:0".parse().unwrap(),
+            NetworkId::REGTEST.magic(),
+        );
+
+        let mut store = OnMemoryChainStore::new();
+        store.initialize(get_test_genesis_block()).unwrap();
+        let checkpoints = Checkpoints::new(vec![(10, get_test_block_hash(10))], true);
+        let mut chain_active = Chain::with_checkpoints(store, checkpoints);
+
+        // headers which are signed by the wrong key and don't lead to the checkpoint.
+        let wrong_key = private_key_from_seed(1);
+        let mut headers = vec![get_test_genesis_block().header];
+        for _ in 0..10 {
+            let header = build_signed_header(headers.last().unwrap(), XField::None, &wrong_key);
+            headers.push(header);
+        }
+        headers.remove(0);
+
+        let mut change = ChainChange::default();
+        match process_headers(&peer, &mut chain_active, headers, 20, &mut change) {
+            Err(Error::MaliciousPeer(0, MaliciousPeerCause::CheckpointMismatch)) => {}
+            _ => panic!("should fail with CheckpointMismatch"),
+        }
+        assert_eq!(chain_active.height(), 0);
+        assert!(change.connected.is_empty());
+        assert!(change.disconnected.is_empty());
+
+        // valid headers are connected after that.
+        let result = process_headers(
+            &peer,
+            &mut chain_active,
+            get_test_headers(1, 10),
+            20,
+            &mut ChainChange::default(),
+        );
+        assert!(result.is_ok());
+        assert_eq!(chain_active.height(), 10);
     }
 }
@@ -0,0 +1,254 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+use std::io;
+use tapyrus::consensus::encode::{self, Decodable, Encodable};
+
+/// The maximum size of the filter in bytes which the peers accept.
+pub const MAX_BLOOM_FILTER_SIZE: usize = 36_000;
+
+/// The maximum number of the hash functions which the peers accept.
+pub const MAX_HASH_FUNCS: u32 = 50;
+
+/// The flag which asks the peer not to update the filter.
+#[cfg(test)]
+pub const BLOOM_UPDATE_NONE: u8 = 0;
+
+/// The flag which asks the peer to add the outpoints of the matched outputs to the filter, so that
+/// the transactions spending them are also matched.
+pub const BLOOM_UPDATE_ALL: u8 = 1;
+
+const LN2_SQUARED: f64 = std::f64::consts::LN_2 * std::f64::consts::LN_2;
+
+/// Bloom filter which is defined in BIP37.
+///
+/// The peer which received the filter in filterload message sends only the transactions which
+/// match the filter in merkleblock and inv messages.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BloomFilter {
+    data: Vec<u8>,
+    hash_funcs: u32,
+    tweak: u32,
+    flags: u8,
+}
+
+impl BloomFilter {
+    /// Return empty filter which is sized for `elements` with the false positive rate `fp_rate`.
+    /// The size is limited to the maximum which the peers accept.
+    pub fn new(elements: usize, fp_rate: f64, tweak: u32, flags: u8) -> BloomFilter {
+        let elements = std::cmp::max(elements, 1) as f64;
+        let size = (-1.0 / LN2_SQUARED * elements * fp_rate.ln() / 8.0) as usize;
+        let size = size.clamp(1, MAX_BLOOM_FILTER_SIZE);
+        let hash_funcs = (size as f64 * 8.0 / elements * std::f64::consts::LN_2) as u32;
+        let hash_funcs = hash_funcs.clamp(1, MAX_HASH_FUNCS);
+
+        BloomFilter {
+            data: vec![0; size],
+            hash_funcs,
+            tweak,
+            flags,
+        }
+    }
+
+    /// Add the data element to the filter.
+    pub fn insert(&mut self, data: &[u8]) {
+        for n in 0..self.hash_funcs {
+            let index = self.bit_index(n, data);
+            self.data[index >> 3] |= 1 << (7 & index);
+        }
+    }
+
+    /// Return true if the data element may be in the filter.
+    #[cfg(test)]
+    pub fn contains(&self, data: &[u8]) -> bool {
+        (0..self.hash_funcs).all(|n| {
+            let index = self.bit_index(n, data);
+            self.data[index >> 3] & (1 << (7 & index)) != 0
+        })
+    }
+
+    fn bit_index(&self, n: u32, data: &[u8]) -> usize {
+        let seed = n.wrapping_mul(0xFBA4_C795).wrapping_add(self.tweak);
+        murmur3(seed, data) as usize % (self.data.len() * 8)
+    }
+
+    /// Return filterload message which loads this filter to the peer.
+    pub fn to_filterload(&self) -> FilterLoadMessage {
+        FilterLoadMessage {
+            filter: self.data.clone(),
+            hash_funcs: self.hash_funcs,
+            tweak: self.tweak,
+            flags: self.flags,
+        }
+    }
+}
+
+#[cfg(test)]
+impl From<FilterLoadMessage> for BloomFilter {
+    fn from(message: FilterLoadMessage) -> BloomFilter {
+        BloomFilter {
+            data: message.filter,
+            hash_funcs: message.hash_funcs,
+            tweak: message.tweak,
+            flags: message.flags,
+        }
+    }
+}
+
+/// MurmurHash3 (x86_32) which is used by the bloom filter.
+fn murmur3(seed: u32, data: &[u8]) -> u32 {
+    const C1: u32 = 0xcc9e_2d51;
+    const C2: u32 = 0x1b87_3593;
+
+    let mut h1 = seed;
+    let mut blocks = data.chunks_exact(4);
+    for block in &mut blocks {
+        let mut k1 = u32::from_le_bytes([block[0], block[1], block[2], block[3]]);
+        k1 = k1.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+        h1 ^= k1;
+        h1 = h1.rotate_left(13).wrapping_mul(5).wrapping_add(0xe654_6b64);
+    }
+
+    let tail = blocks.remainder();
+    if !tail.is_empty() {
+        let mut k1 = 0u32;
+        for (i, byte) in tail.iter().enumerate() {
+            k1 ^= (*byte as u32) << (8 * i);
+        }
+        k1 = k1.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+        h1 ^= k1;
+    }
+
+    h1 ^= data.len() as u32;
+    h1 ^= h1 >> 16;
+    h1 = h1.wrapping_mul(0x85eb_ca6b);
+    h1 ^= h1 >> 13;
+    h1 = h1.wrapping_mul(0xc2b2_ae35);
+    h1 ^= h1 >> 16;
+    h1
+}
+
+/// `filterload` message which sets the bloom filter to the peer.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilterLoadMessage {
+    pub filter: Vec<u8>,
+    pub hash_funcs: u32,
+    pub tweak: u32,
+    pub flags: u8,
+}
+
+impl Encodable for FilterLoadMessage {
+    fn consensus_encode<S: io::Write>(&self, mut s: S) -> Result<usize, encode::Error> {
+        Ok(self.filter.consensus_encode(&mut s)?
+            + self.hash_funcs.consensus_encode(&mut s)?
+            + self.tweak.consensus_encode(&mut s)?
+            + self.flags.consensus_encode(&mut s)?)
+    }
+}
+
+impl Decodable for FilterLoadMessage {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<Self, encode::Error> {
+        Ok(FilterLoadMessage {
+            filter: Decodable::consensus_decode(&mut d)?,
+            hash_funcs: Decodable::consensus_decode(&mut d)?,
+            tweak: Decodable::consensus_decode(&mut d)?,
+            flags: Decodable::consensus_decode(&mut d)?,
+        })
+    }
+}
+
+/// `filteradd` message which adds the data element to the bloom filter of the peer.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilterAddMessage {
+    pub data: Vec<u8>,
+}
+
+impl Encodable for FilterAddMessage {
+    fn consensus_encode<S: io::Write>(&self, s: S) -> Result<usize, encode::Error> {
+        self.data.consensus_encode(s)
+    }
+}
+
+impl Decodable for FilterAddMessage {
+    fn consensus_decode<D: io::Read>(d: D) -> Result<Self, encode::Error> {
+        Ok(FilterAddMessage {
+            data: Decodable::consensus_decode(d)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tapyrus::consensus::serialize;
+
+    #[test]
+    fn test_murmur3() {
+        // test vectors from bitcoin core.
+        assert_eq!(murmur3(0x0000_0000, &[]), 0x0000_0000);
+        assert_eq!(murmur3(0xFBA4_C795, &[]), 0x6a39_6f08);
+        assert_eq!(murmur3(0xffff_ffff, &[]), 0x81f1_6f39);
+        assert_eq!(murmur3(0x0000_0000, &[0x00]), 0x514e_28b7);
+        assert_eq!(murmur3(0xFBA4_C795, &[0x00]), 0xea3f_0b17);
+        assert_eq!(murmur3(0x0000_0000, &[0xff]), 0xfd6c_f10d);
+        assert_eq!(murmur3(0x0000_0000, &[0x00, 0x11]), 0x16c6_b7ab);
+        assert_eq!(murmur3(0x0000_0000, &[0x00, 0x11, 0x22]), 0x8eb5_1c3d);
+        assert_eq!(murmur3(0x0000_0000, &[0x00, 0x11, 0x22, 0x33]), 0xb447_1bf8);
+        assert_eq!(
+            murmur3(0x0000_0000, &[0x00, 0x11, 0x22, 0x33, 0x44]),
+            0xe230_1fa8
+        );
+        assert_eq!(
+            murmur3(
+                0x0000_0000,
+                &[0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77]
+            ),
+            0x8034_d2a0
+        );
+    }
+
+    #[test]
+    fn test_insert_and_serialize() {
+        // test vectors from bitcoin core.
+        let element1 = hex::decode("99108ad8ed9bb6274d3980bab5a85c048f0950c8").unwrap();
+        let element2 = hex::decode("19108ad8ed9bb6274d3980bab5a85c048f0950c8").unwrap();
+        let element3 = hex::decode("b5a2c786d9ef4658287ced5914b37a1b4aa32eee").unwrap();
+        let element4 = hex::decode("b9300670b4c5366e95b2699e8b18bc75e5f729c5").unwrap();
+
+        let mut filter = BloomFilter::new(3, 0.01, 0, BLOOM_UPDATE_ALL);
+        filter.insert(&element1);
+        assert!(filter.contains(&element1));
+        assert!(!filter.contains(&element2));
+        filter.insert(&element3);
+        filter.insert(&element4);
+        assert!(filter.contains(&element3));
+        assert!(filter.contains(&element4));
+        assert_eq!(
+            hex::encode(serialize(&filter.to_filterload())),
+            "03614e9b050000000000000001"
+        );
+
+        let mut filter = BloomFilter::new(3, 0.01, 2_147_483_649, BLOOM_UPDATE_ALL);
+        filter.insert(&element1);
+        filter.insert(&element3);
+        filter.insert(&element4);
+        assert_eq!(
+            hex::encode(serialize(&filter.to_filterload())),
+            "03ce4299050000000100008001"
+        );
+
+        // the filter is restored from the message.
+        let message = filter.to_filterload();
+        let decoded: FilterLoadMessage =
+            tapyrus::consensus::deserialize(&serialize(&message)).unwrap();
+        assert_eq!(BloomFilter::from(decoded), filter);
+    }
+
+    #[test]
+    fn test_size_limit() {
+        let filter = BloomFilter::new(1_000_000, 0.0001, 0, BLOOM_UPDATE_NONE);
+        assert_eq!(filter.data.len(), MAX_BLOOM_FILTER_SIZE);
+        assert!(filter.hash_funcs <= MAX_HASH_FUNCS);
+    }
+}
@@ -0,0 +1,1037 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+use crate::chain::{Chain, ChainStore, FilterHeaderEntry, FilterHeaderStore};
+use crate::network::peer::PeerID;
+use crate::network::{Error, MaliciousPeerCause, WatchList};
+use std::time::{Duration, Instant};
+use tapyrus::hash_types::FilterHash;
+use tapyrus::hashes::Hash;
+use tapyrus::network::message::NetworkMessage;
+use tapyrus::network::message_filter::{
+    CFCheckpt, CFHeaders, CFilter, GetCFCheckpt, GetCFHeaders, GetCFilters,
+};
+use tapyrus::util::bip158::BlockFilter;
+use tapyrus::BlockHash;
+
+/// The filter type of the basic filter which is defined in BIP158.
+pub const FILTER_TYPE_BASIC: u8 = 0;
+
+/// The maximum number of filter headers which are requested in single getcfheaders message.
+pub const MAX_CFHEADERS_RESULTS: i32 = 2_000;
+
+/// The maximum number of filters which are requested in single getcfilters message.
+pub const MAX_CFILTERS_RESULTS: i32 = 1_000;
+
+/// The interval of the filter headers in cfcheckpt message, which is defined in BIP157.
+pub const CFCHECKPT_INTERVAL: i32 = 1_000;
+
+/// The number of peers whose filter checkpoints are compared before the filter headers are
+/// downloaded.
+pub const FILTER_CHECKPOINT_PEERS: usize = 3;
+
+/// The time to wait for the cfcheckpt message. The peer which doesn't answer in time is not
+/// waited.
+const CFCHECKPT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Return the filter header which commits to the filter and the previous filter header.
+pub fn filter_header(filter_hash: &FilterHash, previous: &FilterHash) -> FilterHash {
+    let mut data = [0u8; 64];
+    data[..32].copy_from_slice(&filter_hash[..]);
+    data[32..].copy_from_slice(&previous[..]);
+    FilterHash::hash(&data)
+}
+
+/// The request in flight.
+enum Request {
+    /// getcfheaders for the blocks from `start_height` to `stop_height`.
+    FilterHeaders {
+        start_height: i32,
+        stop_height: i32,
+        stop_hash: BlockHash,
+    },
+    /// getcfilters for the blocks up to `stop_height`. The filter at `next_height` should be
+    /// sent next.
+    Filters {
+        next_height: i32,
+        stop_height: i32,
+        matched: Vec<BlockHash>,
+    },
+}
+
+/// The filter checkpoints which are requested to the peers for the blocks up to `stop_height`.
+struct CheckpointRound {
+    stop_height: i32,
+    stop_hash: BlockHash,
+    /// The peers which are requested, with the time of the request.
+    requested: Vec<(PeerID, Instant)>,
+    /// The peers which didn't answer in time.
+    expired: Vec<PeerID>,
+    /// The filter headers at every interval which each peer sent.
+    replies: Vec<(PeerID, Vec<FilterHash>)>,
+    /// The filter headers which the majority of the peers agreed.
+    agreed: Option<Vec<FilterHash>>,
+}
+
+impl CheckpointRound {
+    fn is_asked(&self, id: PeerID) -> bool {
+        self.requested.iter().any(|(peer, _)| *peer == id)
+            || self.expired.contains(&id)
+            || self.replies.iter().any(|(peer, _)| *peer == id)
+    }
+
+    /// Return the filter headers which the strict majority of the replies have, if the replies
+    /// are enough to decide. `remaining` is the number of the peers which can be asked more.
+    fn majority(&self, remaining: usize) -> Option<Vec<FilterHash>> {
+        let count = self.replies.len();
+        if count == 0 || (count < FILTER_CHECKPOINT_PEERS && remaining > 0) {
+            return None;
+        }
+
+        self.replies
+            .iter()
+            .map(|(_, headers)| headers)
+            .find(|headers| {
+                let votes = self.replies.iter().filter(|(_, h)| h == *headers).count();
+                votes * 2 > count
+            })
+            .cloned()
+    }
+}
+
+/// This struct tracks the download of the compact block filters which are defined in BIP157 and
+/// BIP158 to find the transactions which pay to the watched scripts.
+///
+/// At first the filter checkpoints, which are the filter headers at every 1,000 blocks, are
+/// requested to several peers and compared. The peers which disagree with the majority are
+/// reported as misbehaving. Then the filter headers are downloaded up to the tip of the active
+/// chain. They are verified against the agreed and the configured filter checkpoints and stored
+/// into FilterHeaderStore. Then the filters
+/// are downloaded and verified against the stored filter headers, and the blocks whose filters
+/// match the watched scripts are handed to BlockFetcher to download the full blocks. The filters
+/// can't tell the spends of the watched outpoints, so they are found only in the downloaded
+/// blocks.
+///
+/// All requests are sent to single peer in order of height. The filter headers of the blocks
+/// disconnected by reorganization are removed, and the blocks are scanned again on the new
+/// branch. The cursor of the scan is kept in FilterHeaderStore, and it is rewound to the birth
+/// height of the scripts which are added to the watch list.
+pub struct CompactFilterDownload {
+    store: Box<dyn FilterHeaderStore + Send>,
+    /// Pairs of height and filter header which are trusted.
+    checkpoints: Vec<(i32, FilterHash)>,
+    /// The number of the scripts in the watch list which the scan is for.
+    watched_scripts: usize,
+    /// The peer which is requested.
+    peer: Option<PeerID>,
+    request: Option<Request>,
+    /// The time when the peer sent the last response or was requested.
+    last_progress: Option<Instant>,
+    round: Option<CheckpointRound>,
+    /// The interval of the filter checkpoints. It is changed only in tests.
+    checkpoint_interval: i32,
+    /// The peers which sent the filter checkpoints disagreeing with the majority.
+    dissenters: Vec<PeerID>,
+    /// The peers whose cfcheckpt message is no longer waited, because of the timeout or the
+    /// reorganization.
+    late_checkpoints: Vec<PeerID>,
+}
+
+impl CompactFilterDownload {
+    pub fn new(
+        store: Box<dyn FilterHeaderStore + Send>,
+        checkpoints: Vec<(i32, FilterHash)>,
+    ) -> CompactFilterDownload {
+        CompactFilterDownload {
+            store,
+            checkpoints,
+            watched_scripts: 0,
+            peer: None,
+            request: None,
+            last_progress: None,
+            round: None,
+            checkpoint_interval: CFCHECKPT_INTERVAL,
+            dissenters: vec![],
+            late_checkpoints: vec![],
+        }
+    }
+
+    /// Return the peer which is requested.
+    pub fn peer(&self) -> Option<PeerID> {
+        self.peer
+    }
+
+    /// Return true if the peer doesn't answer the request within `timeout`.
+    pub fn is_stalled(&self, timeout: Duration) -> bool {
+        match (self.peer, self.last_progress) {
+            (Some(_), Some(time)) => time.elapsed() > timeout,
+            _ => false,
+        }
+    }
+
+    /// Forget the request in flight. It is sent again.
+    pub fn reset(&mut self) {
+        self.peer = None;
+        self.request = None;
+        self.last_progress = None;
+    }
+
+    /// Forget the requests to the disconnected peer.
+    pub fn peer_disconnected(&mut self, id: PeerID) {
+        if self.peer == Some(id) {
+            self.reset();
+        }
+        if let Some(round) = self.round.as_mut() {
+            round.requested.retain(|(peer, _)| *peer != id);
+        }
+        self.late_checkpoints.retain(|peer| *peer != id);
+        self.dissenters.retain(|peer| *peer != id);
+    }
+
+    /// Return getcfcheckpt messages to the peers which should be asked the filter checkpoints,
+    /// and record that they are requested. `peers` are the peers which serve the compact block
+    /// filters. The checkpoints are decided when the majority of the peers agree, and the peers
+    /// which disagree are returned by `take_dissenters` after that.
+    pub fn request_checkpoints<S: ChainStore>(
+        &mut self,
+        peers: &[PeerID],
+        chain_active: &Chain<S>,
+    ) -> Result<Vec<(PeerID, NetworkMessage)>, Error> {
+        let stop_height =
+            chain_active.height() / self.checkpoint_interval * self.checkpoint_interval;
+        if stop_height == 0 {
+            if let Some(round) = self.round.take() {
+                self.late_checkpoints
+                    .extend(round.requested.iter().map(|(id, _)| *id));
+            }
+            return Ok(vec![]);
+        }
+
+        // Ask again when the chain grows to the next interval or is reorganized.
+        let stop_hash = chain_active.get(stop_height)?.unwrap().header.block_hash();
+        if self.round.as_ref().map(|round| round.stop_hash) != Some(stop_hash) {
+            if let Some(round) = self.round.take() {
+                self.late_checkpoints
+                    .extend(round.requested.iter().map(|(id, _)| *id));
+            }
+            self.round = Some(CheckpointRound {
+                stop_height,
+                stop_hash,
+                requested: vec![],
+                expired: vec![],
+                replies: vec![],
+                agreed: None,
+            });
+        }
+        let round = self.round.as_mut().unwrap();
+        if round.agreed.is_some() {
+            return Ok(vec![]);
+        }
+
+        let expired: Vec<PeerID> = round
+            .requested
+            .iter()
+            .filter(|(_, time)| time.elapsed() > CFCHECKPT_TIMEOUT)
+            .map(|(id, _)| *id)
+            .collect();
+        round.requested.retain(|(id, _)| !expired.contains(id));
+        self.late_checkpoints.extend(expired.iter().cloned());
+        round.expired.extend(expired);
+        if !round.requested.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let candidates: Vec<PeerID> = peers
+            .iter()
+            .filter(|id| !round.is_asked(**id))
+            .cloned()
+            .collect();
+        if let Some(agreed) = round.majority(candidates.len()) {
+            info!(
+                "Filter checkpoints up to height {} are agreed by {} peers.",
+                round.stop_height,
+                round.replies.iter().filter(|(_, h)| *h == agreed).count()
+            );
+            self.dissenters.extend(
+                round
+                    .replies
+                    .iter()
+                    .filter(|(_, headers)| *headers != agreed)
+                    .map(|(id, _)| *id),
+            );
+            round.agreed = Some(agreed);
+            return Ok(vec![]);
+        }
+
+        let count = std::cmp::max(
+            1,
+            FILTER_CHECKPOINT_PEERS.saturating_sub(round.replies.len()),
+        );
+        Ok(candidates
+            .into_iter()
+            .take(count)
+            .map(|id| {
+                trace!(
+                    "Request filter checkpoints up to height {} to peer {}.",
+                    stop_height,
+                    id
+                );
+                round.requested.push((id, Instant::now()));
+                let message = NetworkMessage::GetCFCheckpt(GetCFCheckpt {
+                    filter_type: FILTER_TYPE_BASIC,
+                    stop_hash,
+                });
+                (id, message)
+            })
+            .collect())
+    }
+
+    /// Return the peers which sent the filter checkpoints disagreeing with the majority.
+    pub fn take_dissenters(&mut self) -> Vec<PeerID> {
+        std::mem::take(&mut self.dissenters)
+    }
+
+    /// Record the filter checkpoints from the peer. They are compared with the others in the next
+    /// call of `request_checkpoints`.
+    pub fn on_cfcheckpt(&mut self, id: PeerID, message: CFCheckpt) -> Result<(), Error> {
+        let interval = self.checkpoint_interval;
+        let checkpoints = &self.checkpoints;
+        let round = match self.round.as_mut() {
+            Some(round)
+                if round.requested.iter().any(|(peer, _)| *peer == id)
+                    && message.filter_type == FILTER_TYPE_BASIC
+                    && message.stop_hash == round.stop_hash =>
+            {
+                round
+            }
+            _ => {
+                // The reply which arrives after the timeout or the reorganization is ignored.
+                if let Some(i) = self.late_checkpoints.iter().position(|peer| *peer == id) {
+                    self.late_checkpoints.remove(i);
+                    return Ok(());
+                }
+                return Err(Error::MaliciousPeer(
+                    id,
+                    MaliciousPeerCause::UnsolicitedData,
+                ));
+            }
+        };
+
+        round.requested.retain(|(peer, _)| *peer != id);
+        if message.filter_headers.len() != (round.stop_height / interval) as usize {
+            return Err(Error::MaliciousPeer(
+                id,
+                MaliciousPeerCause::InvalidFilterHeaders,
+            ));
+        }
+
+        let conflicts_with_config = (1..).zip(message.filter_headers.iter()).any(|(i, header)| {
+            checkpoints
+                .iter()
+                .any(|(h, checkpoint)| *h == i * interval && checkpoint != header)
+        });
+        if conflicts_with_config {
+            return Err(Error::MaliciousPeer(
+                id,
+                MaliciousPeerCause::FilterCheckpointMismatch,
+            ));
+        }
+
+        round.replies.push((id, message.filter_headers));
+        Ok(())
+    }
+
+    /// Return the trusted filter header at the height, which is configured or agreed by the
+    /// peers.
+    fn checkpoint_at(&self, height: i32) -> Option<FilterHash> {
+        if let Some((_, checkpoint)) = self.checkpoints.iter().find(|(h, _)| *h == height) {
+            return Some(*checkpoint);
+        }
+
+        let round = self.round.as_ref()?;
+        let agreed = round.agreed.as_ref()?;
+        if height <= 0 || height > round.stop_height || height % self.checkpoint_interval != 0 {
+            return None;
+        }
+        agreed
+            .get((height / self.checkpoint_interval - 1) as usize)
+            .cloned()
+    }
+
+    /// Return true if the filter headers can be downloaded, that is, the filter checkpoints are
+    /// agreed or not needed.
+    fn is_checkpoint_decided(&self) -> bool {
+        self.round
+            .as_ref()
+            .is_none_or(|round| round.agreed.is_some())
+    }
+
+    /// Return the message which requests the next filter headers or filters to the peer, and
+    /// record that it is requested. Return None if nothing is left or the request is in flight.
+    pub fn request<S: ChainStore>(
+        &mut self,
+        id: PeerID,
+        chain_active: &Chain<S>,
+        watch_list: &WatchList,
+    ) -> Result<Option<NetworkMessage>, Error> {
+        if self.peer.is_some() {
+            return Ok(None);
+        }
+
+        self.rewind_to_active_chain(chain_active)?;
+        self.rewind_to_birth_height(watch_list)?;
+
+        let tip = chain_active.height();
+        let message = if self.store.height() < tip {
+            if !self.is_checkpoint_decided() {
+                return Ok(None);
+            }
+
+            let start_height = self.store.height() + 1;
+            let stop_height = std::cmp::min(tip, start_height + MAX_CFHEADERS_RESULTS - 1);
+            // The block at the stop height exists because it is not above the tip.
+            let stop_hash = chain_active.get(stop_height)?.unwrap().header.block_hash();
+            trace!(
+                "Request filter headers from {} to {} to peer {}.",
+                start_height,
+                stop_height,
+                id
+            );
+            self.request = Some(Request::FilterHeaders {
+                start_height,
+                stop_height,
+                stop_hash,
+            });
+            NetworkMessage::GetCFHeaders(GetCFHeaders {
+                filter_type: FILTER_TYPE_BASIC,
+                start_height: start_height as u32,
+                stop_hash,
+            })
+        } else if !watch_list.scripts().is_empty() && self.next_height() <= tip {
+            let start_height = self.next_height();
+            let stop_height = std::cmp::min(tip, start_height + MAX_CFILTERS_RESULTS - 1);
+            let stop_hash = chain_active.get(stop_height)?.unwrap().header.block_hash();
+            trace!(
+                "Request filters from {} to {} to peer {}.",
+                start_height,
+                stop_height,
+                id
+            );
+            self.request = Some(Request::Filters {
+                next_height: start_height,
+                stop_height,
+                matched: vec![],
+            });
+            NetworkMessage::GetCFilters(GetCFilters {
+                filter_type: FILTER_TYPE_BASIC,
+                start_height: start_height as u32,
+                stop_hash,
+            })
+        } else {
+            return Ok(None);
+        };
+
+        self.peer = Some(id);
+        self.last_progress = Some(Instant::now());
+        Ok(Some(message))
+    }
+
+    /// Remove the filter headers of the blocks which are not in the active chain, and scan again
+    /// from the fork point.
+    fn rewind_to_active_chain<S: ChainStore>(
+        &mut self,
+        chain_active: &Chain<S>,
+    ) -> Result<(), Error> {
+        if let Some((height, hash)) = self.store.scanned() {
+            if self.store.get(height).map(|entry| entry.block_hash) != Some(hash) {
+                warn!(
+                    "The scanned block {} is not in the filter headers. Scan again.",
+                    hash
+                );
+                self.store.set_scanned(None)?;
+            }
+        }
+
+        let mut height = self.store.height();
+        while height >= 0
+            && self.store.get(height).map(|entry| entry.block_hash)
+                != chain_active
+                    .get(height)?
+                    .map(|index| index.header.block_hash())
+        {
+            height -= 1;
+        }
+        if height == self.store.height() {
+            return Ok(());
+        }
+
+        info!("Remove filter headers above height {}.", height);
+        // The cursor is rewound first, so that it doesn't point to the removed entry after crash.
+        self.rewind_scanned(height)?;
+        self.store.rewind(height)?;
+        Ok(())
+    }
+
+    /// Scan again from the lowest birth height of the scripts which are added to the watch list
+    /// since the last request.
+    fn rewind_to_birth_height(&mut self, watch_list: &WatchList) -> Result<(), Error> {
+        let count = watch_list.scripts().len();
+        if count <= self.watched_scripts {
+            return Ok(());
+        }
+
+        if let Some(birth_height) = watch_list.birth_height_after(self.watched_scripts) {
+            self.rewind_scanned(birth_height - 1)?;
+        }
+        self.watched_scripts = count;
+        Ok(())
+    }
+
+    /// Move the cursor of the scan back to the height if it is above.
+    fn rewind_scanned(&mut self, height: i32) -> Result<(), Error> {
+        match self.store.scanned() {
+            Some((scanned_height, _)) if scanned_height > height => {
+                info!("Scan filters again from height {}.", height + 1);
+                let scanned = self
+                    .store
+                    .get(height)
+                    .map(|entry| (height, entry.block_hash));
+                self.store.set_scanned(scanned)?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Remove the filter headers of the disconnected blocks and rewind the scan to the fork point
+    /// after the active chain is reorganized. If the request is in flight, they are rewound
+    /// before the next request instead, because the response is verified against the stored
+    /// filter headers.
+    pub fn rewind<S: ChainStore>(&mut self, chain_active: &Chain<S>) -> Result<(), Error> {
+        if self.peer.is_none() {
+            self.rewind_to_active_chain(chain_active)?;
+        }
+        Ok(())
+    }
+
+    fn next_height(&self) -> i32 {
+        self.store.scanned().map_or(0, |(height, _)| height + 1)
+    }
+
+    /// Return the filter header at the height. The previous of the genesis block is all zero.
+    fn filter_header_at(&self, height: i32) -> FilterHash {
+        self.store
+            .get(height)
+            .map_or(FilterHash::default(), |entry| entry.filter_header)
+    }
+
+    /// Verify the filter headers from the peer and store them.
+    pub fn on_cfheaders<S: ChainStore>(
+        &mut self,
+        id: PeerID,
+        chain_active: &Chain<S>,
+        message: CFHeaders,
+    ) -> Result<(), Error> {
+        let (start_height, stop_height) = match self.request {
+            Some(Request::FilterHeaders {
+                start_height,
+                stop_height,
+                stop_hash,
+            }) if self.peer == Some(id)
+                && message.filter_type == FILTER_TYPE_BASIC
+                && message.stop_hash == stop_hash =>
+            {
+                (start_height, stop_height)
+            }
+            _ => {
+                return Err(Error::MaliciousPeer(
+                    id,
+                    MaliciousPeerCause::UnsolicitedData,
+                ))
+            }
+        };
+
+        if message.filter_hashes.len() != (stop_height - start_height + 1) as usize
+            || message.previous_filter != self.filter_header_at(start_height - 1)
+        {
+            return Err(Error::MaliciousPeer(
+                id,
+                MaliciousPeerCause::InvalidFilterHeaders,
+            ));
+        }
+
+        // The blocks may be disconnected by reorganization after the request.
+        if chain_active.height_of(&message.stop_hash)? != Some(stop_height) {
+            self.reset();
+            return Ok(());
+        }
+
+        let mut previous = message.previous_filter;
+        let mut entries = Vec::with_capacity(message.filter_hashes.len());
+        for (height, filter_hash) in (start_height..).zip(message.filter_hashes.iter()) {
+            let header = filter_header(filter_hash, &previous);
+            let is_mismatch = self
+                .checkpoint_at(height)
+                .is_some_and(|checkpoint| checkpoint != header);
+            if is_mismatch {
+                return Err(Error::MaliciousPeer(
+                    id,
+                    MaliciousPeerCause::FilterCheckpointMismatch,
+                ));
+            }
+
+            // The block at the height exists because the stop hash is in the active chain.
+            entries.push(FilterHeaderEntry {
+                block_hash: chain_active.get(height)?.unwrap().header.block_hash(),
+                filter_header: header,
+            });
+            previous = header;
+        }
+
+        for entry in entries {
+            self.store.push(entry)?;
+        }
+        trace!(
+            "Filter headers are stored up to height {}.",
+            self.store.height()
+        );
+        self.reset();
+        Ok(())
+    }
+
+    /// Verify the filter from the peer against the stored filter header, and match it against
+    /// the watched scripts. Return the hashes of the matched blocks after the last requested
+    /// filter is received. The blocks are scanned then, so they should be fetched by the caller.
+    pub fn on_cfilter(
+        &mut self,
+        id: PeerID,
+        message: CFilter,
+        watch_list: &WatchList,
+    ) -> Result<Vec<BlockHash>, Error> {
+        let height = match self.request {
+            Some(Request::Filters { next_height, .. }) if self.peer == Some(id) => next_height,
+            _ => {
+                return Err(Error::MaliciousPeer(
+                    id,
+                    MaliciousPeerCause::UnsolicitedData,
+                ))
+            }
+        };
+        let entry = match self.store.get(height) {
+            Some(entry)
+                if message.filter_type == FILTER_TYPE_BASIC
+                    && entry.block_hash == message.block_hash =>
+            {
+                entry
+            }
+            _ => {
+                return Err(Error::MaliciousPeer(
+                    id,
+                    MaliciousPeerCause::UnsolicitedData,
+                ))
+            }
+        };
+
+        let filter = BlockFilter::new(&message.filter);
+        if filter.filter_id(&self.filter_header_at(height - 1)) != entry.filter_header {
+            return Err(Error::MaliciousPeer(id, MaliciousPeerCause::InvalidFilter));
+        }
+
+        // An empty query matches any filter, so it is not matched.
+        let scripts = watch_list.scripts();
+        let is_match = !scripts.is_empty()
+            && filter
+                .match_any(
+                    &entry.block_hash,
+                    &mut scripts.iter().map(|script| script.as_bytes()),
+                )
+                .map_err(|_| Error::MaliciousPeer(id, MaliciousPeerCause::InvalidFilter))?;
+        self.last_progress = Some(Instant::now());
+
+        let matched = match self.request.as_mut() {
+            Some(Request::Filters {
+                next_height,
+                stop_height,
+                matched,
+            }) => {
+                if is_match {
+                    matched.push(entry.block_hash);
+                }
+                *next_height += 1;
+                if height < *stop_height {
+                    return Ok(vec![]);
+                }
+                std::mem::take(matched)
+            }
+            _ => unreachable!("the request is checked above"),
+        };
+
+        if !matched.is_empty() {
+            trace!("Filters of {} blocks match the watch list.", matched.len());
+        }
+        self.store.set_scanned(Some((height, entry.block_hash)))?;
+        self.reset();
+        Ok(matched)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chain::store::OnMemoryFilterHeaderStore;
+    use crate::test_helper::{build_branch, get_chain, get_test_genesis_block, get_test_headers};
+    use tapyrus::Script;
+
+    /// Return the filters which the peer serves. Only the genesis block has the transactions.
+    fn filters(count: usize) -> Vec<BlockFilter> {
+        let genesis = get_test_genesis_block();
+        let genesis_filter =
+            BlockFilter::new_script_filter(&genesis, |_| unreachable!("no input to look up"))
+                .unwrap();
+        std::iter::once(genesis_filter)
+            .chain((1..count).map(|_| BlockFilter::new(&[0])))
+            .collect()
+    }
+
+    fn cfheaders(filters: &[BlockFilter], start: usize, stop_hash: BlockHash) -> CFHeaders {
+        let mut previous = FilterHash::default();
+        for filter in &filters[..start] {
+            previous = filter.filter_id(&previous);
+        }
+        CFHeaders {
+            filter_type: FILTER_TYPE_BASIC,
+            stop_hash,
+            previous_filter: previous,
+            filter_hashes: filters[start..]
+                .iter()
+                .map(|filter| FilterHash::hash(&filter.content))
+                .collect(),
+        }
+    }
+
+    fn cfilter(filter: &BlockFilter, block_hash: BlockHash) -> CFilter {
+        CFilter {
+            filter_type: FILTER_TYPE_BASIC,
+            block_hash,
+            filter: filter.content.clone(),
+        }
+    }
+
+    #[test]
+    fn test_download_filters() {
+        let mut chain = get_chain();
+        let headers = get_test_headers(0, 5);
+        for header in headers[1..].iter() {
+            chain.connect_block_header(header.clone()).unwrap();
+        }
+        let coinbase = get_test_genesis_block().txdata[0].clone();
+        let filters = filters(5);
+        let hashes: Vec<BlockHash> = headers.iter().map(|h| h.block_hash()).collect();
+
+        let mut watch_list = WatchList::new();
+        watch_list.add_script(coinbase.output[0].script_pubkey.clone());
+
+        let checkpoint = filters[..3]
+            .iter()
+            .fold(FilterHash::default(), |prev, filter| {
+                filter.filter_id(&prev)
+            });
+        let mut download = CompactFilterDownload::new(
+            Box::new(OnMemoryFilterHeaderStore::new()),
+            vec![(2, checkpoint)],
+        );
+
+        // The filter headers are downloaded at first.
+        match download.request(1, &chain, &watch_list).unwrap() {
+            Some(NetworkMessage::GetCFHeaders(message)) => {
+                assert_eq!(message.start_height, 0);
+                assert_eq!(message.stop_hash, hashes[4]);
+            }
+            _ => panic!("should request filter headers"),
+        }
+        assert!(download.request(1, &chain, &watch_list).unwrap().is_none());
+        download
+            .on_cfheaders(1, &chain, cfheaders(&filters, 0, hashes[4]))
+            .unwrap();
+        assert_eq!(download.store.height(), 4);
+
+        // Then the filters are downloaded, and the matched block is returned.
+        match download.request(1, &chain, &watch_list).unwrap() {
+            Some(NetworkMessage::GetCFilters(message)) => {
+                assert_eq!(message.start_height, 0);
+                assert_eq!(message.stop_hash, hashes[4]);
+            }
+            _ => panic!("should request filters"),
+        }
+        for i in 0..4 {
+            let message = cfilter(&filters[i], hashes[i]);
+            assert!(download
+                .on_cfilter(1, message, &watch_list)
+                .unwrap()
+                .is_empty());
+        }
+        assert_eq!(
+            download
+                .on_cfilter(1, cfilter(&filters[4], hashes[4]), &watch_list)
+                .unwrap(),
+            vec![hashes[0]]
+        );
+        assert_eq!(download.peer(), None);
+        assert_eq!(download.store.scanned(), Some((4, hashes[4])));
+        assert!(download.request(1, &chain, &watch_list).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_reject_invalid_filters() {
+        let mut chain = get_chain();
+        let headers = get_test_headers(0, 3);
+        for header in headers[1..].iter() {
+            chain.connect_block_header(header.clone()).unwrap();
+        }
+        let filters = filters(3);
+        let hashes: Vec<BlockHash> = headers.iter().map(|h| h.block_hash()).collect();
+        let mut watch_list = WatchList::new();
+        watch_list.add_script(
+            get_test_genesis_block().txdata[0].output[0]
+                .script_pubkey
+                .clone(),
+        );
+
+        // The filter headers conflict with the checkpoint.
+        let mut download = CompactFilterDownload::new(
+            Box::new(OnMemoryFilterHeaderStore::new()),
+            vec![(1, FilterHash::hash(&[1]))],
+        );
+        download.request(1, &chain, &watch_list).unwrap();
+        match download.on_cfheaders(1, &chain, cfheaders(&filters, 0, hashes[2])) {
+            Err(Error::MaliciousPeer(_, MaliciousPeerCause::FilterCheckpointMismatch)) => {}
+            _ => panic!("should fail with FilterCheckpointMismatch"),
+        }
+
+        // The filter doesn't match the filter header.
+        let mut download =
+            CompactFilterDownload::new(Box::new(OnMemoryFilterHeaderStore::new()), vec![]);
+        download.request(1, &chain, &watch_list).unwrap();
+        download
+            .on_cfheaders(1, &chain, cfheaders(&filters, 0, hashes[2]))
+            .unwrap();
+        download.request(1, &chain, &watch_list).unwrap();
+        match download.on_cfilter(1, cfilter(&filters[1], hashes[0]), &watch_list) {
+            Err(Error::MaliciousPeer(_, MaliciousPeerCause::InvalidFilter)) => {}
+            _ => panic!("should fail with InvalidFilter"),
+        }
+
+        // The filter is sent by other peer.
+        match download.on_cfilter(2, cfilter(&filters[0], hashes[0]), &watch_list) {
+            Err(Error::MaliciousPeer(_, MaliciousPeerCause::UnsolicitedData)) => {}
+            _ => panic!("should fail with UnsolicitedData"),
+        }
+    }
+
+    #[test]
+    fn test_rewind_after_reorganization() {
+        let mut chain = get_chain();
+        let headers = get_test_headers(0, 5);
+        for header in headers[1..].iter() {
+            chain.connect_block_header(header.clone()).unwrap();
+        }
+        let filters = filters(5);
+        let hashes: Vec<BlockHash> = headers.iter().map(|h| h.block_hash()).collect();
+        let watch_list = WatchList::new();
+
+        let mut download =
+            CompactFilterDownload::new(Box::new(OnMemoryFilterHeaderStore::new()), vec![]);
+        download.request(1, &chain, &watch_list).unwrap();
+        download
+            .on_cfheaders(1, &chain, cfheaders(&filters, 0, hashes[4]))
+            .unwrap();
+        download.store.set_scanned(Some((4, hashes[4]))).unwrap();
+
+        // The filter headers of the disconnected blocks are removed.
+        for header in build_branch(&headers[2], 3) {
+            chain.connect_block_header(header).unwrap();
+        }
+        download.rewind(&chain).unwrap();
+        assert_eq!(download.store.height(), 2);
+        assert_eq!(download.store.scanned(), Some((2, hashes[2])));
+        match download.request(1, &chain, &watch_list).unwrap() {
+            Some(NetworkMessage::GetCFHeaders(message)) => assert_eq!(message.start_height, 3),
+            _ => panic!("should request filter headers"),
+        }
+    }
+
+    /// Return the filter headers at every 2 blocks, which peers send in cfcheckpt message.
+    fn cfcheckpt(filters: &[BlockFilter], stop_hash: BlockHash) -> CFCheckpt {
+        let mut previous = FilterHash::default();
+        let mut filter_headers = vec![];
+        for (height, filter) in filters.iter().enumerate() {
+            previous = filter.filter_id(&previous);
+            if height > 0 && height % 2 == 0 {
+                filter_headers.push(previous);
+            }
+        }
+        CFCheckpt {
+            filter_type: FILTER_TYPE_BASIC,
+            stop_hash,
+            filter_headers,
+        }
+    }
+
+    #[test]
+    fn test_compare_filter_checkpoints() {
+        let mut chain = get_chain();
+        let headers = get_test_headers(0, 6);
+        for header in headers[1..].iter() {
+            chain.connect_block_header(header.clone()).unwrap();
+        }
+        let filters = filters(6);
+        let hashes: Vec<BlockHash> = headers.iter().map(|h| h.block_hash()).collect();
+        let watch_list = WatchList::new();
+
+        let mut download =
+            CompactFilterDownload::new(Box::new(OnMemoryFilterHeaderStore::new()), vec![]);
+        download.checkpoint_interval = 2;
+
+        // The filter checkpoints up to height 4 are requested to 3 peers.
+        let requests = download.request_checkpoints(&[1, 2, 3, 4], &chain).unwrap();
+        let ids: Vec<PeerID> = requests.iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, vec![1, 2, 3]);
+        match &requests[0].1 {
+            NetworkMessage::GetCFCheckpt(message) => assert_eq!(message.stop_hash, hashes[4]),
+            _ => panic!("should request filter checkpoints"),
+        }
+        assert!(download.request(1, &chain, &watch_list).unwrap().is_none());
+
+        // The peer which is not requested can not send the filter checkpoints.
+        match download.on_cfcheckpt(4, cfcheckpt(&filters[..5], hashes[4])) {
+            Err(Error::MaliciousPeer(4, MaliciousPeerCause::UnsolicitedData)) => {}
+            _ => panic!("should fail with UnsolicitedData"),
+        }
+
+        // Peer 3 sends the filter checkpoints which disagree with the others.
+        let forged = self::filters(1)
+            .into_iter()
+            .chain((1..5).map(|_| BlockFilter::new(&[1])))
+            .collect::<Vec<_>>();
+        download
+            .on_cfcheckpt(1, cfcheckpt(&filters[..5], hashes[4]))
+            .unwrap();
+        assert!(download
+            .request_checkpoints(&[1, 2, 3, 4], &chain)
+            .unwrap()
+            .is_empty());
+        download
+            .on_cfcheckpt(2, cfcheckpt(&filters[..5], hashes[4]))
+            .unwrap();
+        download
+            .on_cfcheckpt(3, cfcheckpt(&forged, hashes[4]))
+            .unwrap();
+        assert!(download
+            .request_checkpoints(&[1, 2, 3, 4], &chain)
+            .unwrap()
+            .is_empty());
+        assert_eq!(download.take_dissenters(), vec![3]);
+
+        // The filter headers which disagree with the agreed checkpoints are rejected.
+        match download.request(3, &chain, &watch_list).unwrap() {
+            Some(NetworkMessage::GetCFHeaders(message)) => assert_eq!(message.start_height, 0),
+            _ => panic!("should request filter headers"),
+        }
+        let mut forged = forged;
+        forged.push(BlockFilter::new(&[1]));
+        match download.on_cfheaders(3, &chain, cfheaders(&forged, 0, hashes[5])) {
+            Err(Error::MaliciousPeer(3, MaliciousPeerCause::FilterCheckpointMismatch)) => {}
+            _ => panic!("should fail with FilterCheckpointMismatch"),
+        }
+        assert_eq!(download.store.height(), -1);
+
+        download.reset();
+        download.request(1, &chain, &watch_list).unwrap();
+        download
+            .on_cfheaders(1, &chain, cfheaders(&filters, 0, hashes[5]))
+            .unwrap();
+        assert_eq!(download.store.height(), 5);
+    }
+
+    #[test]
+    fn test_ask_another_peer_when_filter_checkpoints_time_out() {
+        let mut chain = get_chain();
+        let headers = get_test_headers(0, 3);
+        for header in headers[1..].iter() {
+            chain.connect_block_header(header.clone()).unwrap();
+        }
+        let filters = filters(3);
+        let mut download =
+            CompactFilterDownload::new(Box::new(OnMemoryFilterHeaderStore::new()), vec![]);
+        download.checkpoint_interval = 2;
+
+        assert_eq!(
+            download.request_checkpoints(&[1, 2], &chain).unwrap().len(),
+            2
+        );
+        download
+            .on_cfcheckpt(1, cfcheckpt(&filters, headers[2].block_hash()))
+            .unwrap();
+        let round = download.round.as_mut().unwrap();
+        round.requested[0].1 = Instant::now() - CFCHECKPT_TIMEOUT - Duration::from_secs(1);
+
+        // The only reply is enough because no other peer can be asked.
+        assert!(download
+            .request_checkpoints(&[1, 2], &chain)
+            .unwrap()
+            .is_empty());
+        assert!(download.is_checkpoint_decided());
+        assert!(download.take_dissenters().is_empty());
+
+        // The late reply is ignored.
+        download
+            .on_cfcheckpt(2, cfcheckpt(&filters, headers[2].block_hash()))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_scan_again_from_birth_height() {
+        let mut chain = get_chain();
+        let headers = get_test_headers(0, 5);
+        for header in headers[1..].iter() {
+            chain.connect_block_header(header.clone()).unwrap();
+        }
+        let filters = filters(5);
+        let hashes: Vec<BlockHash> = headers.iter().map(|h| h.block_hash()).collect();
+        let mut watch_list = WatchList::new();
+        watch_list.add_script(Script::new());
+
+        // The scan resumes from the cursor in the store.
+        let mut store = OnMemoryFilterHeaderStore::new();
+        let mut previous = FilterHash::default();
+        for (filter, hash) in filters.iter().zip(hashes.iter()) {
+            previous = filter.filter_id(&previous);
+            store
+                .push(FilterHeaderEntry {
+                    block_hash: *hash,
+                    filter_header: previous,
+                })
+                .unwrap();
+        }
+        store.set_scanned(Some((3, hashes[3]))).unwrap();
+        let mut download = CompactFilterDownload::new(Box::new(store), vec![]);
+        match download.request(1, &chain, &watch_list).unwrap() {
+            Some(NetworkMessage::GetCFilters(message)) => assert_eq!(message.start_height, 4),
+            _ => panic!("should request filters"),
+        }
+        download
+            .on_cfilter(1, cfilter(&filters[4], hashes[4]), &watch_list)
+            .unwrap();
+        assert!(download.request(1, &chain, &watch_list).unwrap().is_none());
+
+        // The script which is born at height 2 is added.
+        let coinbase = get_test_genesis_block().txdata[0].clone();
+        watch_list.add_script_with_birth_height(coinbase.output[0].script_pubkey.clone(), Some(2));
+        match download.request(1, &chain, &watch_list).unwrap() {
+            Some(NetworkMessage::GetCFilters(message)) => assert_eq!(message.start_height, 2),
+            _ => panic!("should request filters"),
+        }
+        assert_eq!(download.store.scanned(), Some((1, hashes[1])));
+    }
+}
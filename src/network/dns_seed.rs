@@ -0,0 +1,117 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+use crate::network::address_book::{self, AddressBook};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use tapyrus::network::address::Address;
+use tapyrus::network::constants::ServiceFlags;
+
+/// Resolver looks up the IP addresses of the peers from the name of DNS seed.
+///
+/// The host application can implement this trait to use its own name resolution, for example the
+/// platform API on mobile devices.
+pub trait Resolver: Send + Sync {
+    /// Return the IP addresses which `host` is resolved to.
+    fn resolve(&self, host: &str) -> std::io::Result<Vec<IpAddr>>;
+}
+
+/// Resolver which uses the name resolution of the operating system.
+#[derive(Debug, Clone, Default)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve(&self, host: &str) -> std::io::Result<Vec<IpAddr>> {
+        // The port is required by the API, but it is not used.
+        let addrs = (host, 0).to_socket_addrs()?;
+        Ok(addrs.map(|addr| addr.ip()).collect())
+    }
+}
+
+/// Resolve DNS seeds and add the addresses of the peers to the address book. The peers are
+/// expected to listen on `port`.
+/// Return the number of the addresses which are resolved.
+pub fn query_seeds(
+    resolver: &dyn Resolver,
+    seeds: &[String],
+    port: u16,
+    address_book: &mut AddressBook,
+) -> usize {
+    let now = address_book::now();
+    let mut count = 0;
+
+    for seed in seeds {
+        match resolver.resolve(seed) {
+            Ok(ips) => {
+                info!(
+                    "{} addresses are resolved from DNS seed {}",
+                    ips.len(),
+                    seed
+                );
+                for ip in ips {
+                    let addr = SocketAddr::new(ip, port);
+                    address_book.add(&Address::new(&addr, ServiceFlags::NETWORK), now);
+                    count += 1;
+                }
+            }
+            Err(e) => warn!("Can not resolve DNS seed {}: {}", seed, e),
+        }
+    }
+
+    count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    struct FakeResolver {
+        records: HashMap<String, Vec<IpAddr>>,
+    }
+
+    impl Resolver for FakeResolver {
+        fn resolve(&self, host: &str) -> std::io::Result<Vec<IpAddr>> {
+            self.records
+                .get(host)
+                .cloned()
+                .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "unknown host"))
+        }
+    }
+
+    #[test]
+    fn test_query_seeds() {
+        let mut records = HashMap::new();
+        records.insert(
+            "seed1.example.com".to_string(),
+            vec!["10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap()],
+        );
+        records.insert(
+            "seed2.example.com".to_string(),
+            vec!["2001:db8::1".parse().unwrap()],
+        );
+        let resolver = FakeResolver { records };
+
+        // The seed which can not be resolved is skipped.
+        let seeds = vec![
+            "seed1.example.com".to_string(),
+            "unknown.example.com".to_string(),
+            "seed2.example.com".to_string(),
+        ];
+        let mut book = AddressBook::new();
+        assert_eq!(query_seeds(&resolver, &seeds, 2377, &mut book), 3);
+
+        assert_eq!(book.len(), 3);
+        for addr in &["10.0.0.1:2377", "10.0.0.2:2377", "[2001:db8::1]:2377"] {
+            let entry = book.get(&addr.parse().unwrap()).unwrap();
+            assert!(entry.address.services.has(ServiceFlags::NETWORK));
+            assert_eq!(entry.last_success, 0);
+        }
+    }
+
+    #[test]
+    fn test_system_resolver() {
+        let ips = SystemResolver.resolve("127.0.0.1").unwrap();
+        assert_eq!(ips, vec!["127.0.0.1".parse::<IpAddr>().unwrap()]);
+    }
+}
@@ -0,0 +1,147 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+use crate::chain::ChainStore;
+use crate::network::block_fetcher::BlockFetcher;
+use crate::network::driver::{Context, Driver, Event};
+use crate::network::peer::PeerID;
+use crate::network::{BlockConsumer, Error, MatchedTransaction, Message, RawMessage};
+use std::cell::RefCell;
+use std::sync::Arc;
+use std::time::Duration;
+use tapyrus::network::constants::ServiceFlags;
+use tapyrus::network::message::NetworkMessage;
+use tapyrus::network::message_blockdata::Inventory;
+use tapyrus::Block;
+use tokio::prelude::{Sink, Stream};
+
+/// Time limit for the peer to send the requested block. The block is requested to another peer
+/// after that.
+const BLOCK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// This driver downloads the full blocks whose compact block filters matched the watch list.
+///
+/// The blocks are requested to the full node peers, and the block which is not sent in time is
+/// requested to another peer. The downloaded blocks are handed to the block consumer, and the
+/// transactions in them which match the watch list are emitted for the other drivers.
+pub struct BlockDriver {
+    fetcher: BlockFetcher,
+    consumer: Option<Arc<dyn BlockConsumer + Send + Sync>>,
+}
+
+impl BlockDriver {
+    pub fn new() -> BlockDriver {
+        BlockDriver {
+            fetcher: BlockFetcher::new(),
+            consumer: None,
+        }
+    }
+
+    /// Set the consumer which receives the downloaded blocks.
+    pub fn set_block_consumer(&mut self, consumer: Arc<dyn BlockConsumer + Send + Sync>) {
+        self.consumer = Some(consumer);
+    }
+
+    /// Hand the block from the peer to the block fetcher, and emit the transactions in it which
+    /// match the watch list.
+    fn on_block<T, S>(
+        &mut self,
+        ctx: &mut Context<T, S>,
+        id: PeerID,
+        block: Block,
+    ) -> Result<(), Error>
+    where
+        T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
+        S: ChainStore,
+    {
+        let matched = {
+            let watch_list = ctx.watch_list.lock().unwrap();
+            let block_consumer = self.consumer.clone();
+            let matched = RefCell::new(vec![]);
+            let consumer = |block: &Block, height: i32| {
+                matched.borrow_mut().extend(
+                    block
+                        .txdata
+                        .iter()
+                        .filter(|tx| watch_list.matches(tx))
+                        .map(|tx| MatchedTransaction {
+                            tx: tx.clone(),
+                            height,
+                        }),
+                );
+                if let Some(consumer) = block_consumer.as_ref() {
+                    consumer.consume(block, height);
+                }
+            };
+            self.fetcher
+                .on_block(id, ctx.chain_active, block, &consumer)?;
+            matched.into_inner()
+        };
+
+        if !matched.is_empty() {
+            ctx.emit(Event::TransactionsFound(matched));
+        }
+        Ok(())
+    }
+}
+
+impl<T, S> Driver<T, S> for BlockDriver
+where
+    T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
+    S: ChainStore,
+{
+    fn peer_disconnected(&mut self, id: PeerID) {
+        self.fetcher.peer_disconnected(id);
+    }
+
+    fn on_message(
+        &mut self,
+        ctx: &mut Context<T, S>,
+        id: PeerID,
+        message: Message,
+    ) -> Result<Option<Message>, Error> {
+        match message {
+            Message::Network(NetworkMessage::Block(block)) => {
+                self.on_block(ctx, id, block)?;
+                Ok(None)
+            }
+            message => Ok(Some(message)),
+        }
+    }
+
+    fn on_event(&mut self, _ctx: &mut Context<T, S>, event: &Event) -> Result<(), Error> {
+        if let Event::BlocksMatched(hashes) = event {
+            for hash in hashes {
+                self.fetcher.fetch(*hash);
+            }
+        }
+        Ok(())
+    }
+
+    fn tick(&mut self, _ctx: &mut Context<T, S>) {
+        self.fetcher.check_timeouts(BLOCK_TIMEOUT);
+    }
+
+    /// Request the blocks which are waited by the block fetcher to the full node peers.
+    fn send_requests(&mut self, ctx: &mut Context<T, S>) -> Result<(), Error> {
+        if self.fetcher.is_empty() {
+            return Ok(());
+        }
+
+        let ids: Vec<PeerID> = ctx
+            .peers
+            .iter()
+            .filter(|peer| peer.features.services.has(ServiceFlags::NETWORK))
+            .map(|peer| peer.id)
+            .collect();
+        for (id, hashes) in self.fetcher.requests(&ids) {
+            if let Some(peer) = ctx.peer_mut(id) {
+                trace!("Request {} blocks to peer {}.", hashes.len(), id);
+                let inventory = hashes.into_iter().map(Inventory::Block).collect();
+                peer.start_send(NetworkMessage::GetData(inventory));
+            }
+        }
+        Ok(())
+    }
+}
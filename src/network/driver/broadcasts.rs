@@ -0,0 +1,169 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+use crate::chain::ChainStore;
+use crate::network::bloom_filter::FilterAddMessage;
+use crate::network::driver::{Context, Driver, Event};
+use crate::network::peer::PeerID;
+use crate::network::transaction_broadcast::BroadcastQueue;
+use crate::network::{Error, Message, RawMessage};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tapyrus::network::message::NetworkMessage;
+use tapyrus::network::message_blockdata::Inventory;
+use tokio::prelude::{Sink, Stream};
+
+/// Time limit for the broadcast transaction to be seen from other peers.
+const BROADCAST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// This driver broadcasts the transactions through the peers.
+///
+/// The transactions in the queue are announced to the peers which are not told them yet, and
+/// sent to the peers which request them. The broadcast completes when another peer announces the
+/// transaction back, and fails when a peer rejects it or nobody announces it in time.
+pub struct BroadcastDriver {
+    /// Transactions which are broadcast through the peers.
+    broadcasts: Arc<Mutex<BroadcastQueue>>,
+    /// The peers which the bloom filter is loaded to.
+    filtered_peers: HashSet<PeerID>,
+}
+
+impl BroadcastDriver {
+    pub fn new() -> BroadcastDriver {
+        BroadcastDriver {
+            broadcasts: Arc::new(Mutex::new(BroadcastQueue::new())),
+            filtered_peers: HashSet::new(),
+        }
+    }
+
+    /// Share the queue of the transactions to broadcast.
+    pub fn share_broadcasts(&mut self, broadcasts: Arc<Mutex<BroadcastQueue>>) {
+        self.broadcasts = broadcasts;
+    }
+
+    /// Send the transactions which the peer requests and we are broadcasting.
+    fn on_getdata<T, S>(&mut self, ctx: &mut Context<T, S>, id: PeerID, inventory: Vec<Inventory>)
+    where
+        T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
+        S: ChainStore,
+    {
+        let mut broadcasts = self.broadcasts.lock().unwrap();
+        let peer = match ctx.peer_mut(id) {
+            Some(peer) => peer,
+            None => return,
+        };
+        for inv in inventory {
+            if let Inventory::Transaction(txid) | Inventory::WitnessTransaction(txid) = inv {
+                if let Some(tx) = broadcasts.on_getdata(id, &txid) {
+                    trace!("Send transaction {} to peer {}.", txid, id);
+                    peer.start_send(NetworkMessage::Tx(tx));
+                }
+            }
+        }
+    }
+
+    /// Announce the transactions to broadcast to the peers which are not told them yet. The
+    /// transactions are also added to the bloom filters, so that the peers announce them when
+    /// they are relayed by other nodes.
+    fn announce_transactions<T, S>(&mut self, ctx: &mut Context<T, S>)
+    where
+        T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
+        S: ChainStore,
+    {
+        let mut broadcasts = self.broadcasts.lock().unwrap();
+        for peer in ctx.peers.iter_mut() {
+            let txids = broadcasts.announcements(peer.id);
+            if txids.is_empty() {
+                continue;
+            }
+
+            if self.filtered_peers.contains(&peer.id) {
+                for txid in txids.iter() {
+                    peer.start_send(Message::FilterAdd(FilterAddMessage {
+                        data: txid[..].to_vec(),
+                    }));
+                }
+            }
+            trace!("Announce {} transactions to peer {}.", txids.len(), peer.id);
+            let inventory = txids.into_iter().map(Inventory::Transaction).collect();
+            peer.start_send(NetworkMessage::Inv(inventory));
+        }
+    }
+
+    /// Report the outcomes of the completed broadcasts. The listeners are called after the
+    /// queue is unlocked, so that they can broadcast other transactions.
+    fn report_broadcasts(&mut self) {
+        let completed = self.broadcasts.lock().unwrap().take_completed();
+        for (listeners, result) in completed {
+            for listener in listeners {
+                listener(result.clone());
+            }
+        }
+    }
+}
+
+impl<T, S> Driver<T, S> for BroadcastDriver
+where
+    T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
+    S: ChainStore,
+{
+    fn peer_disconnected(&mut self, id: PeerID) {
+        self.filtered_peers.remove(&id);
+    }
+
+    /// Record the announcements of the transactions which we are broadcasting. The inv message
+    /// is passed back, because the other drivers also look into it.
+    fn on_message(
+        &mut self,
+        ctx: &mut Context<T, S>,
+        id: PeerID,
+        message: Message,
+    ) -> Result<Option<Message>, Error> {
+        match message {
+            Message::Network(NetworkMessage::Inv(inventory)) => {
+                {
+                    let mut broadcasts = self.broadcasts.lock().unwrap();
+                    for inv in inventory.iter() {
+                        if let Inventory::Transaction(txid) | Inventory::WitnessTransaction(txid) =
+                            inv
+                        {
+                            broadcasts.on_inv(id, txid);
+                        }
+                    }
+                }
+                Ok(Some(Message::Network(NetworkMessage::Inv(inventory))))
+            }
+            Message::Network(NetworkMessage::GetData(inventory)) => {
+                self.on_getdata(ctx, id, inventory);
+                Ok(None)
+            }
+            Message::Network(NetworkMessage::Reject(reject)) => {
+                self.broadcasts.lock().unwrap().on_reject(id, &reject);
+                Ok(None)
+            }
+            message => Ok(Some(message)),
+        }
+    }
+
+    fn on_event(&mut self, _ctx: &mut Context<T, S>, event: &Event) -> Result<(), Error> {
+        if let Event::BloomFilterLoaded(id) = event {
+            self.filtered_peers.insert(*id);
+        }
+        Ok(())
+    }
+
+    fn tick(&mut self, _ctx: &mut Context<T, S>) {
+        self.broadcasts
+            .lock()
+            .unwrap()
+            .check_timeouts(BROADCAST_TIMEOUT);
+    }
+
+    fn send_requests(&mut self, ctx: &mut Context<T, S>) -> Result<(), Error> {
+        self.announce_transactions(ctx);
+        self.report_broadcasts();
+        Ok(())
+    }
+}
@@ -0,0 +1,142 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+use crate::chain::{ChainStore, FilterHeaderStore};
+use crate::network::compact_filter_download::CompactFilterDownload;
+use crate::network::driver::{Context, Driver, Event};
+use crate::network::peer::PeerID;
+use crate::network::{Error, MaliciousPeerCause, Message, RawMessage};
+use std::time::Duration;
+use tapyrus::hash_types::FilterHash;
+use tapyrus::network::constants::ServiceFlags;
+use tapyrus::network::message::NetworkMessage;
+use tokio::prelude::{Sink, Stream};
+
+/// Time limit for the peer to answer the request of the compact block filters.
+const COMPACT_FILTER_TIMEOUT: Duration = Duration::from_secs(2 * 60);
+
+/// This driver looks up the blocks matching the watch list with the compact block filters of
+/// BIP157 instead of the bloom filters of BIP37.
+///
+/// After the block headers are downloaded, the filter checkpoints are compared between the peers
+/// which serve the compact block filters, and the peers which disagree with the majority are
+/// banned. Then the filter headers and the filters are requested to one of them. The peer which
+/// doesn't answer in time is disconnected, and the request is sent to another peer. The matched
+/// blocks are emitted for the other drivers to download.
+pub struct CompactFilterDriver {
+    download: CompactFilterDownload,
+}
+
+impl CompactFilterDriver {
+    /// Return the driver which stores the filter headers into `store` and verifies them against
+    /// `checkpoints`.
+    pub fn new(
+        store: Box<dyn FilterHeaderStore + Send>,
+        checkpoints: Vec<(i32, FilterHash)>,
+    ) -> CompactFilterDriver {
+        CompactFilterDriver {
+            download: CompactFilterDownload::new(store, checkpoints),
+        }
+    }
+}
+
+impl<T, S> Driver<T, S> for CompactFilterDriver
+where
+    T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
+    S: ChainStore,
+{
+    fn peer_disconnected(&mut self, id: PeerID) {
+        self.download.peer_disconnected(id);
+    }
+
+    fn on_message(
+        &mut self,
+        ctx: &mut Context<T, S>,
+        id: PeerID,
+        message: Message,
+    ) -> Result<Option<Message>, Error> {
+        match message {
+            Message::Network(NetworkMessage::CFHeaders(message)) => {
+                self.download.on_cfheaders(id, ctx.chain_active, message)?;
+            }
+            Message::Network(NetworkMessage::CFCheckpt(message)) => {
+                self.download.on_cfcheckpt(id, message)?;
+            }
+            Message::Network(NetworkMessage::CFilter(message)) => {
+                let matched = {
+                    let watch_list = ctx.watch_list.lock().unwrap();
+                    self.download.on_cfilter(id, message, &watch_list)?
+                };
+                if !matched.is_empty() {
+                    ctx.emit(Event::BlocksMatched(matched));
+                }
+            }
+            message => return Ok(Some(message)),
+        }
+        Ok(None)
+    }
+
+    fn on_event(&mut self, ctx: &mut Context<T, S>, event: &Event) -> Result<(), Error> {
+        match event {
+            Event::ChainReorganized(_) => self.download.rewind(ctx.chain_active)?,
+            Event::PeerMisbehaved(id) if self.download.peer() == Some(*id) => self.download.reset(),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Disconnect the peer which doesn't answer the request of the compact block filters in
+    /// time. The request is sent to another peer after that.
+    fn tick(&mut self, ctx: &mut Context<T, S>) {
+        if let Some(id) = self.download.peer() {
+            if self.download.is_stalled(COMPACT_FILTER_TIMEOUT) {
+                ctx.disconnect(id, Error::CompactFilterTimeout(id).to_string());
+            }
+        }
+    }
+
+    /// Request the filter checkpoints to the peers, and the next filter headers or filters to
+    /// the peer which serves the compact block filters.
+    fn send_requests(&mut self, ctx: &mut Context<T, S>) -> Result<(), Error> {
+        if !ctx.headers_synced {
+            return Ok(());
+        }
+
+        let filter_peers: Vec<PeerID> = ctx
+            .peers
+            .iter()
+            .filter(|peer| peer.features.services.has(ServiceFlags::COMPACT_FILTERS))
+            .map(|peer| peer.id)
+            .collect();
+        for (id, message) in self
+            .download
+            .request_checkpoints(&filter_peers, ctx.chain_active)?
+        {
+            if let Some(peer) = ctx.peer_mut(id) {
+                peer.start_send(message);
+            }
+        }
+        for id in self.download.take_dissenters() {
+            ctx.misbehaving(id, MaliciousPeerCause::FilterCheckpointMismatch);
+        }
+
+        if self.download.peer().is_some() {
+            return Ok(());
+        }
+        // The dissenters are not trusted though they are not disconnected yet.
+        let id = match filter_peers.into_iter().find(|id| !ctx.is_reported(*id)) {
+            Some(id) => id,
+            None => return Ok(()),
+        };
+
+        let message = {
+            let watch_list = ctx.watch_list.lock().unwrap();
+            self.download.request(id, ctx.chain_active, &watch_list)?
+        };
+        if let (Some(message), Some(peer)) = (message, ctx.peer_mut(id)) {
+            peer.start_send(message);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,312 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+use crate::chain::{ChainChange, ChainStore};
+use crate::network::block_header_download::{process_headers, MAX_HEADERS_RESULTS};
+use crate::network::driver::{Context, Driver, Event};
+use crate::network::peer::PeerID;
+use crate::network::{Error, Message, RawMessage, SyncProgress, SyncProgressListener};
+use std::time::{Duration, Instant};
+use tapyrus::network::message::NetworkMessage;
+use tapyrus::network::message_blockdata::Inventory;
+use tapyrus::BlockHash;
+use tokio::prelude::{Sink, Stream};
+
+/// Default time limit for the sync peer to answer getheaders message.
+pub const DEFAULT_GETHEADERS_TIMEOUT: Duration = Duration::from_secs(2 * 60);
+
+/// This driver keeps the active chain in sync with the peers.
+///
+/// Each getheaders message is sent to the peers in turn, so a single peer can not stall the
+/// download. After the initial download, the peers are asked to announce new blocks with headers
+/// message by sendheaders message, and the announced headers or the headers of the blocks
+/// announced by inv message are connected to the chain. The sync peer which doesn't answer
+/// getheaders within the timeout is disconnected, and the headers are requested to another peer.
+/// The reorganization of the active chain is emitted for the other drivers.
+pub struct HeaderSyncDriver {
+    /// The peer which is requested the following block headers.
+    sync_peer: Option<PeerID>,
+    /// The time when the block headers were requested to the sync peer.
+    sync_requested: Option<Instant>,
+    getheaders_timeout: Duration,
+    synced: bool,
+    max_headers_results: usize,
+    /// The highest height which the peers advertised.
+    best_known_height: i32,
+    sync_progress_listener: Option<SyncProgressListener>,
+    sync_progress: Option<SyncProgress>,
+}
+
+impl HeaderSyncDriver {
+    pub fn new() -> HeaderSyncDriver {
+        HeaderSyncDriver {
+            sync_peer: None,
+            sync_requested: None,
+            getheaders_timeout: DEFAULT_GETHEADERS_TIMEOUT,
+            synced: false,
+            max_headers_results: MAX_HEADERS_RESULTS,
+            best_known_height: 0,
+            sync_progress_listener: None,
+            sync_progress: None,
+        }
+    }
+
+    /// Set time limit for the sync peer to answer getheaders message.
+    pub fn set_getheaders_timeout(&mut self, timeout: Duration) {
+        self.getheaders_timeout = timeout;
+    }
+
+    /// Set the maximum number of block headers in single headers message.
+    #[cfg(test)]
+    pub fn set_max_headers_results(&mut self, max_headers_results: usize) {
+        self.max_headers_results = max_headers_results;
+    }
+
+    /// Set the listener which is called when the sync progress changes.
+    pub fn set_sync_progress_listener(&mut self, listener: SyncProgressListener) {
+        self.sync_progress_listener = Some(listener);
+    }
+
+    /// Return true if the block headers are downloaded and no getheaders is in flight.
+    pub fn is_synced(&self) -> bool {
+        self.synced && self.sync_peer.is_none()
+    }
+
+    fn on_headers<T, S>(
+        &mut self,
+        ctx: &mut Context<T, S>,
+        id: PeerID,
+        headers: Vec<tapyrus::BlockHeader>,
+    ) -> Result<(), Error>
+    where
+        T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
+        S: ChainStore,
+    {
+        // The announcement of new block can be ahead of our chain while syncing.
+        let is_announcement = self.sync_peer != Some(id);
+        let connectable = match headers.first() {
+            Some(header) => ctx
+                .chain_active
+                .get_locator_from(&header.prev_blockhash)?
+                .is_some(),
+            None => true,
+        };
+        if is_announcement && !connectable {
+            // Download the missing headers if the initial download is completed.
+            if self.is_synced() {
+                let locators = ctx.chain_active.get_locator()?;
+                self.request_headers(ctx, id, locators);
+            }
+            return Ok(());
+        }
+
+        let mut change = ChainChange::default();
+        let i = match ctx.peers.iter().position(|peer| peer.id == id) {
+            Some(i) => i,
+            None => return Ok(()),
+        };
+        let result = process_headers(
+            &ctx.peers[i],
+            ctx.chain_active,
+            headers,
+            self.max_headers_results,
+            &mut change,
+        );
+        if change.is_reorg() {
+            ctx.emit(Event::ChainReorganized(change));
+        }
+        self.on_headers_processed(ctx, i, result?);
+        Ok(())
+    }
+
+    fn on_headers_processed<T, S>(
+        &mut self,
+        ctx: &mut Context<T, S>,
+        i: usize,
+        locators: Option<Vec<BlockHash>>,
+    ) where
+        T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
+        S: ChainStore,
+    {
+        // The headers which are not requested are announcements of new blocks. The following
+        // headers of them are requested only if no other request is in flight.
+        let id = ctx.peers[i].id;
+        if self.sync_peer != Some(id) {
+            if let (Some(locators), None) = (locators, self.sync_peer) {
+                self.request_headers(ctx, id, locators);
+            }
+            return;
+        }
+
+        match locators {
+            Some(locators) => {
+                // Request the following headers from the next peer.
+                let next = ctx.peers[(i + 1) % ctx.peers.len()].id;
+                self.request_headers(ctx, next, locators);
+            }
+            None => {
+                self.sync_peer = None;
+                if self.synced {
+                    return;
+                }
+
+                info!(
+                    "Block headers are downloaded. height: {}",
+                    ctx.chain_active.height()
+                );
+                self.synced = true;
+
+                // Ask the peers to announce new blocks with headers.
+                for peer in ctx.peers.iter_mut() {
+                    peer.start_send(NetworkMessage::SendHeaders);
+                }
+            }
+        }
+    }
+
+    /// Request the headers of the announced blocks which are not known.
+    fn on_inv<T, S>(
+        &mut self,
+        ctx: &mut Context<T, S>,
+        id: PeerID,
+        inventory: &[Inventory],
+    ) -> Result<(), Error>
+    where
+        T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
+        S: ChainStore,
+    {
+        if !self.is_synced() {
+            // The blocks will be downloaded by the request in flight.
+            return Ok(());
+        }
+
+        let mut has_unknown_block = false;
+        for inv in inventory {
+            if let Inventory::Block(hash) | Inventory::WitnessBlock(hash) = inv {
+                if ctx.chain_active.get_locator_from(hash)?.is_none() {
+                    has_unknown_block = true;
+                    break;
+                }
+            }
+        }
+        if has_unknown_block {
+            trace!("New block is announced by peer {}.", id);
+            let locators = ctx.chain_active.get_locator()?;
+            self.request_headers(ctx, id, locators);
+        }
+        Ok(())
+    }
+
+    fn request_headers<T, S>(
+        &mut self,
+        ctx: &mut Context<T, S>,
+        id: PeerID,
+        locators: Vec<BlockHash>,
+    ) where
+        T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
+        S: ChainStore,
+    {
+        if let Some(peer) = ctx.peer_mut(id) {
+            trace!("Request block headers to peer {}.", id);
+            peer.send_getheaders(locators);
+            self.sync_peer = Some(id);
+            self.sync_requested = Some(Instant::now());
+        }
+    }
+
+    /// Report the sync progress to the listener if it changed.
+    fn update_sync_progress(&mut self, height: i32) {
+        let progress = SyncProgress {
+            height,
+            best_known_height: std::cmp::max(height, self.best_known_height),
+        };
+
+        if self.sync_progress == Some(progress) {
+            return;
+        }
+
+        if progress.height < progress.best_known_height {
+            info!("Syncing block headers {}", progress);
+        }
+        if let Some(listener) = self.sync_progress_listener.as_ref() {
+            listener(progress);
+        }
+        self.sync_progress = Some(progress);
+    }
+}
+
+impl<T, S> Driver<T, S> for HeaderSyncDriver
+where
+    T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
+    S: ChainStore,
+{
+    fn peer_connected(&mut self, ctx: &mut Context<T, S>, id: PeerID) {
+        let synced = self.synced;
+        if let Some(peer) = ctx.peer_mut(id) {
+            self.best_known_height = std::cmp::max(self.best_known_height, peer.start_height());
+            if synced {
+                peer.start_send(NetworkMessage::SendHeaders);
+            }
+        }
+    }
+
+    fn peer_disconnected(&mut self, id: PeerID) {
+        // Another peer will be requested the headers.
+        if self.sync_peer == Some(id) {
+            self.sync_peer = None;
+        }
+    }
+
+    fn on_message(
+        &mut self,
+        ctx: &mut Context<T, S>,
+        id: PeerID,
+        message: Message,
+    ) -> Result<Option<Message>, Error> {
+        let result = match message {
+            Message::Network(NetworkMessage::Headers(headers)) => {
+                self.on_headers(ctx, id, headers).map(|_| None)
+            }
+            Message::Network(NetworkMessage::Inv(inventory)) => {
+                self.on_inv(ctx, id, &inventory)?;
+                // The announcements of the transactions are processed by other driver.
+                Ok(Some(Message::Network(NetworkMessage::Inv(inventory))))
+            }
+            message => Ok(Some(message)),
+        };
+        ctx.headers_synced = self.is_synced();
+        result
+    }
+
+    fn on_event(&mut self, ctx: &mut Context<T, S>, event: &Event) -> Result<(), Error> {
+        if let Event::PeerMisbehaved(id) = event {
+            if self.sync_peer == Some(*id) {
+                self.sync_peer = None;
+                ctx.headers_synced = self.is_synced();
+            }
+        }
+        Ok(())
+    }
+
+    /// Disconnect the sync peer if it doesn't answer getheaders in time. The headers are
+    /// requested to another peer after that.
+    fn tick(&mut self, ctx: &mut Context<T, S>) {
+        if let (Some(id), Some(requested)) = (self.sync_peer, self.sync_requested) {
+            if requested.elapsed() > self.getheaders_timeout {
+                ctx.disconnect(id, Error::GetHeadersTimeout(id).to_string());
+            }
+        }
+    }
+
+    fn send_requests(&mut self, ctx: &mut Context<T, S>) -> Result<(), Error> {
+        if !self.synced && self.sync_peer.is_none() && !ctx.peers.is_empty() {
+            let id = ctx.peers[0].id;
+            let locators = ctx.chain_active.get_locator()?;
+            self.request_headers(ctx, id, locators);
+        }
+        ctx.headers_synced = self.is_synced();
+        self.update_sync_progress(ctx.chain_active.height());
+        Ok(())
+    }
+}
@@ -0,0 +1,327 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+use crate::chain::ChainStore;
+use crate::network::driver::{Context, Driver, Event};
+use crate::network::mempool::Mempool;
+use crate::network::peer::PeerID;
+use crate::network::{
+    Error, MatchedTransaction, Message, RawMessage, TransactionListener, WatchList,
+};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tapyrus::network::message::NetworkMessage;
+use tapyrus::network::message_blockdata::Inventory;
+use tapyrus::{Transaction, Txid};
+use tokio::prelude::{Sink, Stream};
+
+/// Time limit for the unconfirmed transaction to stay in the pool.
+const MEMPOOL_EXPIRY: Duration = Duration::from_secs(14 * 24 * 60 * 60);
+
+/// Time limit for the peer to send the requested unconfirmed transaction.
+const TX_REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// This driver keeps the pool of the unconfirmed transactions which match the watch list.
+///
+/// The transactions which the peers with the bloom filter announce are downloaded into the pool
+/// if they match the watch list, and the outpoints which they spend are watched while they are in
+/// the pool. The transactions found in the blocks are removed from the pool, and they are added
+/// back to it when their blocks are disconnected by reorganization.
+///
+/// The pool is collected only with the bloom filters of BIP37. Our version message doesn't ask
+/// the peers to relay the transactions, so they announce only the transactions matching the
+/// loaded bloom filter. With the compact block filters, no filter is loaded and the pool stays
+/// empty.
+pub struct MempoolDriver {
+    /// Unconfirmed transactions which match the watch list.
+    mempool: Arc<Mutex<Mempool>>,
+    /// Listener which is called with the matched transactions in the disconnected blocks.
+    disconnected_transaction_listener: Option<TransactionListener>,
+    /// The peers which the bloom filter is loaded to.
+    filtered_peers: HashSet<PeerID>,
+}
+
+impl MempoolDriver {
+    pub fn new() -> MempoolDriver {
+        MempoolDriver {
+            mempool: Arc::new(Mutex::new(Mempool::new())),
+            disconnected_transaction_listener: None,
+            filtered_peers: HashSet::new(),
+        }
+    }
+
+    /// Share the pool of the unconfirmed transactions which match the watch list.
+    pub fn share_mempool(&mut self, mempool: Arc<Mutex<Mempool>>) {
+        self.mempool = mempool;
+    }
+
+    /// Set the listener which is called when the block including the matched transaction is
+    /// disconnected by reorganization.
+    pub fn set_disconnected_transaction_listener(&mut self, listener: TransactionListener) {
+        self.disconnected_transaction_listener = Some(listener);
+    }
+
+    /// Watch the outpoints which the transactions in the pool spend, so that the conflicting
+    /// spend is found when it is confirmed. The outpoints of the removed transactions are no
+    /// longer watched.
+    fn update_mempool_outpoints(&self, watch_list: &Mutex<WatchList>) {
+        let outpoints = self.mempool.lock().unwrap().spent_outpoints();
+        watch_list.lock().unwrap().set_mempool_outpoints(outpoints);
+    }
+
+    /// Request the announced transactions from the peer which the bloom filter is loaded to,
+    /// because it announces only the transactions which match the filter.
+    fn on_inv<T, S>(&mut self, ctx: &mut Context<T, S>, id: PeerID, inventory: &[Inventory])
+    where
+        T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
+        S: ChainStore,
+    {
+        if !self.filtered_peers.contains(&id) {
+            return;
+        }
+        let txids: Vec<Txid> = inventory
+            .iter()
+            .filter_map(|inv| match inv {
+                Inventory::Transaction(txid) | Inventory::WitnessTransaction(txid) => Some(*txid),
+                _ => None,
+            })
+            .collect();
+        if txids.is_empty() {
+            return;
+        }
+
+        let requests = self.mempool.lock().unwrap().request(id, &txids);
+        if let (false, Some(peer)) = (requests.is_empty(), ctx.peer_mut(id)) {
+            trace!("Request {} transactions to peer {}.", requests.len(), id);
+            let inventory = requests.into_iter().map(Inventory::Transaction).collect();
+            peer.start_send(NetworkMessage::GetData(inventory));
+        }
+    }
+
+    /// Add the unconfirmed transaction to the pool if it matches the watch list.
+    fn on_unconfirmed_tx<T, S>(&mut self, ctx: &mut Context<T, S>, tx: Transaction)
+    where
+        T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
+        S: ChainStore,
+    {
+        let added = {
+            let watch_list = ctx.watch_list.lock().unwrap();
+            self.mempool.lock().unwrap().on_tx(tx, &watch_list)
+        };
+        if added {
+            self.update_mempool_outpoints(ctx.watch_list);
+        }
+    }
+
+    /// Remove the transactions found in the blocks from the pool.
+    fn on_matched_transactions<T, S>(
+        &mut self,
+        ctx: &mut Context<T, S>,
+        matched: &[MatchedTransaction],
+    ) -> Result<(), Error>
+    where
+        T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
+        S: ChainStore,
+    {
+        {
+            let mut mempool = self.mempool.lock().unwrap();
+            for matched in matched.iter() {
+                // The block is in the active chain because it was just verified.
+                let block_hash = ctx
+                    .chain_active
+                    .get(matched.height)?
+                    .unwrap()
+                    .header
+                    .block_hash();
+                mempool.on_confirmed(&matched.tx, block_hash, matched.height);
+            }
+            mempool.prune_confirmed(ctx.chain_active.height());
+        }
+        self.update_mempool_outpoints(ctx.watch_list);
+        Ok(())
+    }
+
+    /// Add the matched transactions in the disconnected blocks back to the pool, and report them
+    /// to the listener.
+    fn on_chain_reorganized<T, S>(
+        &mut self,
+        ctx: &mut Context<T, S>,
+        disconnected: Vec<MatchedTransaction>,
+    ) where
+        T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
+        S: ChainStore,
+    {
+        if !disconnected.is_empty() {
+            self.update_mempool_outpoints(ctx.watch_list);
+        }
+        for matched in disconnected {
+            info!(
+                "Transaction {} at height {} is disconnected.",
+                matched.tx.txid(),
+                matched.height
+            );
+            if let Some(listener) = self.disconnected_transaction_listener.as_ref() {
+                listener(matched);
+            }
+        }
+    }
+}
+
+impl<T, S> Driver<T, S> for MempoolDriver
+where
+    T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
+    S: ChainStore,
+{
+    fn peer_disconnected(&mut self, id: PeerID) {
+        self.mempool.lock().unwrap().peer_disconnected(id);
+        self.filtered_peers.remove(&id);
+    }
+
+    fn on_message(
+        &mut self,
+        ctx: &mut Context<T, S>,
+        id: PeerID,
+        message: Message,
+    ) -> Result<Option<Message>, Error> {
+        match message {
+            Message::Network(NetworkMessage::Inv(inventory)) => {
+                self.on_inv(ctx, id, &inventory);
+                Ok(Some(Message::Network(NetworkMessage::Inv(inventory))))
+            }
+            Message::Network(NetworkMessage::Tx(tx))
+                if self.mempool.lock().unwrap().is_requested(id, &tx.txid()) =>
+            {
+                self.on_unconfirmed_tx(ctx, tx);
+                Ok(None)
+            }
+            message => Ok(Some(message)),
+        }
+    }
+
+    fn on_event(&mut self, ctx: &mut Context<T, S>, event: &Event) -> Result<(), Error> {
+        match event {
+            Event::TransactionsFound(matched) => self.on_matched_transactions(ctx, matched)?,
+            Event::ChainReorganized(change) => {
+                let disconnected = {
+                    let mut mempool = self.mempool.lock().unwrap();
+                    change
+                        .disconnected
+                        .iter()
+                        .flat_map(|index| mempool.on_disconnected(&index.header.block_hash()))
+                        .collect()
+                };
+                self.on_chain_reorganized(ctx, disconnected);
+            }
+            Event::BloomFilterLoaded(id) => {
+                self.filtered_peers.insert(*id);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn tick(&mut self, ctx: &mut Context<T, S>) {
+        self.mempool
+            .lock()
+            .unwrap()
+            .expire(MEMPOOL_EXPIRY, TX_REQUEST_TIMEOUT);
+        self.update_mempool_outpoints(ctx.watch_list);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chain::ChainChange;
+    use crate::network::Peer;
+    use crate::test_helper::{
+        build_branch, channel, get_chain, get_test_genesis_block, get_test_headers, TwoWayChannel,
+    };
+    use std::net::SocketAddr;
+    use tapyrus::network::constants::NetworkId;
+    use tapyrus::OutPoint;
+
+    #[test]
+    fn test_request_transactions_only_from_filtered_peers() {
+        let mut chain = get_chain();
+        let (_here, there) = channel::<RawMessage>();
+        let addr: SocketAddr = "10.0.0.1:12383".parse().unwrap();
+        let mut peers = vec![Peer::new(0, there, addr, NetworkId::REGTEST.magic())];
+        let watch_list = Mutex::new(WatchList::new());
+        let mut driver = MempoolDriver::new();
+        let mut ctx = Context::new(&mut peers, &mut chain, &watch_list, true);
+
+        let txid = get_test_genesis_block().txdata[0].txid();
+        let inv = || Message::Network(NetworkMessage::Inv(vec![Inventory::Transaction(txid)]));
+
+        // The peer without the bloom filter, such as the peer serving the compact block filters,
+        // is not asked for the announced transactions.
+        driver.on_message(&mut ctx, 0, inv()).unwrap();
+        assert!(!driver.mempool.lock().unwrap().is_requested(0, &txid));
+
+        driver
+            .on_event(&mut ctx, &Event::BloomFilterLoaded(0))
+            .unwrap();
+        driver.on_message(&mut ctx, 0, inv()).unwrap();
+        assert!(driver.mempool.lock().unwrap().is_requested(0, &txid));
+    }
+
+    #[test]
+    fn test_report_transactions_in_disconnected_blocks() {
+        let mut chain = get_chain();
+        let headers = get_test_headers(0, 5);
+        for header in headers[1..].iter() {
+            chain.connect_block_header(header.clone()).unwrap();
+        }
+        let mut peers: Vec<Peer<TwoWayChannel<RawMessage>>> = vec![];
+        let watch_list = Mutex::new(WatchList::new());
+        let mut driver = MempoolDriver::new();
+        let disconnected = Arc::new(Mutex::new(vec![]));
+        let disconnected_for_listener = disconnected.clone();
+        driver.set_disconnected_transaction_listener(Arc::new(move |tx| {
+            disconnected_for_listener.lock().unwrap().push(tx);
+        }));
+
+        // The transaction is found at height 4.
+        let coinbase = get_test_genesis_block().txdata[0].clone();
+        let mut tx = coinbase.clone();
+        tx.input[0].previous_output = OutPoint::new(coinbase.txid(), 0);
+        let matched = MatchedTransaction {
+            tx: tx.clone(),
+            height: 4,
+        };
+        let mut ctx = Context::new(&mut peers, &mut chain, &watch_list, true);
+        driver
+            .on_event(&mut ctx, &Event::TransactionsFound(vec![matched.clone()]))
+            .unwrap();
+        assert!(driver.mempool.lock().unwrap().transactions().is_empty());
+
+        let mut change = ChainChange::default();
+        for header in build_branch(&headers[2], 3) {
+            change.append(ctx.chain_active.connect_block_header(header).unwrap());
+        }
+        driver
+            .on_event(&mut ctx, &Event::ChainReorganized(change))
+            .unwrap();
+        assert_eq!(*disconnected.lock().unwrap(), vec![matched]);
+        assert_eq!(
+            driver.mempool.lock().unwrap().transactions(),
+            vec![tx.clone()]
+        );
+
+        // The spent outpoint is watched to find the conflicting spend while it is in the pool.
+        let mut double_spend = tx;
+        double_spend.output.clear();
+        assert!(watch_list.lock().unwrap().matches(&double_spend));
+
+        driver
+            .mempool
+            .lock()
+            .unwrap()
+            .expire(Duration::from_secs(0), Duration::from_secs(0));
+        driver.tick(&mut ctx);
+        assert!(!watch_list.lock().unwrap().matches(&double_spend));
+    }
+}
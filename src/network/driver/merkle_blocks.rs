@@ -0,0 +1,187 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+use crate::chain::ChainStore;
+use crate::network::bloom_filter::{BloomFilter, FilterAddMessage, BLOOM_UPDATE_ALL};
+use crate::network::driver::{Context, Driver, Event};
+use crate::network::merkle_block_download::{MerkleBlockDownload, MAX_MERKLE_BLOCKS_IN_FLIGHT};
+use crate::network::peer::PeerID;
+use crate::network::{Error, Message, RawMessage};
+use std::collections::HashMap;
+use std::time::Duration;
+use tapyrus::network::constants::ServiceFlags;
+use tapyrus::network::message::NetworkMessage;
+use tokio::prelude::{Sink, Stream};
+
+/// Time limit for the peer to send the next requested merkle block.
+const MERKLE_BLOCK_TIMEOUT: Duration = Duration::from_secs(2 * 60);
+
+/// The false positive rate of the bloom filter which is loaded to the peers.
+const BLOOM_FALSE_POSITIVE_RATE: f64 = 0.0005;
+
+/// The number of elements which can be added to the loaded bloom filter without losing accuracy.
+const BLOOM_FILTER_ELEMENTS_MARGIN: usize = 100;
+
+/// This driver looks up the transactions matching the watch list with the bloom filters of
+/// BIP37.
+///
+/// The bloom filter of the watch list is loaded to the peers which support BIP37, and the
+/// elements which are watched after loading are added to the filters. After the block headers
+/// are downloaded, the merkle blocks which are not scanned yet are requested to the peer which
+/// the bloom filter is loaded to. The peer which doesn't send them in time is disconnected, and
+/// they are requested to another peer. The found transactions are emitted for the other drivers.
+pub struct MerkleBlockDriver {
+    download: MerkleBlockDownload,
+    /// The number of the watch list elements which are loaded to the bloom filter of each peer.
+    filters: HashMap<PeerID, usize>,
+    filter_tweak: u32,
+}
+
+impl MerkleBlockDriver {
+    pub fn new() -> MerkleBlockDriver {
+        MerkleBlockDriver {
+            download: MerkleBlockDownload::new(),
+            filters: HashMap::new(),
+            filter_tweak: rand::random(),
+        }
+    }
+
+    /// Load the bloom filter of the watch list to the peers which support BIP37, and add the
+    /// elements which are watched after loading to the filters.
+    fn update_filters<T, S>(&mut self, ctx: &mut Context<T, S>)
+    where
+        T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
+        S: ChainStore,
+    {
+        let watch_list = ctx.watch_list.lock().unwrap();
+        if watch_list.is_empty() {
+            return;
+        }
+        let elements = watch_list.elements();
+
+        let mut loaded = vec![];
+        for peer in ctx.peers.iter_mut() {
+            if !peer.features.services.has(ServiceFlags::BLOOM) {
+                continue;
+            }
+
+            match self.filters.get(&peer.id) {
+                Some(&count) => {
+                    for element in &elements[count..] {
+                        peer.start_send(Message::FilterAdd(FilterAddMessage {
+                            data: element.clone(),
+                        }));
+                    }
+                }
+                None => {
+                    let mut filter = BloomFilter::new(
+                        elements.len() + BLOOM_FILTER_ELEMENTS_MARGIN,
+                        BLOOM_FALSE_POSITIVE_RATE,
+                        self.filter_tweak,
+                        BLOOM_UPDATE_ALL,
+                    );
+                    for element in elements {
+                        filter.insert(element);
+                    }
+                    trace!("Load bloom filter to peer {}.", peer.id);
+                    peer.start_send(Message::FilterLoad(filter.to_filterload()));
+                    loaded.push(peer.id);
+                }
+            }
+            self.filters.insert(peer.id, elements.len());
+        }
+        drop(watch_list);
+
+        for id in loaded {
+            ctx.emit(Event::BloomFilterLoaded(id));
+        }
+    }
+
+    /// Request the merkle blocks which are not scanned yet to the peer which the bloom filter is
+    /// loaded to. They are requested after the block headers are downloaded.
+    fn request_merkle_blocks<T, S>(&mut self, ctx: &mut Context<T, S>) -> Result<(), Error>
+    where
+        T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
+        S: ChainStore,
+    {
+        if !ctx.headers_synced || self.download.peer().is_some() {
+            return Ok(());
+        }
+
+        let filters = &self.filters;
+        let peer = match ctx
+            .peers
+            .iter_mut()
+            .find(|peer| filters.contains_key(&peer.id))
+        {
+            Some(peer) => peer,
+            None => return Ok(()),
+        };
+
+        let hashes =
+            self.download
+                .request(peer.id, ctx.chain_active, MAX_MERKLE_BLOCKS_IN_FLIGHT)?;
+        if !hashes.is_empty() {
+            peer.start_send(Message::GetMerkleBlocks(hashes));
+        }
+        Ok(())
+    }
+}
+
+impl<T, S> Driver<T, S> for MerkleBlockDriver
+where
+    T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
+    S: ChainStore,
+{
+    fn peer_disconnected(&mut self, id: PeerID) {
+        self.download.peer_disconnected(id);
+        self.filters.remove(&id);
+    }
+
+    fn on_message(
+        &mut self,
+        ctx: &mut Context<T, S>,
+        id: PeerID,
+        message: Message,
+    ) -> Result<Option<Message>, Error> {
+        let matched = match message {
+            Message::MerkleBlock(block) => {
+                self.download.on_merkle_block(id, ctx.chain_active, block)?
+            }
+            Message::Network(NetworkMessage::Tx(tx)) if self.download.expects(id, &tx.txid()) => {
+                self.download.on_tx(id, tx)
+            }
+            message => return Ok(Some(message)),
+        };
+
+        if !matched.is_empty() {
+            ctx.emit(Event::TransactionsFound(matched));
+        }
+        Ok(None)
+    }
+
+    fn on_event(&mut self, ctx: &mut Context<T, S>, event: &Event) -> Result<(), Error> {
+        match event {
+            Event::ChainReorganized(_) => self.download.rewind(ctx.chain_active)?,
+            Event::PeerMisbehaved(id) if self.download.peer() == Some(*id) => self.download.reset(),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Disconnect the peer which doesn't send the requested merkle blocks in time. The blocks
+    /// are requested to another peer after that.
+    fn tick(&mut self, ctx: &mut Context<T, S>) {
+        if let Some(id) = self.download.peer() {
+            if self.download.is_stalled(MERKLE_BLOCK_TIMEOUT) {
+                ctx.disconnect(id, Error::MerkleBlockTimeout(id).to_string());
+            }
+        }
+    }
+
+    fn send_requests(&mut self, ctx: &mut Context<T, S>) -> Result<(), Error> {
+        self.update_filters(ctx);
+        self.request_merkle_blocks(ctx)
+    }
+}
@@ -0,0 +1,188 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+//! The drivers of the features which PeerManager runs over the connected peers.
+//!
+//! PeerManager keeps the connections, and dispatches the messages from the peers, the
+//! disconnections and the timer ticks to the drivers. Each driver owns the state of single
+//! feature, such as the block header download or the unconfirmed transactions, and talks to the
+//! others only through the events in Context.
+
+use crate::chain::{Chain, ChainChange, ChainStore};
+use crate::network::peer::PeerID;
+use crate::network::{
+    Error, MaliciousPeerCause, MatchedTransaction, Message, Peer, RawMessage, WatchList,
+};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use tapyrus::BlockHash;
+use tokio::prelude::{Sink, Stream};
+
+mod blocks;
+pub use self::blocks::BlockDriver;
+
+mod broadcasts;
+pub use self::broadcasts::BroadcastDriver;
+
+mod compact_filters;
+pub use self::compact_filters::CompactFilterDriver;
+
+mod headers;
+pub use self::headers::{HeaderSyncDriver, DEFAULT_GETHEADERS_TIMEOUT};
+
+mod mempool;
+pub use self::mempool::MempoolDriver;
+
+mod merkle_blocks;
+pub use self::merkle_blocks::MerkleBlockDriver;
+
+mod transaction_reports;
+pub use self::transaction_reports::TransactionReportDriver;
+
+/// The events which a driver emits for the other drivers.
+#[derive(Debug)]
+pub enum Event {
+    /// The active chain is reorganized by the block headers.
+    ChainReorganized(ChainChange),
+    /// The peer misbehaved and is kept connected. The requests to it may never be answered, so
+    /// they should be sent to another peer.
+    PeerMisbehaved(PeerID),
+    /// The compact block filters of the blocks match the watch list, so the full blocks should
+    /// be downloaded.
+    BlocksMatched(Vec<BlockHash>),
+    /// The transactions matching the watch list are found in the blocks of the active chain.
+    TransactionsFound(Vec<MatchedTransaction>),
+    /// The bloom filter is loaded to the peer, so it announces the matching transactions.
+    BloomFilterLoaded(PeerID),
+}
+
+/// The misbehaviors and the disconnections which the drivers ask PeerManager to apply.
+#[derive(Debug, Default)]
+pub struct Reports {
+    pub misbehaviors: Vec<(PeerID, MaliciousPeerCause)>,
+    pub disconnects: Vec<(PeerID, String)>,
+}
+
+impl Reports {
+    /// Move the reports of `other` to the end of these.
+    pub fn append(&mut self, mut other: Reports) {
+        self.misbehaviors.append(&mut other.misbehaviors);
+        self.disconnects.append(&mut other.disconnects);
+    }
+}
+
+/// The state of PeerManager which is lent to the drivers on each call.
+pub struct Context<'a, T, S>
+where
+    T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
+    S: ChainStore,
+{
+    /// Connected peers ordered by the time of connection.
+    pub peers: &'a mut Vec<Peer<T>>,
+    pub chain_active: &'a mut Chain<S>,
+    /// Scripts and outpoints which are looked up from the blocks.
+    pub watch_list: &'a Mutex<WatchList>,
+    /// True if the block headers are downloaded and no getheaders is in flight. The blocks are
+    /// scanned only then.
+    pub headers_synced: bool,
+    events: VecDeque<Event>,
+    reports: Reports,
+}
+
+impl<'a, T, S> Context<'a, T, S>
+where
+    T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
+    S: ChainStore,
+{
+    pub fn new(
+        peers: &'a mut Vec<Peer<T>>,
+        chain_active: &'a mut Chain<S>,
+        watch_list: &'a Mutex<WatchList>,
+        headers_synced: bool,
+    ) -> Context<'a, T, S> {
+        Context {
+            peers,
+            chain_active,
+            watch_list,
+            headers_synced,
+            events: VecDeque::new(),
+            reports: Reports::default(),
+        }
+    }
+
+    /// Return the connected peer.
+    pub fn peer_mut(&mut self, id: PeerID) -> Option<&mut Peer<T>> {
+        self.peers.iter_mut().find(|peer| peer.id == id)
+    }
+
+    /// Emit the event for the other drivers. It is dispatched after the current call returns.
+    pub fn emit(&mut self, event: Event) {
+        self.events.push_back(event);
+    }
+
+    /// Return the event which is not dispatched yet.
+    pub fn next_event(&mut self) -> Option<Event> {
+        self.events.pop_front()
+    }
+
+    /// Report the misbehavior of the peer which is found outside of its messages.
+    pub fn misbehaving(&mut self, id: PeerID, cause: MaliciousPeerCause) {
+        self.reports.misbehaviors.push((id, cause));
+    }
+
+    /// Ask PeerManager to disconnect the peer.
+    pub fn disconnect(&mut self, id: PeerID, reason: String) {
+        self.reports.disconnects.push((id, reason));
+    }
+
+    /// Return true if the misbehavior or the disconnection of the peer is reported.
+    pub fn is_reported(&self, id: PeerID) -> bool {
+        self.reports
+            .misbehaviors
+            .iter()
+            .any(|(peer, _)| *peer == id)
+            || self.reports.disconnects.iter().any(|(peer, _)| *peer == id)
+    }
+
+    /// Return the reported misbehaviors and the peers to disconnect.
+    pub fn into_reports(self) -> Reports {
+        self.reports
+    }
+}
+
+/// This trait presents the driver of single feature over the connected peers.
+pub trait Driver<T, S>
+where
+    T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
+    S: ChainStore,
+{
+    /// Start talking to the peer which completed handshake.
+    fn peer_connected(&mut self, _ctx: &mut Context<T, S>, _id: PeerID) {}
+
+    /// Forget the state of the disconnected peer. The requests to it are sent to another peer.
+    fn peer_disconnected(&mut self, id: PeerID);
+
+    /// Process the message from the peer. Return the message back if the driver doesn't consume
+    /// it, so that it is passed to the next driver.
+    fn on_message(
+        &mut self,
+        ctx: &mut Context<T, S>,
+        id: PeerID,
+        message: Message,
+    ) -> Result<Option<Message>, Error>;
+
+    /// Process the event which is emitted by a driver.
+    fn on_event(&mut self, _ctx: &mut Context<T, S>, _event: &Event) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Check the timeouts. It is called at the interval of the timer of PeerManager.
+    fn tick(&mut self, _ctx: &mut Context<T, S>) {}
+
+    /// Send the requests which are ready. It is called after the messages from the peers are
+    /// processed.
+    fn send_requests(&mut self, _ctx: &mut Context<T, S>) -> Result<(), Error> {
+        Ok(())
+    }
+}
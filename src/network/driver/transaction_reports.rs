@@ -0,0 +1,61 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+use crate::chain::ChainStore;
+use crate::network::driver::{Context, Driver, Event};
+use crate::network::peer::PeerID;
+use crate::network::{Error, Message, RawMessage, TransactionListener};
+use tokio::prelude::{Sink, Stream};
+
+/// This driver reports the transactions which the other drivers found in the blocks of the
+/// active chain to the listener.
+pub struct TransactionReportDriver {
+    transaction_listener: Option<TransactionListener>,
+}
+
+impl TransactionReportDriver {
+    pub fn new() -> TransactionReportDriver {
+        TransactionReportDriver {
+            transaction_listener: None,
+        }
+    }
+
+    /// Set the listener which is called when the transaction matching the watch list is found.
+    pub fn set_transaction_listener(&mut self, listener: TransactionListener) {
+        self.transaction_listener = Some(listener);
+    }
+}
+
+impl<T, S> Driver<T, S> for TransactionReportDriver
+where
+    T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
+    S: ChainStore,
+{
+    fn peer_disconnected(&mut self, _id: PeerID) {}
+
+    fn on_message(
+        &mut self,
+        _ctx: &mut Context<T, S>,
+        _id: PeerID,
+        message: Message,
+    ) -> Result<Option<Message>, Error> {
+        Ok(Some(message))
+    }
+
+    fn on_event(&mut self, _ctx: &mut Context<T, S>, event: &Event) -> Result<(), Error> {
+        if let Event::TransactionsFound(matched) = event {
+            for matched in matched {
+                info!(
+                    "Transaction {} is found at height {}.",
+                    matched.tx.txid(),
+                    matched.height
+                );
+                if let Some(listener) = self.transaction_listener.as_ref() {
+                    listener(matched.clone());
+                }
+            }
+        }
+        Ok(())
+    }
+}
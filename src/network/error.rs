@@ -4,15 +4,32 @@
 
 use crate::network::peer::PeerID;
 use crate::network::utils::codec;
+use std::fmt;
+use tapyrus::network::constants::ServiceFlags;
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug)]
 pub enum Error {
     IoError(std::io::Error),
     CodecError(codec::Error),
     UnboundedSendError(tokio::sync::mpsc::error::UnboundedSendError),
     UnboundedRecvError(tokio::sync::mpsc::error::UnboundedRecvError),
+    ChainError(crate::chain::Error),
     MaliciousPeer(PeerID, MaliciousPeerCause),
-    WrongMagicBytes,
+    /// The remote peer closed the connection.
+    ConnectionClosed,
+    /// The remote peer didn't complete the handshake in time.
+    HandshakeTimeout,
+    /// The remote peer doesn't support the protocol features which we need.
+    IncompatiblePeer(PeerID, IncompatiblePeerCause),
+    /// The connection goes back to ourselves.
+    SelfConnection,
+    /// The remote peer didn't answer getheaders message in time.
+    GetHeadersTimeout(PeerID),
+    /// The remote peer didn't send the requested merkle blocks in time.
+    MerkleBlockTimeout(PeerID),
+    /// The remote peer didn't send the requested compact block filters in time.
+    CompactFilterTimeout(PeerID),
 }
 
 #[derive(Debug)]
@@ -20,6 +37,104 @@ pub enum MaliciousPeerCause {
     /// The peer send over maximum number which is MAX_HEADERS_RESULTS of headers in single
     /// headers message.
     SendOverMaxHeadersResults,
+    /// The peer send block header which has invalid block proof.
+    InvalidBlockProof,
+    /// The peer send headers which are not connected to our chain or each other.
+    DisconnectedHeaders,
+    /// The peer send headers which conflict with the checkpoints.
+    CheckpointMismatch,
+    /// The peer send message with magic bytes of other network.
+    WrongMagicBytes,
+    /// The peer send message which is larger than the limit of the protocol.
+    OversizedMessage,
+    /// The peer send data which we never requested.
+    UnsolicitedData,
+    /// The peer send merkle block whose partial merkle tree doesn't match the block header.
+    InvalidMerkleBlock,
+    /// The peer didn't send the matched transactions before the next merkle block.
+    IncompleteMerkleBlock,
+    /// The peer send filter headers which don't follow our filter header chain.
+    InvalidFilterHeaders,
+    /// The peer send filter headers which conflict with the filter checkpoints.
+    FilterCheckpointMismatch,
+    /// The peer send compact block filter which doesn't match the filter header.
+    InvalidFilter,
+    /// The peer send block whose transactions don't match the merkle root of the header.
+    InvalidBlock,
+}
+
+#[derive(Debug)]
+pub enum IncompatiblePeerCause {
+    /// The peer speaks the protocol version which is older than MIN_PROTOCOL_VERSION.
+    ObsoleteVersion(u32),
+    /// The peer doesn't provide the services which we need.
+    MissingServices(ServiceFlags),
+    /// The user agent of the peer is longer than MAX_USER_AGENT_LENGTH.
+    InvalidUserAgent,
+}
+
+impl MaliciousPeerCause {
+    /// Return misbehavior score for the cause. The peer is banned when the sum of its scores
+    /// reaches `BAN_THRESHOLD`.
+    pub fn score(&self) -> u32 {
+        match self {
+            MaliciousPeerCause::SendOverMaxHeadersResults => 20,
+            MaliciousPeerCause::InvalidBlockProof => 100,
+            MaliciousPeerCause::DisconnectedHeaders => 20,
+            MaliciousPeerCause::CheckpointMismatch => 100,
+            MaliciousPeerCause::WrongMagicBytes => 100,
+            MaliciousPeerCause::OversizedMessage => 20,
+            MaliciousPeerCause::UnsolicitedData => 10,
+            MaliciousPeerCause::InvalidMerkleBlock => 100,
+            MaliciousPeerCause::IncompleteMerkleBlock => 20,
+            MaliciousPeerCause::InvalidFilterHeaders => 20,
+            MaliciousPeerCause::FilterCheckpointMismatch => 100,
+            MaliciousPeerCause::InvalidFilter => 100,
+            MaliciousPeerCause::InvalidBlock => 100,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::IoError(e) => write!(f, "IO error: {}", e),
+            Error::CodecError(e) => write!(f, "Codec error: {}", e),
+            Error::UnboundedSendError(e) => write!(f, "Channel send error: {}", e),
+            Error::UnboundedRecvError(e) => write!(f, "Channel receive error: {}", e),
+            Error::ChainError(e) => write!(f, "Chain error: {}", e),
+            Error::MaliciousPeer(id, cause) => write!(f, "Malicious peer {}: {:?}", id, cause),
+            Error::ConnectionClosed => write!(f, "Connection closed"),
+            Error::HandshakeTimeout => write!(f, "Handshake timed out"),
+            Error::IncompatiblePeer(id, cause) => {
+                write!(f, "Incompatible peer {}: {}", id, cause)
+            }
+            Error::SelfConnection => write!(f, "Connected to ourselves"),
+            Error::GetHeadersTimeout(id) => {
+                write!(f, "Peer {} did not answer getheaders in time", id)
+            }
+            Error::MerkleBlockTimeout(id) => {
+                write!(f, "Peer {} did not send merkle blocks in time", id)
+            }
+            Error::CompactFilterTimeout(id) => {
+                write!(f, "Peer {} did not send compact block filters in time", id)
+            }
+        }
+    }
+}
+
+impl fmt::Display for IncompatiblePeerCause {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IncompatiblePeerCause::ObsoleteVersion(version) => {
+                write!(f, "obsolete protocol version {}", version)
+            }
+            IncompatiblePeerCause::MissingServices(services) => {
+                write!(f, "missing required services, it provides {}", services)
+            }
+            IncompatiblePeerCause::InvalidUserAgent => write!(f, "too long user agent"),
+        }
+    }
 }
 
 impl From<std::io::Error> for Error {
@@ -45,3 +160,9 @@ impl From<tokio::sync::mpsc::error::UnboundedRecvError> for Error {
         Error::UnboundedRecvError(e)
     }
 }
+
+impl From<crate::chain::Error> for Error {
+    fn from(e: crate::chain::Error) -> Error {
+        Error::ChainError(e)
+    }
+}
@@ -2,38 +2,106 @@
 // Distributed under the MIT software license, see the accompanying
 // file COPYING or http://www.opensource.org/licenses/mit-license.php.
 
-use crate::network::peer::version_message;
-use crate::network::{Error, Peer};
-use tapyrus::network::message::{NetworkMessage, RawNetworkMessage};
+use crate::network::message::{Message, RawMessage};
+use crate::network::peer::{version_message, Features, PeerID};
+use crate::network::{Error, IncompatiblePeerCause, Peer};
+use std::time::{Duration, Instant};
+use tapyrus::network::constants::{ServiceFlags, PROTOCOL_VERSION};
+use tapyrus::network::message::NetworkMessage;
+use tapyrus::network::message_network::VersionMessage;
 use tokio::prelude::*;
+use tokio::timer::Delay;
+
+/// Default time limit to complete the exchange of version and verack messages.
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The oldest protocol version which we can talk to.
+pub const MIN_PROTOCOL_VERSION: u32 = 10000;
+
+/// The services which the peers must provide by default. We download block headers from them.
+pub const REQUIRED_SERVICES: ServiceFlags = ServiceFlags::NETWORK;
+
+/// The maximum length of the user agent which the peer can send.
+pub const MAX_USER_AGENT_LENGTH: usize = 256;
 
 pub struct Handshake<T>
 where
-    T: Sink<SinkItem = RawNetworkMessage> + Stream<Item = RawNetworkMessage>,
+    T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
 {
     peer: Option<Peer<T>>,
     sent_version: bool,
     received_version: bool,
     received_verack: bool,
+    start_height: i32,
+    required_services: ServiceFlags,
+    /// Nonce of the version message which we sent.
+    nonce: Option<u64>,
+    deadline: Delay,
 }
 
 impl<T> Handshake<T>
 where
-    T: Sink<SinkItem = RawNetworkMessage> + Stream<Item = RawNetworkMessage>,
+    T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
 {
-    pub fn new(peer: Peer<T>) -> Handshake<T> {
+    /// Return handshake which advertises `start_height` as our best block height. It fails with
+    /// `Error::HandshakeTimeout` if it doesn't complete within `timeout`.
+    pub fn new(peer: Peer<T>, start_height: i32, timeout: Duration) -> Handshake<T> {
         Handshake {
             peer: Some(peer),
             sent_version: false,
             received_version: false,
             received_verack: false,
+            start_height,
+            required_services: REQUIRED_SERVICES,
+            nonce: None,
+            deadline: Delay::new(Instant::now() + timeout),
         }
     }
+
+    /// Require the peer to provide `services` instead of `REQUIRED_SERVICES`.
+    pub fn require_services(mut self, services: ServiceFlags) -> Handshake<T> {
+        self.required_services = services;
+        self
+    }
+}
+
+/// Check the version message of the peer and return the features which we can use.
+fn negotiate(
+    id: PeerID,
+    version: &VersionMessage,
+    required_services: ServiceFlags,
+) -> Result<Features, Error> {
+    if version.version < MIN_PROTOCOL_VERSION {
+        return Err(Error::IncompatiblePeer(
+            id,
+            IncompatiblePeerCause::ObsoleteVersion(version.version),
+        ));
+    }
+
+    if !version.services.has(required_services) {
+        return Err(Error::IncompatiblePeer(
+            id,
+            IncompatiblePeerCause::MissingServices(version.services),
+        ));
+    }
+
+    if version.user_agent.len() > MAX_USER_AGENT_LENGTH {
+        return Err(Error::IncompatiblePeer(
+            id,
+            IncompatiblePeerCause::InvalidUserAgent,
+        ));
+    }
+
+    Ok(Features {
+        version: std::cmp::min(version.version, PROTOCOL_VERSION),
+        services: version.services,
+        relay: version.relay,
+    })
 }
 
 impl<T> Future for Handshake<T>
 where
-    T: Sink<SinkItem = RawNetworkMessage> + Stream<Item = RawNetworkMessage>,
+    T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
     Error: From<T::Error>,
 {
     type Item = Peer<T>;
@@ -42,23 +110,38 @@ where
     fn poll(&mut self) -> Result<Async<Self::Item>, Self::Error> {
         if let Some(ref mut peer) = self.peer {
             if !self.sent_version {
-                peer.start_send(NetworkMessage::Version(version_message()));
+                let version = version_message(self.start_height);
+                self.nonce = Some(version.nonce);
+                peer.start_send(NetworkMessage::Version(version));
                 self.sent_version = true;
             }
 
             loop {
                 match peer.poll()? {
-                    Async::Ready(Some(NetworkMessage::Version(version))) => {
+                    Async::Ready(Some(Message::Network(NetworkMessage::Version(version)))) => {
+                        // The version message which we sent comes back.
+                        if self.nonce == Some(version.nonce) {
+                            return Err(Error::SelfConnection);
+                        }
+
+                        peer.features = negotiate(peer.id, &version, self.required_services)?;
+                        trace!(
+                            "Peer {} speaks version {} with services {}. user agent: {}",
+                            peer.id,
+                            version.version,
+                            version.services,
+                            version.user_agent
+                        );
                         peer.version = Some(version);
 
                         // send verack message
-                        let _ = peer.start_send(NetworkMessage::Verack);
+                        peer.start_send(NetworkMessage::Verack);
                         self.received_version = true;
                     }
-                    Async::Ready(Some(NetworkMessage::Verack)) => {
+                    Async::Ready(Some(Message::Network(NetworkMessage::Verack))) => {
                         self.received_verack = true;
                     }
-                    Async::Ready(None) => break,
+                    Async::Ready(None) => return Err(Error::ConnectionClosed),
                     Async::Ready(_) => {} // ignore other messages.
                     Async::NotReady => break,
                 }
@@ -72,10 +155,17 @@ where
         // check either handshake finished
         if self.sent_version && self.received_version && self.received_verack {
             let peer = self.peer.take().unwrap();
-            trace!("Handshake complete. peer: {}", peer.id);
-            Ok(Async::Ready(peer))
-        } else {
-            Ok(Async::NotReady)
+            trace!("Handshake complete. peer: {}, addr: {}", peer.id, peer.addr);
+            return Ok(Async::Ready(peer));
+        }
+
+        match self.deadline.poll() {
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Ok(Async::Ready(_)) => Err(Error::HandshakeTimeout),
+            Err(e) => {
+                error!("Timer error: {}", e);
+                Err(Error::HandshakeTimeout)
+            }
         }
     }
 }
@@ -83,47 +173,105 @@ where
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::test_helper::channel;
+    use crate::test_helper::{channel, TwoWayChannel};
     use tapyrus::network::constants::NetworkId;
 
+    /// Return version message of the remote peer which provides NODE_NETWORK service.
+    fn remote_version(start_height: i32) -> VersionMessage {
+        let mut version = version_message(start_height);
+        version.services = ServiceFlags::NETWORK;
+        version
+    }
+
+    /// Run handshake with the remote peer which sends `version`, or echoes our version message
+    /// back if it is None.
+    fn run_handshake(
+        version: Option<VersionMessage>,
+        required_services: ServiceFlags,
+    ) -> Result<Peer<TwoWayChannel<RawMessage>>, Error> {
+        let (here, there) = channel::<RawMessage>();
+
+        let addr = "0.0.0.0:0".parse().unwrap();
+        let peer = Peer::new(0, there, addr, NetworkId::REGTEST.magic());
+
+        let remote = here
+            .into_future()
+            .map_err(|_| ())
+            .and_then(move |(msg, mut here)| {
+                let ours = match msg {
+                    Some(RawMessage {
+                        payload: Message::Network(NetworkMessage::Version(version)),
+                        ..
+                    }) => version,
+                    _ => panic!("should send version message"),
+                };
+                for payload in [
+                    NetworkMessage::Version(version.unwrap_or(ours)),
+                    NetworkMessage::Verack,
+                ] {
+                    let _ = here.start_send(RawMessage {
+                        magic: NetworkId::REGTEST.magic(),
+                        payload: payload.into(),
+                    });
+                }
+                let _ = here.poll_complete();
+
+                // keep the channel open until the handshake finishes.
+                here.for_each(|_| Ok(())).map_err(|_| ())
+            });
+
+        let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+        runtime.spawn(remote);
+        runtime.block_on(
+            Handshake::new(peer, 0, DEFAULT_HANDSHAKE_TIMEOUT).require_services(required_services),
+        )
+    }
+
     #[test]
     fn test_handshake() {
-        let (here, there) = channel::<RawNetworkMessage>();
+        let (here, there) = channel::<RawMessage>();
 
         let addr = "0.0.0.0:0".parse().unwrap();
         let peer = Peer::new(0, there, addr, NetworkId::REGTEST.magic());
 
         let future = tokio::prelude::future::lazy(move || {
-            let handshake = Handshake::new(peer).map(|_| {}).map_err(|_| {});
+            let handshake = Handshake::new(peer, 5, DEFAULT_HANDSHAKE_TIMEOUT)
+                .map(|peer| {
+                    // the height which the remote peer advertised is recorded.
+                    assert_eq!(peer.start_height(), 10);
+                    assert_eq!(peer.features.version, PROTOCOL_VERSION);
+                    assert!(peer.features.services.has(ServiceFlags::NETWORK));
+                })
+                .map_err(|_| {});
 
             tokio::spawn(handshake);
 
             let test_future = here
                 .into_future()
                 .and_then(|(msg, mut here)| {
-                    // check version message received.
+                    // check version message received with our height.
                     match msg {
-                        Some(RawNetworkMessage {
-                            payload: NetworkMessage::Version(_),
+                        Some(RawMessage {
+                            payload: Message::Network(NetworkMessage::Version(version)),
                             ..
                         }) => {
-                            assert!(true);
+                            assert_eq!(version.start_height, 5);
                         }
-                        _ => assert!(false),
+                        _ => unreachable!(),
                     }
 
                     // send version message.
-                    let version = RawNetworkMessage {
+                    let version = RawMessage {
                         magic: NetworkId::REGTEST.magic(),
-                        payload: NetworkMessage::Version(version_message()),
+                        payload: NetworkMessage::Version(remote_version(10)).into(),
                     };
 
                     let _ = here.start_send(version);
 
                     // send verack message.
-                    let verack = RawNetworkMessage {
+                    let verack = RawMessage {
                         magic: NetworkId::REGTEST.magic(),
-                        payload: NetworkMessage::Verack,
+                        payload: NetworkMessage::Verack.into(),
                     };
                     let _ = here.start_send(verack);
 
@@ -135,13 +283,11 @@ mod tests {
                 .map(|(msg, _here)| {
                     // check verack message received.
                     match msg {
-                        Some(RawNetworkMessage {
-                            payload: NetworkMessage::Verack,
+                        Some(RawMessage {
+                            payload: Message::Network(NetworkMessage::Verack),
                             ..
-                        }) => {
-                            assert!(true);
-                        }
-                        _ => assert!(false),
+                        }) => {}
+                        _ => unreachable!(),
                     }
                 })
                 .map_err(|_| {});
@@ -153,4 +299,74 @@ mod tests {
 
         tokio::runtime::current_thread::run(future);
     }
+
+    #[test]
+    fn test_handshake_timeout() {
+        let (_here, there) = channel::<RawMessage>();
+
+        let addr = "0.0.0.0:0".parse().unwrap();
+        let peer = Peer::new(0, there, addr, NetworkId::REGTEST.magic());
+
+        // The remote peer never replies.
+        let future = Handshake::new(peer, 0, Duration::from_millis(10)).then(|result| {
+            match result {
+                Err(Error::HandshakeTimeout) => {}
+                _ => panic!("should fail with HandshakeTimeout"),
+            }
+            Ok(())
+        });
+
+        tokio::runtime::current_thread::run(future);
+    }
+
+    #[test]
+    fn test_negotiate_features() {
+        let mut version = remote_version(0);
+        version.services = ServiceFlags::NETWORK | ServiceFlags::BLOOM;
+        version.relay = true;
+
+        let peer = run_handshake(Some(version), REQUIRED_SERVICES).unwrap();
+        assert_eq!(
+            peer.features,
+            Features {
+                version: PROTOCOL_VERSION,
+                services: ServiceFlags::NETWORK | ServiceFlags::BLOOM,
+                relay: true,
+            }
+        );
+    }
+
+    #[test]
+    fn test_reject_incompatible_peer() {
+        let mut obsolete = remote_version(0);
+        obsolete.version = MIN_PROTOCOL_VERSION - 1;
+        match run_handshake(Some(obsolete), REQUIRED_SERVICES) {
+            Err(Error::IncompatiblePeer(_, IncompatiblePeerCause::ObsoleteVersion(version))) => {
+                assert_eq!(version, MIN_PROTOCOL_VERSION - 1)
+            }
+            _ => panic!("should reject obsolete version"),
+        }
+
+        // The peer doesn't serve compact filters.
+        let required = ServiceFlags::NETWORK | ServiceFlags::COMPACT_FILTERS;
+        match run_handshake(Some(remote_version(0)), required) {
+            Err(Error::IncompatiblePeer(_, IncompatiblePeerCause::MissingServices(_))) => {}
+            _ => panic!("should reject peer without required services"),
+        }
+
+        let mut long_user_agent = remote_version(0);
+        long_user_agent.user_agent = "a".repeat(MAX_USER_AGENT_LENGTH + 1);
+        match run_handshake(Some(long_user_agent), REQUIRED_SERVICES) {
+            Err(Error::IncompatiblePeer(_, IncompatiblePeerCause::InvalidUserAgent)) => {}
+            _ => panic!("should reject too long user agent"),
+        }
+    }
+
+    #[test]
+    fn test_detect_self_connection() {
+        match run_handshake(None, ServiceFlags::NONE) {
+            Err(Error::SelfConnection) => {}
+            _ => panic!("should detect self connection"),
+        }
+    }
 }
@@ -0,0 +1,355 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+use crate::network::peer::PeerID;
+use crate::network::{MatchedTransaction, WatchList};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tapyrus::{BlockHash, OutPoint, Transaction, Txid};
+
+/// The number of the recent blocks whose matched transactions are kept, so that they return to
+/// the pool when the blocks are disconnected by reorganization.
+pub const MAX_REORG_DEPTH: i32 = 100;
+
+/// The maximum number of the transactions which are requested to single peer at once. The
+/// transactions which are announced over it are not requested.
+pub const MAX_TX_REQUESTS_PER_PEER: usize = 100;
+
+/// Unconfirmed transaction in the pool.
+struct MempoolEntry {
+    tx: Transaction,
+    added_at: Instant,
+    /// The order of addition.
+    sequence: u64,
+}
+
+/// This struct keeps the unconfirmed transactions which match the watch list. It is shared
+/// between SPV and PeerManager.
+///
+/// The transactions which the peers announce by inv message are requested, and the received ones
+/// are added to the pool if they match the watch list. They are removed when they are confirmed,
+/// when the transactions which spend the same outputs are confirmed, or when they stay in the
+/// pool too long. The matched transactions in the recent blocks are added back when the blocks
+/// are disconnected by reorganization.
+#[derive(Default)]
+pub struct Mempool {
+    entries: HashMap<Txid, MempoolEntry>,
+    /// The transactions which are requested and not received yet, with the peer and the time of
+    /// the request.
+    requested: HashMap<Txid, (PeerID, Instant)>,
+    /// The matched transactions in the recent blocks, with the height of the block.
+    confirmed: HashMap<BlockHash, (i32, Vec<Transaction>)>,
+    next_sequence: u64,
+}
+
+impl Mempool {
+    pub fn new() -> Mempool {
+        Mempool::default()
+    }
+
+    /// Return the transactions announced by the peer which are neither in the pool nor
+    /// requested, and record that they are requested to the peer. They are limited so that the
+    /// peer has at most `MAX_TX_REQUESTS_PER_PEER` requests in flight.
+    pub fn request(&mut self, id: PeerID, txids: &[Txid]) -> Vec<Txid> {
+        let in_flight = self
+            .requested
+            .values()
+            .filter(|(peer, _)| *peer == id)
+            .count();
+        let mut requests = vec![];
+        for txid in txids {
+            if in_flight + requests.len() >= MAX_TX_REQUESTS_PER_PEER {
+                break;
+            }
+            if self.entries.contains_key(txid) || self.requested.contains_key(txid) {
+                continue;
+            }
+            self.requested.insert(*txid, (id, Instant::now()));
+            requests.push(*txid);
+        }
+        requests
+    }
+
+    /// Return true if the transaction is requested to the peer and not received yet.
+    pub fn is_requested(&self, id: PeerID, txid: &Txid) -> bool {
+        self.requested
+            .get(txid)
+            .is_some_and(|(peer, _)| *peer == id)
+    }
+
+    /// Forget the requests to the disconnected peer. The transactions are requested again when
+    /// other peer announces them.
+    pub fn peer_disconnected(&mut self, id: PeerID) {
+        self.requested.retain(|_, (peer, _)| *peer != id);
+    }
+
+    /// Add the requested transaction to the pool if it matches the watch list. Return true if it
+    /// is added.
+    pub fn on_tx(&mut self, tx: Transaction, watch_list: &WatchList) -> bool {
+        let txid = tx.txid();
+        if self.requested.remove(&txid).is_none() || !watch_list.matches(&tx) {
+            return false;
+        }
+
+        info!("Unconfirmed transaction {} is found.", txid);
+        self.insert(tx);
+        true
+    }
+
+    fn insert(&mut self, tx: Transaction) {
+        self.entries.insert(
+            tx.txid(),
+            MempoolEntry {
+                tx,
+                added_at: Instant::now(),
+                sequence: self.next_sequence,
+            },
+        );
+        self.next_sequence += 1;
+    }
+
+    /// Remove the transaction which is confirmed in the block, and the transactions which
+    /// conflict with it. The transactions which spend the outputs of the conflicting ones are
+    /// also removed because they can never be confirmed.
+    pub fn on_confirmed(&mut self, tx: &Transaction, block_hash: BlockHash, height: i32) {
+        let txid = tx.txid();
+        if self.entries.remove(&txid).is_some() {
+            info!("Unconfirmed transaction {} is confirmed.", txid);
+        }
+        let (_, txs) = self
+            .confirmed
+            .entry(block_hash)
+            .or_insert_with(|| (height, vec![]));
+        if !txs.iter().any(|confirmed| confirmed.txid() == txid) {
+            txs.push(tx.clone());
+        }
+
+        let mut conflicts: Vec<Txid> = self
+            .entries
+            .values()
+            .filter(|entry| {
+                entry.tx.input.iter().any(|input| {
+                    tx.input
+                        .iter()
+                        .any(|spent| spent.previous_output == input.previous_output)
+                })
+            })
+            .map(|entry| entry.tx.txid())
+            .collect();
+        while let Some(conflict) = conflicts.pop() {
+            if self.entries.remove(&conflict).is_none() {
+                continue;
+            }
+            info!(
+                "Unconfirmed transaction {} is dropped because it conflicts with {}.",
+                conflict, txid
+            );
+            conflicts.extend(
+                self.entries
+                    .values()
+                    .filter(|entry| {
+                        entry
+                            .tx
+                            .input
+                            .iter()
+                            .any(|input| input.previous_output.txid == conflict)
+                    })
+                    .map(|entry| entry.tx.txid()),
+            );
+        }
+    }
+
+    /// Add back the matched transactions in the block which is disconnected by reorganization,
+    /// and return them with the height where they were confirmed.
+    pub fn on_disconnected(&mut self, block_hash: &BlockHash) -> Vec<MatchedTransaction> {
+        let (height, txs) = match self.confirmed.remove(block_hash) {
+            Some(confirmed) => confirmed,
+            None => return vec![],
+        };
+        txs.into_iter()
+            .map(|tx| {
+                info!(
+                    "Transaction {} returns to the pool because block {} is disconnected.",
+                    tx.txid(),
+                    block_hash
+                );
+                self.insert(tx.clone());
+                MatchedTransaction { tx, height }
+            })
+            .collect()
+    }
+
+    /// Forget the matched transactions in the blocks which are deeper than `MAX_REORG_DEPTH`
+    /// from the tip.
+    pub fn prune_confirmed(&mut self, tip_height: i32) {
+        self.confirmed
+            .retain(|_, (height, _)| *height > tip_height - MAX_REORG_DEPTH);
+    }
+
+    /// Remove the transactions which are in the pool longer than `expiry`, and forget the
+    /// requests which are not answered within `request_timeout`.
+    pub fn expire(&mut self, expiry: Duration, request_timeout: Duration) {
+        self.entries.retain(|txid, entry| {
+            let is_expired = entry.added_at.elapsed() > expiry;
+            if is_expired {
+                info!("Unconfirmed transaction {} is expired.", txid);
+            }
+            !is_expired
+        });
+        self.requested
+            .retain(|_, (_, requested_at)| requested_at.elapsed() <= request_timeout);
+    }
+
+    /// Return the outpoints which the unconfirmed transactions spend. They are watched while the
+    /// transactions are in the pool, so that the conflicting spend is found when it is confirmed.
+    pub fn spent_outpoints(&self) -> Vec<OutPoint> {
+        let mut outpoints: Vec<OutPoint> = self
+            .entries
+            .values()
+            .flat_map(|entry| entry.tx.input.iter().map(|input| input.previous_output))
+            .collect();
+        outpoints.sort();
+        outpoints.dedup();
+        outpoints
+    }
+
+    /// Return the unconfirmed transactions in the order of addition.
+    pub fn transactions(&self) -> Vec<Transaction> {
+        let mut entries: Vec<&MempoolEntry> = self.entries.values().collect();
+        entries.sort_by_key(|entry| entry.sequence);
+        entries.into_iter().map(|entry| entry.tx.clone()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helper::{get_test_block_hash, get_test_genesis_block};
+    use tapyrus::{OutPoint, TxIn};
+
+    /// Return the transaction which spends the outpoint and pays to the script of the genesis
+    /// coinbase. `lock_time` makes the transactions spending the same outpoint differ.
+    fn spend(outpoint: OutPoint, lock_time: u32) -> Transaction {
+        let mut tx = get_test_genesis_block().txdata[0].clone();
+        tx.input = vec![TxIn {
+            previous_output: outpoint,
+            ..tx.input[0].clone()
+        }];
+        tx.lock_time = lock_time;
+        tx
+    }
+
+    fn watch_list() -> WatchList {
+        let mut watch_list = WatchList::new();
+        let coinbase = get_test_genesis_block().txdata[0].clone();
+        watch_list.add_script(coinbase.output[0].script_pubkey.clone());
+        watch_list
+    }
+
+    #[test]
+    fn test_collect_unconfirmed_transactions() {
+        let watch_list = watch_list();
+        let coinbase = get_test_genesis_block().txdata[0].clone();
+        let tx = spend(OutPoint::new(coinbase.txid(), 0), 0);
+        let mut unmatched = tx.clone();
+        unmatched.output.clear();
+
+        let mut mempool = Mempool::new();
+        assert_eq!(
+            mempool.request(1, &[tx.txid(), unmatched.txid()]),
+            vec![tx.txid(), unmatched.txid()]
+        );
+        assert!(mempool.request(2, &[tx.txid()]).is_empty());
+        assert!(mempool.is_requested(1, &tx.txid()));
+        assert!(!mempool.is_requested(2, &tx.txid()));
+        assert!(mempool.on_tx(tx.clone(), &watch_list));
+        assert!(!mempool.on_tx(unmatched, &watch_list));
+
+        // The transaction which is not requested is not added.
+        assert!(!mempool.on_tx(spend(OutPoint::new(coinbase.txid(), 1), 0), &watch_list));
+        assert_eq!(mempool.transactions(), vec![tx.clone()]);
+        assert!(mempool.request(1, &[tx.txid()]).is_empty());
+        assert_eq!(
+            mempool.spent_outpoints(),
+            vec![OutPoint::new(coinbase.txid(), 0)]
+        );
+
+        mempool.expire(Duration::from_secs(60), Duration::from_secs(60));
+        assert_eq!(mempool.transactions(), vec![tx]);
+        mempool.expire(Duration::from_secs(0), Duration::from_secs(0));
+        assert!(mempool.transactions().is_empty());
+        assert!(mempool.spent_outpoints().is_empty());
+    }
+
+    #[test]
+    fn test_limit_requests_per_peer() {
+        let coinbase = get_test_genesis_block().txdata[0].clone();
+        let txids: Vec<Txid> = (0..MAX_TX_REQUESTS_PER_PEER as u32 + 1)
+            .map(|i| spend(OutPoint::new(coinbase.txid(), i), 0).txid())
+            .collect();
+
+        let mut mempool = Mempool::new();
+        assert_eq!(
+            mempool.request(1, &txids[1..]).len(),
+            MAX_TX_REQUESTS_PER_PEER
+        );
+        assert!(mempool.request(1, &txids[..1]).is_empty());
+        assert_eq!(mempool.request(2, &txids), vec![txids[0]]);
+
+        // The requests to the disconnected peer are forgotten.
+        mempool.peer_disconnected(1);
+        assert_eq!(
+            mempool.request(3, &txids[1..]).len(),
+            MAX_TX_REQUESTS_PER_PEER
+        );
+    }
+
+    #[test]
+    fn test_remove_confirmed_and_conflicting_transactions() {
+        let watch_list = watch_list();
+        let coinbase = get_test_genesis_block().txdata[0].clone();
+        let tx1 = spend(OutPoint::new(coinbase.txid(), 0), 0);
+        let tx2 = spend(OutPoint::new(coinbase.txid(), 1), 0);
+        let child = spend(OutPoint::new(tx2.txid(), 0), 0);
+        let double_spend = spend(OutPoint::new(coinbase.txid(), 1), 1);
+
+        let mut mempool = Mempool::new();
+        mempool.request(1, &[tx1.txid(), tx2.txid(), child.txid()]);
+        for tx in [&tx1, &tx2, &child] {
+            assert!(mempool.on_tx(tx.clone(), &watch_list));
+        }
+
+        mempool.on_confirmed(&tx1, get_test_block_hash(1), 1);
+        assert_eq!(mempool.transactions(), vec![tx2.clone(), child.clone()]);
+
+        // The child of the conflicting transaction is also dropped.
+        mempool.on_confirmed(&double_spend, get_test_block_hash(2), 2);
+        assert!(mempool.transactions().is_empty());
+    }
+
+    #[test]
+    fn test_restore_transactions_in_disconnected_block() {
+        let coinbase = get_test_genesis_block().txdata[0].clone();
+        let tx = spend(OutPoint::new(coinbase.txid(), 0), 0);
+        let old = spend(OutPoint::new(coinbase.txid(), 1), 0);
+
+        let mut mempool = Mempool::new();
+        mempool.on_confirmed(&tx, get_test_block_hash(5), 5);
+        mempool.on_confirmed(&old, get_test_block_hash(1), 1);
+
+        // The block which is too deep is forgotten.
+        mempool.prune_confirmed(1 + MAX_REORG_DEPTH);
+        assert!(mempool.on_disconnected(&get_test_block_hash(1)).is_empty());
+
+        assert_eq!(
+            mempool.on_disconnected(&get_test_block_hash(5)),
+            vec![MatchedTransaction {
+                tx: tx.clone(),
+                height: 5
+            }]
+        );
+        assert_eq!(mempool.transactions(), vec![tx]);
+        assert!(mempool.on_disconnected(&get_test_block_hash(5)).is_empty());
+    }
+}
@@ -0,0 +1,531 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+use crate::chain::{Chain, ChainStore};
+use crate::network::peer::PeerID;
+use crate::network::{Error, MaliciousPeerCause};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tapyrus::{BlockHash, MerkleBlock, Transaction, Txid};
+
+/// The maximum number of merkle blocks which are requested in single getdata message.
+pub const MAX_MERKLE_BLOCKS_IN_FLIGHT: usize = 500;
+
+/// Transaction which matches the watch list, with the height of the block which includes it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MatchedTransaction {
+    /// The matched transaction.
+    pub tx: Transaction,
+    /// The height of the block which includes the transaction.
+    pub height: i32,
+}
+
+/// Callback which is called when the transaction which matches the watch list is found.
+pub type TransactionListener = Arc<dyn Fn(MatchedTransaction) + Send + Sync>;
+
+/// Verify the partial merkle tree in the merkle block against the merkle root of the block header
+/// in the active chain. Return the height of the block and the ids of the matched transactions.
+pub fn verify_merkle_block<S: ChainStore>(
+    id: PeerID,
+    chain_active: &Chain<S>,
+    block: &MerkleBlock,
+) -> Result<(i32, Vec<Txid>), Error> {
+    let index = chain_active
+        .get_by_hash(&block.header.block_hash())?
+        .ok_or(Error::MaliciousPeer(
+            id,
+            MaliciousPeerCause::UnsolicitedData,
+        ))?;
+
+    let mut matches = vec![];
+    let mut indexes = vec![];
+    match block.txn.extract_matches(&mut matches, &mut indexes) {
+        Ok(merkle_root) if merkle_root == index.header.merkle_root => Ok((index.height, matches)),
+        _ => Err(Error::MaliciousPeer(
+            id,
+            MaliciousPeerCause::InvalidMerkleBlock,
+        )),
+    }
+}
+
+/// Merkle block which waits for the matched transactions.
+struct PendingBlock {
+    height: i32,
+    hash: BlockHash,
+    txids: Vec<Txid>,
+    txs: HashMap<Txid, Transaction>,
+}
+
+/// This struct tracks the download of the merkle blocks to find the transactions which match the
+/// bloom filter loaded to the peer.
+///
+/// The merkle blocks are requested from single peer in order of height, and the peer sends the
+/// matched transactions following each merkle block. The blocks are scanned from the genesis
+/// block, and the blocks disconnected by reorganization are scanned again on the new branch.
+#[derive(Default)]
+pub struct MerkleBlockDownload {
+    /// The height and hash of the last block which was scanned.
+    scanned: Option<(i32, BlockHash)>,
+    /// The peer which is requested the merkle blocks.
+    peer: Option<PeerID>,
+    /// The blocks which are requested and not received yet, in order of height.
+    requested: VecDeque<BlockHash>,
+    /// The time when the peer sent the last merkle block or was requested.
+    last_progress: Option<Instant>,
+    pending: Option<PendingBlock>,
+    /// The blocks which were requested before reorganization and not received yet. They are
+    /// ignored when the peer sends them late.
+    abandoned: Vec<(PeerID, BlockHash)>,
+    /// The matched transactions which follow the abandoned merkle blocks.
+    abandoned_txids: Vec<(PeerID, Txid)>,
+}
+
+impl MerkleBlockDownload {
+    pub fn new() -> MerkleBlockDownload {
+        MerkleBlockDownload::default()
+    }
+
+    /// Return the peer which is requested the merkle blocks.
+    pub fn peer(&self) -> Option<PeerID> {
+        self.peer
+    }
+
+    /// Return true if the peer doesn't send the requested merkle block within `timeout`.
+    pub fn is_stalled(&self, timeout: Duration) -> bool {
+        match (self.peer, self.last_progress) {
+            (Some(_), Some(time)) => time.elapsed() > timeout,
+            _ => false,
+        }
+    }
+
+    /// Forget the request in flight. The blocks are requested again.
+    pub fn reset(&mut self) {
+        self.peer = None;
+        self.requested.clear();
+        self.last_progress = None;
+        self.pending = None;
+    }
+
+    /// Return the hashes of the blocks which follow the scanned blocks in the active chain, and
+    /// record that they are requested from the peer. Return empty if all blocks are scanned or
+    /// the request is in flight.
+    pub fn request<S: ChainStore>(
+        &mut self,
+        id: PeerID,
+        chain_active: &Chain<S>,
+        max: usize,
+    ) -> Result<Vec<BlockHash>, Error> {
+        if self.peer.is_some() {
+            return Ok(vec![]);
+        }
+
+        self.rewind_to_active_chain(chain_active)?;
+
+        let start = self.next_height();
+        let end = std::cmp::min(chain_active.height(), start + max as i32 - 1);
+        let mut hashes = vec![];
+        for height in start..=end {
+            if let Some(index) = chain_active.get(height)? {
+                hashes.push(index.header.block_hash());
+            }
+        }
+        if hashes.is_empty() {
+            return Ok(hashes);
+        }
+
+        trace!(
+            "Request merkle blocks from {} to {} to peer {}.",
+            start,
+            end,
+            id
+        );
+        self.peer = Some(id);
+        self.requested = hashes.iter().cloned().collect();
+        self.last_progress = Some(Instant::now());
+        Ok(hashes)
+    }
+
+    /// If the last scanned block is disconnected by reorganization, scan again from the fork
+    /// point.
+    fn rewind_to_active_chain<S: ChainStore>(
+        &mut self,
+        chain_active: &Chain<S>,
+    ) -> Result<(), Error> {
+        let (height, hash) = match self.scanned {
+            Some(scanned) => scanned,
+            None => return Ok(()),
+        };
+        if chain_active.height_of(&hash)? == Some(height) {
+            return Ok(());
+        }
+
+        // The locator of the disconnected block contains the block below the fork point.
+        self.scanned = None;
+        for hash in chain_active.get_locator_from(&hash)?.unwrap_or_default() {
+            if let Some(height) = chain_active.height_of(&hash)? {
+                self.scanned = Some((height, hash));
+                break;
+            }
+        }
+        info!(
+            "Rescan merkle blocks from height {} after reorganization.",
+            self.next_height()
+        );
+        Ok(())
+    }
+
+    /// Rewind the scan to the fork point after the active chain is reorganized. The request in
+    /// flight is forgotten if it includes the disconnected blocks, and the replies to it are
+    /// ignored when they arrive late.
+    pub fn rewind<S: ChainStore>(&mut self, chain_active: &Chain<S>) -> Result<(), Error> {
+        let mut is_stale = false;
+        for hash in self
+            .requested
+            .iter()
+            .chain(self.pending.as_ref().map(|pending| &pending.hash))
+        {
+            if !chain_active.contains(hash)? {
+                is_stale = true;
+                break;
+            }
+        }
+        if is_stale {
+            if let Some(id) = self.peer {
+                self.abandoned
+                    .extend(self.requested.iter().map(|hash| (id, *hash)));
+                if let Some(pending) = self.pending.as_ref() {
+                    self.abandoned_txids.extend(
+                        pending
+                            .txids
+                            .iter()
+                            .filter(|txid| !pending.txs.contains_key(*txid))
+                            .map(|txid| (id, *txid)),
+                    );
+                }
+            }
+            self.reset();
+        }
+        self.rewind_to_active_chain(chain_active)
+    }
+
+    /// Forget the request to the disconnected peer.
+    pub fn peer_disconnected(&mut self, id: PeerID) {
+        if self.peer == Some(id) {
+            self.reset();
+        }
+        self.abandoned.retain(|(peer, _)| *peer != id);
+        self.abandoned_txids.retain(|(peer, _)| *peer != id);
+    }
+
+    fn next_height(&self) -> i32 {
+        self.scanned.map_or(0, |(height, _)| height + 1)
+    }
+
+    /// Process the merkle block from the peer. Return the matched transactions if the block
+    /// doesn't wait for them.
+    pub fn on_merkle_block<S: ChainStore>(
+        &mut self,
+        id: PeerID,
+        chain_active: &Chain<S>,
+        block: MerkleBlock,
+    ) -> Result<Vec<MatchedTransaction>, Error> {
+        let hash = block.header.block_hash();
+        if let Some(i) = self.abandoned.iter().position(|entry| *entry == (id, hash)) {
+            // The block was requested before reorganization, so the peer is not to blame.
+            self.abandoned.remove(i);
+            let mut txids = vec![];
+            if block.txn.extract_matches(&mut txids, &mut vec![]).is_ok() {
+                self.abandoned_txids
+                    .extend(txids.into_iter().map(|txid| (id, txid)));
+            }
+            return Ok(vec![]);
+        }
+
+        if self.peer != Some(id) || self.requested.front() != Some(&hash) {
+            return Err(Error::MaliciousPeer(
+                id,
+                MaliciousPeerCause::UnsolicitedData,
+            ));
+        }
+
+        // The matched transactions of the previous block should have been sent before.
+        if self.pending.is_some() {
+            return Err(Error::MaliciousPeer(
+                id,
+                MaliciousPeerCause::IncompleteMerkleBlock,
+            ));
+        }
+
+        let (height, txids) = verify_merkle_block(id, chain_active, &block)?;
+        self.requested.pop_front();
+        self.last_progress = Some(Instant::now());
+
+        if height != self.next_height() {
+            // The active chain was reorganized after the request.
+            self.reset();
+            return Ok(vec![]);
+        }
+
+        self.pending = Some(PendingBlock {
+            height,
+            hash,
+            txids,
+            txs: HashMap::new(),
+        });
+        Ok(self.complete_pending())
+    }
+
+    /// Return true if the transaction from the peer is waited by the merkle block, or follows
+    /// the abandoned merkle block.
+    pub fn expects(&self, id: PeerID, txid: &Txid) -> bool {
+        self.is_pending(id, txid) || self.abandoned_txids.contains(&(id, *txid))
+    }
+
+    fn is_pending(&self, id: PeerID, txid: &Txid) -> bool {
+        self.peer == Some(id)
+            && self.pending.as_ref().is_some_and(|pending| {
+                pending.txids.contains(txid) && !pending.txs.contains_key(txid)
+            })
+    }
+
+    /// Process the transaction which is matched in the merkle block. Return the matched
+    /// transactions if all of them are received. The transaction which follows the abandoned
+    /// merkle block is dropped.
+    pub fn on_tx(&mut self, id: PeerID, tx: Transaction) -> Vec<MatchedTransaction> {
+        let txid = tx.txid();
+        if !self.is_pending(id, &txid) {
+            self.abandoned_txids.retain(|entry| *entry != (id, txid));
+            return vec![];
+        }
+
+        if let Some(pending) = self.pending.as_mut() {
+            pending.txs.insert(txid, tx);
+        }
+        self.complete_pending()
+    }
+
+    fn complete_pending(&mut self) -> Vec<MatchedTransaction> {
+        let is_complete = self
+            .pending
+            .as_ref()
+            .is_some_and(|pending| pending.txs.len() == pending.txids.len());
+        if !is_complete {
+            return vec![];
+        }
+
+        let PendingBlock {
+            height,
+            hash,
+            txids,
+            mut txs,
+        } = self.pending.take().unwrap();
+        self.scanned = Some((height, hash));
+        if self.requested.is_empty() {
+            self.peer = None;
+            self.last_progress = None;
+        }
+
+        txids
+            .iter()
+            .filter_map(|txid| txs.remove(txid))
+            .map(|tx| MatchedTransaction { tx, height })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helper::{build_branch, get_chain, get_test_genesis_block, get_test_headers};
+    use std::collections::HashSet;
+    use tapyrus::hashes::Hash;
+    use tapyrus::BlockHeader;
+
+    /// Return merkle block which pretends the block has single transaction which doesn't match.
+    fn unmatched_merkle_block(header: &BlockHeader) -> MerkleBlock {
+        let txid = Txid::from_hash(header.merkle_root.as_hash());
+        MerkleBlock::from_header_txids(header, &[txid], &HashSet::new())
+    }
+
+    #[test]
+    fn test_verify_merkle_block() {
+        let chain = get_chain();
+        let genesis = get_test_genesis_block();
+        let txid = genesis.txdata[0].txid();
+
+        let matched = MerkleBlock::from_block(&genesis, &vec![txid].into_iter().collect());
+        assert_eq!(
+            verify_merkle_block(0, &chain, &matched).unwrap(),
+            (0, vec![txid])
+        );
+
+        // The tree of other transactions doesn't match the merkle root.
+        let other = Txid::from_slice(&[1; 32]).unwrap();
+        let invalid = MerkleBlock::from_header_txids(&genesis.header, &[other], &HashSet::new());
+        match verify_merkle_block(0, &chain, &invalid) {
+            Err(Error::MaliciousPeer(_, MaliciousPeerCause::InvalidMerkleBlock)) => {}
+            _ => panic!("should fail with InvalidMerkleBlock"),
+        }
+
+        // The block is not in the chain.
+        let unknown = unmatched_merkle_block(&get_test_headers(1, 1)[0]);
+        match verify_merkle_block(0, &chain, &unknown) {
+            Err(Error::MaliciousPeer(_, MaliciousPeerCause::UnsolicitedData)) => {}
+            _ => panic!("should fail with UnsolicitedData"),
+        }
+    }
+
+    #[test]
+    fn test_download() {
+        let mut chain = get_chain();
+        let headers = get_test_headers(0, 5);
+        for header in headers[1..].iter() {
+            chain.connect_block_header(header.clone()).unwrap();
+        }
+        let genesis = get_test_genesis_block();
+        let coinbase = genesis.txdata[0].clone();
+
+        let mut download = MerkleBlockDownload::new();
+        let hashes: Vec<BlockHash> = headers.iter().map(|h| h.block_hash()).collect();
+        assert_eq!(
+            download.request(1, &chain, 3).unwrap(),
+            hashes[0..3].to_vec()
+        );
+        assert_eq!(download.peer(), Some(1));
+        assert!(download.request(1, &chain, 3).unwrap().is_empty());
+
+        // The genesis block waits for the matched transaction.
+        let matched =
+            MerkleBlock::from_block(&genesis, &vec![coinbase.txid()].into_iter().collect());
+        assert!(download
+            .on_merkle_block(1, &chain, matched)
+            .unwrap()
+            .is_empty());
+        assert!(download.expects(1, &coinbase.txid()));
+        assert_eq!(
+            download.on_tx(1, coinbase.clone()),
+            vec![MatchedTransaction {
+                tx: coinbase,
+                height: 0
+            }]
+        );
+
+        // The blocks should be sent in order by the requested peer.
+        let block1 = unmatched_merkle_block(&headers[1]);
+        let block2 = unmatched_merkle_block(&headers[2]);
+        assert!(download.on_merkle_block(2, &chain, block1.clone()).is_err());
+        assert!(download.on_merkle_block(1, &chain, block2.clone()).is_err());
+        assert!(download
+            .on_merkle_block(1, &chain, block1)
+            .unwrap()
+            .is_empty());
+        assert!(download
+            .on_merkle_block(1, &chain, block2)
+            .unwrap()
+            .is_empty());
+        assert_eq!(download.peer(), None);
+
+        // The following blocks are requested next.
+        assert_eq!(
+            download.request(2, &chain, 3).unwrap(),
+            hashes[3..5].to_vec()
+        );
+        download.reset();
+        assert_eq!(
+            download.request(2, &chain, 3).unwrap(),
+            hashes[3..5].to_vec()
+        );
+    }
+
+    #[test]
+    fn test_rewind_after_reorganization() {
+        let mut chain = get_chain();
+        let headers = get_test_headers(0, 5);
+        for header in headers[1..].iter() {
+            chain.connect_block_header(header.clone()).unwrap();
+        }
+
+        let mut download = MerkleBlockDownload::new();
+        download.request(1, &chain, 3).unwrap();
+        download.scanned = Some((2, headers[2].block_hash()));
+        download.reset();
+        assert_eq!(download.request(1, &chain, 3).unwrap().len(), 2);
+
+        // The blocks in flight are disconnected, so the branch is scanned from the fork point.
+        let branch = build_branch(&headers[1], 4);
+        for header in branch.iter() {
+            chain.connect_block_header(header.clone()).unwrap();
+        }
+        download.rewind(&chain).unwrap();
+        assert_eq!(download.peer(), None);
+        let hashes: Vec<BlockHash> = branch.iter().map(|h| h.block_hash()).collect();
+        assert_eq!(
+            download.request(1, &chain, 3).unwrap(),
+            hashes[0..3].to_vec()
+        );
+    }
+
+    #[test]
+    fn test_ignore_late_merkle_block_after_reorganization() {
+        let mut chain = get_chain();
+        let headers = get_test_headers(0, 5);
+        for header in headers[1..].iter() {
+            chain.connect_block_header(header.clone()).unwrap();
+        }
+        let genesis = get_test_genesis_block();
+        let coinbase = genesis.txdata[0].clone();
+
+        let mut download = MerkleBlockDownload::new();
+        download.scanned = Some((1, headers[1].block_hash()));
+        download.request(1, &chain, 3).unwrap();
+
+        // The merkle block at height 2 waits for the matched transaction.
+        download.pending = Some(PendingBlock {
+            height: 2,
+            hash: headers[2].block_hash(),
+            txids: vec![coinbase.txid()],
+            txs: HashMap::new(),
+        });
+        download.requested.pop_front();
+
+        // The blocks in flight are disconnected by reorganization.
+        for header in build_branch(&headers[1], 4) {
+            chain.connect_block_header(header).unwrap();
+        }
+        download.rewind(&chain).unwrap();
+        assert_eq!(download.peer(), None);
+
+        // The late replies from the peer are ignored.
+        assert!(download.expects(1, &coinbase.txid()));
+        assert!(!download.expects(2, &coinbase.txid()));
+        assert!(download.on_tx(1, coinbase.clone()).is_empty());
+        assert!(!download.expects(1, &coinbase.txid()));
+
+        let late = MerkleBlock::from_header_txids(
+            &headers[3],
+            &[coinbase.txid()],
+            &vec![coinbase.txid()].into_iter().collect(),
+        );
+        match download.on_merkle_block(2, &chain, late.clone()) {
+            Err(Error::MaliciousPeer(2, MaliciousPeerCause::UnsolicitedData)) => {}
+            _ => panic!("should fail with UnsolicitedData"),
+        }
+        assert!(download
+            .on_merkle_block(1, &chain, late.clone())
+            .unwrap()
+            .is_empty());
+        assert!(download.expects(1, &coinbase.txid()));
+
+        // The same block is unsolicited if it is sent again.
+        match download.on_merkle_block(1, &chain, late) {
+            Err(Error::MaliciousPeer(1, MaliciousPeerCause::UnsolicitedData)) => {}
+            _ => panic!("should fail with UnsolicitedData"),
+        }
+
+        // The abandoned requests are forgotten when the peer is disconnected.
+        download.peer_disconnected(1);
+        assert!(!download.expects(1, &coinbase.txid()));
+    }
+}
@@ -0,0 +1,170 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+use crate::network::bloom_filter::{FilterAddMessage, FilterLoadMessage};
+use std::io;
+use tapyrus::consensus::encode::{self, CheckedData, Decodable, Encodable, VarInt};
+use tapyrus::consensus::{deserialize, serialize};
+use tapyrus::network::message::{CommandString, NetworkMessage, RawNetworkMessage};
+use tapyrus::{BlockHash, MerkleBlock};
+
+/// Inventory type of getdata message which requests merkle block.
+const MSG_FILTERED_BLOCK: u32 = 3;
+
+/// Message which is exchanged with the peers.
+///
+/// rust-tapyrus doesn't support the messages of BIP37, so they are defined here and the other
+/// messages are wrapped.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    /// Message which rust-tapyrus supports.
+    Network(NetworkMessage),
+    /// `filterload`
+    FilterLoad(FilterLoadMessage),
+    /// `filteradd`
+    FilterAdd(FilterAddMessage),
+    /// `getdata` which requests merkle blocks of the blocks.
+    GetMerkleBlocks(Vec<BlockHash>),
+    /// `merkleblock`
+    MerkleBlock(MerkleBlock),
+}
+
+impl Message {
+    pub fn cmd(&self) -> &'static str {
+        match self {
+            Message::Network(message) => message.cmd(),
+            Message::FilterLoad(_) => "filterload",
+            Message::FilterAdd(_) => "filteradd",
+            Message::GetMerkleBlocks(_) => "getdata",
+            Message::MerkleBlock(_) => "merkleblock",
+        }
+    }
+}
+
+impl From<NetworkMessage> for Message {
+    fn from(message: NetworkMessage) -> Message {
+        Message::Network(message)
+    }
+}
+
+/// Message with the magic bytes of the network.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RawMessage {
+    pub magic: u32,
+    pub payload: Message,
+}
+
+impl RawMessage {
+    /// Serialize the message into `s`. The message is consumed so that the message of
+    /// rust-tapyrus is serialized without copy.
+    pub fn encode<S: io::Write>(self, mut s: S) -> Result<usize, encode::Error> {
+        let payload = match self.payload {
+            Message::Network(payload) => {
+                return RawNetworkMessage {
+                    magic: self.magic,
+                    payload,
+                }
+                .consensus_encode(s);
+            }
+            Message::FilterLoad(ref message) => serialize(message),
+            Message::FilterAdd(ref message) => serialize(message),
+            Message::GetMerkleBlocks(ref hashes) => {
+                let mut bytes = serialize(&VarInt(hashes.len() as u64));
+                for hash in hashes {
+                    bytes.extend(serialize(&MSG_FILTERED_BLOCK));
+                    bytes.extend(serialize(hash));
+                }
+                bytes
+            }
+            Message::MerkleBlock(ref message) => serialize(message),
+        };
+
+        Ok(self.magic.consensus_encode(&mut s)?
+            + CommandString::from(self.payload.cmd()).consensus_encode(&mut s)?
+            + CheckedData(payload).consensus_encode(&mut s)?)
+    }
+}
+
+impl Decodable for RawMessage {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<Self, encode::Error> {
+        let magic: u32 = Decodable::consensus_decode(&mut d)?;
+        let command = CommandString::consensus_decode(&mut d)?;
+        let CheckedData(payload) = CheckedData::consensus_decode(&mut d)?;
+
+        let payload = match command.to_string().as_str() {
+            "filterload" => Message::FilterLoad(deserialize(&payload)?),
+            "filteradd" => Message::FilterAdd(deserialize(&payload)?),
+            "merkleblock" => Message::MerkleBlock(deserialize(&payload)?),
+            _ => {
+                // Let rust-tapyrus decode the other messages.
+                let mut bytes = serialize(&magic);
+                bytes.extend(serialize(&command));
+                bytes.extend(serialize(&CheckedData(payload)));
+                let message: RawNetworkMessage = deserialize(&bytes)?;
+                Message::Network(message.payload)
+            }
+        };
+
+        Ok(RawMessage { magic, payload })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helper::get_test_genesis_block;
+    use std::collections::HashSet;
+    use tapyrus::network::constants::NetworkId;
+
+    fn encode(payload: Message) -> Vec<u8> {
+        let message = RawMessage {
+            magic: NetworkId::REGTEST.magic(),
+            payload,
+        };
+        let mut bytes = vec![];
+        message.encode(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn test_encode_and_decode() {
+        let genesis = get_test_genesis_block();
+        let txids: HashSet<_> = genesis.txdata.iter().map(|tx| tx.txid()).collect();
+
+        let messages = vec![
+            Message::Network(NetworkMessage::Ping(1)),
+            Message::FilterLoad(FilterLoadMessage {
+                filter: vec![1, 2, 3],
+                hash_funcs: 4,
+                tweak: 5,
+                flags: 1,
+            }),
+            Message::FilterAdd(FilterAddMessage { data: vec![6, 7] }),
+            Message::MerkleBlock(MerkleBlock::from_block(&genesis, &txids)),
+        ];
+        for message in messages {
+            let cmd = message.cmd();
+            let bytes = encode(message);
+            let decoded: RawMessage = deserialize(&bytes).unwrap();
+            assert_eq!(decoded.magic, NetworkId::REGTEST.magic());
+            assert_eq!(decoded.payload.cmd(), cmd);
+
+            // The bits of the partial merkle tree are padded, so compare the serialization.
+            assert_eq!(encode(decoded.payload), bytes);
+        }
+    }
+
+    #[test]
+    fn test_encode_getdata_for_merkle_blocks() {
+        let hash = get_test_genesis_block().block_hash();
+        let bytes = encode(Message::GetMerkleBlocks(vec![hash]));
+
+        // magic(4bytes) + command(12bytes) + length(4bytes) + checksum(4bytes)
+        let payload = &bytes[24..];
+        assert_eq!(&bytes[4..11], b"getdata");
+        assert_eq!(payload[0], 1);
+        assert_eq!(payload[1..5], MSG_FILTERED_BLOCK.to_le_bytes());
+        assert_eq!(payload[5..], serialize(&hash)[..]);
+    }
+}
@@ -10,15 +10,57 @@
 mod peer;
 pub use self::peer::connect;
 pub use self::peer::Peer;
+pub use self::peer::PeerStats;
 
 mod handshake;
-pub use self::handshake::Handshake;
+pub use self::handshake::{Handshake, DEFAULT_HANDSHAKE_TIMEOUT, REQUIRED_SERVICES};
 
 mod block_header_download;
-pub use self::block_header_download::BlockHeaderDownload;
+
+mod message;
+pub use self::message::{Message, RawMessage};
+
+pub(crate) mod bloom_filter;
+
+mod watch_list;
+pub use self::watch_list::WatchList;
+
+mod merkle_block_download;
+pub use self::merkle_block_download::{MatchedTransaction, TransactionListener};
+
+mod compact_filter_download;
+
+mod block_fetcher;
+pub use self::block_fetcher::BlockConsumer;
+
+mod mempool;
+pub use self::mempool::Mempool;
+
+mod transaction_broadcast;
+pub use self::transaction_broadcast::{BroadcastQueue, BroadcastResult};
+
+pub(crate) mod address_book;
+pub use self::address_book::AddressBook;
+
+mod backoff;
+
+mod ban_list;
+pub use self::ban_list::BanList;
+
+mod dns_seed;
+pub use self::dns_seed::{query_seeds, Resolver, SystemResolver};
+
+mod driver;
+pub use self::driver::DEFAULT_GETHEADERS_TIMEOUT;
+
+mod peer_manager;
+pub use self::peer_manager::{
+    ConnectionListener, ConnectionState, PeerManager, SyncProgress, SyncProgressListener,
+    TcpConnector,
+};
 
 pub mod utils;
 
 mod error;
 pub use self::error::Error;
-pub use self::error::MaliciousPeerCause;
+pub use self::error::{IncompatiblePeerCause, MaliciousPeerCause};
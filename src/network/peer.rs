@@ -2,19 +2,19 @@
 // Distributed under the MIT software license, see the accompanying
 // file COPYING or http://www.opensource.org/licenses/mit-license.php.
 
-use crate::chain::{Chain, ChainStore};
-use crate::network::{utils::codec::NetworkMessagesCodec, Error};
+use crate::network::message::{Message, RawMessage};
+use crate::network::utils::codec;
+use crate::network::{utils::codec::NetworkMessagesCodec, Error, MaliciousPeerCause};
 use rand::{thread_rng, RngCore};
 use std::{
     borrow::BorrowMut,
     net::SocketAddr,
-    time::{SystemTime, UNIX_EPOCH},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
+use tapyrus::consensus::encode;
 use tapyrus::network::message_blockdata::GetHeadersMessage;
 use tapyrus::network::{
-    address::Address,
-    constants::ServiceFlags,
-    message::{NetworkMessage, RawNetworkMessage},
+    address::Address, constants::ServiceFlags, message::NetworkMessage,
     message_network::VersionMessage,
 };
 use tapyrus::BlockHash;
@@ -22,20 +22,64 @@ use tokio::{codec::Framed, net::TcpStream, prelude::*};
 
 pub type PeerID = u64;
 
+/// Statistics of the connected peer.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerStats {
+    /// ID of the peer.
+    pub id: PeerID,
+    /// Address of the peer.
+    pub addr: SocketAddr,
+    /// Round-trip time of the last ping. It is None until the peer answers the ping.
+    pub latency: Option<Duration>,
+    /// The height of the best block which the peer had when it connected.
+    pub start_height: i32,
+}
+
+/// Features which are negotiated with the peer in the handshake.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Features {
+    /// The protocol version which both of us speak.
+    pub version: u32,
+    /// The services which the peer provides.
+    pub services: ServiceFlags,
+    /// Whether the peer relays transactions to us without filter.
+    pub relay: bool,
+}
+
+impl Default for Features {
+    fn default() -> Features {
+        Features {
+            version: 0,
+            services: ServiceFlags::NONE,
+            relay: false,
+        }
+    }
+}
+
 pub struct Peer<T>
 where
-    T: Sink<SinkItem = RawNetworkMessage> + Stream<Item = RawNetworkMessage>,
+    T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
 {
     pub id: PeerID,
     pub addr: SocketAddr,
     pub magic: u32,
     pub stream: T,
     pub version: Option<VersionMessage>,
+    /// Features which are negotiated in the handshake.
+    pub features: Features,
+    /// Nonce of the ping which is not answered yet.
+    pub ping_nonce: Option<u64>,
+    /// The time when the last ping was sent.
+    pub ping_sent: Option<Instant>,
+    /// Round-trip time of the last ping.
+    pub latency: Option<Duration>,
+    /// The time when the connection was opened.
+    pub connected_at: Instant,
 }
 
 impl<T> Peer<T>
 where
-    T: Sink<SinkItem = RawNetworkMessage> + Stream<Item = RawNetworkMessage>,
+    T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
 {
     pub fn new(id: u64, stream: T, addr: SocketAddr, magic: u32) -> Peer<T> {
         Peer {
@@ -44,16 +88,39 @@ where
             magic,
             stream,
             version: None,
+            features: Features::default(),
+            ping_nonce: None,
+            ping_sent: None,
+            latency: None,
+            connected_at: Instant::now(),
+        }
+    }
+
+    /// Return statistics of the peer.
+    pub fn stats(&self) -> PeerStats {
+        PeerStats {
+            id: self.id,
+            addr: self.addr,
+            latency: self.latency,
+            start_height: self.start_height(),
         }
     }
 
+    /// Return the height of the best block which the peer advertised in version message.
+    pub fn start_height(&self) -> i32 {
+        self.version
+            .as_ref()
+            .map_or(0, |version| version.start_height)
+    }
+
     /// Start to send message.
     /// This function just put message into buffer on sink. So call stream.poll_complete() to  send
     /// to remote.
-    pub fn start_send(&mut self, message: NetworkMessage) {
+    pub fn start_send<M: Into<Message>>(&mut self, message: M) {
+        let message = message.into();
         trace!("Sending message: {:?}", message);
 
-        let raw_msg = RawNetworkMessage {
+        let raw_msg = RawMessage {
             magic: self.magic,
             payload: message,
         };
@@ -67,28 +134,65 @@ where
     }
 
     /// Send getheaders message to peer.
-    pub fn send_getheaders<S: ChainStore>(&mut self, chain: &Chain<S>) {
-        let locators = chain.get_locator();
+    pub fn send_getheaders(&mut self, locators: Vec<BlockHash>) {
         let stop_hash = BlockHash::default();
         let getheaders = GetHeadersMessage::new(locators, stop_hash);
         self.start_send(NetworkMessage::GetHeaders(getheaders));
     }
+
+    /// Send ping message with random nonce to peer.
+    pub fn send_ping(&mut self) {
+        let nonce = thread_rng().next_u64();
+        self.ping_nonce = Some(nonce);
+        self.ping_sent = Some(Instant::now());
+        self.start_send(NetworkMessage::Ping(nonce));
+    }
+
+    /// Record the latency if the pong answers the last ping. The pong with other nonce is
+    /// ignored.
+    pub fn on_pong(&mut self, nonce: u64) {
+        if self.ping_nonce != Some(nonce) {
+            trace!("Ignore pong with unexpected nonce from peer {}.", self.id);
+            return;
+        }
+
+        self.ping_nonce = None;
+        self.latency = self.ping_sent.map(|sent| sent.elapsed());
+    }
+
+    /// Return true if the peer doesn't answer the ping within `timeout`.
+    pub fn is_ping_timed_out(&self, timeout: Duration) -> bool {
+        match (self.ping_nonce, self.ping_sent) {
+            (Some(_), Some(sent)) => sent.elapsed() > timeout,
+            _ => false,
+        }
+    }
 }
 
 impl<T> Stream for Peer<T>
 where
-    T: Sink<SinkItem = RawNetworkMessage> + Stream<Item = RawNetworkMessage>,
+    T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
     Error: From<T::Error>,
 {
-    type Item = NetworkMessage;
+    type Item = Message;
     type Error = Error;
 
     fn poll(&mut self) -> Result<Async<Option<Self::Item>>, Self::Error> {
-        match self.stream.poll()? {
+        let message = self.stream.poll().map_err(|e| match Error::from(e) {
+            Error::CodecError(codec::Error::Encode(encode::Error::OversizedVectorAllocation {
+                ..
+            })) => Error::MaliciousPeer(self.id, MaliciousPeerCause::OversizedMessage),
+            e => e,
+        })?;
+
+        match message {
             Async::Ready(Some(message)) => {
                 if message.magic != self.magic {
                     info!("Wrong magic bytes.");
-                    return Err(Error::WrongMagicBytes);
+                    return Err(Error::MaliciousPeer(
+                        self.id,
+                        MaliciousPeerCause::WrongMagicBytes,
+                    ));
                 }
 
                 trace!("Receive message: {:?}", message);
@@ -101,6 +205,7 @@ where
 }
 
 pub fn connect(
+    id: PeerID,
     address: &SocketAddr,
     magic: u32,
 ) -> impl Future<Item = Peer<Framed<TcpStream, NetworkMessagesCodec>>, Error = Error> {
@@ -110,12 +215,13 @@ pub fn connect(
             let addr = stream.peer_addr().unwrap();
             trace!("Success to create TCP connection to {}", addr);
             let stream = Framed::new(stream, NetworkMessagesCodec::new());
-            Peer::new(0, stream, addr, magic)
+            Peer::new(id, stream, addr, magic)
         })
-        .map_err(|e| Error::from(e))
+        .map_err(Error::from)
 }
 
-pub fn version_message() -> VersionMessage {
+/// Return version message which advertises `start_height` as our best block height.
+pub fn version_message(start_height: i32) -> VersionMessage {
     let blank_addr = "[0:0:0:0:0:0:0:0]:0".parse().unwrap();
 
     // now in unix time
@@ -129,10 +235,7 @@ pub fn version_message() -> VersionMessage {
     // generate random value
     let nonce = thread_rng().borrow_mut().next_u64();
 
-    // TODO: after block database is constructed, set actual latest block height.
-    let start_height = 0;
-
-    const VERSION: &'static str = env!("CARGO_PKG_VERSION");
+    const VERSION: &str = env!("CARGO_PKG_VERSION");
 
     // build message
     VersionMessage::new(
@@ -145,3 +248,41 @@ pub fn version_message() -> VersionMessage {
         start_height,
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helper::channel;
+    use tapyrus::network::constants::NetworkId;
+
+    #[test]
+    fn test_ping_pong() {
+        let (here, there) = channel::<RawMessage>();
+        let mut peer = Peer::new(
+            0,
+            there,
+            "0.0.0.0:0".parse().unwrap(),
+            NetworkId::REGTEST.magic(),
+        );
+
+        peer.send_ping();
+        peer.flush();
+        let nonce = match Stream::wait(here).next() {
+            Some(Ok(RawMessage {
+                payload: Message::Network(NetworkMessage::Ping(nonce)),
+                ..
+            })) => nonce,
+            _ => panic!("should send ping"),
+        };
+        assert!(peer.is_ping_timed_out(Duration::from_secs(0)));
+        assert!(!peer.is_ping_timed_out(Duration::from_secs(60)));
+
+        // pong with other nonce is ignored.
+        peer.on_pong(nonce.wrapping_add(1));
+        assert_eq!(peer.stats().latency, None);
+
+        peer.on_pong(nonce);
+        assert!(peer.stats().latency.is_some());
+        assert!(!peer.is_ping_timed_out(Duration::from_secs(0)));
+    }
+}
@@ -0,0 +1,1495 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+use crate::chain::{Chain, ChainStore, FilterHeaderStore};
+use crate::network::address_book::{self, AddressBook};
+use crate::network::backoff::Backoff;
+use crate::network::ban_list::{BanList, DEFAULT_BAN_DURATION};
+use crate::network::driver::{
+    BlockDriver, BroadcastDriver, CompactFilterDriver, Context, Driver, Event, HeaderSyncDriver,
+    MempoolDriver, MerkleBlockDriver, Reports, TransactionReportDriver,
+};
+use crate::network::mempool::Mempool;
+use crate::network::peer::{PeerID, PeerStats};
+use crate::network::transaction_broadcast::BroadcastQueue;
+use crate::network::utils::codec::NetworkMessagesCodec;
+use crate::network::{
+    connect, BlockConsumer, Error, Handshake, MaliciousPeerCause, Message, Peer, RawMessage,
+    TransactionListener, WatchList, REQUIRED_SERVICES,
+};
+use crate::ChainState;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tapyrus::hash_types::FilterHash;
+use tapyrus::network::address::Address;
+use tapyrus::network::constants::ServiceFlags;
+use tapyrus::network::message::NetworkMessage;
+use tokio::codec::Framed;
+use tokio::net::TcpStream;
+use tokio::prelude::*;
+use tokio::timer::Interval;
+
+/// Interval to check the number of connections and open new connections.
+const CONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The maximum number of addresses in single addr message.
+pub const MAX_ADDR_TO_SEND: usize = 1_000;
+
+/// The misbehavior score of the peer to be banned.
+pub const BAN_THRESHOLD: u32 = 100;
+
+/// Interval to send ping message to each peer.
+const PING_INTERVAL: Duration = Duration::from_secs(2 * 60);
+
+/// The peer which doesn't answer ping within this duration is disconnected.
+const PING_TIMEOUT: Duration = Duration::from_secs(20 * 60);
+
+/// Future which is resolved to the peer completed handshake.
+pub type ConnectFuture<T> = Box<dyn Future<Item = Peer<T>, Error = Error> + Send>;
+
+/// This trait presents the way to open connection to remote peer.
+pub trait Connector {
+    /// Stream of messages between the remote peer.
+    type Stream: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>;
+
+    /// Open connection to the address and complete handshake. `start_height` is the height of
+    /// our chain which is advertised to the peer.
+    fn connect(
+        &mut self,
+        id: PeerID,
+        addr: &SocketAddr,
+        start_height: i32,
+    ) -> ConnectFuture<Self::Stream>;
+}
+
+/// Connector which opens TCP connection.
+pub struct TcpConnector {
+    magic: u32,
+    handshake_timeout: Duration,
+    required_services: ServiceFlags,
+}
+
+impl TcpConnector {
+    pub fn new(magic: u32, handshake_timeout: Duration) -> TcpConnector {
+        TcpConnector {
+            magic,
+            handshake_timeout,
+            required_services: REQUIRED_SERVICES,
+        }
+    }
+
+    /// Require the peers to provide `services` instead of `REQUIRED_SERVICES`.
+    pub fn require_services(mut self, services: ServiceFlags) -> TcpConnector {
+        self.required_services = services;
+        self
+    }
+}
+
+impl Connector for TcpConnector {
+    type Stream = Framed<TcpStream, NetworkMessagesCodec>;
+
+    fn connect(
+        &mut self,
+        id: PeerID,
+        addr: &SocketAddr,
+        start_height: i32,
+    ) -> ConnectFuture<Self::Stream> {
+        let timeout = self.handshake_timeout;
+        let required_services = self.required_services;
+        Box::new(connect(id, addr, self.magic).and_then(move |peer| {
+            Handshake::new(peer, start_height, timeout).require_services(required_services)
+        }))
+    }
+}
+
+/// State of the connections to the network which is reported to the embedder.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConnectionState {
+    /// No peer is connected, and the connections are being opened.
+    Connecting,
+    /// The peers are connected.
+    Connected {
+        /// The number of the connected peers.
+        peers: usize,
+    },
+    /// No peer is connected, and the reconnection is waiting for the backoff.
+    Disconnected,
+}
+
+/// Callback which is called when the connection state changes.
+pub type ConnectionListener = Arc<dyn Fn(ConnectionState) + Send + Sync>;
+
+/// Progress of the block header download which is reported to the embedder.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SyncProgress {
+    /// The height of our chain.
+    pub height: i32,
+    /// The highest height which the peers advertised in version message. It is never less than
+    /// `height`.
+    pub best_known_height: i32,
+}
+
+impl fmt::Display for SyncProgress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.height, self.best_known_height)
+    }
+}
+
+/// Callback which is called when the sync progress changes.
+pub type SyncProgressListener = Arc<dyn Fn(SyncProgress) + Send + Sync>;
+
+/// Connection which is not completed handshake yet.
+struct Connecting<T>
+where
+    T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
+{
+    id: PeerID,
+    addr: SocketAddr,
+    future: ConnectFuture<T>,
+}
+
+/// The drivers of the features which run over the connected peers.
+struct Drivers {
+    headers: HeaderSyncDriver,
+    /// The merkle block download, which is not used with the compact block filters.
+    merkle_blocks: Option<MerkleBlockDriver>,
+    /// The download of the compact block filters. The bloom filters are not used if it is set.
+    compact_filters: Option<CompactFilterDriver>,
+    /// The download of the full blocks whose compact block filters matched.
+    blocks: BlockDriver,
+    broadcasts: BroadcastDriver,
+    mempool: MempoolDriver,
+    /// The reports of the found transactions, which run after the pool of the unconfirmed
+    /// transactions is updated.
+    transaction_reports: TransactionReportDriver,
+}
+
+impl Drivers {
+    fn new() -> Drivers {
+        Drivers {
+            headers: HeaderSyncDriver::new(),
+            merkle_blocks: Some(MerkleBlockDriver::new()),
+            compact_filters: None,
+            blocks: BlockDriver::new(),
+            broadcasts: BroadcastDriver::new(),
+            mempool: MempoolDriver::new(),
+            transaction_reports: TransactionReportDriver::new(),
+        }
+    }
+
+    /// Return the drivers in the order which the messages are passed to them.
+    fn iter_mut<T, S>(&mut self) -> Vec<&mut dyn Driver<T, S>>
+    where
+        T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
+        S: ChainStore,
+    {
+        let mut drivers: Vec<&mut dyn Driver<T, S>> = vec![&mut self.headers];
+        if let Some(driver) = self.merkle_blocks.as_mut() {
+            drivers.push(driver);
+        }
+        if let Some(driver) = self.compact_filters.as_mut() {
+            drivers.push(driver);
+        }
+        drivers.push(&mut self.blocks);
+        drivers.push(&mut self.broadcasts);
+        drivers.push(&mut self.mempool);
+        drivers.push(&mut self.transaction_reports);
+        drivers
+    }
+
+    fn peer_connected<T, S>(&mut self, ctx: &mut Context<T, S>, id: PeerID)
+    where
+        T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
+        S: ChainStore,
+    {
+        for driver in self.iter_mut() {
+            driver.peer_connected(ctx, id);
+        }
+    }
+
+    fn peer_disconnected<T, S>(&mut self, id: PeerID)
+    where
+        T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
+        S: ChainStore,
+    {
+        for driver in self.iter_mut::<T, S>() {
+            driver.peer_disconnected(id);
+        }
+    }
+
+    /// Pass the message to the drivers in turn until one of them consumes it. Return the
+    /// message back if no driver consumes it.
+    fn on_message<T, S>(
+        &mut self,
+        ctx: &mut Context<T, S>,
+        id: PeerID,
+        message: Message,
+    ) -> Result<Option<Message>, Error>
+    where
+        T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
+        S: ChainStore,
+    {
+        let mut result = Ok(Some(message));
+        for driver in self.iter_mut() {
+            result = match result {
+                Ok(Some(message)) => driver.on_message(ctx, id, message),
+                _ => break,
+            };
+        }
+
+        // The events are dispatched even if the rest of the message is invalid, because the
+        // chain may be reorganized by the valid part of it.
+        self.dispatch_events(ctx)?;
+        result
+    }
+
+    /// Pass the events which the drivers emitted to all the drivers.
+    fn dispatch_events<T, S>(&mut self, ctx: &mut Context<T, S>) -> Result<(), Error>
+    where
+        T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
+        S: ChainStore,
+    {
+        while let Some(event) = ctx.next_event() {
+            for driver in self.iter_mut() {
+                driver.on_event(ctx, &event)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn tick<T, S>(&mut self, ctx: &mut Context<T, S>)
+    where
+        T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
+        S: ChainStore,
+    {
+        for driver in self.iter_mut() {
+            driver.tick(ctx);
+        }
+    }
+
+    /// Let the drivers send their requests. The events are dispatched after each driver, so
+    /// that the following drivers see them in the same poll.
+    fn send_requests<T, S>(&mut self, ctx: &mut Context<T, S>) -> Result<(), Error>
+    where
+        T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
+        S: ChainStore,
+    {
+        for i in 0..self.iter_mut::<T, S>().len() {
+            self.iter_mut()[i].send_requests(ctx)?;
+            self.dispatch_events(ctx)?;
+        }
+        Ok(())
+    }
+}
+
+/// This future keeps outbound connections and dispatches the messages from them to the drivers
+/// of the features.
+///
+/// The connections are opened to the addresses in the address book until the number of them
+/// reaches `max_outbound`, and a peer which is disconnected or misbehaves is replaced with new
+/// one. The address book learns new addresses from the connected peers with getaddr message. The
+/// other messages are passed to the drivers, which keep the chain in sync with the peers, look up
+/// the transactions matching the watch list and broadcast the transactions. The drivers are
+/// told the disconnections and the ticks of the timer, and they ask the manager to disconnect
+/// the peers which stall their requests. The future keeps running until it is dropped.
+///
+/// Each misbehavior of the peer adds score to it, and the peer is disconnected and banned for
+/// `DEFAULT_BAN_DURATION` when the score reaches `BAN_THRESHOLD`. The peers are pinged every
+/// `PING_INTERVAL` to measure the latency, and disconnected if they don't answer within
+/// `PING_TIMEOUT`.
+///
+/// The address which fails to connect or is disconnected is not connected again until the
+/// exponential backoff with jitter expires. After reconnecting, the block headers are requested
+/// from the current tip. The changes of the connection state are reported to the listener.
+pub struct PeerManager<C, S>
+where
+    C: Connector,
+    S: ChainStore,
+{
+    connector: C,
+    chain_state: Arc<Mutex<ChainState<S>>>,
+    address_book: AddressBook,
+    ban_list: BanList,
+    max_outbound: usize,
+    next_peer_id: PeerID,
+    connecting: Vec<Connecting<C::Stream>>,
+    /// Connected peers ordered by the time of connection.
+    peers: Vec<Peer<C::Stream>>,
+    /// Misbehavior score of each connected peer.
+    scores: HashMap<PeerID, u32>,
+    timer: Interval,
+    ping_interval: Duration,
+    ping_timeout: Duration,
+    /// Statistics of the connected peers which are shared with the owner of the manager.
+    stats: Arc<Mutex<Vec<PeerStats>>>,
+    backoff: Backoff,
+    connection_listener: Option<ConnectionListener>,
+    connection_state: Option<ConnectionState>,
+    /// Scripts and outpoints which are looked up from the blocks.
+    watch_list: Arc<Mutex<WatchList>>,
+    drivers: Drivers,
+}
+
+impl<C, S> PeerManager<C, S>
+where
+    C: Connector,
+    S: ChainStore,
+    Error: From<<C::Stream as Stream>::Error>,
+{
+    pub fn new(
+        connector: C,
+        chain_state: Arc<Mutex<ChainState<S>>>,
+        address_book: AddressBook,
+        ban_list: BanList,
+        max_outbound: usize,
+    ) -> PeerManager<C, S> {
+        PeerManager {
+            connector,
+            chain_state,
+            address_book,
+            ban_list,
+            max_outbound,
+            next_peer_id: 0,
+            connecting: vec![],
+            peers: vec![],
+            scores: HashMap::new(),
+            timer: Interval::new(Instant::now(), CONNECT_INTERVAL),
+            ping_interval: PING_INTERVAL,
+            ping_timeout: PING_TIMEOUT,
+            stats: Arc::new(Mutex::new(vec![])),
+            backoff: Backoff::default(),
+            connection_listener: None,
+            connection_state: None,
+            watch_list: Arc::new(Mutex::new(WatchList::new())),
+            drivers: Drivers::new(),
+        }
+    }
+
+    /// Share the watch list. The transactions matching it are looked up after the block headers
+    /// are downloaded, and the elements added later are sent to the peers.
+    pub fn share_watch_list(&mut self, watch_list: Arc<Mutex<WatchList>>) {
+        self.watch_list = watch_list;
+    }
+
+    /// Share the queue of the transactions to broadcast. The transactions added later are
+    /// announced to the peers immediately.
+    pub fn share_broadcasts(&mut self, broadcasts: Arc<Mutex<BroadcastQueue>>) {
+        self.drivers.broadcasts.share_broadcasts(broadcasts);
+    }
+
+    /// Share the pool of the unconfirmed transactions which match the watch list. It is collected
+    /// only with the bloom filters, and stays empty with the compact block filters.
+    pub fn share_mempool(&mut self, mempool: Arc<Mutex<Mempool>>) {
+        self.drivers.mempool.share_mempool(mempool);
+    }
+
+    /// Set the listener which is called when the transaction matching the watch list is found.
+    pub fn set_transaction_listener(&mut self, listener: TransactionListener) {
+        self.drivers
+            .transaction_reports
+            .set_transaction_listener(listener);
+    }
+
+    /// Set the listener which is called when the block including the matched transaction is
+    /// disconnected by reorganization. The transaction is added back to the pool of the
+    /// unconfirmed transactions.
+    pub fn set_disconnected_transaction_listener(&mut self, listener: TransactionListener) {
+        self.drivers
+            .mempool
+            .set_disconnected_transaction_listener(listener);
+    }
+
+    /// Set the consumer which receives the full blocks downloaded after their compact block
+    /// filters matched the watch list.
+    pub fn set_block_consumer(&mut self, consumer: Arc<dyn BlockConsumer + Send + Sync>) {
+        self.drivers.blocks.set_block_consumer(consumer);
+    }
+
+    /// Look up the transactions with the compact block filters of BIP157 instead of the bloom
+    /// filters of BIP37. The filter headers are stored into `store` and verified against
+    /// `checkpoints`.
+    pub fn enable_compact_filters(
+        &mut self,
+        store: Box<dyn FilterHeaderStore + Send>,
+        checkpoints: Vec<(i32, FilterHash)>,
+    ) {
+        self.drivers.merkle_blocks = None;
+        self.drivers.compact_filters = Some(CompactFilterDriver::new(store, checkpoints));
+    }
+
+    /// Set the listener which is called when the sync progress changes.
+    pub fn set_sync_progress_listener(&mut self, listener: SyncProgressListener) {
+        self.drivers.headers.set_sync_progress_listener(listener);
+    }
+
+    /// Set the listener which is called when the connection state changes.
+    pub fn set_connection_listener(&mut self, listener: ConnectionListener) {
+        self.connection_listener = Some(listener);
+    }
+
+    /// Report the connection state to the listener if it changed.
+    fn update_connection_state(&mut self) {
+        let state = if !self.peers.is_empty() {
+            ConnectionState::Connected {
+                peers: self.peers.len(),
+            }
+        } else if !self.connecting.is_empty() {
+            ConnectionState::Connecting
+        } else {
+            ConnectionState::Disconnected
+        };
+
+        if self.connection_state.as_ref() == Some(&state) {
+            return;
+        }
+
+        if state == ConnectionState::Disconnected {
+            match self.backoff.next_attempt_in() {
+                Some(delay) => info!("All peers are disconnected. Reconnect in {:?}.", delay),
+                None => info!("All peers are disconnected."),
+            }
+        }
+
+        if let Some(listener) = self.connection_listener.as_ref() {
+            listener(state.clone());
+        }
+        self.connection_state = Some(state);
+    }
+
+    /// Share the statistics of the connected peers. They are updated while the manager runs.
+    pub fn share_stats(&mut self, stats: Arc<Mutex<Vec<PeerStats>>>) {
+        self.stats = stats;
+    }
+
+    /// Set time limit for the sync peer to answer getheaders message.
+    pub fn set_getheaders_timeout(&mut self, timeout: Duration) {
+        self.drivers.headers.set_getheaders_timeout(timeout);
+    }
+
+    /// Call `f` with the drivers and the context lent to them. Return the result with the
+    /// reports of the drivers.
+    fn run_drivers<R>(
+        &mut self,
+        chain_active: &mut Chain<S>,
+        f: impl FnOnce(&mut Drivers, &mut Context<C::Stream, S>) -> R,
+    ) -> (R, Reports) {
+        let headers_synced = self.drivers.headers.is_synced();
+        let mut ctx = Context::new(
+            &mut self.peers,
+            chain_active,
+            &self.watch_list,
+            headers_synced,
+        );
+        let result = f(&mut self.drivers, &mut ctx);
+        (result, ctx.into_reports())
+    }
+
+    /// Apply the misbehaviors and the disconnections which the drivers reported. The reported
+    /// misbehavior which doesn't reach the ban only adds score, because the driver reporting it
+    /// doesn't rely on the peer already.
+    fn apply_reports(&mut self, reports: Reports) {
+        for (id, cause) in reports.misbehaviors {
+            if let Some(i) = self.peers.iter().position(|peer| peer.id == id) {
+                if self.misbehaving(i, &cause) {
+                    self.disconnect(i, "banned");
+                }
+            }
+        }
+        for (id, reason) in reports.disconnects {
+            if let Some(i) = self.peers.iter().position(|peer| peer.id == id) {
+                self.disconnect(i, &reason);
+            }
+        }
+    }
+
+    /// Send ping to the peers, and disconnect the peers which don't answer the ping.
+    fn check_pings(&mut self) {
+        let ping_interval = self.ping_interval;
+        let mut i = 0;
+        while i < self.peers.len() {
+            let peer = &mut self.peers[i];
+            if peer.is_ping_timed_out(self.ping_timeout) {
+                self.disconnect(i, "ping timeout");
+                continue;
+            }
+
+            let should_ping = peer.ping_nonce.is_none()
+                && peer
+                    .ping_sent
+                    .is_none_or(|sent| sent.elapsed() >= ping_interval);
+            if should_ping {
+                peer.send_ping();
+            }
+            i += 1;
+        }
+    }
+
+    fn update_stats(&self) {
+        let mut stats = self.stats.lock().unwrap();
+        *stats = self.peers.iter().map(|peer| peer.stats()).collect();
+    }
+
+    /// Open new connections until the number of connections reaches `max_outbound`.
+    /// `start_height` is the height of our chain which is advertised to the peers.
+    fn open_connections(&mut self, start_height: i32) {
+        while self.peers.len() + self.connecting.len() < self.max_outbound {
+            let addr = match self.next_address() {
+                Some(addr) => addr,
+                None => break,
+            };
+
+            let id = self.next_peer_id;
+            self.next_peer_id += 1;
+
+            trace!("Connect to peer {}. addr: {}", id, addr);
+            let future = self.connector.connect(id, &addr, start_height);
+            self.connecting.push(Connecting { id, addr, future });
+        }
+    }
+
+    /// Return address which is not connected yet, not banned and not waiting for backoff.
+    fn next_address(&self) -> Option<SocketAddr> {
+        self.address_book.select(|addr| {
+            self.ban_list.is_banned(&addr.ip())
+                || self.backoff.is_waiting(addr)
+                || self.peers.iter().any(|peer| peer.addr == *addr)
+                || self.connecting.iter().any(|c| c.addr == *addr)
+        })
+    }
+
+    /// Add the addresses which the peer announced to the address book.
+    fn on_addr(&mut self, i: usize, addresses: Vec<(u32, Address)>) -> Result<(), Error> {
+        if addresses.len() > MAX_ADDR_TO_SEND {
+            return Err(Error::MaliciousPeer(
+                self.peers[i].id,
+                MaliciousPeerCause::OversizedMessage,
+            ));
+        }
+
+        // The time in the future is not trusted.
+        let now = address_book::now();
+        for (time, address) in addresses {
+            self.address_book.add(&address, std::cmp::min(time, now));
+        }
+        Ok(())
+    }
+
+    /// Add misbehavior score to the peer. Return true if the peer is banned.
+    fn misbehaving(&mut self, i: usize, cause: &MaliciousPeerCause) -> bool {
+        let peer = &self.peers[i];
+        let score = self.scores.entry(peer.id).or_insert(0);
+        *score += cause.score();
+        warn!(
+            "Peer {} misbehaves: {:?}. score: {}",
+            peer.id, cause, *score
+        );
+
+        if *score < BAN_THRESHOLD {
+            return false;
+        }
+
+        info!("Ban peer {}. addr: {}", peer.id, peer.addr);
+        self.ban_list
+            .ban(peer.addr.ip(), address_book::now() + DEFAULT_BAN_DURATION);
+        self.save_ban_list();
+        true
+    }
+
+    fn save_address_book(&mut self) {
+        if let Err(e) = self.address_book.save() {
+            warn!("Can not save address book: {}", e);
+        }
+    }
+
+    fn save_ban_list(&mut self) {
+        if let Err(e) = self.ban_list.save() {
+            warn!("Can not save ban list: {}", e);
+        }
+    }
+
+    fn poll_connecting(&mut self, chain_active: &mut Chain<S>) {
+        let mut i = 0;
+        while i < self.connecting.len() {
+            match self.connecting[i].future.poll() {
+                Ok(Async::NotReady) => i += 1,
+                Ok(Async::Ready(mut peer)) => {
+                    let connecting = self.connecting.swap_remove(i);
+                    info!(
+                        "Connected to peer {}. addr: {}, start_height: {}",
+                        peer.id,
+                        peer.addr,
+                        peer.start_height()
+                    );
+                    self.address_book.mark_success(&connecting.addr);
+                    peer.start_send(NetworkMessage::GetAddr);
+                    let id = peer.id;
+                    self.peers.push(peer);
+                    let ((), reports) = self
+                        .run_drivers(chain_active, |drivers, ctx| drivers.peer_connected(ctx, id));
+                    self.apply_reports(reports);
+                }
+                Err(e @ Error::IncompatiblePeer(..)) | Err(e @ Error::SelfConnection) => {
+                    // We can never talk to the address, so it is not tried again.
+                    let connecting = self.connecting.swap_remove(i);
+                    warn!(
+                        "Can not connect to peer {}. addr: {}, error: {}",
+                        connecting.id, connecting.addr, e
+                    );
+                    self.address_book.remove(&connecting.addr);
+                }
+                Err(e) => {
+                    let connecting = self.connecting.swap_remove(i);
+                    // The failure may be caused by our network while no peer is connected.
+                    if !self.peers.is_empty() {
+                        self.address_book.mark_failure(&connecting.addr);
+                    }
+                    let delay = self.backoff.failed(&connecting.addr);
+                    warn!(
+                        "Can not connect to peer {}. addr: {}, error: {}, retry in {:?}",
+                        connecting.id, connecting.addr, e, delay
+                    );
+                }
+            }
+        }
+    }
+
+    /// Process messages from the peers. Return error if the chain or the filter headers can not
+    /// be read or stored, because the manager can not continue.
+    fn poll_peers(&mut self, chain_active: &mut Chain<S>) -> Result<(), Error> {
+        // The reports are applied after all the peers are polled, because they may disconnect
+        // other peers than the polled one.
+        let mut reports = Reports::default();
+        let mut i = 0;
+        while i < self.peers.len() {
+            match self.poll_peer(i, chain_active, &mut reports) {
+                Ok(true) => i += 1,
+                Ok(false) => self.disconnect(i, "connection closed"),
+                Err(Error::MaliciousPeer(_, cause)) => {
+                    if self.misbehaving(i, &cause) {
+                        self.disconnect(i, "banned");
+                    } else {
+                        // The peer may not answer to the requests, so request to another peer.
+                        let id = self.peers[i].id;
+                        let (result, misbehaved) =
+                            self.run_drivers(chain_active, |drivers, ctx| {
+                                ctx.emit(Event::PeerMisbehaved(id));
+                                drivers.dispatch_events(ctx)
+                            });
+                        reports.append(misbehaved);
+                        result?;
+                    }
+                    // Otherwise the remaining messages from the peer are processed again.
+                }
+                Err(e @ Error::ChainError(_)) => return Err(e),
+                Err(e) => self.disconnect(i, &e.to_string()),
+            }
+        }
+        self.apply_reports(reports);
+        Ok(())
+    }
+
+    /// Process messages from the peer. Return false if the connection is closed.
+    fn poll_peer(
+        &mut self,
+        i: usize,
+        chain_active: &mut Chain<S>,
+        reports: &mut Reports,
+    ) -> Result<bool, Error> {
+        loop {
+            let message = match self.peers[i].poll() {
+                Ok(Async::Ready(Some(message))) => message,
+                Ok(Async::Ready(None)) => return Ok(false),
+                Ok(Async::NotReady) => return Ok(true),
+                Err(Error::MaliciousPeer(_, cause)) => {
+                    // The stream can not be read any more.
+                    self.misbehaving(i, &cause);
+                    return Ok(false);
+                }
+                Err(e) => return Err(e),
+            };
+
+            let message = match message {
+                Message::Network(NetworkMessage::Addr(addresses)) => {
+                    self.on_addr(i, addresses)?;
+                    continue;
+                }
+                Message::Network(NetworkMessage::Ping(nonce)) => {
+                    self.peers[i].start_send(NetworkMessage::Pong(nonce));
+                    continue;
+                }
+                Message::Network(NetworkMessage::Pong(nonce)) => {
+                    self.peers[i].on_pong(nonce);
+                    continue;
+                }
+                message => message,
+            };
+
+            let id = self.peers[i].id;
+            let (result, reported) = self.run_drivers(chain_active, |drivers, ctx| {
+                drivers.on_message(ctx, id, message)
+            });
+            reports.append(reported);
+
+            // The data which no driver requested is unsolicited, and other messages are ignored.
+            let unsolicited = match result? {
+                Some(Message::Network(message)) => matches!(
+                    message,
+                    NetworkMessage::Tx(_)
+                        | NetworkMessage::Block(_)
+                        | NetworkMessage::CFHeaders(_)
+                        | NetworkMessage::CFCheckpt(_)
+                        | NetworkMessage::CFilter(_)
+                ),
+                Some(_) => true,
+                None => false,
+            };
+            if unsolicited {
+                return Err(Error::MaliciousPeer(
+                    id,
+                    MaliciousPeerCause::UnsolicitedData,
+                ));
+            }
+        }
+    }
+
+    fn disconnect(&mut self, i: usize, reason: &str) {
+        let peer = self.peers.remove(i);
+        self.scores.remove(&peer.id);
+        info!(
+            "Disconnect peer {}. addr: {}, reason: {}",
+            peer.id, peer.addr, reason
+        );
+
+        // The connection which was kept long enough is not counted as failure.
+        if peer.connected_at.elapsed() >= self.backoff.max() {
+            self.backoff.succeeded(&peer.addr);
+        } else {
+            self.backoff.failed(&peer.addr);
+        }
+
+        // The drivers send the requests to the peer to another peer.
+        self.drivers.peer_disconnected::<C::Stream, S>(peer.id);
+    }
+}
+
+impl<C, S> Future for PeerManager<C, S>
+where
+    C: Connector,
+    S: ChainStore,
+    Error: From<<C::Stream as Stream>::Error>,
+{
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Result<Async<Self::Item>, Self::Error> {
+        let chain_state = self.chain_state.clone();
+        let mut chain_state = chain_state.lock().unwrap();
+        let chain_active = chain_state.borrow_mut_chain_active();
+
+        loop {
+            match self.timer.poll() {
+                Ok(Async::Ready(Some(_))) => {
+                    self.save_address_book();
+                    self.save_ban_list();
+                    self.check_pings();
+                    let ((), reports) =
+                        self.run_drivers(chain_active, |drivers, ctx| drivers.tick(ctx));
+                    self.apply_reports(reports);
+                    self.open_connections(chain_active.height());
+                }
+                Ok(_) => break,
+                Err(e) => {
+                    error!("Timer error: {}", e);
+                    break;
+                }
+            }
+        }
+
+        self.poll_connecting(chain_active);
+
+        if let Err(e) = self.poll_peers(chain_active) {
+            error!("Can not update the chain. Stop the peer manager: {}", e);
+            return Err(e);
+        }
+
+        let (result, reports) =
+            self.run_drivers(chain_active, |drivers, ctx| drivers.send_requests(ctx));
+        self.apply_reports(reports);
+        if let Err(e) = result {
+            error!(
+                "Can not access the chain or the filter headers. Stop the peer manager: {}",
+                e
+            );
+            return Err(e);
+        }
+
+        for peer in self.peers.iter_mut() {
+            peer.flush();
+        }
+        self.update_stats();
+        self.update_connection_state();
+
+        Ok(Async::NotReady)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chain::store::{OnMemoryChainStore, OnMemoryFilterHeaderStore};
+    use crate::chain::{self, AggregatePublicKeyEntry, BlockIndex};
+    use crate::network::peer::version_message;
+    use crate::network::{BroadcastResult, MatchedTransaction};
+    use crate::test_helper::{
+        channel, get_chain, get_test_genesis_block, get_test_headers, temp_dir, FakePeer,
+        TwoWayChannel,
+    };
+    use std::collections::HashMap;
+    use tapyrus::network::constants::NetworkId;
+    use tapyrus::{BlockHash, BlockHeader, OutPoint, Transaction};
+
+    /// How the stand-in peer behaves on each connection.
+    #[derive(Clone)]
+    enum Behavior {
+        Honest,
+        Disconnect,
+        InvalidProof,
+        /// Send addr message and disconnect without serving headers.
+        AddrOnly,
+        /// Never answer getheaders message.
+        Stall,
+        /// Announce 3 new blocks after receiving sendheaders message.
+        Announce,
+        /// Reject the transactions which it downloads.
+        RejectTx,
+        /// Announce back the transactions instead of downloading them.
+        KnowTx,
+        /// Announce the unconfirmed transactions after the bloom filter is loaded.
+        Mempool(Vec<Transaction>),
+    }
+
+    /// Connector which connects to stand-in peers serving 23 block headers.
+    struct FakeConnector {
+        behaviors: Vec<Behavior>,
+        connections: usize,
+        getheaders: Arc<Mutex<HashMap<SocketAddr, usize>>>,
+        connected: Arc<Mutex<Vec<SocketAddr>>>,
+        /// The heights which the manager advertises on each connection.
+        start_heights: Arc<Mutex<Vec<i32>>>,
+        /// Addresses which the stand-in peers send in response to getaddr message.
+        addresses: Vec<(u32, Address)>,
+    }
+
+    impl FakeConnector {
+        fn new(behaviors: Vec<Behavior>) -> FakeConnector {
+            FakeConnector {
+                behaviors,
+                connections: 0,
+                getheaders: Arc::new(Mutex::new(HashMap::new())),
+                connected: Arc::new(Mutex::new(vec![])),
+                start_heights: Arc::new(Mutex::new(vec![])),
+                addresses: vec![],
+            }
+        }
+    }
+
+    impl Connector for FakeConnector {
+        type Stream = TwoWayChannel<RawMessage>;
+
+        fn connect(
+            &mut self,
+            id: PeerID,
+            addr: &SocketAddr,
+            start_height: i32,
+        ) -> ConnectFuture<Self::Stream> {
+            self.start_heights.lock().unwrap().push(start_height);
+            let (here, there) = channel::<RawMessage>();
+            let behavior = self
+                .behaviors
+                .get(self.connections)
+                .cloned()
+                .unwrap_or(Behavior::Honest);
+            self.connections += 1;
+            self.connected.lock().unwrap().push(*addr);
+
+            let mut headers: Vec<BlockHeader> = get_test_headers(0, 24);
+            match behavior {
+                Behavior::Honest
+                | Behavior::AddrOnly
+                | Behavior::Stall
+                | Behavior::Announce
+                | Behavior::RejectTx
+                | Behavior::KnowTx
+                | Behavior::Mempool(_) => {}
+                Behavior::Disconnect => headers.truncate(0),
+                Behavior::InvalidProof => headers[5].proof = None,
+            }
+
+            if !headers.is_empty() {
+                let getheaders = self.getheaders.clone();
+                let addr = *addr;
+                let addr_only = matches!(behavior, Behavior::AddrOnly);
+                let mut remote = FakePeer::new(here, headers, 10)
+                    .with_addresses(self.addresses.clone(), addr_only)
+                    .on_getheaders(move || {
+                        *getheaders.lock().unwrap().entry(addr).or_insert(0) += 1;
+                    });
+                match behavior {
+                    Behavior::Stall => remote = remote.ignore_getheaders(),
+                    Behavior::Announce => remote = remote.announce(get_test_headers(24, 3)),
+                    Behavior::RejectTx => remote = remote.reject_transactions(),
+                    Behavior::KnowTx => remote = remote.know_transactions(),
+                    Behavior::Mempool(ref txs) => remote = remote.with_mempool(txs.clone()),
+                    _ => {}
+                }
+                tokio::spawn(remote);
+            }
+
+            let mut peer = Peer::new(id, there, *addr, NetworkId::REGTEST.magic());
+            peer.version = Some(version_message(23));
+            peer.features.services =
+                ServiceFlags::NETWORK | ServiceFlags::BLOOM | ServiceFlags::COMPACT_FILTERS;
+            Box::new(future::ok(peer))
+        }
+    }
+
+    fn address_book(addresses: &[SocketAddr]) -> AddressBook {
+        let mut book = AddressBook::new();
+        for addr in addresses {
+            book.add(&Address::new(addr, ServiceFlags::NETWORK), 0);
+        }
+        book
+    }
+
+    fn run_manager(
+        connector: FakeConnector,
+        address_book: AddressBook,
+        max_outbound: usize,
+    ) -> i32 {
+        run_manager_until(
+            connector,
+            address_book,
+            max_outbound,
+            |height| height >= 23,
+            |_| {},
+        )
+    }
+
+    /// Run the manager until `done` returns true for the height of the chain. It gives up after
+    /// 10 seconds.
+    fn run_manager_until(
+        connector: FakeConnector,
+        address_book: AddressBook,
+        max_outbound: usize,
+        done: impl Fn(i32) -> bool + 'static,
+        configure: impl FnOnce(&mut PeerManager<FakeConnector, OnMemoryChainStore>) + 'static,
+    ) -> i32 {
+        let chain_state = Arc::new(Mutex::new(ChainState::new(get_chain())));
+        let chain_state_for_manager = chain_state.clone();
+        let chain_state_for_check = chain_state.clone();
+
+        let future = future::lazy(move || {
+            let mut manager = PeerManager::new(
+                connector,
+                chain_state_for_manager,
+                address_book,
+                BanList::new(),
+                max_outbound,
+            );
+            manager.timer = Interval::new(Instant::now(), Duration::from_millis(10));
+            manager.drivers.headers.set_max_headers_results(10);
+            manager.set_getheaders_timeout(Duration::from_millis(50));
+            manager.backoff = Backoff::new(Duration::from_millis(10), Duration::from_millis(100));
+            configure(&mut manager);
+
+            let deadline = Instant::now() + Duration::from_secs(10);
+            let reached = Interval::new(Instant::now(), Duration::from_millis(10))
+                .map_err(|_| ())
+                .take_while(move |_| {
+                    let chain_state = chain_state_for_check.lock().unwrap();
+                    let current = chain_state.borrow_chain_active().height();
+                    Ok(!done(current) && Instant::now() < deadline)
+                })
+                .for_each(|_| Ok(()));
+
+            manager
+                .map_err(|e| panic!("manager failed: {}", e))
+                .select(reached)
+                .map(|_| ())
+                .map_err(|_| ())
+        });
+        tokio::runtime::current_thread::run(future);
+
+        let chain_state = chain_state.lock().unwrap();
+        chain_state.borrow_chain_active().height()
+    }
+
+    #[test]
+    fn test_download_headers_from_multiple_peers() {
+        let connector = FakeConnector::new(vec![]);
+        let getheaders = connector.getheaders.clone();
+        let addresses = vec![
+            "10.0.0.1:12383".parse().unwrap(),
+            "10.0.0.2:12383".parse().unwrap(),
+        ];
+
+        assert_eq!(run_manager(connector, address_book(&addresses), 2), 23);
+
+        // getheaders messages are sent to both peers.
+        let getheaders = getheaders.lock().unwrap();
+        assert!(getheaders[&addresses[0]] > 0);
+        assert!(getheaders[&addresses[1]] > 0);
+    }
+
+    #[test]
+    fn test_replace_disconnected_peer() {
+        let connector = FakeConnector::new(vec![Behavior::Disconnect]);
+        let addresses = vec!["10.0.0.1:12383".parse().unwrap()];
+
+        assert_eq!(run_manager(connector, address_book(&addresses), 1), 23);
+    }
+
+    #[test]
+    fn test_follow_new_blocks_after_initial_download() {
+        let connector = FakeConnector::new(vec![Behavior::Announce]);
+        let addresses = vec!["10.0.0.1:12383".parse().unwrap()];
+
+        // The first block is announced by headers, and the others by inv.
+        let height = run_manager_until(
+            connector,
+            address_book(&addresses),
+            1,
+            |height| height >= 26,
+            |_| {},
+        );
+        assert_eq!(height, 26);
+    }
+
+    #[test]
+    fn test_report_sync_progress() {
+        let connector = FakeConnector::new(vec![]);
+        let start_heights = connector.start_heights.clone();
+        let addresses = vec!["10.0.0.1:12383".parse().unwrap()];
+
+        let progress = Arc::new(Mutex::new(vec![]));
+        let progress_for_listener = progress.clone();
+        let listener: SyncProgressListener = Arc::new(move |p| {
+            progress_for_listener.lock().unwrap().push(p);
+        });
+
+        let height = run_manager_until(
+            connector,
+            address_book(&addresses),
+            1,
+            |height| height >= 23,
+            |manager| manager.set_sync_progress_listener(listener),
+        );
+        assert_eq!(height, 23);
+
+        // The manager advertises its tip, which is the genesis block on the first connection.
+        assert_eq!(start_heights.lock().unwrap()[0], 0);
+
+        // The progress goes toward the height which the peer advertised.
+        let progress: Vec<(i32, i32)> = progress
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|p| (p.height, p.best_known_height))
+            .collect();
+        assert_eq!(
+            progress,
+            vec![(0, 0), (0, 23), (10, 23), (20, 23), (23, 23)]
+        );
+    }
+
+    #[test]
+    fn test_find_transactions_with_bloom_filter() {
+        let connector = FakeConnector::new(vec![]);
+        let addresses = vec!["10.0.0.1:12383".parse().unwrap()];
+
+        // The genesis block pays to the script.
+        let coinbase = get_test_genesis_block().txdata[0].clone();
+        let watch_list = Arc::new(Mutex::new(WatchList::new()));
+        watch_list
+            .lock()
+            .unwrap()
+            .add_script(coinbase.output[0].script_pubkey.clone());
+
+        let matched = Arc::new(Mutex::new(vec![]));
+        let matched_for_listener = matched.clone();
+        let matched_for_check = matched.clone();
+        let listener: TransactionListener = Arc::new(move |tx| {
+            matched_for_listener.lock().unwrap().push(tx);
+        });
+
+        let height = run_manager_until(
+            connector,
+            address_book(&addresses),
+            1,
+            move |height| height >= 23 && !matched_for_check.lock().unwrap().is_empty(),
+            |manager| {
+                manager.share_watch_list(watch_list);
+                manager.set_transaction_listener(listener);
+            },
+        );
+        assert_eq!(height, 23);
+        assert_eq!(
+            *matched.lock().unwrap(),
+            vec![MatchedTransaction {
+                tx: coinbase,
+                height: 0
+            }]
+        );
+    }
+
+    #[test]
+    fn test_find_transactions_with_compact_filters() {
+        let connector = FakeConnector::new(vec![]);
+        let addresses = vec!["10.0.0.1:12383".parse().unwrap()];
+
+        let coinbase = get_test_genesis_block().txdata[0].clone();
+        let watch_list = Arc::new(Mutex::new(WatchList::new()));
+        watch_list
+            .lock()
+            .unwrap()
+            .add_script(coinbase.output[0].script_pubkey.clone());
+
+        let matched = Arc::new(Mutex::new(vec![]));
+        let matched_for_listener = matched.clone();
+        let matched_for_check = matched.clone();
+        let listener: TransactionListener = Arc::new(move |tx| {
+            matched_for_listener.lock().unwrap().push(tx);
+        });
+
+        let height = run_manager_until(
+            connector,
+            address_book(&addresses),
+            1,
+            move |height| height >= 23 && !matched_for_check.lock().unwrap().is_empty(),
+            |manager| {
+                manager.share_watch_list(watch_list);
+                manager.set_transaction_listener(listener);
+                manager.enable_compact_filters(Box::new(OnMemoryFilterHeaderStore::new()), vec![]);
+            },
+        );
+        assert_eq!(height, 23);
+        assert_eq!(
+            *matched.lock().unwrap(),
+            vec![MatchedTransaction {
+                tx: coinbase,
+                height: 0
+            }]
+        );
+    }
+
+    /// Broadcast a transaction through the stand-in peers and return the outcomes.
+    fn broadcast(behaviors: Vec<Behavior>) -> Vec<BroadcastResult> {
+        let connector = FakeConnector::new(behaviors);
+        let addresses = vec![
+            "10.0.0.1:12383".parse().unwrap(),
+            "10.0.0.2:12383".parse().unwrap(),
+        ];
+
+        let results = Arc::new(Mutex::new(vec![]));
+        let results_for_listener = results.clone();
+        let results_for_check = results.clone();
+        let broadcasts = Arc::new(Mutex::new(BroadcastQueue::new()));
+        broadcasts.lock().unwrap().add(
+            get_test_genesis_block().txdata[0].clone(),
+            Box::new(move |result| results_for_listener.lock().unwrap().push(result)),
+        );
+
+        run_manager_until(
+            connector,
+            address_book(&addresses),
+            2,
+            move |_| !results_for_check.lock().unwrap().is_empty(),
+            |manager| manager.share_broadcasts(broadcasts),
+        );
+        let results = results.lock().unwrap();
+        results.clone()
+    }
+
+    #[test]
+    fn test_broadcast_transaction() {
+        assert_eq!(
+            broadcast(vec![Behavior::Honest, Behavior::KnowTx]),
+            vec![BroadcastResult::Accepted]
+        );
+        assert_eq!(
+            broadcast(vec![Behavior::RejectTx, Behavior::RejectTx]),
+            vec![BroadcastResult::Rejected("insufficient fee".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_collect_unconfirmed_transactions() {
+        let coinbase = get_test_genesis_block().txdata[0].clone();
+        let mut payment = coinbase.clone();
+        payment.input[0].previous_output = OutPoint::new(coinbase.txid(), 0);
+        let mut unmatched = payment.clone();
+        unmatched.output.clear();
+
+        let connector =
+            FakeConnector::new(vec![Behavior::Mempool(vec![unmatched, payment.clone()])]);
+        let addresses = vec!["10.0.0.1:12383".parse().unwrap()];
+        let watch_list = Arc::new(Mutex::new(WatchList::new()));
+        watch_list
+            .lock()
+            .unwrap()
+            .add_script(coinbase.output[0].script_pubkey.clone());
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let mempool_for_manager = mempool.clone();
+        let mempool_for_check = mempool.clone();
+        let watch_list_for_check = watch_list.clone();
+
+        run_manager_until(
+            connector,
+            address_book(&addresses),
+            1,
+            move |_| !mempool_for_check.lock().unwrap().transactions().is_empty(),
+            |manager| {
+                manager.share_watch_list(watch_list);
+                manager.share_mempool(mempool_for_manager);
+            },
+        );
+        assert_eq!(
+            mempool.lock().unwrap().transactions(),
+            vec![payment.clone()]
+        );
+
+        // The spent outpoint is watched to find the conflicting spend.
+        let mut double_spend = payment;
+        double_spend.output.clear();
+        assert!(watch_list_for_check.lock().unwrap().matches(&double_spend));
+    }
+
+    /// ChainStore which fails to store new blocks.
+    struct ReadOnlyChainStore(OnMemoryChainStore);
+
+    impl ChainStore for ReadOnlyChainStore {
+        fn height(&self) -> i32 {
+            self.0.height()
+        }
+
+        fn get(&self, height: i32) -> Result<Option<BlockIndex>, chain::Error> {
+            self.0.get(height)
+        }
+
+        fn height_of(&self, hash: &BlockHash) -> Result<Option<i32>, chain::Error> {
+            self.0.height_of(hash)
+        }
+
+        fn update_tip(&mut self, _: &BlockIndex) -> Result<(), chain::Error> {
+            Err(std::io::Error::other("read only").into())
+        }
+
+        fn rewind(&mut self, _: i32) -> Result<(), chain::Error> {
+            Err(std::io::Error::other("read only").into())
+        }
+
+        fn aggregate_public_keys(&self) -> &[AggregatePublicKeyEntry] {
+            self.0.aggregate_public_keys()
+        }
+    }
+
+    #[test]
+    fn test_stop_when_chain_can_not_be_stored() {
+        let mut store = OnMemoryChainStore::new();
+        store.initialize(get_test_genesis_block()).unwrap();
+        let chain_state = Arc::new(Mutex::new(ChainState::new(Chain::new(ReadOnlyChainStore(
+            store,
+        )))));
+        let addresses = vec!["10.0.0.1:12383".parse().unwrap()];
+        let error = Arc::new(Mutex::new(None));
+        let error_for_manager = error.clone();
+
+        tokio::runtime::current_thread::run(future::lazy(move || {
+            let mut manager = PeerManager::new(
+                FakeConnector::new(vec![]),
+                chain_state,
+                address_book(&addresses),
+                BanList::new(),
+                1,
+            );
+            manager.timer = Interval::new(Instant::now(), Duration::from_millis(10));
+            manager.map_err(move |e| {
+                *error_for_manager.lock().unwrap() = Some(e);
+            })
+        }));
+
+        let error = error.lock().unwrap().take();
+        match error {
+            Some(Error::ChainError(chain::Error::IoError(_))) => {}
+            _ => panic!("should stop with ChainError"),
+        }
+    }
+
+    #[test]
+    fn test_reconnect_with_backoff() {
+        let connector = FakeConnector::new(vec![Behavior::Disconnect, Behavior::Disconnect]);
+        let connected = connector.connected.clone();
+        let addresses = vec!["10.0.0.1:12383".parse().unwrap()];
+
+        let states = Arc::new(Mutex::new(vec![]));
+        let states_for_listener = states.clone();
+        let listener: ConnectionListener = Arc::new(move |state| {
+            states_for_listener.lock().unwrap().push(state);
+        });
+
+        let height = run_manager_until(
+            connector,
+            address_book(&addresses),
+            1,
+            |height| height >= 23,
+            |manager| manager.set_connection_listener(listener),
+        );
+        assert_eq!(height, 23);
+
+        // The same address is connected again after the connection drops.
+        assert_eq!(connected.lock().unwrap().len(), 3);
+
+        let states = states.lock().unwrap();
+        assert!(states.contains(&ConnectionState::Disconnected));
+        assert_eq!(
+            states.last(),
+            Some(&ConnectionState::Connected { peers: 1 })
+        );
+    }
+
+    #[test]
+    fn test_replace_malicious_peer() {
+        let connector = FakeConnector::new(vec![Behavior::InvalidProof]);
+        let connected = connector.connected.clone();
+        let addresses = vec![
+            "10.0.0.1:12383".parse().unwrap(),
+            "10.0.0.2:12383".parse().unwrap(),
+        ];
+
+        assert_eq!(run_manager(connector, address_book(&addresses), 1), 23);
+
+        // The malicious peer is banned, so it is never connected again.
+        let connected = connected.lock().unwrap();
+        let malicious = connected[0];
+        assert_eq!(connected.iter().filter(|a| **a == malicious).count(), 1);
+    }
+
+    #[test]
+    fn test_replace_stalled_sync_peer() {
+        let connector = FakeConnector::new(vec![Behavior::Stall]);
+        let getheaders = connector.getheaders.clone();
+        let connected = connector.connected.clone();
+        let addresses = vec![
+            "10.0.0.1:12383".parse().unwrap(),
+            "10.0.0.2:12383".parse().unwrap(),
+        ];
+
+        assert_eq!(run_manager(connector, address_book(&addresses), 1), 23);
+
+        // The stalled peer was requested the headers, but it was replaced.
+        let stalled = connected.lock().unwrap()[0];
+        assert!(getheaders.lock().unwrap()[&stalled] > 0);
+        assert!(connected.lock().unwrap().len() > 1);
+    }
+
+    #[test]
+    fn test_ban_by_misbehavior_score() {
+        let datadir = temp_dir("peer_manager_ban");
+        let chain_state = Arc::new(Mutex::new(ChainState::new(get_chain())));
+        let mut manager = PeerManager::new(
+            FakeConnector::new(vec![]),
+            chain_state,
+            AddressBook::new(),
+            BanList::open(&datadir).unwrap(),
+            1,
+        );
+
+        let (_here, there) = channel::<RawMessage>();
+        let addr: SocketAddr = "10.0.0.1:12383".parse().unwrap();
+        manager
+            .peers
+            .push(Peer::new(0, there, addr, NetworkId::REGTEST.magic()));
+
+        // The score is accumulated until it reaches the threshold.
+        for _ in 0..9 {
+            assert!(!manager.misbehaving(0, &MaliciousPeerCause::UnsolicitedData));
+        }
+        assert!(!manager.ban_list.is_banned(&addr.ip()));
+        assert!(manager.misbehaving(0, &MaliciousPeerCause::UnsolicitedData));
+        assert!(manager.ban_list.is_banned(&addr.ip()));
+
+        // The ban list is stored in the data directory.
+        assert!(BanList::open(&datadir).unwrap().is_banned(&addr.ip()));
+
+        std::fs::remove_dir_all(&datadir).unwrap();
+    }
+
+    #[test]
+    fn test_learn_addresses_from_peers() {
+        let addr1: SocketAddr = "10.0.0.1:12383".parse().unwrap();
+        let addr2: SocketAddr = "10.0.0.2:12383".parse().unwrap();
+        let addr3: SocketAddr = "10.0.0.3:12383".parse().unwrap();
+
+        // The first peer only tells other addresses, so headers must be downloaded from them.
+        let mut connector = FakeConnector::new(vec![Behavior::AddrOnly]);
+        connector.addresses = vec![
+            (100, Address::new(&addr2, ServiceFlags::NETWORK)),
+            (100, Address::new(&addr3, ServiceFlags::NONE)),
+        ];
+        let connected = connector.connected.clone();
+
+        let datadir = temp_dir("peer_manager");
+        let mut book = AddressBook::open(&datadir).unwrap();
+        book.add(&Address::new(&addr1, ServiceFlags::NETWORK), 0);
+
+        assert_eq!(run_manager(connector, book, 2), 23);
+        assert!(connected.lock().unwrap().contains(&addr2));
+        assert!(!connected.lock().unwrap().contains(&addr3));
+
+        // The address book is stored in the data directory.
+        let book = AddressBook::open(&datadir).unwrap();
+        assert!(book.get(&addr1).unwrap().last_success > 0);
+        assert!(book.get(&addr2).is_some());
+        assert!(book.get(&addr3).is_none());
+
+        std::fs::remove_dir_all(&datadir).unwrap();
+    }
+
+    #[test]
+    fn test_disconnect_peer_not_answering_ping() {
+        let chain_state = Arc::new(Mutex::new(ChainState::new(get_chain())));
+        let mut manager = PeerManager::new(
+            FakeConnector::new(vec![]),
+            chain_state,
+            AddressBook::new(),
+            BanList::new(),
+            1,
+        );
+        manager.ping_timeout = Duration::from_millis(1);
+        let stats = Arc::new(Mutex::new(vec![]));
+        manager.share_stats(stats.clone());
+
+        let (_here, there) = channel::<RawMessage>();
+        let addr: SocketAddr = "10.0.0.1:12383".parse().unwrap();
+        manager
+            .peers
+            .push(Peer::new(0, there, addr, NetworkId::REGTEST.magic()));
+
+        // ping is sent to new peer.
+        manager.check_pings();
+        assert!(manager.peers[0].ping_nonce.is_some());
+        manager.update_stats();
+        assert_eq!(stats.lock().unwrap()[0].addr, addr);
+
+        // the peer is disconnected after the timeout.
+        std::thread::sleep(Duration::from_millis(2));
+        manager.check_pings();
+        assert!(manager.peers.is_empty());
+        manager.update_stats();
+        assert!(stats.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,266 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+use crate::network::peer::PeerID;
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+use tapyrus::network::message_network::Reject;
+use tapyrus::{Transaction, Txid};
+
+/// Outcome of the transaction broadcast.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BroadcastResult {
+    /// The transaction was announced by the peer which didn't download it from us, so it is
+    /// propagated in the network.
+    Accepted,
+    /// The peer rejected the transaction with the reason.
+    Rejected(String),
+    /// The peers downloaded the transaction and didn't reject it in time, but it was not seen
+    /// from other peers.
+    Sent,
+    /// No peer downloaded the transaction in time.
+    TimedOut,
+}
+
+/// Callback which is called once with the outcome of the transaction broadcast.
+pub type BroadcastListener = Box<dyn FnOnce(BroadcastResult) + Send>;
+
+/// Transaction which is being broadcast.
+struct PendingBroadcast {
+    tx: Transaction,
+    listeners: Vec<BroadcastListener>,
+    started_at: Instant,
+    /// The peers which the transaction was announced to.
+    announced: HashSet<PeerID>,
+    /// The peers which downloaded the transaction.
+    sent: HashSet<PeerID>,
+    result: Option<BroadcastResult>,
+}
+
+/// This struct tracks the transactions which are broadcast through the peers. It is shared
+/// between SPV and PeerManager.
+///
+/// The transaction is announced to the peers by inv message and sent to the peers which request
+/// it by getdata message. The broadcast completes when a peer rejects it, when a peer which didn't
+/// download it from us announces it, or when the time limit expires.
+#[derive(Default)]
+pub struct BroadcastQueue {
+    pending: Vec<PendingBroadcast>,
+}
+
+impl BroadcastQueue {
+    pub fn new() -> BroadcastQueue {
+        BroadcastQueue::default()
+    }
+
+    /// Start to broadcast the transaction. The listener is called when the broadcast completes.
+    pub fn add(&mut self, tx: Transaction, listener: BroadcastListener) {
+        let txid = tx.txid();
+        if let Some(pending) = self.pending.iter_mut().find(|p| p.tx.txid() == txid) {
+            pending.listeners.push(listener);
+            return;
+        }
+
+        self.pending.push(PendingBroadcast {
+            tx,
+            listeners: vec![listener],
+            started_at: Instant::now(),
+            announced: HashSet::new(),
+            sent: HashSet::new(),
+            result: None,
+        });
+    }
+
+    /// Return the ids of the transactions which are not announced to the peer yet, and record
+    /// that they are announced.
+    pub fn announcements(&mut self, id: PeerID) -> Vec<Txid> {
+        self.pending
+            .iter_mut()
+            .filter(|pending| pending.result.is_none())
+            .filter_map(|pending| {
+                if pending.announced.insert(id) {
+                    Some(pending.tx.txid())
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Return the transaction which the peer requests, and record that it is sent.
+    pub fn on_getdata(&mut self, id: PeerID, txid: &Txid) -> Option<Transaction> {
+        let pending = self.find_pending(txid)?;
+        pending.sent.insert(id);
+        Some(pending.tx.clone())
+    }
+
+    /// Complete the broadcast of the transaction which the peer announced if the peer didn't
+    /// download it from us.
+    pub fn on_inv(&mut self, id: PeerID, txid: &Txid) {
+        if let Some(pending) = self.find_pending(txid) {
+            if !pending.sent.contains(&id) {
+                debug!("Transaction {} is announced by peer {}.", txid, id);
+                pending.result = Some(BroadcastResult::Accepted);
+            }
+        }
+    }
+
+    /// Complete the broadcast of the transaction which the peer rejected.
+    pub fn on_reject(&mut self, id: PeerID, reject: &Reject) {
+        if reject.message.as_ref() != "tx" {
+            return;
+        }
+
+        let txid = Txid::from_hash(reject.hash);
+        if let Some(pending) = self.find_pending(&txid) {
+            warn!(
+                "Transaction {} is rejected by peer {}. reason: {}",
+                txid, id, reject.reason
+            );
+            pending.result = Some(BroadcastResult::Rejected(reject.reason.to_string()));
+        }
+    }
+
+    fn find_pending(&mut self, txid: &Txid) -> Option<&mut PendingBroadcast> {
+        self.pending
+            .iter_mut()
+            .find(|pending| pending.result.is_none() && pending.tx.txid() == *txid)
+    }
+
+    /// Complete the broadcasts which are started before `timeout`.
+    pub fn check_timeouts(&mut self, timeout: Duration) {
+        for pending in self.pending.iter_mut() {
+            if pending.result.is_none() && pending.started_at.elapsed() > timeout {
+                pending.result = Some(if pending.sent.is_empty() {
+                    BroadcastResult::TimedOut
+                } else {
+                    BroadcastResult::Sent
+                });
+            }
+        }
+    }
+
+    /// Remove the completed broadcasts, and return their listeners with the outcomes. The
+    /// listeners should be called after the queue is unlocked.
+    pub fn take_completed(&mut self) -> Vec<(Vec<BroadcastListener>, BroadcastResult)> {
+        let mut completed = vec![];
+        let mut i = 0;
+        while i < self.pending.len() {
+            if self.pending[i].result.is_none() {
+                i += 1;
+                continue;
+            }
+
+            let pending = self.pending.swap_remove(i);
+            info!(
+                "Broadcast of transaction {} completed. result: {:?}",
+                pending.tx.txid(),
+                pending.result
+            );
+            completed.push((pending.listeners, pending.result.unwrap()));
+        }
+        completed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helper::get_test_genesis_block;
+    use std::borrow::Cow;
+    use std::sync::{Arc, Mutex};
+    use tapyrus::network::message_network::RejectReason;
+
+    /// Return the listener which records the outcome into `results`.
+    fn listener(results: &Arc<Mutex<Vec<BroadcastResult>>>) -> BroadcastListener {
+        let results = results.clone();
+        Box::new(move |result| results.lock().unwrap().push(result))
+    }
+
+    fn report(queue: &mut BroadcastQueue) {
+        for (listeners, result) in queue.take_completed() {
+            for listener in listeners {
+                listener(result.clone());
+            }
+        }
+    }
+
+    #[test]
+    fn test_broadcast_accepted() {
+        let tx = get_test_genesis_block().txdata[0].clone();
+        let txid = tx.txid();
+        let results = Arc::new(Mutex::new(vec![]));
+
+        let mut queue = BroadcastQueue::new();
+        queue.add(tx.clone(), listener(&results));
+        queue.add(tx.clone(), listener(&results));
+        assert_eq!(queue.announcements(1), vec![txid]);
+        assert!(queue.announcements(1).is_empty());
+        assert_eq!(queue.announcements(2), vec![txid]);
+        assert_eq!(queue.on_getdata(1, &txid), Some(tx));
+
+        // The peer which downloaded the transaction doesn't prove the propagation.
+        queue.on_inv(1, &txid);
+        report(&mut queue);
+        assert!(results.lock().unwrap().is_empty());
+
+        queue.on_inv(2, &txid);
+        report(&mut queue);
+        assert_eq!(
+            *results.lock().unwrap(),
+            vec![BroadcastResult::Accepted, BroadcastResult::Accepted]
+        );
+        assert_eq!(queue.on_getdata(1, &txid), None);
+    }
+
+    #[test]
+    fn test_broadcast_rejected() {
+        let tx = get_test_genesis_block().txdata[0].clone();
+        let txid = tx.txid();
+        let results = Arc::new(Mutex::new(vec![]));
+
+        let mut queue = BroadcastQueue::new();
+        queue.add(tx, listener(&results));
+        queue.announcements(1);
+        queue.on_getdata(1, &txid);
+        queue.on_reject(
+            1,
+            &Reject {
+                message: "tx".into(),
+                ccode: RejectReason::Fee,
+                reason: Cow::from("insufficient fee"),
+                hash: txid.as_hash(),
+            },
+        );
+        report(&mut queue);
+        assert_eq!(
+            *results.lock().unwrap(),
+            vec![BroadcastResult::Rejected("insufficient fee".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_broadcast_timeout() {
+        let tx = get_test_genesis_block().txdata[0].clone();
+        let mut other = tx.clone();
+        other.lock_time += 1;
+        let results = Arc::new(Mutex::new(vec![]));
+
+        let mut queue = BroadcastQueue::new();
+        queue.add(tx.clone(), listener(&results));
+        queue.add(other, listener(&results));
+        queue.on_getdata(1, &tx.txid());
+
+        queue.check_timeouts(Duration::from_secs(60));
+        report(&mut queue);
+        assert!(results.lock().unwrap().is_empty());
+
+        queue.check_timeouts(Duration::from_secs(0));
+        report(&mut queue);
+        assert_eq!(
+            *results.lock().unwrap(),
+            vec![BroadcastResult::Sent, BroadcastResult::TimedOut]
+        );
+    }
+}
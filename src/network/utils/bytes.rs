@@ -18,7 +18,7 @@ pub struct BytesMut<'a> {
 }
 
 impl<'a> BytesMut<'a> {
-    pub fn new(b: &'a mut bytes::BytesMut) -> BytesMut {
+    pub fn new(b: &'a mut bytes::BytesMut) -> BytesMut<'a> {
         BytesMut { inner: b }
     }
 
@@ -32,10 +32,10 @@ impl<'a> Write for BytesMut<'a> {
         let remaining_capacity = self.remaining_mut();
         if remaining_capacity >= buf.len() {
             self.inner.put_slice(buf);
-            return Ok(buf.len());
+            Ok(buf.len())
         } else {
             self.inner.put_slice(&buf[..remaining_capacity]);
-            return Ok(remaining_capacity);
+            Ok(remaining_capacity)
         }
     }
 
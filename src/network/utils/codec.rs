@@ -3,12 +3,10 @@
 // file COPYING or http://www.opensource.org/licenses/mit-license.php.
 
 use super::bytes::BytesMut;
+use crate::network::message::RawMessage;
 use byteorder::{LittleEndian, ReadBytesExt};
-use std::{io, io::ErrorKind};
-use tapyrus::{
-    consensus::{deserialize_partial, encode, Encodable},
-    network::message::RawNetworkMessage,
-};
+use std::{fmt, io, io::ErrorKind};
+use tapyrus::consensus::{deserialize_partial, encode};
 use tokio::codec::{Decoder, Encoder};
 
 #[derive(Debug)]
@@ -17,6 +15,15 @@ pub enum Error {
     Io(io::Error),
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Encode(e) => write!(f, "{}", e),
+            Error::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
 impl std::convert::From<std::io::Error> for Error {
     fn from(e: io::Error) -> Error {
         Error::Io(e)
@@ -34,11 +41,11 @@ impl NetworkMessagesCodec {
 }
 
 impl Decoder for NetworkMessagesCodec {
-    type Item = RawNetworkMessage;
+    type Item = RawMessage;
     type Error = Error;
 
-    fn decode(&mut self, src: &mut bytes::BytesMut) -> Result<Option<RawNetworkMessage>, Error> {
-        match deserialize_partial::<RawNetworkMessage>(&src) {
+    fn decode(&mut self, src: &mut bytes::BytesMut) -> Result<Option<RawMessage>, Error> {
+        match deserialize_partial::<RawMessage>(src) {
             Ok((raw_msg, consumed)) => {
                 src.advance(consumed);
                 Ok(Some(raw_msg))
@@ -65,17 +72,19 @@ impl Decoder for NetworkMessagesCodec {
 }
 
 impl Encoder for NetworkMessagesCodec {
-    type Item = RawNetworkMessage;
+    type Item = RawMessage;
     type Error = io::Error;
 
     fn encode(
         &mut self,
-        message: RawNetworkMessage,
+        message: RawMessage,
         buf: &mut bytes::BytesMut,
     ) -> Result<(), Self::Error> {
         let mut buf = BytesMut::new(buf);
 
-        message.consensus_encode(&mut buf).unwrap();
+        message
+            .encode(&mut buf)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e.to_string()))?;
         Ok(())
     }
 }
@@ -83,6 +92,7 @@ impl Encoder for NetworkMessagesCodec {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::network::message::Message;
     use crate::network::peer::version_message;
     use bytes::BufMut;
     use tapyrus::network::constants::NetworkId;
@@ -108,14 +118,14 @@ mod tests {
         let mut buf = bytes::BytesMut::with_capacity(1024);
         buf.put_slice(&data);
 
-        if let Ok(Some(RawNetworkMessage {
-            payload: NetworkMessage::Version(msg),
+        if let Ok(Some(RawMessage {
+            payload: Message::Network(NetworkMessage::Version(msg)),
             ..
         })) = codec.decode(&mut buf)
         {
             assert_eq!(msg.user_agent, "/tapyrus-spv:0.1.0/".to_string());
         } else {
-            assert!(false);
+            unreachable!();
         }
 
         assert_eq!(buf.len(), 8);
@@ -140,7 +150,7 @@ mod tests {
         if let Ok(None) = codec.decode(&mut buf) {
             assert_eq!(buf.len(), 0);
         } else {
-            assert!(false, "decode should return `Ok(None)`");
+            panic!("decode should return `Ok(None)`");
         }
     }
 
@@ -161,9 +171,9 @@ mod tests {
 
     #[test]
     fn encode_test() {
-        let msg = RawNetworkMessage {
+        let msg = RawMessage {
             magic: NetworkId::REGTEST.magic(),
-            payload: NetworkMessage::Version(version_message()),
+            payload: Message::Network(NetworkMessage::Version(version_message(0))),
         };
 
         let mut codec = NetworkMessagesCodec::new();
@@ -0,0 +1,182 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+use tapyrus::blockdata::script::Instruction;
+use tapyrus::consensus::serialize;
+use tapyrus::{OutPoint, Script, Transaction};
+
+/// Scripts and outpoints which the wallet is interested in.
+///
+/// The transactions which pay to the scripts or spend the outpoints are looked up from the
+/// network. The data elements which are matched against the transactions are kept in the order of
+/// addition, so that the peers can be told only the new ones.
+///
+/// The script may have the birth height, which is the height of the first block that can pay to
+/// it. The blocks from the birth height are scanned again when the script is added.
+///
+/// The outpoints which the unconfirmed transactions in the mempool spend are watched separately,
+/// and they are replaced as the mempool changes. Their data elements stay in the loaded bloom
+/// filters because BIP37 can't remove them, but the transactions are not matched after that.
+#[derive(Debug, Default)]
+pub struct WatchList {
+    scripts: Vec<Script>,
+    /// The birth heights of the scripts in the same order.
+    birth_heights: Vec<Option<i32>>,
+    outpoints: Vec<OutPoint>,
+    mempool_outpoints: Vec<OutPoint>,
+    elements: Vec<Vec<u8>>,
+}
+
+impl WatchList {
+    pub fn new() -> WatchList {
+        WatchList::default()
+    }
+
+    /// Watch the transactions which pay to `script`. Return false if it is already watched.
+    pub fn add_script(&mut self, script: Script) -> bool {
+        self.add_script_with_birth_height(script, None)
+    }
+
+    /// Watch the transactions which pay to `script` in the blocks from `birth_height`. Return
+    /// false if it is already watched.
+    pub fn add_script_with_birth_height(
+        &mut self,
+        script: Script,
+        birth_height: Option<i32>,
+    ) -> bool {
+        if self.scripts.contains(&script) {
+            return false;
+        }
+
+        // The peers match the data pushes in the scripts, such as the public key hash.
+        for instruction in script.instructions() {
+            if let Ok(Instruction::PushBytes(data)) = instruction {
+                if !data.is_empty() && !self.elements.iter().any(|e| e[..] == *data) {
+                    self.elements.push(data.to_vec());
+                }
+            }
+        }
+        self.scripts.push(script);
+        self.birth_heights.push(birth_height);
+        true
+    }
+
+    /// Watch the transactions which spend `outpoint`. Return false if it is already watched.
+    pub fn add_outpoint(&mut self, outpoint: OutPoint) -> bool {
+        if self.outpoints.contains(&outpoint) {
+            return false;
+        }
+
+        self.add_element(serialize(&outpoint));
+        self.outpoints.push(outpoint);
+        true
+    }
+
+    /// Watch the transactions which spend `outpoints` instead of the outpoints which the
+    /// mempool spent before.
+    pub fn set_mempool_outpoints(&mut self, outpoints: Vec<OutPoint>) {
+        for outpoint in outpoints.iter() {
+            self.add_element(serialize(outpoint));
+        }
+        self.mempool_outpoints = outpoints;
+    }
+
+    fn add_element(&mut self, element: Vec<u8>) {
+        if !self.elements.contains(&element) {
+            self.elements.push(element);
+        }
+    }
+
+    /// Return the watched scripts in the order of addition.
+    pub fn scripts(&self) -> &[Script] {
+        &self.scripts
+    }
+
+    /// Return the lowest birth height of the scripts which are added after the first `count`
+    /// scripts.
+    pub fn birth_height_after(&self, count: usize) -> Option<i32> {
+        self.birth_heights
+            .iter()
+            .skip(count)
+            .filter_map(|height| *height)
+            .min()
+    }
+
+    /// Return true if the transaction pays to the watched scripts or spends the watched
+    /// outpoints.
+    pub fn matches(&self, tx: &Transaction) -> bool {
+        tx.output
+            .iter()
+            .any(|output| self.scripts.contains(&output.script_pubkey))
+            || tx.input.iter().any(|input| {
+                self.outpoints.contains(&input.previous_output)
+                    || self.mempool_outpoints.contains(&input.previous_output)
+            })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.elements.is_empty()
+    }
+
+    /// Return the data elements to match in the order of addition.
+    pub fn elements(&self) -> &[Vec<u8>] {
+        &self.elements
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helper::get_test_genesis_block;
+
+    #[test]
+    fn test_elements() {
+        let genesis = get_test_genesis_block();
+        let coinbase = &genesis.txdata[0];
+        let script = coinbase.output[0].script_pubkey.clone();
+        let outpoint = OutPoint::new(coinbase.txid(), 0);
+
+        let mut list = WatchList::new();
+        assert!(list.is_empty());
+
+        // The script is split into the data pushes.
+        assert!(list.add_script(script.clone()));
+        assert!(!list.add_script(script));
+        assert_eq!(list.elements().len(), 1);
+        assert_eq!(list.elements()[0].len(), 34);
+
+        assert!(list.matches(coinbase));
+
+        assert_eq!(list.birth_height_after(0), None);
+        assert!(list.add_script_with_birth_height(Script::new(), Some(10)));
+        assert_eq!(list.birth_height_after(0), Some(10));
+        assert_eq!(list.birth_height_after(2), None);
+        assert_eq!(list.elements().len(), 1);
+
+        assert!(list.add_outpoint(outpoint));
+        assert!(!list.add_outpoint(outpoint));
+        assert_eq!(list.elements().len(), 2);
+        assert_eq!(list.elements()[1], serialize(&outpoint));
+    }
+
+    #[test]
+    fn test_mempool_outpoints() {
+        let coinbase = get_test_genesis_block().txdata[0].clone();
+        let mut tx = coinbase.clone();
+        tx.output.clear();
+        let outpoint = tx.input[0].previous_output;
+
+        let mut list = WatchList::new();
+        assert!(!list.matches(&tx));
+        list.set_mempool_outpoints(vec![outpoint]);
+        assert!(list.matches(&tx));
+        assert_eq!(list.elements(), &[serialize(&outpoint)]);
+
+        // The element is kept for the peers which it is sent to.
+        list.set_mempool_outpoints(vec![]);
+        assert!(!list.matches(&tx));
+        list.set_mempool_outpoints(vec![outpoint]);
+        assert_eq!(list.elements().len(), 1);
+    }
+}
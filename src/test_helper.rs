@@ -4,10 +4,27 @@
 
 use crate::chain::store::OnMemoryChainStore;
 use crate::chain::{BlockIndex, Chain, ChainStore};
-use crate::network::Error;
+use crate::chain_params::ChainParams;
+use crate::network::bloom_filter::BloomFilter;
+use crate::network::{Error, Message, RawMessage};
 use hex::decode as hex_decode;
+use std::path::PathBuf;
+use tapyrus::blockdata::block::XField;
+use tapyrus::blockdata::script::Instruction;
 use tapyrus::consensus::deserialize;
-use tapyrus::{Block, BlockHash, BlockHeader};
+use tapyrus::hash_types::FilterHash;
+use tapyrus::hashes::Hash;
+use tapyrus::network::address::Address;
+use tapyrus::network::constants::NetworkId;
+use tapyrus::network::message::NetworkMessage;
+use tapyrus::network::message_blockdata::Inventory;
+use tapyrus::network::message_filter::{CFHeaders, CFilter};
+use tapyrus::network::message_network::{Reject, RejectReason};
+use tapyrus::secp256k1::SecretKey;
+use tapyrus::util::bip158::BlockFilter;
+use tapyrus::{
+    Block, BlockHash, BlockHeader, MerkleBlock, Network, PrivateKey, Signature, Transaction, Txid,
+};
 use tokio::prelude::*;
 use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 
@@ -128,11 +145,59 @@ pub static HEADER_STRINGS: [&str; 100] = [
     "01000000ac6017ade9a79940e5380d43fff8bdf713e81bbd9fdb691144c2e82ffe7f49fc41ac5269352c4f285d893943de4c95666a31ff6bf2802350480f088e1bc94aadc706f028137081e7266f723638d08e82ebcb2be2b3ac1c2d423dff5152e34a38f76ac860004060201a3c0e0afd2a75f1a093068366a506077cf9420405f7ad0e49c71a47f0b6965040c060f2fac9c87a0c70440d1c0a8b91b0ff11cefc1cf88a62e76c11bc63"
 ];
 
-pub static GENESIS_BLOCK_HEX: &str = "010000000000000000000000000000000000000000000000000000000000000000000000623fd6e71aaec98e129d8b447ba7c6fe88cd27346cc556353d2d8232a2829f0a49b4a19f4dc3f0526dca905dcaff6a8e34537d04b450e0ac5568ce89a9373e301665c860012102260b9be70a87125fd0e2da368db857a2d8ee1cb85a3c8b81490f4f35f99b212a40f457d5dd7caae6bf89a50efd13cf4a9e3857760f747e107d1184263e1212ef98cda52410bd822e92c44b4a22a2f6116a0df2b130afe315af6a7289567b32c1ca01010000000100000000000000000000000000000000000000000000000000000000000000000000000000ffffffff0100f2052a010000002776a9226d6b597162714c54584e52344568747853376e37734e5357385646546f314e55336e88ac00000000";
+/// The private key for the aggregate public key in the genesis block.
+pub static GENESIS_PRIVATE_KEY_WIF: &str = "cSo6nWAdX4NhjUb6AXMNnNGedRfN2budY2UednwBs6UxVxmhEWui";
+
+pub fn get_test_private_key() -> PrivateKey {
+    PrivateKey::from_wif(GENESIS_PRIVATE_KEY_WIF).unwrap()
+}
+
+/// Return private key which is derived from seed. It is useful for making another federation.
+pub fn private_key_from_seed(seed: u8) -> PrivateKey {
+    PrivateKey {
+        compressed: true,
+        network: Network::Dev,
+        key: SecretKey::from_slice(&[seed; 32]).unwrap(),
+    }
+}
+
+/// Build block header which is connected to `prev` and signed by `private_key`.
+pub fn build_signed_header(
+    prev: &BlockHeader,
+    xfield: XField,
+    private_key: &PrivateKey,
+) -> BlockHeader {
+    let mut header = BlockHeader {
+        version: prev.version,
+        prev_blockhash: prev.block_hash(),
+        merkle_root: prev.merkle_root,
+        im_merkle_root: prev.im_merkle_root,
+        time: prev.time + 1,
+        xfield,
+        proof: None,
+    };
+    let sighash = header.signature_hash().into_inner();
+    header.proof = Some(Signature::sign(private_key, &sighash).unwrap());
+    header
+}
+
+/// Build branch which has `count` blocks on `prev`.
+pub fn build_branch(prev: &BlockHeader, count: usize) -> Vec<BlockHeader> {
+    let private_key = get_test_private_key();
+    let mut prev = prev.clone();
+    let mut branch = Vec::new();
+
+    for _ in 0..count {
+        let header = build_signed_header(&prev, XField::None, &private_key);
+        prev = header.clone();
+        branch.push(header);
+    }
+
+    branch
+}
 
 pub fn get_test_genesis_block() -> Block {
-    let bytes = hex_decode(GENESIS_BLOCK_HEX).unwrap();
-    deserialize(&bytes).unwrap()
+    ChainParams::regtest().genesis
 }
 
 pub fn get_test_block_hash(height: usize) -> BlockHash {
@@ -163,10 +228,15 @@ pub fn get_test_headers(start: usize, count: usize) -> Vec<BlockHeader> {
 // return initialized chain
 pub fn get_chain() -> Chain<OnMemoryChainStore> {
     let mut store = OnMemoryChainStore::new();
-    store.initialize(get_test_genesis_block());
+    store.initialize(get_test_genesis_block()).unwrap();
     Chain::new(store)
 }
 
+/// Return path of new temporary directory for test. The directory is not created.
+pub fn temp_dir(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("tapyrus-spv-{}-{}", name, rand::random::<u32>()))
+}
+
 pub struct TwoWayChannel<T> {
     sender: UnboundedSender<T>,
     receiver: UnboundedReceiver<T>,
@@ -196,19 +266,15 @@ impl<T> Sink for TwoWayChannel<T> {
         &mut self,
         item: Self::SinkItem,
     ) -> Result<AsyncSink<Self::SinkItem>, Self::SinkError> {
-        self.sender
-            .start_send(item)
-            .map_err(|e| Self::SinkError::from(e))
+        self.sender.start_send(item).map_err(Self::SinkError::from)
     }
 
     fn poll_complete(&mut self) -> Result<Async<()>, Self::SinkError> {
-        self.sender
-            .poll_complete()
-            .map_err(|e| Self::SinkError::from(e))
+        self.sender.poll_complete().map_err(Self::SinkError::from)
     }
 
     fn close(&mut self) -> Result<Async<()>, Self::SinkError> {
-        self.sender.close().map_err(|e| Self::SinkError::from(e))
+        self.sender.close().map_err(Self::SinkError::from)
     }
 }
 
@@ -217,6 +283,337 @@ impl<T> Stream for TwoWayChannel<T> {
     type Error = Error;
 
     fn poll(&mut self) -> Result<Async<Option<Self::Item>>, Self::Error> {
-        self.receiver.poll().map_err(|e| Self::Error::from(e))
+        self.receiver.poll().map_err(Self::Error::from)
+    }
+}
+
+/// Stand-in for remote peer. It serves block headers for getheaders messages, merkle blocks
+/// which match the loaded bloom filter, and compact block filters. Only the genesis block has the
+/// transactions.
+pub struct FakePeer {
+    stream: TwoWayChannel<RawMessage>,
+    headers: Vec<BlockHeader>,
+    max_headers_results: usize,
+    on_getheaders: Option<Box<dyn FnMut() + Send>>,
+    addresses: Vec<(u32, Address)>,
+    disconnect_after_addr: bool,
+    disconnected: bool,
+    ignore_getheaders: bool,
+    announcements: Vec<BlockHeader>,
+    filter: Option<BloomFilter>,
+    reject_transactions: bool,
+    know_transactions: bool,
+    mempool: Vec<Transaction>,
+}
+
+impl FakePeer {
+    /// `headers` should start with genesis block header.
+    pub fn new(
+        stream: TwoWayChannel<RawMessage>,
+        headers: Vec<BlockHeader>,
+        max_headers_results: usize,
+    ) -> FakePeer {
+        FakePeer {
+            stream,
+            headers,
+            max_headers_results,
+            on_getheaders: None,
+            addresses: vec![],
+            disconnect_after_addr: false,
+            disconnected: false,
+            ignore_getheaders: false,
+            announcements: vec![],
+            filter: None,
+            reject_transactions: false,
+            know_transactions: false,
+            mempool: vec![],
+        }
+    }
+
+    /// Set addresses which the peer sends in response to getaddr message. If
+    /// `disconnect_after_addr` is true, the peer disconnects after sending them.
+    pub fn with_addresses(
+        mut self,
+        addresses: Vec<(u32, Address)>,
+        disconnect_after_addr: bool,
+    ) -> FakePeer {
+        self.addresses = addresses;
+        self.disconnect_after_addr = disconnect_after_addr;
+        self
+    }
+
+    /// Set callback which is called when the peer receives getheaders message.
+    pub fn on_getheaders<F: FnMut() + Send + 'static>(mut self, f: F) -> FakePeer {
+        self.on_getheaders = Some(Box::new(f));
+        self
+    }
+
+    /// Make the peer stall by never answering getheaders message.
+    pub fn ignore_getheaders(mut self) -> FakePeer {
+        self.ignore_getheaders = true;
+        self
+    }
+
+    /// Set new blocks which are announced after the peer receives sendheaders message. The first
+    /// one is announced by headers message, and the last one is announced by inv message.
+    pub fn announce(mut self, headers: Vec<BlockHeader>) -> FakePeer {
+        self.announcements = headers;
+        self
+    }
+
+    /// Make the peer reject the transactions which it downloads.
+    pub fn reject_transactions(mut self) -> FakePeer {
+        self.reject_transactions = true;
+        self
+    }
+
+    /// Make the peer pretend to have received the announced transactions from other nodes, so
+    /// that it announces them back instead of downloading.
+    pub fn know_transactions(mut self) -> FakePeer {
+        self.know_transactions = true;
+        self
+    }
+
+    /// Set unconfirmed transactions which are announced after the bloom filter is loaded.
+    pub fn with_mempool(mut self, txs: Vec<Transaction>) -> FakePeer {
+        self.mempool = txs;
+        self
+    }
+
+    pub fn send<M: Into<Message>>(&mut self, message: M) {
+        let _ = self.stream.start_send(RawMessage {
+            magic: NetworkId::REGTEST.magic(),
+            payload: message.into(),
+        });
+    }
+
+    fn process_message(&mut self, message: Message) {
+        let message = match message {
+            Message::Network(message) => message,
+            Message::FilterLoad(message) => {
+                self.filter = Some(BloomFilter::from(message));
+                let inventory: Vec<Inventory> = self
+                    .mempool
+                    .iter()
+                    .map(|tx| Inventory::Transaction(tx.txid()))
+                    .collect();
+                if !inventory.is_empty() {
+                    self.send(NetworkMessage::Inv(inventory));
+                }
+                return;
+            }
+            Message::FilterAdd(message) => {
+                if let Some(filter) = self.filter.as_mut() {
+                    filter.insert(&message.data);
+                }
+                return;
+            }
+            Message::GetMerkleBlocks(hashes) => {
+                for hash in hashes {
+                    self.send_merkle_block(&hash);
+                }
+                return;
+            }
+            Message::MerkleBlock(_) => return,
+        };
+
+        if let NetworkMessage::Ping(nonce) = message {
+            self.send(NetworkMessage::Pong(nonce));
+        }
+
+        if let NetworkMessage::SendHeaders = message {
+            let announcements = std::mem::take(&mut self.announcements);
+            if let (Some(first), Some(last)) = (announcements.first(), announcements.last()) {
+                self.send(NetworkMessage::Headers(vec![first.clone()]));
+                self.send(NetworkMessage::Inv(vec![Inventory::Block(
+                    last.block_hash(),
+                )]));
+            }
+            self.headers.extend(announcements);
+        }
+
+        if let NetworkMessage::GetAddr = message {
+            self.send(NetworkMessage::Addr(self.addresses.clone()));
+            self.disconnected = self.disconnect_after_addr;
+        }
+
+        if let NetworkMessage::GetCFHeaders(ref request) = message {
+            if let Some(stop) = self.position(&request.stop_hash) {
+                let start = request.start_height as usize;
+                let filters: Vec<BlockFilter> = (0..=stop).map(|i| self.filter(i)).collect();
+                let previous_filter = filters[..start]
+                    .iter()
+                    .fold(FilterHash::default(), |prev, filter| {
+                        filter.filter_id(&prev)
+                    });
+                self.send(NetworkMessage::CFHeaders(CFHeaders {
+                    filter_type: request.filter_type,
+                    stop_hash: request.stop_hash,
+                    previous_filter,
+                    filter_hashes: filters[start..]
+                        .iter()
+                        .map(|filter| FilterHash::hash(&filter.content))
+                        .collect(),
+                }));
+            }
+        }
+
+        if let NetworkMessage::GetCFilters(ref request) = message {
+            if let Some(stop) = self.position(&request.stop_hash) {
+                for i in request.start_height as usize..=stop {
+                    self.send(NetworkMessage::CFilter(CFilter {
+                        filter_type: request.filter_type,
+                        block_hash: self.headers[i].block_hash(),
+                        filter: self.filter(i).content,
+                    }));
+                }
+            }
+        }
+
+        if let NetworkMessage::Inv(ref inventory) = message {
+            let transactions: Vec<Inventory> = inventory
+                .iter()
+                .filter(|inv| matches!(inv, Inventory::Transaction(_)))
+                .cloned()
+                .collect();
+            if !transactions.is_empty() {
+                if self.know_transactions {
+                    self.send(NetworkMessage::Inv(transactions));
+                } else {
+                    self.send(NetworkMessage::GetData(transactions));
+                }
+            }
+        }
+
+        if let NetworkMessage::Tx(ref tx) = message {
+            if self.reject_transactions {
+                self.send(NetworkMessage::Reject(Reject {
+                    message: "tx".into(),
+                    ccode: RejectReason::Fee,
+                    reason: "insufficient fee".into(),
+                    hash: tx.txid().as_hash(),
+                }));
+            }
+        }
+
+        if let NetworkMessage::GetData(inventory) = message {
+            let genesis = get_test_genesis_block();
+            for inv in inventory {
+                if inv == Inventory::Block(genesis.block_hash()) {
+                    self.send(NetworkMessage::Block(genesis.clone()));
+                }
+                if let Some(tx) = self
+                    .mempool
+                    .iter()
+                    .find(|tx| inv == Inventory::Transaction(tx.txid()))
+                {
+                    let tx = tx.clone();
+                    self.send(NetworkMessage::Tx(tx));
+                }
+            }
+            return;
+        }
+
+        if let NetworkMessage::GetHeaders(getheaders) = message {
+            if let Some(f) = self.on_getheaders.as_mut() {
+                f();
+            }
+            if self.ignore_getheaders {
+                return;
+            }
+
+            // Find the fork point from locator and send the following headers.
+            let start = getheaders
+                .locator_hashes
+                .iter()
+                .find_map(|hash| self.headers.iter().position(|h| h.block_hash() == *hash))
+                .map_or(0, |i| i + 1);
+            let end = std::cmp::min(start + self.max_headers_results, self.headers.len());
+            let headers = self.headers[start..end].to_vec();
+            self.send(NetworkMessage::Headers(headers));
+        }
+    }
+
+    fn position(&self, hash: &BlockHash) -> Option<usize> {
+        self.headers.iter().position(|h| h.block_hash() == *hash)
+    }
+
+    /// Return the basic filter of the block at the index. The filters of the blocks other than
+    /// the genesis block are empty.
+    fn filter(&self, index: usize) -> BlockFilter {
+        let genesis = get_test_genesis_block();
+        if self.headers[index] == genesis.header {
+            BlockFilter::new_script_filter(&genesis, |_| unreachable!("no input to look up"))
+                .unwrap()
+        } else {
+            BlockFilter::new(&[0])
+        }
+    }
+
+    /// Send the merkle block and the matched transactions. The transaction matches if any data
+    /// push in its output scripts is in the filter.
+    fn send_merkle_block(&mut self, hash: &BlockHash) {
+        let header = match self.headers.iter().find(|h| h.block_hash() == *hash) {
+            Some(header) => header.clone(),
+            None => return,
+        };
+
+        let genesis = get_test_genesis_block();
+        let txs: Vec<Transaction> = if header == genesis.header {
+            genesis.txdata.clone()
+        } else {
+            vec![]
+        };
+        let matched: Vec<Transaction> = txs
+            .into_iter()
+            .filter(|tx| {
+                tx.output.iter().any(|output| {
+                    output.script_pubkey.instructions().any(|instruction| {
+                        match (instruction, self.filter.as_ref()) {
+                            (Ok(Instruction::PushBytes(data)), Some(filter)) => {
+                                filter.contains(data)
+                            }
+                            _ => false,
+                        }
+                    })
+                })
+            })
+            .collect();
+
+        let block = if header == genesis.header {
+            let txids = matched.iter().map(|tx| tx.txid()).collect();
+            MerkleBlock::from_block(&genesis, &txids)
+        } else {
+            // The other blocks pretend to have single transaction which doesn't match.
+            let txid = Txid::from_hash(header.merkle_root.as_hash());
+            MerkleBlock::from_header_txids(&header, &[txid], &Default::default())
+        };
+        self.send(Message::MerkleBlock(block));
+        for tx in matched {
+            self.send(NetworkMessage::Tx(tx));
+        }
+    }
+}
+
+impl Future for FakePeer {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Result<Async<()>, ()> {
+        loop {
+            if self.disconnected {
+                let _ = self.stream.poll_complete();
+                return Ok(Async::Ready(()));
+            }
+
+            match self.stream.poll() {
+                Ok(Async::Ready(Some(message))) => self.process_message(message.payload),
+                Ok(Async::Ready(None)) => return Ok(Async::Ready(())),
+                Ok(Async::NotReady) => break,
+                Err(_) => return Err(()),
+            }
+        }
+        let _ = self.stream.poll_complete();
+        Ok(Async::NotReady)
     }
 }
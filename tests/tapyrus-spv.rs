@@ -2,11 +2,14 @@
 // Distributed under the MIT software license, see the accompanying
 // file COPYING or http://www.opensource.org/licenses/mit-license.php.
 
+//! Integration tests for tapyrus-spv.
+
 #![deny(warnings, missing_docs)]
 
 extern crate tapyrus_spv;
 
 #[test]
+#[allow(clippy::assertions_on_constants)]
 fn sample() {
     assert!(true);
 }